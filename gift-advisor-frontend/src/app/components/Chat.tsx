@@ -1,45 +1,160 @@
 import { useEffect, useRef, useState } from 'react';
-import { useChatWebSocket } from '../hooks/useWebSocket';
+import { Payload, QuickReply, RecommendationCard, ServerEnvelope, useChatWebSocket } from '../hooks/useWebSocket';
 
 interface Message {
   user_id: string;
   message: string;
   message_type: 'user_message' | 'bot_response' | 'typing' | 'error';
+  payloads?: Payload[];
+  // 生成中に届いた推薦（応答が揃うまでの仮表示）
+  streamed?: RecommendationCard[];
+}
+
+// 生成中の途中経過をタイピング表示に反映する。扱わないフレームは `null`
+function applyStreaming(messages: Message[], frame: ServerEnvelope): Message[] | null {
+  if (frame.type !== 'bot_delta' && frame.type !== 'recommendation') {
+    return null;
+  }
+  return messages.map(m => {
+    if (m.message_type !== 'typing') return m;
+    return frame.type === 'bot_delta'
+      ? { ...m, message: (m.message === '...' ? '' : m.message) + frame.text }
+      : { ...m, streamed: [...(m.streamed ?? []), frame.card] };
+  });
+}
+
+// 表示に使うフレームだけをメッセージに変換する
+function toMessage(frame: ServerEnvelope): Message | null {
+  switch (frame.type) {
+    case 'typing':
+      return { user_id: 'bot', message: '...', message_type: 'typing' };
+    case 'bot_response':
+      return { user_id: 'bot', message: frame.text, message_type: 'bot_response', payloads: frame.payloads };
+    case 'error':
+      return { user_id: 'system', message: frame.message, message_type: 'error' };
+    default:
+      return null;
+  }
+}
+
+function PayloadView({ payload, onReply }: { payload: Payload; onReply: (reply: QuickReply) => void }) {
+  const button = (reply: QuickReply) => (
+    <button
+      key={reply.value}
+      onClick={() => onReply(reply)}
+      className="px-3 py-1 mr-2 mt-2 border border-blue-500 text-blue-600 rounded-full hover:bg-blue-50"
+    >
+      {reply.label}
+    </button>
+  );
+
+  switch (payload.kind) {
+    case 'text':
+      return <p>{payload.text}</p>;
+    case 'quick_replies':
+      return (
+        <div>
+          <p>{payload.prompt}</p>
+          <div>{payload.options.map(button)}</div>
+        </div>
+      );
+    case 'confirmation':
+      return (
+        <div>
+          <p>{payload.prompt}</p>
+          <ul className="my-2 text-sm">
+            {payload.summary.map(item => (
+              <li key={item.label}>{item.label}: {item.value}</li>
+            ))}
+          </ul>
+          <div>{[payload.confirm, payload.cancel].map(button)}</div>
+        </div>
+      );
+    case 'recommendation_cards':
+      return (
+        <div className="grid grid-cols-1 gap-2 mt-2">
+          {payload.cards.map(card => (
+            <div key={card.name} className="bg-white rounded-lg shadow p-3">
+              <div className="flex justify-between">
+                <span className="font-semibold">{card.name}</span>
+                <span className="font-bold">¥{card.price.toLocaleString()}</span>
+              </div>
+              <p className="text-sm text-gray-500">
+                {card.store_url ? (
+                  <a href={card.store_url} target="_blank" rel="noopener noreferrer" className="underline">{card.store}</a>
+                ) : card.store}
+              </p>
+              <p className="text-sm mt-1">{card.reason}</p>
+              <p className="text-xs text-gray-600 mt-1">マナー: {card.manner_advice}</p>
+              {card.url && (
+                <a href={card.url} target="_blank" rel="noopener noreferrer" className="text-xs text-blue-600 underline">商品ページ</a>
+              )}
+              {card.sources && card.sources.length > 0 && (
+                <ul className="text-xs text-gray-500 mt-1">
+                  {card.sources.map((source, index) => (
+                    <li key={source.url}>
+                      <a href={source.url} target="_blank" rel="noopener noreferrer" className="underline">
+                        [{index + 1}] {source.title ?? new URL(source.url).hostname}
+                      </a>
+                    </li>
+                  ))}
+                </ul>
+              )}
+            </div>
+          ))}
+        </div>
+      );
+    case 'deadline_reminder':
+      return <p className="mt-2 text-sm text-orange-700">⏰ {payload.message}</p>;
+    default:
+      return <p>{(payload as Payload).fallback}</p>;
+  }
 }
 
 export default function Chat() {
   const [messages, setMessages] = useState<Message[]>([]);
   const [input, setInput] = useState('');
   const messagesEndRef = useRef<HTMLDivElement>(null);
-  const { sendMessage, lastMessage, readyState } = useChatWebSocket();
-
-  useEffect(() => {
-    if (lastMessage) {
-      try {
-        const message = JSON.parse(lastMessage.data) as Message;
-        setMessages(prev => [...prev, message]);
-      } catch (error) {
-        console.error('Failed to parse message:', error);
+  // この端末から送ったメッセージのID。他の端末の発言だけを表示に加える
+  const sentIdsRef = useRef(new Set<string>());
+  const { sendMessage, readyState } = useChatWebSocket(frame => {
+    if (frame.type === 'user_message') {
+      if (!sentIdsRef.current.has(frame.id)) {
+        setMessages(prev => [...prev, { user_id: frame.user_id, message: frame.text, message_type: 'user_message' }]);
       }
+      return;
+    }
+    if (frame.type === 'bot_delta' || frame.type === 'recommendation') {
+      setMessages(prev => applyStreaming(prev, frame) ?? prev);
+      return;
+    }
+    const message = toMessage(frame);
+    if (message) {
+      // 応答が届いたらタイピング表示を消す
+      setMessages(prev => [...prev.filter(m => m.message_type !== 'typing'), message]);
     }
-  }, [lastMessage]);
+  });
 
   useEffect(() => {
     messagesEndRef.current?.scrollIntoView({ behavior: 'smooth' });
   }, [messages]);
 
-  const handleSubmit = (e: React.FormEvent) => {
-    e.preventDefault();
-    if (!input.trim()) return;
-
+  const send = (text: string) => {
     const message: Message = {
       user_id: 'user',
-      message: input,
+      message: text,
       message_type: 'user_message',
     };
 
-    sendMessage(JSON.stringify(message));
+    const id = sendMessage(message.message);
+    if (id) sentIdsRef.current.add(id);
     setMessages(prev => [...prev, message]);
+  };
+
+  const handleSubmit = (e: React.FormEvent) => {
+    e.preventDefault();
+    if (!input.trim()) return;
+    send(input);
     setInput('');
   };
 
@@ -66,12 +181,21 @@ export default function Chat() {
                   : 'bg-gray-100'
               }`}
             >
-              {msg.message_type === 'typing' ? (
+              {msg.message_type === 'typing' && msg.streamed?.length ? (
+                <PayloadView
+                  payload={{ kind: 'recommendation_cards', cards: msg.streamed, source: 'llm', fallback: '' }}
+                  onReply={reply => send(reply.value)}
+                />
+              ) : msg.message_type === 'typing' ? (
                 <div className="flex space-x-2">
                   <div className="w-2 h-2 bg-gray-500 rounded-full animate-bounce" />
                   <div className="w-2 h-2 bg-gray-500 rounded-full animate-bounce delay-100" />
                   <div className="w-2 h-2 bg-gray-500 rounded-full animate-bounce delay-200" />
                 </div>
+              ) : msg.payloads?.length ? (
+                msg.payloads.map((payload, i) => (
+                  <PayloadView key={i} payload={payload} onReply={reply => send(reply.value)} />
+                ))
               ) : (
                 msg.message
               )}
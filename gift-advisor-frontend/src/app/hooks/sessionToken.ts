@@ -0,0 +1,47 @@
+const API_URL = process.env.NEXT_PUBLIC_API_URL || 'http://localhost:3001';
+const STORAGE_KEY = 'gift-advisor.session-token';
+// 期限の直前に切れないよう、残りがこの秒数を下回ったら更新する
+const REFRESH_MARGIN_SECONDS = 60;
+
+// サーバーの src/app/auth.rs の IssuedToken と対応する
+interface IssuedToken {
+  token: string;
+  token_type: string;
+  user_id: string;
+  expires_at: number;
+  expires_in: number;
+}
+
+function load(): IssuedToken | null {
+  try {
+    const stored = localStorage.getItem(STORAGE_KEY);
+    return stored ? (JSON.parse(stored) as IssuedToken) : null;
+  } catch {
+    return null;
+  }
+}
+
+async function request(path: string, token?: string): Promise<IssuedToken | null> {
+  const response = await fetch(`${API_URL}${path}`, {
+    method: 'POST',
+    headers: token ? { Authorization: `Bearer ${token}` } : undefined,
+  });
+  return response.ok ? ((await response.json()) as IssuedToken) : null;
+}
+
+// 保存済みのトークンを返す。期限が近ければ更新し、使えなければ匿名のユーザーとして発行し直す
+export async function getAccessToken(): Promise<string> {
+  const now = Date.now() / 1000;
+  let issued = load();
+  if (issued && issued.expires_at - now < REFRESH_MARGIN_SECONDS) {
+    issued = issued.expires_at > now ? await request('/auth/refresh', issued.token) : null;
+  }
+  if (!issued) {
+    issued = await request('/auth/anonymous');
+  }
+  if (!issued) {
+    throw new Error('セッショントークンを取得できませんでした');
+  }
+  localStorage.setItem(STORAGE_KEY, JSON.stringify(issued));
+  return issued.token;
+}
@@ -1,20 +1,107 @@
 import { useCallback, useEffect, useRef } from 'react';
 import useWebSocket, { ReadyState } from 'react-use-websocket';
+import { getAccessToken } from './sessionToken';
 import { useIsClient } from './useIsClient';
 
 const WS_URL = process.env.NEXT_PUBLIC_WS_URL || 'ws://localhost:3001/ws';
 
-export function useChatWebSocket() {
+// サーバーの src/app/chat/protocol.rs と対応する（docs/websocket_protocol.schema.json）
+export const PROTOCOL_VERSION = 1;
+
+export interface QuickReply {
+  label: string;
+  value: string;
+}
+
+export interface RecommendationCard {
+  name: string;
+  price: number;
+  store: string;
+  reason: string;
+  manner_advice: string;
+  url?: string;
+  store_url?: string;
+  sources?: SourceLink[];
+}
+
+export interface SourceLink {
+  url: string;
+  title?: string;
+}
+
+// 表示用の部品。対応していない種類は `fallback` をそのまま表示する
+export type Payload = { fallback: string } & (
+  | { kind: 'text'; text: string }
+  | { kind: 'quick_replies'; slot: string; prompt: string; options: QuickReply[] }
+  | { kind: 'recommendation_cards'; cards: RecommendationCard[]; source: 'llm' | 'catalog' }
+  | { kind: 'confirmation'; prompt: string; summary: { label: string; value: string }[]; confirm: QuickReply; cancel: QuickReply }
+  | { kind: 'deadline_reminder'; due_within_days: number; message: string }
+);
+
+export type ServerFrame =
+  | { type: 'welcome'; session_id: string; resumed: boolean; replayed: number; truncated?: boolean }
+  | { type: 'ack'; id: string; duplicate?: boolean }
+  | { type: 'user_message'; id: string; user_id: string; text: string }
+  | { type: 'typing'; reply_to: string }
+  | { type: 'done'; reply_to: string }
+  | { type: 'bot_delta'; reply_to: string; text: string }
+  | { type: 'recommendation'; reply_to: string; index: number; card: RecommendationCard }
+  | { type: 'bot_response'; reply_to: string; text: string; payloads?: Payload[] }
+  | { type: 'error'; code: string; message: string; reply_to?: string; retry_after_ms?: number }
+  | { type: 'server_restarting'; reconnect_after_ms: number };
+
+export type ServerEnvelope = ServerFrame & { v: number; seq?: number };
+
+export function useChatWebSocket(onFrame: (frame: ServerEnvelope) => void) {
   const isClient = useIsClient();
-  
-  const { sendMessage: wssSendMessage, lastMessage, readyState } = useWebSocket(
-    isClient ? WS_URL : null,
+  // 再接続時にセッションを再開し、受信できなかったメッセージを再送してもらう
+  const sessionIdRef = useRef<string | null>(null);
+  const lastSeqRef = useRef(0);
+  const onFrameRef = useRef(onFrame);
+  onFrameRef.current = onFrame;
+
+  // 接続のたびにトークンを取得する。ブラウザのWebSocketはヘッダーを付けられないため、クエリで送る
+  const getUrl = useCallback(async () => {
+    const token = await getAccessToken();
+    return `${WS_URL}?access_token=${encodeURIComponent(token)}`;
+  }, []);
+
+  const { sendJsonMessage, readyState } = useWebSocket(
+    isClient ? getUrl : null,
     {
       shouldReconnect: (closeEvent) => true,
       reconnectAttempts: 10,
       reconnectInterval: 3000,
       onOpen: () => {
         console.log('WebSocket接続が確立されました');
+        sendJsonMessage({
+          v: PROTOCOL_VERSION,
+          type: 'hello',
+          session_id: sessionIdRef.current ?? undefined,
+          last_seq: sessionIdRef.current ? lastSeqRef.current : undefined,
+        });
+      },
+      onMessage: (event: MessageEvent) => {
+        let envelope: ServerEnvelope;
+        try {
+          envelope = JSON.parse(event.data) as ServerEnvelope;
+        } catch (error) {
+          console.error('Failed to parse message:', error);
+          return;
+        }
+        if (envelope.type === 'welcome') {
+          if (!envelope.resumed) {
+            lastSeqRef.current = 0;
+          }
+          sessionIdRef.current = envelope.session_id;
+        }
+        if (envelope.seq !== undefined) {
+          // 再送で重複して届いたものは無視する
+          if (envelope.seq <= lastSeqRef.current) return;
+          lastSeqRef.current = envelope.seq;
+          sendJsonMessage({ v: PROTOCOL_VERSION, type: 'ack', seq: envelope.seq });
+        }
+        onFrameRef.current(envelope);
       },
       onClose: (event) => {
         console.log('WebSocket接続が切断されました', {
@@ -32,14 +119,17 @@ export function useChatWebSocket() {
     }
   );
 
-  const sendMessage = useCallback((message: string) => {
+  // 送信したメッセージのIDを返す。応答の `reply_to` と対応する
+  const sendMessage = useCallback((text: string) => {
     if (!isClient) {
       console.warn('クライアントサイドでのみメッセージを送信できます');
-      return;
+      return null;
     }
-    console.log('送信メッセージ:', message);
-    wssSendMessage(message);
-  }, [wssSendMessage, isClient]);
+    const id = crypto.randomUUID();
+    console.log('送信メッセージ:', text);
+    sendJsonMessage({ v: PROTOCOL_VERSION, type: 'message', id, text });
+    return id;
+  }, [sendJsonMessage, isClient]);
 
   const connectionStatus = {
     [ReadyState.CONNECTING]: '接続中...',
@@ -55,16 +145,9 @@ export function useChatWebSocket() {
     }
   }, [connectionStatus, isClient]);
 
-  useEffect(() => {
-    if (lastMessage && isClient) {
-      console.log('受信メッセージ:', lastMessage);
-    }
-  }, [lastMessage, isClient]);
-
   return {
     sendMessage,
-    lastMessage,
     readyState,
     connectionStatus,
   };
-} 
\ No newline at end of file
+}
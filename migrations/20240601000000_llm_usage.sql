@@ -0,0 +1,17 @@
+-- Create llm_usage table (one row per upstream LLM call)
+CREATE TABLE IF NOT EXISTS llm_usage (
+    id BIGSERIAL PRIMARY KEY,
+    user_id VARCHAR(255),
+    conversation_id VARCHAR(255),
+    feature VARCHAR(32) NOT NULL,
+    provider VARCHAR(64) NOT NULL,
+    model VARCHAR(128) NOT NULL,
+    prompt_tokens BIGINT NOT NULL,
+    completion_tokens BIGINT NOT NULL,
+    latency_ms BIGINT NOT NULL,
+    cost_usd DOUBLE PRECISION NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX idx_llm_usage_created_at ON llm_usage(created_at);
+CREATE INDEX idx_llm_usage_user_id ON llm_usage(user_id);
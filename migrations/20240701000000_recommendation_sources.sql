@@ -0,0 +1,5 @@
+-- Keep product / store links and the search results each recommendation was based on
+ALTER TABLE gift_recommendations
+    ADD COLUMN IF NOT EXISTS url TEXT,
+    ADD COLUMN IF NOT EXISTS store_url TEXT,
+    ADD COLUMN IF NOT EXISTS sources JSONB NOT NULL DEFAULT '[]';
@@ -0,0 +1,5 @@
+-- Record which prompt template revision (name@version) produced each recommendation
+ALTER TABLE gift_recommendations
+    ADD COLUMN IF NOT EXISTS prompt_version VARCHAR(100);
+
+CREATE INDEX IF NOT EXISTS idx_gift_recommendations_prompt_version ON gift_recommendations(prompt_version);
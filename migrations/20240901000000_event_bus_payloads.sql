@@ -0,0 +1,8 @@
+-- Event bus payloads too large for NOTIFY (the notification carries only the row id)
+CREATE TABLE IF NOT EXISTS event_bus_payloads (
+    id BIGSERIAL PRIMARY KEY,
+    payload TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_event_bus_payloads_created_at ON event_bus_payloads(created_at);
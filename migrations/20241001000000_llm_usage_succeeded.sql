@@ -0,0 +1,3 @@
+-- Record failed upstream calls too (e.g. attempts that fell back to the next model)
+ALTER TABLE llm_usage
+    ADD COLUMN IF NOT EXISTS succeeded BOOLEAN NOT NULL DEFAULT TRUE;
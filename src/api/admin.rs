@@ -0,0 +1,122 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use super::auth::AdminPrincipal;
+use super::gift::AppState;
+
+#[derive(Debug, Serialize)]
+struct ReloadRejected {
+    /// 引き続き有効な設定のバージョン
+    version: u64,
+    fingerprint: String,
+    errors: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct AdminError {
+    error: String,
+}
+
+// 利用の集計の期間の上限（日）
+const MAX_USAGE_REPORT_DAYS: u32 = 90;
+
+#[derive(Debug, Deserialize)]
+struct UsageQuery {
+    /// 今日を含む直近の日数
+    #[serde(default = "default_usage_days")]
+    days: u32,
+}
+
+fn default_usage_days() -> u32 {
+    7
+}
+
+pub fn admin_routes() -> Router<AppState> {
+    Router::new()
+        .route("/admin/config", get(get_config_status))
+        .route("/admin/config/reload", post(reload_config))
+        .route("/admin/usage", get(get_usage_report))
+}
+
+async fn get_config_status(State(state): State<AppState>, _admin: AdminPrincipal) -> Response {
+    match state.runtime_config() {
+        Some(runtime) => Json(runtime.status()).into_response(),
+        None => not_enabled(),
+    }
+}
+
+async fn reload_config(State(state): State<AppState>, _admin: AdminPrincipal) -> Response {
+    let Some(runtime) = state.runtime_config().cloned() else {
+        return not_enabled();
+    };
+
+    // ファイルの読み込みはブロッキングI/Oのため専用スレッドで行う
+    let result = tokio::task::spawn_blocking({
+        let runtime = runtime.clone();
+        move || runtime.reload()
+    })
+    .await;
+
+    match result {
+        Ok(Ok(outcome)) => Json(outcome).into_response(),
+        Ok(Err(errors)) => {
+            let current = runtime.current();
+            let body = ReloadRejected {
+                version: current.version,
+                fingerprint: current.fingerprint.clone(),
+                errors: errors.0,
+            };
+            (StatusCode::UNPROCESSABLE_ENTITY, Json(body)).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Configuration reload task failed: {:?}", e);
+            let body = AdminError {
+                error: "Configuration reload failed".to_string(),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(body)).into_response()
+        }
+    }
+}
+
+/// 上流の呼び出しの日ごとの合計（モデル別・ユーザー別）。トークン数と推定費用を含む
+async fn get_usage_report(
+    State(state): State<AppState>,
+    _admin: AdminPrincipal,
+    Query(query): Query<UsageQuery>,
+) -> Response {
+    if !(1..=MAX_USAGE_REPORT_DAYS).contains(&query.days) {
+        let body = AdminError {
+            error: format!("days must be between 1 and {}", MAX_USAGE_REPORT_DAYS),
+        };
+        return (StatusCode::BAD_REQUEST, Json(body)).into_response();
+    }
+    let Some(usage) = state.usage() else {
+        let body = AdminError {
+            error: "Usage accounting is not enabled".to_string(),
+        };
+        return (StatusCode::SERVICE_UNAVAILABLE, Json(body)).into_response();
+    };
+    match usage.report(query.days).await {
+        Ok(report) => Json(report).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to build usage report: {:?}", e);
+            let body = AdminError {
+                error: "Failed to build usage report".to_string(),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(body)).into_response()
+        }
+    }
+}
+
+fn not_enabled() -> Response {
+    let body = AdminError {
+        error: "Runtime configuration reloading is not enabled".to_string(),
+    };
+    (StatusCode::SERVICE_UNAVAILABLE, Json(body)).into_response()
+}
@@ -0,0 +1,216 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Json, Query, State},
+    http::{header, request::Parts},
+    routing::post,
+    Router,
+};
+use serde::Deserialize;
+
+use crate::app::auth::{AuthError, IssuedToken, Principal, PrincipalKind};
+use crate::error::AppError;
+use crate::logging::request;
+use super::gift::AppState;
+
+// サーバー間連携でAPIキーを送るヘッダー
+const API_KEY_HEADER: &str = "x-api-key";
+// APIキーと一緒に、パートナー側のエンドユーザーのIDを送るヘッダー
+const USER_ID_HEADER: &str = "x-user-id";
+
+/// セッショントークンの発行
+///
+/// ブラウザは `POST /auth/anonymous` で匿名のトークンを受け取り、期限が切れる前に `POST /auth/refresh` で更新する。
+/// パートナーはAPIキーで `POST /auth/token` を呼び、自身が認証したユーザーのトークンを発行する。
+pub fn auth_routes() -> Router<AppState> {
+    Router::new()
+        .route("/auth/anonymous", post(start_anonymous))
+        .route("/auth/token", post(issue_for_user))
+        .route("/auth/refresh", post(refresh))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IssueTokenRequest {
+    /// パートナー側のユーザーID。発行されるトークンのユーザーIDは `{パートナー名}:{user_id}` になる
+    user_id: String,
+}
+
+// ヘッダーを付けられないWebSocket・EventSourceの接続用
+#[derive(Debug, Deserialize)]
+struct AccessTokenQuery {
+    access_token: Option<String>,
+}
+
+async fn start_anonymous(State(state): State<AppState>) -> Json<IssuedToken> {
+    Json(state.auth().issue_anonymous())
+}
+
+async fn issue_for_user(
+    State(state): State<AppState>,
+    principal: Principal,
+    Json(request): Json<IssueTokenRequest>,
+) -> Result<Json<IssuedToken>, AppError> {
+    Ok(Json(state.auth().issue_for_partner(&principal, &request.user_id)?))
+}
+
+async fn refresh(State(state): State<AppState>, principal: Principal) -> Result<Json<IssuedToken>, AppError> {
+    if principal.kind != PrincipalKind::Session {
+        return Err(AuthError::Forbidden.into());
+    }
+    Ok(Json(state.auth().issue(&principal.user_id)))
+}
+
+/// `Authorization: Bearer <トークン>`、`X-API-Key: <キー>`、クエリの `access_token` のいずれかで認証する
+///
+/// APIキーに `X-User-Id` を添えた場合は、パートナーのそのエンドユーザーとして扱う。
+#[async_trait]
+impl FromRequestParts<AppState> for Principal {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let principal = authenticate(parts, state)?;
+        request::record_user(&principal.user_id);
+        Ok(principal)
+    }
+}
+
+// ヘッダーまたはクエリの認証情報を確認する
+fn authenticate(parts: &Parts, state: &AppState) -> Result<Principal, AppError> {
+    let bearer = parts
+        .headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string());
+    if let Some(token) = bearer {
+        return Ok(state.auth().verify_token(&token)?);
+    }
+    if let Some(key) = parts.headers.get(API_KEY_HEADER).and_then(|value| value.to_str().ok()) {
+        let partner = state.auth().verify_api_key(key.trim())?;
+        return match parts.headers.get(USER_ID_HEADER) {
+            Some(user_id) => {
+                let user_id = user_id.to_str().map_err(|_| AuthError::InvalidUserId)?;
+                Ok(state.auth().partner_user(&partner, user_id)?)
+            }
+            None => Ok(partner),
+        };
+    }
+    let query = Query::<AccessTokenQuery>::try_from_uri(&parts.uri).ok();
+    match query.and_then(|Query(query)| query.access_token) {
+        Some(token) => Ok(state.auth().verify_token(&token)?),
+        None => Err(AuthError::Missing.into()),
+    }
+}
+
+/// 管理用のAPIキーで認証された利用者
+pub struct AdminPrincipal(pub Principal);
+
+#[async_trait]
+impl FromRequestParts<AppState> for AdminPrincipal {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let principal = Principal::from_request_parts(parts, state).await?;
+        if !principal.admin {
+            return Err(AuthError::Forbidden.into());
+        }
+        Ok(Self(principal))
+    }
+}
+
+/// 会話やユーザーごとの上限の単位になるエンドユーザー
+///
+/// APIキーだけではパートナーのすべてのエンドユーザーが同じユーザーになるため、`X-User-Id` を必須にする。
+pub struct UserPrincipal(pub Principal);
+
+#[async_trait]
+impl FromRequestParts<AppState> for UserPrincipal {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let principal = Principal::from_request_parts(parts, state).await?;
+        if principal.kind == PrincipalKind::ApiKey {
+            return Err(AppError::Validation(format!("APIキーで呼び出す場合は {} ヘッダーが必要です", USER_ID_HEADER)));
+        }
+        Ok(Self(principal))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{Request, StatusCode};
+    use axum::response::IntoResponse;
+
+    async fn authenticate(state: &AppState, request: Request<()>) -> Result<Principal, StatusCode> {
+        let (mut parts, _) = request.into_parts();
+        Principal::from_request_parts(&mut parts, state)
+            .await
+            .map_err(|rejection| rejection.into_response().status())
+    }
+
+    #[tokio::test]
+    async fn test_principal_is_taken_from_bearer_token_or_query() {
+        let state = AppState::new("test_key".to_string());
+        let issued = state.auth().issue("u1");
+
+        let request = Request::get("/chat")
+            .header(header::AUTHORIZATION, format!("Bearer {}", issued.token))
+            .body(())
+            .unwrap();
+        assert_eq!(authenticate(&state, request).await.unwrap().user_id, "u1");
+
+        let request = Request::get(format!("/ws?access_token={}", issued.token)).body(()).unwrap();
+        assert_eq!(authenticate(&state, request).await.unwrap().user_id, "u1");
+
+        let request = Request::get("/ws").body(()).unwrap();
+        assert_eq!(authenticate(&state, request).await, Err(StatusCode::UNAUTHORIZED));
+        let request = Request::get("/ws").header(API_KEY_HEADER, "unknown").body(()).unwrap();
+        assert_eq!(authenticate(&state, request).await, Err(StatusCode::UNAUTHORIZED));
+
+        // セッショントークンでは管理用エンドポイントを呼び出せない
+        let (mut parts, _) = Request::get("/admin/config")
+            .header(header::AUTHORIZATION, format!("Bearer {}", issued.token))
+            .body(())
+            .unwrap()
+            .into_parts();
+        let rejection = AdminPrincipal::from_request_parts(&mut parts, &state).await.err().unwrap();
+        assert_eq!(rejection.into_response().status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_api_key_requests_name_the_end_user() {
+        use crate::app::auth::Authenticator;
+        use crate::config::config::{ApiKeyConfig, AuthConfig};
+        use crate::config::secret::Secret;
+
+        let state = AppState::new("test_key".to_string()).with_authenticator(Authenticator::new(&AuthConfig {
+            api_keys: vec![ApiKeyConfig {
+                name: "partner".to_string(),
+                key: Secret::new("partner-key-0123456789abcdef"),
+                admin: false,
+            }],
+            ..AuthConfig::default()
+        }));
+        let request = |user_id: Option<&str>| {
+            let request = Request::get("/chat").header(API_KEY_HEADER, "partner-key-0123456789abcdef");
+            match user_id {
+                Some(user_id) => request.header(USER_ID_HEADER, user_id),
+                None => request,
+            }
+            .body(())
+            .unwrap()
+        };
+
+        let end_user = authenticate(&state, request(Some("u-42"))).await.unwrap();
+        assert_eq!(end_user.user_id, "partner:u-42");
+        assert_eq!(end_user.kind, PrincipalKind::PartnerUser { partner: "partner".to_string() });
+        let (mut parts, _) = request(Some("u-42")).into_parts();
+        assert!(UserPrincipal::from_request_parts(&mut parts, &state).await.is_ok());
+
+        // エンドユーザーを指定しないAPIキーは、会話などのエンドユーザー単位の操作に使えない
+        assert_eq!(authenticate(&state, request(None)).await.unwrap().kind, PrincipalKind::ApiKey);
+        let (mut parts, _) = request(None).into_parts();
+        let rejection = UserPrincipal::from_request_parts(&mut parts, &state).await.err().unwrap();
+        assert_eq!(rejection.into_response().status(), StatusCode::BAD_REQUEST);
+    }
+}
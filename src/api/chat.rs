@@ -1,44 +1,150 @@
 use axum::{
-    extract::Json,
-    response::IntoResponse,
+    extract::{Json, State},
+    response::{IntoResponse, Response},
     http::StatusCode,
+    routing::post,
+    Router,
 };
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use anyhow::anyhow;
 use crate::app::chat::chatbot::ChatBot;
+use crate::app::chat::message::Payload;
+use crate::app::chat::responder::{self, IncomingMessage};
+use crate::app::gift::quota::QuotaExceeded;
+use crate::error::AppError;
+use crate::logging::request::record_conversation;
+use super::auth::UserPrincipal;
+use super::gift::AppState;
 
+/// ユーザーは認証情報から決まるため、本文には含めない
 #[derive(Debug, Deserialize)]
 pub struct ChatRequest {
-    user_id: String,
     message: String,
+    /// 前回の応答の `session_id`。指定すると聞き取りの続きから応答する
+    #[serde(default)]
+    session_id: Option<String>,
 }
 
+/// WebSocketの `bot_response` と同じ内容を返す
 #[derive(Debug, Serialize)]
 pub struct ChatResponse {
+    session_id: String,
     message: String,
-    recommendations: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    payloads: Vec<Payload>,
 }
 
+pub fn chat_routes() -> Router<AppState> {
+    Router::new().route("/chat", post(handle_chat))
+}
+
+/// WebSocketやSSEと同じくセッションの順番を取って応答する。応答はセッションに接続中の端末にも送られる
 pub async fn handle_chat(
+    State(state): State<AppState>,
+    UserPrincipal(principal): UserPrincipal,
     Json(request): Json<ChatRequest>,
-) -> impl IntoResponse {
-    let chatbot = ChatBot::new();
-    
-    match chatbot.process_message(&request.message).await {
-        Ok(response) => {
+) -> Response {
+    // 停止時はこの呼び出しが終わるまで待つ
+    let _inflight = state.shutdown().track();
+    let attached = state
+        .sessions()
+        .attach_as(Some(&principal.user_id), request.session_id.as_deref(), None);
+    let session_id = attached.session.lock().unwrap().id().to_string();
+    record_conversation(&session_id);
+    let chatbot = ChatBot::new()
+        .with_classifier(state.intent_classifier())
+        .with_recommender(state.recommender().clone())
+        .with_user(principal.user_id.clone())
+        .with_conversation(session_id.clone());
+    let message = IncomingMessage {
+        id: uuid::Uuid::new_v4().to_string(),
+        user_id: principal.user_id,
+        text: request.message,
+    };
+    let history = state.history().cloned();
+    let reply = responder::respond(Arc::new(chatbot), history, &attached.session, message, state.shutdown().track());
+    let result = match reply {
+        Some(reply) => reply.await.unwrap_or_else(|e| Err(anyhow!("Chat reply was cancelled: {}", e))),
+        None => Err(anyhow!("Duplicate chat message")),
+    };
+    state.sessions().detach(&attached.session, attached.listener);
+
+    match result {
+        Ok(bot_message) => {
+            let message = bot_message.fallback_text();
             let chat_response = ChatResponse {
-                message: response,
-                recommendations: None, // 後で実装
+                session_id,
+                message,
+                payloads: bot_message.payloads,
             };
-            (StatusCode::OK, Json(chat_response))
+            (StatusCode::OK, Json(chat_response)).into_response()
+        }
+        // 1日の利用上限に達した場合は、再試行できるまでの時間を付けて 429 を返す
+        Err(e) if e.is::<QuotaExceeded>() => {
+            let exceeded = e.downcast::<QuotaExceeded>().expect("checked above");
+            AppError::from(exceeded).into_response()
         }
+        // 生成の失敗はセッションのタスクで記録している
         Err(_) => {
             (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(ChatResponse {
+                    session_id,
                     message: "内部エラーが発生しました".to_string(),
-                    recommendations: None,
+                    payloads: Vec::new(),
                 }),
             )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::chat::protocol::ServerFrame;
+
+    #[tokio::test]
+    async fn test_http_reply_reaches_connected_devices() {
+        let state = AppState::new("test_key".to_string());
+        let principal = state.auth().verify_token(&state.auth().issue("u").token).unwrap();
+        let mut device = state.sessions().attach_as(Some("u"), None, None);
+
+        let request = ChatRequest { message: "こんにちは".to_string(), session_id: None };
+        let response = handle_chat(State(state.clone()), UserPrincipal(principal), Json(request)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let mut frames = Vec::new();
+        while let Ok(envelope) = device.events.try_recv() {
+            frames.push(envelope.frame);
         }
+        assert!(matches!(frames.first(), Some(ServerFrame::Ack { duplicate: false, .. })));
+        assert!(frames.iter().any(|frame| matches!(frame, ServerFrame::BotResponse { .. })));
+        assert_eq!(state.sessions().len(), 1);
+    }
+
+    #[test]
+    fn test_chat_uses_reloaded_intent_patterns() {
+        use crate::app::nlp::intent_classifier::Intent;
+        use crate::config::loader::ConfigLoader;
+        use crate::config::runtime::RuntimeConfig;
+
+        let dir = tempfile::tempdir().unwrap();
+        let env = [
+            ("PERPLEXITY_API_KEY", "key".to_string()),
+            ("RULES_INTENT_PATTERNS_PATH", dir.path().join("intent_patterns.toml").display().to_string()),
+            ("RULES_GIFT_RULES_PATH", dir.path().join("gift_rules.toml").display().to_string()),
+        ];
+        let loader = ConfigLoader::new()
+            .with_config_dir(dir.path())
+            .with_env(env.into_iter().map(|(k, v)| (k.to_string(), v)));
+        let runtime = Arc::new(RuntimeConfig::new(loader).unwrap());
+        let state = AppState::new("test_key".to_string()).with_runtime_config(runtime.clone());
+        assert_eq!(state.intent_classifier().classify("やあ"), Intent::Unknown);
+
+        std::fs::write(dir.path().join("intent_patterns.toml"), "[patterns]\nGreeting = [\"やあ\"]\n").unwrap();
+        runtime.reload().unwrap();
+        assert_eq!(state.intent_classifier().classify("やあ"), Intent::Greeting);
     }
-} 
\ No newline at end of file
+}
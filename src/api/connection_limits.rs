@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// IPアドレス・ユーザーごとの同時接続数
+#[derive(Default)]
+pub struct ConnectionLimiter {
+    per_ip: Arc<Counter<IpAddr>>,
+    per_user: Arc<Counter<String>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 上限に達していなければ接続を数える。戻り値を破棄すると数から外れる
+    pub fn acquire_ip(&self, ip: IpAddr, max: usize) -> Option<ConnectionPermit> {
+        Counter::acquire(&self.per_ip, ip, max)
+    }
+
+    pub fn acquire_user(&self, user_id: &str, max: usize) -> Option<ConnectionPermit> {
+        Counter::acquire(&self.per_user, user_id.to_string(), max)
+    }
+
+    pub fn ip_connections(&self, ip: IpAddr) -> usize {
+        self.per_ip.count(&ip)
+    }
+
+    pub fn user_connections(&self, user_id: &str) -> usize {
+        self.per_user.count(&user_id.to_string())
+    }
+}
+
+struct Counter<K> {
+    counts: Mutex<HashMap<K, usize>>,
+}
+
+impl<K> Default for Counter<K> {
+    fn default() -> Self {
+        Self {
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone + Send + Sync + 'static> Counter<K> {
+    fn acquire(counter: &Arc<Self>, key: K, max: usize) -> Option<ConnectionPermit> {
+        let mut counts = counter.counts.lock().unwrap();
+        let count = counts.entry(key.clone()).or_insert(0);
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        let counter = counter.clone();
+        Some(ConnectionPermit {
+            release: Some(Box::new(move || counter.release(&key))),
+        })
+    }
+
+    fn release(&self, key: &K) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(key) {
+            *count -= 1;
+            // 接続のなくなったキーは残さない
+            if *count == 0 {
+                counts.remove(key);
+            }
+        }
+    }
+
+    fn count(&self, key: &K) -> usize {
+        self.counts.lock().unwrap().get(key).copied().unwrap_or(0)
+    }
+}
+
+/// 接続を数えている間保持する。破棄すると数から外れる
+pub struct ConnectionPermit {
+    release: Option<Box<dyn FnOnce() + Send + Sync>>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        if let Some(release) = self.release.take() {
+            release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permits_are_released_on_drop() {
+        let limiter = ConnectionLimiter::new();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        let first = limiter.acquire_ip(ip, 2).unwrap();
+        let _second = limiter.acquire_ip(ip, 2).unwrap();
+        assert!(limiter.acquire_ip(ip, 2).is_none());
+        assert!(limiter.acquire_ip("192.0.2.2".parse().unwrap(), 2).is_some());
+
+        drop(first);
+        assert_eq!(limiter.ip_connections(ip), 1);
+        assert!(limiter.acquire_ip(ip, 2).is_some());
+
+        let user = limiter.acquire_user("u1", 1).unwrap();
+        assert!(limiter.acquire_user("u1", 1).is_none());
+        drop(user);
+        assert_eq!(limiter.user_connections("u1"), 0);
+    }
+}
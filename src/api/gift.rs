@@ -1,40 +1,264 @@
 use axum::{
-    routing::post,
+    routing::{get, post},
     Router,
     Json,
     extract::State,
+    response::{IntoResponse, Response},
 };
 use std::sync::Arc;
 
-use crate::app::gift::recommendation::{GiftRecommender, GiftRequest, GiftRecommendation};
+use crate::app::auth::{Authenticator, Principal};
+use crate::app::chat::history::HistoryWriter;
+use crate::app::chat::session::SessionRegistry;
+use crate::app::database::gift_cache::GiftCache;
+use crate::app::database::pool::Database;
+use crate::app::gift::quota::QuotaExceeded;
+use crate::app::nlp::intent_classifier::IntentClassifier;
+use crate::app::gift::recommendation::{
+    GiftRecommender, GiftRequest, GiftRecommendation, RecommendationCacheStats,
+    RecommendationSource, Recommendations,
+};
+use crate::app::usage::{UsageContext, UsageFeature, UsageRecorder};
+use crate::app::pubsub::event_bus::{EventBridge, EventBus, InProcessBus};
+use crate::app::shutdown::Shutdown;
+use crate::config::config::{AuthConfig, RateLimitConfig, WebSocketConfig};
+use crate::config::runtime::RuntimeConfig;
+use crate::error::AppError;
+use super::auth::UserPrincipal;
+use super::connection_limits::ConnectionLimiter;
+use super::rate_limit::RateLimiter;
 
 #[derive(Clone)]
 pub struct AppState {
     recommender: Arc<GiftRecommender>,
+    runtime: Option<Arc<RuntimeConfig>>,
+    database: Option<Arc<Database>>,
+    // データベースなしでは運用しない（/readyz で未設定を異常とする）
+    database_required: bool,
+    history: Option<HistoryWriter>,
+    sessions: Arc<SessionRegistry>,
+    shutdown: Shutdown,
+    websocket: WebSocketConfig,
+    connections: Arc<ConnectionLimiter>,
+    events: Arc<EventBridge>,
+    auth: Arc<Authenticator>,
+    rate_limits: Arc<RateLimiter>,
+    usage: Option<UsageRecorder>,
 }
 
 impl AppState {
     pub fn new(perplexity_api_key: String) -> Self {
+        let sessions = Arc::new(SessionRegistry::new());
         Self {
             recommender: Arc::new(GiftRecommender::new(perplexity_api_key)),
+            runtime: None,
+            database: None,
+            database_required: false,
+            history: None,
+            events: Arc::new(EventBridge::new(Arc::new(InProcessBus::new()), sessions.clone())),
+            sessions,
+            shutdown: Shutdown::new(),
+            websocket: WebSocketConfig::default(),
+            connections: Arc::new(ConnectionLimiter::new()),
+            auth: Arc::new(Authenticator::new(&AuthConfig::default())),
+            rate_limits: Arc::new(RateLimiter::new(RateLimitConfig::default())),
+            usage: None,
+        }
+    }
+
+    pub fn with_cache(perplexity_api_key: String, cache: GiftCache) -> Self {
+        let sessions = Arc::new(SessionRegistry::new());
+        Self {
+            recommender: Arc::new(GiftRecommender::with_cache(perplexity_api_key, cache)),
+            runtime: None,
+            database: None,
+            database_required: false,
+            history: None,
+            events: Arc::new(EventBridge::new(Arc::new(InProcessBus::new()), sessions.clone())),
+            sessions,
+            shutdown: Shutdown::new(),
+            websocket: WebSocketConfig::default(),
+            connections: Arc::new(ConnectionLimiter::new()),
+            auth: Arc::new(Authenticator::new(&AuthConfig::default())),
+            rate_limits: Arc::new(RateLimiter::new(RateLimitConfig::default())),
+            usage: None,
+        }
+    }
+
+    pub fn with_recommender(recommender: GiftRecommender) -> Self {
+        let sessions = Arc::new(SessionRegistry::new());
+        Self {
+            recommender: Arc::new(recommender),
+            runtime: None,
+            database: None,
+            database_required: false,
+            history: None,
+            events: Arc::new(EventBridge::new(Arc::new(InProcessBus::new()), sessions.clone())),
+            sessions,
+            shutdown: Shutdown::new(),
+            websocket: WebSocketConfig::default(),
+            connections: Arc::new(ConnectionLimiter::new()),
+            auth: Arc::new(Authenticator::new(&AuthConfig::default())),
+            rate_limits: Arc::new(RateLimiter::new(RateLimitConfig::default())),
+            usage: None,
         }
     }
+
+    /// 管理用エンドポイントから再読み込みできるようにする
+    pub fn with_runtime_config(mut self, runtime: Arc<RuntimeConfig>) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+
+    pub fn with_database(mut self, database: Arc<Database>) -> Self {
+        self.database = Some(database);
+        self
+    }
+
+    /// データベースが設定されていない場合も、準備ができていないとみなす
+    pub fn with_database_required(mut self, required: bool) -> Self {
+        self.database_required = required;
+        self
+    }
+
+    /// WebSocketの会話を履歴として保存する
+    pub fn with_history(mut self, history: HistoryWriter) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    /// 停止要求をセッションや処理中のリクエストに伝える
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// WebSocket接続のタイムアウトと上限
+    pub fn with_websocket_config(mut self, websocket: WebSocketConfig) -> Self {
+        self.websocket = websocket;
+        self
+    }
+
+    /// セッションのイベントを他のインスタンスと共有する。受信は [`EventBridge::start`] で始める
+    pub fn with_event_bus(mut self, bus: Arc<dyn EventBus>) -> Self {
+        self.events = Arc::new(EventBridge::new(bus, self.sessions.clone()));
+        self
+    }
+
+    /// セッショントークンの署名鍵とAPIキー
+    pub fn with_authenticator(mut self, authenticator: Authenticator) -> Self {
+        self.auth = Arc::new(authenticator);
+        self
+    }
+
+    /// 管理用エンドポイントで上流の利用を集計できるようにする
+    pub fn with_usage_recorder(mut self, usage: UsageRecorder) -> Self {
+        self.usage = Some(usage);
+        self
+    }
+
+    /// ユーザー・APIキー・IPアドレスごとの流量制限
+    pub fn with_rate_limits(mut self, config: RateLimitConfig) -> Self {
+        self.rate_limits = Arc::new(RateLimiter::new(config));
+        self
+    }
+
+    pub fn recommender(&self) -> &Arc<GiftRecommender> {
+        &self.recommender
+    }
+
+    pub fn runtime_config(&self) -> Option<&Arc<RuntimeConfig>> {
+        self.runtime.as_ref()
+    }
+
+    /// 再読み込みされた最新のパターンで発言を分類する分類器
+    pub fn intent_classifier(&self) -> IntentClassifier {
+        self.runtime
+            .as_ref()
+            .map(|runtime| runtime.current().intent_classifier.clone())
+            .unwrap_or_else(IntentClassifier::new)
+    }
+
+    pub fn database(&self) -> Option<&Arc<Database>> {
+        self.database.as_ref()
+    }
+
+    pub fn database_required(&self) -> bool {
+        self.database_required
+    }
+
+    pub fn history(&self) -> Option<&HistoryWriter> {
+        self.history.as_ref()
+    }
+
+    pub fn sessions(&self) -> &Arc<SessionRegistry> {
+        &self.sessions
+    }
+
+    pub fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+
+    pub fn websocket_config(&self) -> &WebSocketConfig {
+        &self.websocket
+    }
+
+    pub fn connections(&self) -> &Arc<ConnectionLimiter> {
+        &self.connections
+    }
+
+    pub fn events(&self) -> &Arc<EventBridge> {
+        &self.events
+    }
+
+    pub fn auth(&self) -> &Arc<Authenticator> {
+        &self.auth
+    }
+
+    pub fn rate_limits(&self) -> &Arc<RateLimiter> {
+        &self.rate_limits
+    }
+
+    pub fn usage(&self) -> Option<&UsageRecorder> {
+        self.usage.as_ref()
+    }
 }
 
 pub fn gift_routes() -> Router<AppState> {
     Router::new()
         .route("/recommendations", post(get_recommendations))
+        .route("/recommendations/cache/stats", get(get_cache_stats))
 }
 
+// 縮退モードでカタログから提案した場合にレスポンスへ付けるヘッダー
+const RECOMMENDATION_SOURCE_HEADER: &str = "x-recommendation-source";
+
 async fn get_recommendations(
     State(state): State<AppState>,
+    UserPrincipal(principal): UserPrincipal,
     Json(request): Json<GiftRequest>,
-) -> Json<Vec<GiftRecommendation>> {
-    match state.recommender.get_recommendations(request).await {
-        Ok(recommendations) => Json(recommendations),
+) -> Response {
+    // 停止時はこの呼び出しが終わるまで待つ
+    let _inflight = state.shutdown.track();
+    match state.recommender.recommend_for(&UsageContext::new(UsageFeature::Recommendation).with_user(principal.user_id), request).await {
+        Ok(Recommendations { items, source: RecommendationSource::Catalog }) => {
+            ([(RECOMMENDATION_SOURCE_HEADER, "catalog")], Json(items)).into_response()
+        }
+        Ok(recommendations) => Json(recommendations.items).into_response(),
+        Err(e) if e.is::<QuotaExceeded>() => {
+            let exceeded = e.downcast::<QuotaExceeded>().expect("checked above");
+            AppError::from(exceeded).into_response()
+        }
         Err(e) => {
             tracing::error!("Failed to get recommendations: {:?}", e);
-            Json(vec![]) // エラー時は空の配列を返す
+            Json(Vec::<GiftRecommendation>::new()).into_response() // エラー時は空の配列を返す
         }
     }
-} 
\ No newline at end of file
+} 
+
+async fn get_cache_stats(
+    State(state): State<AppState>,
+    _principal: Principal,
+) -> Json<RecommendationCacheStats> {
+    Json(state.recommender.cache_stats().await)
+}
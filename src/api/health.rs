@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use serde::Serialize;
+
+use super::gift::AppState;
+use crate::app::gift::circuit_breaker::CircuitState;
+
+// 依存先が応答しない場合でもプローブを長く待たせない
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum CheckStatus {
+    Up,
+    Down,
+    /// 設定されていないため確認していない
+    Disabled,
+}
+
+#[derive(Debug, Serialize)]
+struct DependencyCheck {
+    status: CheckStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+impl DependencyCheck {
+    fn new(status: CheckStatus, detail: impl Into<String>) -> Self {
+        Self {
+            status,
+            latency_ms: None,
+            detail: Some(detail.into()),
+        }
+    }
+
+    fn disabled() -> Self {
+        Self {
+            status: CheckStatus::Disabled,
+            latency_ms: None,
+            detail: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Readiness {
+    Ready,
+    /// LLMまたはキャッシュが使えないが、カタログのみの推薦で応答を続けている
+    Degraded,
+    Unavailable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ServingMode {
+    Full,
+    CatalogOnly,
+}
+
+#[derive(Debug, Serialize)]
+struct ReadinessReport {
+    status: Readiness,
+    mode: ServingMode,
+    /// 停止処理中。新しいリクエストを振り分けないよう 503 を返す
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    draining: bool,
+    checks: BTreeMap<&'static str, DependencyCheck>,
+}
+
+#[derive(Debug, Serialize)]
+struct Liveness {
+    status: &'static str,
+}
+
+pub fn health_routes() -> Router<AppState> {
+    Router::new()
+        .route("/healthz", get(liveness))
+        .route("/readyz", get(readiness))
+}
+
+/// プロセスが応答できるかだけを返す。依存先の状態は見ない
+async fn liveness() -> Json<Liveness> {
+    Json(Liveness { status: "ok" })
+}
+
+/// 依存先ごとの状態を返す
+///
+/// データベースかマイグレーションに問題があれば 503 を返す。データベースが必要な構成で設定されていない場合も同様。
+/// LLMやキャッシュが使えない場合は縮退モード（`degraded`）として 200 を返す。
+/// 停止処理中は依存先の状態によらず 503 を返す。
+async fn readiness(State(state): State<AppState>) -> (StatusCode, Json<ReadinessReport>) {
+    let (database, migrations, cache) = tokio::join!(
+        check_database(&state),
+        check_migrations(&state),
+        check_cache(&state),
+    );
+    let llm = check_llm(&state);
+
+    let required_down = [&database, &migrations]
+        .iter()
+        .any(|check| check.status == CheckStatus::Down);
+    let llm_down = llm.status == CheckStatus::Down;
+    let draining = state.shutdown().is_triggered();
+    let status = if required_down || draining {
+        Readiness::Unavailable
+    } else if llm_down || cache.status == CheckStatus::Down {
+        Readiness::Degraded
+    } else {
+        Readiness::Ready
+    };
+    let mode = if llm_down { ServingMode::CatalogOnly } else { ServingMode::Full };
+
+    let checks = BTreeMap::from([
+        ("database", database),
+        ("migrations", migrations),
+        ("llm", llm),
+        ("cache", cache),
+    ]);
+    let code = if status == Readiness::Unavailable {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    (code, Json(ReadinessReport { status, mode, draining, checks }))
+}
+
+async fn check_database(state: &AppState) -> DependencyCheck {
+    match state.database() {
+        Some(database) => timed("database", database.ping(), |_| (CheckStatus::Up, None)).await,
+        None => not_configured(state),
+    }
+}
+
+async fn check_migrations(state: &AppState) -> DependencyCheck {
+    let Some(database) = state.database() else {
+        return not_configured(state);
+    };
+    timed("migrations", database.migration_status(), |status| {
+        if status.is_up_to_date() {
+            (CheckStatus::Up, Some(format!("{} applied", status.applied)))
+        } else {
+            (CheckStatus::Down, Some(format!("pending migrations: {:?}", status.pending)))
+        }
+    })
+    .await
+}
+
+async fn check_cache(state: &AppState) -> DependencyCheck {
+    let cache = state.recommender().cache();
+    let mut check = timed("cache", cache.ping(), |_| (CheckStatus::Up, None)).await;
+    check.detail.get_or_insert_with(|| cache.backend_name().to_string());
+    check
+}
+
+// データベースが設定されていない。必要な構成では異常とする
+fn not_configured(state: &AppState) -> DependencyCheck {
+    if state.database_required() {
+        DependencyCheck::new(CheckStatus::Down, "not configured")
+    } else {
+        DependencyCheck::disabled()
+    }
+}
+
+fn check_llm(state: &AppState) -> DependencyCheck {
+    let circuit = state.recommender().circuit_state();
+    let status = match circuit {
+        CircuitState::Closed => CheckStatus::Up,
+        CircuitState::Open | CircuitState::HalfOpen => CheckStatus::Down,
+    };
+    let circuit = serde_json::to_value(circuit)
+        .ok()
+        .and_then(|value| value.as_str().map(String::from))
+        .unwrap_or_default();
+    DependencyCheck::new(status, format!("circuit {}", circuit))
+}
+
+// タイムアウト付きで確認し、所要時間と結果を記録する。成功時の状態は `evaluate` で判定する
+async fn timed<T, F>(
+    name: &str,
+    check: F,
+    evaluate: impl FnOnce(&T) -> (CheckStatus, Option<String>),
+) -> DependencyCheck
+where
+    F: Future<Output = anyhow::Result<T>>,
+{
+    let started = Instant::now();
+    let result = tokio::time::timeout(CHECK_TIMEOUT, check).await;
+    let latency_ms = Some(u64::try_from(started.elapsed().as_millis()).unwrap_or(u64::MAX));
+
+    let (status, detail) = match result {
+        Ok(Ok(value)) => evaluate(&value),
+        Ok(Err(e)) => {
+            tracing::warn!("Readiness check {} failed: {:?}", name, e);
+            (CheckStatus::Down, Some(e.to_string()))
+        }
+        Err(_) => {
+            tracing::warn!("Readiness check {} timed out", name);
+            (CheckStatus::Down, Some(format!("timed out after {:?}", CHECK_TIMEOUT)))
+        }
+    };
+    DependencyCheck { status, latency_ms, detail }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::gift::circuit_breaker::ProviderCircuits;
+    use crate::app::gift::recommendation::GiftRecommender;
+    use crate::config::config::DEFAULT_LLM_PROVIDER;
+
+    #[tokio::test]
+    async fn test_readiness_reports_degraded_mode() {
+        let (code, Json(report)) = readiness(State(AppState::new("test_key".to_string()))).await;
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!((report.status, report.mode), (Readiness::Ready, ServingMode::Full));
+        assert_eq!(report.checks["database"].status, CheckStatus::Disabled);
+
+        let recommender = GiftRecommender::new("test_key".to_string())
+            .with_circuit_breakers(ProviderCircuits::new(1, Duration::from_secs(60)));
+        recommender.circuit(DEFAULT_LLM_PROVIDER).record_failure();
+        let (code, Json(report)) = readiness(State(AppState::with_recommender(recommender))).await;
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!((report.status, report.mode), (Readiness::Degraded, ServingMode::CatalogOnly));
+        assert_eq!(report.checks["llm"].detail.as_deref(), Some("circuit open"));
+
+        // データベースが必要な構成で設定されていない
+        let (code, Json(report)) =
+            readiness(State(AppState::new("test_key".to_string()).with_database_required(true))).await;
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(report.status, Readiness::Unavailable);
+        assert_eq!(report.checks["database"].detail.as_deref(), Some("not configured"));
+
+        let state = AppState::new("test_key".to_string());
+        state.shutdown().trigger();
+        let (code, Json(report)) = readiness(State(state)).await;
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(report.draining);
+    }
+}
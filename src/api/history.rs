@@ -1,15 +1,26 @@
 use axum::{
-    extract::Query,
-    response::IntoResponse,
-    http::StatusCode,
-    Json,
+    extract::{Query, State},
+    routing::get,
+    Json, Router,
 };
 use serde::{Deserialize, Serialize};
+use crate::app::chat::history::HistoryEntry;
+use crate::error::AppError;
+use super::auth::UserPrincipal;
+use super::gift::AppState;
 
+// 1回に返す履歴の件数の既定値と上限
+const DEFAULT_LIMIT: usize = 10;
+const MAX_LIMIT: usize = 100;
+
+pub fn history_routes() -> Router<AppState> {
+    Router::new().route("/chat/history", get(get_chat_history))
+}
+
+/// 取得する件数。ユーザーは認証情報から決まるため、クエリでは指定できない
 #[derive(Debug, Deserialize)]
 pub struct HistoryParams {
-    user_id: String,
-    limit: Option<i32>,
+    limit: Option<usize>,
 }
 
 #[derive(Debug, Serialize)]
@@ -20,12 +31,66 @@ pub struct ChatMessage {
     timestamp: i64,
 }
 
+impl From<HistoryEntry> for ChatMessage {
+    fn from(entry: HistoryEntry) -> Self {
+        Self {
+            user_id: entry.user_id,
+            message: entry.message,
+            response: entry.response,
+            timestamp: entry.created_at.unix_timestamp(),
+        }
+    }
+}
+
+/// 呼び出したユーザーの最近の会話履歴を古い順に返す。履歴を保存していない場合は空
 pub async fn get_chat_history(
+    State(state): State<AppState>,
+    UserPrincipal(principal): UserPrincipal,
     Query(params): Query<HistoryParams>,
-) -> impl IntoResponse {
-    // TODO: データベースからの履歴取得を実装
-    let _limit = params.limit.unwrap_or(10);
-    let messages: Vec<ChatMessage> = vec![]; // 仮の空実装
-    
-    (StatusCode::OK, Json(messages))
-} 
\ No newline at end of file
+) -> Result<Json<Vec<ChatMessage>>, AppError> {
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let Some(history) = state.history() else {
+        return Ok(Json(Vec::new()));
+    };
+    let entries = history.recent(&principal.user_id, limit).await.map_err(|e| {
+        tracing::error!("Failed to load chat history: {:?}", e);
+        AppError::Internal("履歴を取得できませんでした".to_string())
+    })?;
+    Ok(Json(entries.into_iter().map(ChatMessage::from).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::app::chat::history::{HistoryWriter, MemoryHistoryStore};
+
+    #[tokio::test]
+    async fn test_history_is_read_for_the_authenticated_user_only() {
+        let store = Arc::new(MemoryHistoryStore::new());
+        let writer = HistoryWriter::spawn(store);
+        writer.record(HistoryEntry::new("u1", "上司へのお返し", "ご相談ありがとうございます"));
+        writer.record(HistoryEntry::new("u2", "友人の結婚祝い", "おめでとうございます"));
+        writer.record(HistoryEntry::new("u1", "予算は5000円", "承知しました"));
+        writer.flush().await;
+        let state = AppState::new("test_key".to_string()).with_history(writer);
+        let principal = state.auth().verify_token(&state.auth().issue("u1").token).unwrap();
+
+        let Json(messages) = get_chat_history(
+            State(state.clone()),
+            UserPrincipal(principal.clone()),
+            Query(HistoryParams { limit: None }),
+        )
+        .await
+        .unwrap();
+        let texts: Vec<_> = messages.iter().map(|message| message.message.as_str()).collect();
+        assert_eq!(texts, ["上司へのお返し", "予算は5000円"]);
+
+        let Json(messages) =
+            get_chat_history(State(state), UserPrincipal(principal), Query(HistoryParams { limit: Some(1) }))
+                .await
+                .unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].message, "予算は5000円");
+    }
+}
@@ -0,0 +1,17 @@
+use axum::{
+    http::header::CONTENT_TYPE,
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+
+use super::gift::AppState;
+use crate::metrics;
+
+pub fn metrics_routes() -> Router<AppState> {
+    Router::new().route("/metrics", get(get_metrics))
+}
+
+async fn get_metrics() -> impl IntoResponse {
+    ([(CONTENT_TYPE, prometheus::TEXT_FORMAT)], metrics::global().render())
+}
@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use axum::{
+    extract::{ConnectInfo, FromRequestParts, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::app::auth::{Principal, PrincipalKind};
+use crate::config::config::{BucketConfig, RateLimitConfig};
+use crate::error::AppError;
+use crate::metrics;
+use super::gift::AppState;
+
+// 制限しないパス。監視やロードバランサーからの呼び出しを止めない
+const UNLIMITED_PATHS: &[&str] = &["/healthz", "/readyz", "/metrics"];
+// バケットの数がこれを超えたら、満杯に戻ったバケットを捨てる
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// ユーザー・APIキー・IPアドレスごとのトークンバケット
+///
+/// RESTのリクエストとWebSocketのメッセージの両方で1つずつトークンを使う。
+/// 認証済みの場合も、IPアドレスのバケットと利用者のバケットの両方から取る。
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    per_user: Buckets<String>,
+    per_api_key: Buckets<String>,
+    per_ip: Buckets<IpAddr>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            per_user: Buckets::default(),
+            per_api_key: Buckets::default(),
+            per_ip: Buckets::default(),
+        }
+    }
+
+    /// トークンを1つ取る。足りない場合は、次のトークンが補充されるまでの時間を返す
+    pub fn check(&self, principal: Option<&Principal>, ip: Option<IpAddr>) -> Result<(), RateLimitExceeded> {
+        self.take(principal, ip).inspect_err(|exceeded| {
+            metrics::global().rate_limited.with_label_values(&[exceeded.scope]).inc();
+        })
+    }
+
+    fn take(&self, principal: Option<&Principal>, ip: Option<IpAddr>) -> Result<(), RateLimitExceeded> {
+        if let Some(ip) = ip {
+            self.per_ip
+                .take(ip, self.config.per_ip)
+                .map_err(|retry_after| RateLimitExceeded { scope: "ip", retry_after })?;
+        }
+        let Some(principal) = principal else {
+            return Ok(());
+        };
+        // パートナーのエンドユーザーはパートナーのAPIキーの上限を共有する
+        let (scope, buckets, config, key) = match &principal.kind {
+            PrincipalKind::Session => ("user", &self.per_user, self.config.per_user, &principal.user_id),
+            PrincipalKind::ApiKey => ("api_key", &self.per_api_key, self.config.per_api_key, &principal.user_id),
+            PrincipalKind::PartnerUser { partner } => ("api_key", &self.per_api_key, self.config.per_api_key, partner),
+        };
+        buckets.take(key.clone(), config).map_err(|retry_after| {
+            // 受け付けなかったリクエストの分はIPアドレスのバケットに戻す
+            if let Some(ip) = ip {
+                self.per_ip.refund(&ip, self.config.per_ip);
+            }
+            RateLimitExceeded { scope, retry_after }
+        })
+    }
+}
+
+/// 流量制限に達した
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitExceeded {
+    /// どのバケットが空になったか（`user`、`api_key`、`ip`）
+    pub scope: &'static str,
+    pub retry_after: Duration,
+}
+
+impl From<RateLimitExceeded> for AppError {
+    fn from(exceeded: RateLimitExceeded) -> Self {
+        AppError::RateLimited {
+            message: "リクエストが多すぎます。しばらく待ってから再度お試しください".to_string(),
+            retry_after: exceeded.retry_after,
+        }
+    }
+}
+
+struct Buckets<K> {
+    buckets: Mutex<HashMap<K, Bucket>>,
+}
+
+impl<K> Default for Buckets<K> {
+    fn default() -> Self {
+        Self {
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    // 前回からの経過時間の分を補充する
+    fn refill(&mut self, config: BucketConfig, now: Instant) {
+        let per_second = f64::from(config.per_minute) / 60.0;
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_second).min(f64::from(config.burst));
+        self.updated = now;
+    }
+}
+
+impl<K: Eq + Hash> Buckets<K> {
+    fn take(&self, key: K, config: BucketConfig) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| {
+                bucket.refill(config, now);
+                bucket.tokens < f64::from(config.burst)
+            });
+        }
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: f64::from(config.burst),
+            updated: now,
+        });
+        bucket.refill(config, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        let per_second = f64::from(config.per_minute) / 60.0;
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+    }
+
+    fn refund(&self, key: &K, config: BucketConfig) {
+        if let Some(bucket) = self.buckets.lock().unwrap().get_mut(key) {
+            bucket.tokens = (bucket.tokens + 1.0).min(f64::from(config.burst));
+        }
+    }
+}
+
+/// RESTのリクエストを制限するミドルウェア。認証できないリクエストはIPアドレスだけで数える
+pub async fn limit_requests(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if UNLIMITED_PATHS.contains(&request.uri().path()) {
+        return next.run(request).await;
+    }
+    let (mut parts, body) = request.into_parts();
+    let principal = Principal::from_request_parts(&mut parts, &state).await.ok();
+    let ip = connect_info.map(|ConnectInfo(addr)| addr.ip());
+    if let Err(exceeded) = state.rate_limits().check(principal.as_ref(), ip) {
+        return AppError::from(exceeded).into_response();
+    }
+    next.run(Request::from_parts(parts, body)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(user_id: &str, kind: PrincipalKind) -> Principal {
+        Principal {
+            user_id: user_id.to_string(),
+            kind,
+            admin: false,
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn test_buckets_are_separate_per_user_and_share_the_ip() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            per_user: BucketConfig { burst: 2, per_minute: 60 },
+            per_api_key: BucketConfig { burst: 5, per_minute: 60 },
+            per_ip: BucketConfig { burst: 3, per_minute: 60 },
+            ..RateLimitConfig::default()
+        });
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let u1 = principal("u1", PrincipalKind::Session);
+
+        assert!(limiter.check(Some(&u1), Some(ip)).is_ok());
+        assert!(limiter.check(Some(&u1), Some(ip)).is_ok());
+        let exceeded = limiter.check(Some(&u1), Some(ip)).unwrap_err();
+        assert_eq!(exceeded.scope, "user");
+        assert!(exceeded.retry_after > Duration::ZERO && exceeded.retry_after <= Duration::from_secs(1));
+
+        // ユーザーの上限で断った分はIPアドレスから引かれていない
+        let u2 = principal("u2", PrincipalKind::Session);
+        assert!(limiter.check(Some(&u2), Some(ip)).is_ok());
+        assert_eq!(limiter.check(Some(&u2), Some(ip)).unwrap_err().scope, "ip");
+
+        // 別のIPアドレスのAPIキーは影響を受けない
+        let partner = principal("partner", PrincipalKind::ApiKey);
+        assert!(limiter.check(Some(&partner), Some("192.0.2.2".parse().unwrap())).is_ok());
+        assert!(limiter.check(None, None).is_ok());
+
+        // パートナーのエンドユーザーはパートナーのAPIキーの上限を共有する
+        let end_user = principal("partner:u1", PrincipalKind::PartnerUser { partner: "partner".to_string() });
+        for _ in 0..4 {
+            assert!(limiter.check(Some(&end_user), None).is_ok());
+        }
+        assert_eq!(limiter.check(Some(&end_user), None).unwrap_err().scope, "api_key");
+    }
+}
@@ -0,0 +1,334 @@
+use axum::{
+    extract::{Json, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
+    Router,
+};
+use futures::stream::{self, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+use crate::app::chat::chatbot::ChatBot;
+use crate::app::chat::message::{BotMessage, PayloadContent, RecommendationCard};
+use crate::app::chat::protocol::{ServerEnvelope, ServerFrame};
+use crate::app::chat::responder::{self, IncomingMessage};
+use crate::app::chat::session::{ChatSession, SessionRegistry};
+use crate::app::gift::recommendation::GiftRequest;
+use crate::app::shutdown::InflightGuard;
+use crate::app::usage::{UsageContext, UsageFeature};
+use crate::logging::request::record_conversation;
+use super::auth::UserPrincipal;
+use super::gift::AppState;
+
+// プロキシに無通信の接続を切られないよう、コメント行を送る間隔
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+// 再起動時にクライアントへ再接続を促すまでの目安
+const RECONNECT_AFTER_MS: u64 = 3000;
+
+/// WebSocketを使えない環境向けのServer-Sent Events版のエンドポイント
+///
+/// チャットは `GET /sse/chat` でイベントを受け取り、`POST /sse/chat/messages` で発言を送る。
+/// イベントはWebSocketと同じフレームで、`event` にフレームの `type`、番号付きのフレームは `id` に `seq` が入る。
+/// 生成中のテキストの断片（WebSocketの `bot_delta`）は `delta` イベントで送る。
+/// `EventSource` はヘッダーを付けられないため、`GET /sse/chat` はクエリの `access_token` でも認証できる。
+pub fn sse_routes() -> Router<AppState> {
+    Router::new()
+        .route("/sse/chat", get(chat_events))
+        .route("/sse/chat/messages", post(post_message))
+        .route("/sse/recommendations", post(recommendation_events))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatEventsQuery {
+    /// 再開するセッション。省略すると新しいセッションを開始する
+    #[serde(default)]
+    session_id: Option<String>,
+    /// `Last-Event-ID` ヘッダーを付けられない初回接続用
+    #[serde(default)]
+    last_event_id: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PostMessageRequest {
+    session_id: String,
+    id: String,
+    text: String,
+}
+
+#[derive(Debug, Serialize)]
+struct SseError {
+    error: String,
+}
+
+async fn chat_events(
+    State(state): State<AppState>,
+    UserPrincipal(principal): UserPrincipal,
+    Query(query): Query<ChatEventsQuery>,
+    headers: HeaderMap,
+) -> Response {
+    // 停止中は新しいセッションを受け付けない
+    if state.shutdown().is_triggered() {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    // 再接続時はブラウザが受信済みの最後の `id` を `Last-Event-ID` で送ってくる
+    let last_seq = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .or(query.last_event_id);
+
+    let attached = state
+        .sessions()
+        .attach_as(Some(&principal.user_id), query.session_id.as_deref(), last_seq);
+    record_conversation(attached.session.lock().unwrap().id());
+    let welcome = attached.welcome();
+
+    // 切断されたらセッションを再開待ちにする
+    let guard = Attachment {
+        sessions: state.sessions().clone(),
+        session: attached.session,
+        listener: attached.listener,
+        _inflight: state.shutdown().track(),
+    };
+    let shutdown = state.shutdown().clone();
+    let events = stream::iter(std::iter::once(welcome).chain(attached.replay))
+        .chain(UnboundedReceiverStream::new(attached.events))
+        .take_until(async move { shutdown.triggered().await })
+        .chain(stream::once(async {
+            ServerEnvelope::transient(ServerFrame::ServerRestarting { reconnect_after_ms: RECONNECT_AFTER_MS })
+        }))
+        .map(move |envelope| {
+            let _ = &guard;
+            event(&envelope)
+        });
+    sse(events).into_response()
+}
+
+async fn post_message(
+    State(state): State<AppState>,
+    UserPrincipal(principal): UserPrincipal,
+    Json(request): Json<PostMessageRequest>,
+) -> Response {
+    // 他のユーザーのセッションは見つからないものとして扱う
+    let session = state
+        .sessions()
+        .get(&request.session_id)
+        .filter(|session| session.lock().unwrap().user_id() == Some(principal.user_id.as_str()));
+    let Some(session) = session else {
+        let body = SseError {
+            error: "Session not found; reconnect to /sse/chat".to_string(),
+        };
+        return (StatusCode::NOT_FOUND, Json(body)).into_response();
+    };
+    record_conversation(&request.session_id);
+    // 停止時はこの呼び出しが終わるまで待つ
+    let _inflight = state.shutdown().track();
+    let chatbot = ChatBot::new()
+        .with_classifier(state.intent_classifier())
+        .with_recommender(state.recommender().clone())
+        .with_user(principal.user_id.clone())
+        .with_conversation(request.session_id.clone());
+
+    // 応答はセッションに接続中の `GET /sse/chat` やWebSocketに送る。この呼び出しが切断されても生成は続く
+    let message = IncomingMessage {
+        id: request.id,
+        user_id: principal.user_id,
+        text: request.text,
+    };
+    let history = state.history().cloned();
+    if let Some(reply) = responder::respond(Arc::new(chatbot), history, &session, message, state.shutdown().track()) {
+        let _ = reply.await;
+    }
+    StatusCode::ACCEPTED.into_response()
+}
+
+/// 推薦だけを生成する。途中経過を送り、最後に `bot_response` で確認済みの一覧を送る
+///
+/// 推薦はキャッシュされるため、切断された場合は同じ条件で呼び出し直せばよい。
+async fn recommendation_events(
+    State(state): State<AppState>,
+    UserPrincipal(principal): UserPrincipal,
+    Json(request): Json<GiftRequest>,
+) -> Response {
+    if state.shutdown().is_triggered() {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    let reply_to = uuid::Uuid::new_v4().to_string();
+    let (frames, receiver) = mpsc::unbounded_channel();
+    let recommender = state.recommender().clone();
+    let usage = UsageContext::new(UsageFeature::Recommendation).with_user(principal.user_id);
+    let inflight = state.shutdown().track();
+    let task = tokio::spawn(async move {
+        let _inflight = inflight;
+        let (events, mut events_rx) = mpsc::unbounded_channel();
+        let mut streamed = 0;
+        let result = {
+            let recommend = recommender.recommend_streaming(&usage, request, events);
+            tokio::pin!(recommend);
+            loop {
+                tokio::select! {
+                    result = &mut recommend => break result,
+                    Some(event) = events_rx.recv() => {
+                        let _ = frames.send(responder::progress(&reply_to, event, &mut streamed));
+                    }
+                }
+            }
+        };
+        while let Ok(event) = events_rx.try_recv() {
+            let _ = frames.send(responder::progress(&reply_to, event, &mut streamed));
+        }
+
+        let frame = match result {
+            Ok(recommendations) => {
+                let message = BotMessage::new().with(PayloadContent::RecommendationCards {
+                    cards: recommendations.items.iter().map(RecommendationCard::from).collect(),
+                    source: recommendations.source,
+                });
+                ServerFrame::BotResponse {
+                    reply_to: reply_to.clone(),
+                    text: message.fallback_text(),
+                    payloads: message.payloads,
+                }
+            }
+            Err(e) => {
+                tracing::error!("Failed to get recommendations: {:?}", e);
+                responder::failure(&e, "推薦の取得中にエラーが発生しました", &reply_to)
+            }
+        };
+        let _ = frames.send(ServerEnvelope::transient(frame));
+        let _ = frames.send(ServerEnvelope::transient(ServerFrame::Done { reply_to }));
+    });
+
+    // 切断されたら生成を中断する
+    let task = AbortOnDrop(task);
+    let events = UnboundedReceiverStream::new(receiver).map(move |envelope| {
+        let _ = &task;
+        event(&envelope)
+    });
+    sse(events).into_response()
+}
+
+fn sse<S>(events: S) -> Sse<S>
+where
+    S: Stream<Item = Result<Event, Infallible>> + Send + 'static,
+{
+    Sse::new(events).keep_alive(KeepAlive::new().interval(HEARTBEAT_INTERVAL).text("heartbeat"))
+}
+
+/// フレームをSSEのイベントにする。`event` はフレームの種類、`id` は通し番号
+fn event(envelope: &ServerEnvelope) -> Result<Event, Infallible> {
+    let data = serde_json::to_value(envelope).unwrap_or_default();
+    let mut event = Event::default().data(data.to_string());
+    if let Some(kind) = event_name(envelope, &data) {
+        event = event.event(kind);
+    }
+    if let Some(seq) = envelope.seq {
+        event = event.id(seq.to_string());
+    }
+    Ok(event)
+}
+
+// イベントの種類。生成中のテキストの断片は、`EventSource` で受け取りやすい `delta` にする
+fn event_name<'a>(envelope: &ServerEnvelope, data: &'a serde_json::Value) -> Option<&'a str> {
+    match envelope.frame {
+        ServerFrame::BotDelta { .. } => Some("delta"),
+        _ => data.get("type").and_then(|kind| kind.as_str()),
+    }
+}
+
+// SSEの接続中はセッションを接続済みにしておき、切断時に再開待ちにする
+struct Attachment {
+    sessions: Arc<SessionRegistry>,
+    session: Arc<Mutex<ChatSession>>,
+    listener: u64,
+    _inflight: InflightGuard,
+}
+
+impl Drop for Attachment {
+    fn drop(&mut self) {
+        self.sessions.detach(&self.session, self.listener);
+    }
+}
+
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sequenced_frames_carry_event_ids_for_resume() {
+        let state = AppState::new("test_key".to_string());
+        let principal = state.auth().verify_token(&state.auth().issue("u").token).unwrap();
+        let attached = state.sessions().attach_as(Some("u"), None, None);
+        let session_id = attached.session.lock().unwrap().id().to_string();
+        let mut listener = attached.events;
+
+        let request = PostMessageRequest {
+            session_id: session_id.clone(),
+            id: "c1".to_string(),
+            text: "こんにちは".to_string(),
+        };
+        let response = post_message(State(state.clone()), UserPrincipal(principal.clone()), Json(request)).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        let mut kinds = Vec::new();
+        while let Ok(envelope) = listener.try_recv() {
+            kinds.push((serde_json::to_value(&envelope).unwrap()["type"].clone(), envelope.seq));
+        }
+        assert_eq!(
+            kinds,
+            vec![
+                ("ack".into(), Some(1)),
+                ("user_message".into(), Some(2)),
+                ("typing".into(), None),
+                ("bot_response".into(), Some(3)),
+                ("done".into(), None),
+            ]
+        );
+
+        // `Last-Event-ID: 2` で再接続すると、受信できなかった応答が再送される
+        let (replay, truncated) = attached.session.lock().unwrap().replay_after(2);
+        assert!(!truncated);
+        assert_eq!(replay.iter().map(|envelope| envelope.seq).collect::<Vec<_>>(), vec![Some(3)]);
+
+        let missing = PostMessageRequest {
+            session_id: "missing".to_string(),
+            id: "c2".to_string(),
+            text: "こんにちは".to_string(),
+        };
+        let response = post_message(State(state.clone()), UserPrincipal(principal), Json(missing)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // 他のユーザーのセッションには送れない
+        let other = state.auth().verify_token(&state.auth().issue("u2").token).unwrap();
+        let request = PostMessageRequest {
+            session_id,
+            id: "c3".to_string(),
+            text: "こんにちは".to_string(),
+        };
+        let response = post_message(State(state), UserPrincipal(other), Json(request)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // 生成中のテキストの断片は `delta` イベントで送る
+        let delta = ServerEnvelope::transient(ServerFrame::BotDelta { reply_to: "c1".to_string(), text: "タオル".to_string() });
+        let data = serde_json::to_value(&delta).unwrap();
+        assert_eq!(event_name(&delta, &data), Some("delta"));
+        assert_eq!(data["text"], "タオル");
+    }
+}
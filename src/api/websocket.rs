@@ -1,97 +1,679 @@
 use axum::{
-    extract::ws::{Message, WebSocket, WebSocketUpgrade},
-    response::IntoResponse,
+    extract::ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+    extract::{ConnectInfo, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
 };
 use futures::{sink::SinkExt, stream::StreamExt};
-use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+use tracing::Instrument;
+use crate::app::auth::Principal;
 use crate::app::chat::chatbot::ChatBot;
+use crate::app::chat::protocol::{
+    ClientEnvelope, ClientFrame, ErrorCode, ServerEnvelope, ServerFrame, PROTOCOL_VERSION,
+};
+use crate::app::chat::responder::{self, IncomingMessage};
+use crate::app::chat::session::ChatSession;
+use crate::config::config::WebSocketConfig;
+use crate::logging::request::record_conversation;
+use crate::metrics;
+use super::auth::UserPrincipal;
+use super::connection_limits::ConnectionPermit;
+use super::gift::AppState;
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct ChatMessage {
-    user_id: String,
-    message: String,
-    message_type: MessageType,
-}
+// アイドル・接続時間の上限による通常の切断（RFC 6455 の 1000 Normal Closure）
+const CLOSE_NORMAL: u16 = 1000;
+// 同じユーザーの接続数の上限を超えた（RFC 6455 の 1008 Policy Violation）
+const CLOSE_POLICY_VIOLATION: u16 = 1008;
+// サーバー再起動による切断を表すクローズコード（RFC 6455 の 1012 Service Restart）
+const CLOSE_SERVICE_RESTART: u16 = 1012;
+// 対応していないプロトコルで接続された（RFC 6455 の 1002 Protocol Error）
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+// 再起動時にクライアントへ再接続を促すまでの目安
+const RECONNECT_AFTER_MS: u64 = 3000;
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-pub enum MessageType {
-    UserMessage,
-    BotResponse,
-    Typing,
-    Error,
+pub fn websocket_routes() -> Router<AppState> {
+    Router::new().route("/ws", get(ws_handler))
 }
 
+/// 接続の前に認証する。ブラウザはヘッダーを付けられないため、クエリの `access_token` でトークンを送る
 pub async fn ws_handler(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    UserPrincipal(principal): UserPrincipal,
     ws: WebSocketUpgrade,
-) -> impl IntoResponse {
-    ws.on_upgrade(handle_socket)
+) -> Response {
+    // 停止中は新しいセッションを受け付けない
+    if state.shutdown().is_triggered() {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    let config = state.websocket_config().clone();
+    // 接続元ごとの上限。接続元が分からない場合は数えない
+    let ip = connect_info.map(|ConnectInfo(addr)| addr.ip());
+    let ip_permit = match ip {
+        Some(ip) => {
+            match state.connections().acquire_ip(ip, config.max_connections_per_ip) {
+                Some(permit) => Some(permit),
+                None => {
+                    tracing::warn!("Rejected WebSocket connection from {}: too many connections", ip);
+                    return StatusCode::TOO_MANY_REQUESTS.into_response();
+                }
+            }
+        }
+        None => None,
+    };
+    // 接続中のログにも、接続したリクエストの `user_id` と `conversation_id` を付ける
+    let span = tracing::Span::current();
+    ws.max_message_size(config.max_message_bytes)
+        .on_upgrade(move |socket| handle_socket(socket, state, principal, ip, ip_permit).instrument(span))
 }
 
-async fn handle_socket(socket: WebSocket) {
+async fn handle_socket(
+    socket: WebSocket,
+    state: AppState,
+    principal: Principal,
+    ip: Option<IpAddr>,
+    _ip_permit: Option<ConnectionPermit>,
+) {
+    // 接続中のセッション数（切断時にガードの破棄で減る）
+    let _session = metrics::global().websocket_session();
+    // 停止時は処理中の応答を返し終えるまで待ってもらう
+    let _inflight = state.shutdown().track();
+    let shutdown = state.shutdown().clone();
+    let config = state.websocket_config().clone();
     let (mut sender, mut receiver) = socket.split();
-    let chatbot = Arc::new(ChatBot::new());
+
+    // 送信は専用のタスクで行い、受信側は送信の完了を待たない。
+    // 受信しないクライアントのためにメモリを使い続けないよう、送信待ちの数には上限を設ける
+    let (outbound, mut outbound_rx) = mpsc::channel(config.outbound_queue_size);
+    let writer = tokio::spawn(async move {
+        while let Some(message) = outbound_rx.recv().await {
+            let closing = matches!(message, Message::Close(_));
+            if sender.send(message).await.is_err() || closing {
+                break;
+            }
+        }
+    });
+    let mut liveness = Liveness::new(&config);
+    // トークンの期限が切れたら閉じる。クライアントは更新したトークンで再接続する
+    if let Some(remaining) = principal.remaining() {
+        liveness.session_deadline = liveness.session_deadline.min(Instant::now() + remaining);
+    }
+    let mut connection = Connection::new(state, outbound.clone(), principal, ip);
+    let mut pending = VecDeque::new();
 
     // メッセージ受信ループ
-    while let Some(Ok(message)) = receiver.next().await {
-        if let Message::Text(text) = message {
-            match serde_json::from_str::<ChatMessage>(&text) {
-                Ok(chat_message) => {
-                    // タイピング状態を送信
-                    let typing = ChatMessage {
-                        user_id: chat_message.user_id.clone(),
-                        message: "...".to_string(),
-                        message_type: MessageType::Typing,
-                    };
-                    if let Ok(typing_text) = serde_json::to_string(&typing) {
-                        let _ = sender.send(Message::Text(typing_text)).await;
+    loop {
+        if connection.overflowed() {
+            break;
+        }
+        let text = match pending.pop_front() {
+            Some(text) => text,
+            None => {
+                let idle_deadline = liveness.idle_deadline();
+                let session_deadline = liveness.session_deadline;
+                tokio::select! {
+                    message = receiver.next() => match liveness.receive(message) {
+                        Incoming::Text(text) => text,
+                        Incoming::Closed => break,
+                        Incoming::Control => continue,
+                    },
+                    _ = liveness.ping.tick() => {
+                        if !liveness.ping(&outbound) {
+                            break;
+                        }
+                        continue;
+                    }
+                    _ = tokio::time::sleep_until(idle_deadline) => {
+                        connection.close(CLOSE_NORMAL, "idle timeout");
+                        break;
                     }
+                    _ = tokio::time::sleep_until(session_deadline) => {
+                        // クライアントはセッションを指定して再接続する
+                        connection.close(CLOSE_NORMAL, "session expired");
+                        break;
+                    }
+                    _ = shutdown.triggered() => {
+                        // 再接続を促してから閉じる
+                        connection.send(&ServerEnvelope::transient(ServerFrame::ServerRestarting {
+                            reconnect_after_ms: RECONNECT_AFTER_MS,
+                        }));
+                        connection.close(CLOSE_SERVICE_RESTART, "server restarting");
+                        break;
+                    }
+                }
+            }
+        };
 
-                    // チャットボットで処理
-                    match chatbot.process_message(&chat_message.message).await {
-                        Ok(bot_response) => {
-                            let response = ChatMessage {
-                                user_id: chat_message.user_id,
-                                message: bot_response,
-                                message_type: MessageType::BotResponse,
-                            };
-
-                            if let Ok(response_text) = serde_json::to_string(&response) {
-                                if sender.send(Message::Text(response_text)).await.is_err() {
-                                    break;
-                                }
+        // 応答の生成中も受信を続け、pingへの応答や取り消しを処理する。
+        // 同じセッションのメッセージは会話の順序を保つため1件ずつ処理し、後続は処理待ちにする
+        let handling_id = match parse_frame(&text) {
+            Some(ClientFrame::Message { id, .. }) => Some(id),
+            _ => None,
+        };
+        let outcome = {
+            let handling = connection.handle_text(&text);
+            tokio::pin!(handling);
+            loop {
+                tokio::select! {
+                    keep_open = &mut handling => break Handled::Done(keep_open),
+                    message = receiver.next() => match liveness.receive(message) {
+                        Incoming::Text(text) => match parse_frame(&text) {
+                            Some(ClientFrame::Cancel { id }) if handling_id.as_ref() == Some(&id) => {
+                                break Handled::Cancelled(id);
                             }
-                        }
-                        Err(_) => {
-                            let error_message = ChatMessage {
-                                user_id: "system".to_string(),
-                                message: "メッセージの処理中にエラーが発生しました".to_string(),
-                                message_type: MessageType::Error,
-                            };
-
-                            if let Ok(error_text) = serde_json::to_string(&error_message) {
-                                if sender.send(Message::Text(error_text)).await.is_err() {
-                                    break;
+                            Some(ClientFrame::Cancel { id }) => {
+                                let before = pending.len();
+                                pending.retain(|queued| !is_message(queued, &id));
+                                if pending.len() < before {
+                                    cancelled(&outbound, id);
                                 }
                             }
+                            Some(ClientFrame::Message { id, .. }) if pending.len() >= config.max_pending_messages => {
+                                send_frame(&outbound, &ServerEnvelope::transient(ServerFrame::Error {
+                                    code: ErrorCode::Busy,
+                                    message: "処理待ちのメッセージが多すぎます。しばらくしてから送信してください".to_string(),
+                                    reply_to: Some(id),
+                                    retry_after_ms: None,
+                                }));
+                            }
+                            _ => pending.push_back(text),
+                        },
+                        // 切断された。応答の生成はセッションのタスクで続き、他の端末や再接続した端末に届く
+                        Incoming::Closed => break Handled::Disconnected,
+                        Incoming::Control => {}
+                    },
+                    _ = liveness.ping.tick() => {
+                        if !liveness.ping(&outbound) {
+                            break Handled::Disconnected;
                         }
                     }
                 }
-                Err(_) => {
-                    let error_message = ChatMessage {
-                        user_id: "system".to_string(),
-                        message: "無効なメッセージ形式です".to_string(),
-                        message_type: MessageType::Error,
-                    };
-
-                    if let Ok(error_text) = serde_json::to_string(&error_message) {
-                        if sender.send(Message::Text(error_text)).await.is_err() {
-                            break;
-                        }
-                    }
+            }
+        };
+        match outcome {
+            Handled::Done(true) => {}
+            Handled::Cancelled(id) => {
+                connection.cancel(&id);
+                cancelled(&outbound, id);
+            }
+            Handled::Done(false) | Handled::Disconnected => break,
+        }
+    }
+
+    let overflowed = connection.overflowed();
+    connection.detach();
+    drop(connection);
+    drop(outbound);
+    if overflowed {
+        // 送信が追いつかないクライアントは閉じる。再接続すれば番号付きのフレームは再送される
+        tracing::warn!("Closing WebSocket connection: outbound queue is full");
+        writer.abort();
+    } else {
+        let _ = writer.await;
+    }
+}
+
+enum Handled {
+    Done(bool),
+    Cancelled(String),
+    Disconnected,
+}
+
+enum Incoming {
+    Text(String),
+    /// ping・pongなど、応答を必要としないフレーム
+    Control,
+    Closed,
+}
+
+/// 接続の死活とタイムアウトの管理
+struct Liveness {
+    ping: Interval,
+    // 最後に何かを受信した時刻（pongを含む）
+    last_received: Instant,
+    // 最後にクライアントのメッセージを受信した時刻
+    last_message: Instant,
+    // この時間何も受信しなければ切断されたとみなす
+    dead_after: Duration,
+    idle_timeout: Duration,
+    session_deadline: Instant,
+}
+
+impl Liveness {
+    fn new(config: &WebSocketConfig) -> Self {
+        let now = Instant::now();
+        let ping_interval = Duration::from_secs(config.ping_interval_seconds);
+        let mut ping = tokio::time::interval_at(now + ping_interval, ping_interval);
+        ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Self {
+            ping,
+            last_received: now,
+            last_message: now,
+            dead_after: ping_interval + Duration::from_secs(config.pong_timeout_seconds),
+            idle_timeout: Duration::from_secs(config.idle_timeout_seconds),
+            session_deadline: now + Duration::from_secs(config.max_session_seconds),
+        }
+    }
+
+    fn receive(&mut self, message: Option<Result<Message, axum::Error>>) -> Incoming {
+        let now = Instant::now();
+        match message {
+            Some(Ok(Message::Text(text))) => {
+                self.last_received = now;
+                self.last_message = now;
+                Incoming::Text(text)
+            }
+            Some(Ok(Message::Close(_))) | None => Incoming::Closed,
+            Some(Err(e)) => {
+                // 上限を超えるサイズのメッセージもここで切断される
+                tracing::debug!("WebSocket receive failed: {}", e);
+                Incoming::Closed
+            }
+            Some(Ok(_)) => {
+                self.last_received = now;
+                Incoming::Control
+            }
+        }
+    }
+
+    /// pingを送る。しばらく何も受信していなければ `false`（相手が応答しない）
+    fn ping(&self, outbound: &mpsc::Sender<Message>) -> bool {
+        if self.last_received.elapsed() > self.dead_after {
+            tracing::info!("Closing unresponsive WebSocket connection");
+            return false;
+        }
+        let _ = outbound.try_send(Message::Ping(Vec::new()));
+        true
+    }
+
+    fn idle_deadline(&self) -> Instant {
+        self.last_message + self.idle_timeout
+    }
+}
+
+fn parse_frame(text: &str) -> Option<ClientFrame> {
+    serde_json::from_str::<ClientEnvelope>(text).ok().map(|envelope| envelope.frame)
+}
+
+fn is_message(text: &str, id: &str) -> bool {
+    matches!(parse_frame(text), Some(ClientFrame::Message { id: queued, .. }) if queued == id)
+}
+
+// 取り消したメッセージに対する応答の終わりを知らせる
+fn cancelled(outbound: &mpsc::Sender<Message>, id: String) {
+    send_frame(outbound, &ServerEnvelope::transient(ServerFrame::Error {
+        code: ErrorCode::Cancelled,
+        message: "メッセージの処理を取り消しました".to_string(),
+        reply_to: Some(id.clone()),
+        retry_after_ms: None,
+    }));
+    send_frame(outbound, &ServerEnvelope::transient(ServerFrame::Done { reply_to: id }));
+}
+
+/// 送信待ちに入れる。キューがいっぱいの場合は `false`
+fn send_frame(outbound: &mpsc::Sender<Message>, envelope: &ServerEnvelope) -> bool {
+    let Ok(text) = serde_json::to_string(envelope) else {
+        return true;
+    };
+    !matches!(outbound.try_send(Message::Text(text)), Err(TrySendError::Full(_)))
+}
+
+// 接続したユーザーとして応答するチャットボット。再読み込みしたルールを使うためメッセージごとに作る
+fn chatbot(state: &AppState, principal: &Principal, session_id: String) -> ChatBot {
+    ChatBot::new()
+        .with_classifier(state.intent_classifier())
+        .with_recommender(state.recommender().clone())
+        .with_user(principal.user_id.clone())
+        .with_conversation(session_id)
+}
+
+/// 1つのWebSocket接続の状態
+struct Connection {
+    state: AppState,
+    session: Option<Arc<Mutex<ChatSession>>>,
+    // セッションでのこの接続の番号
+    listener: u64,
+    // セッションに送られたフレームをこの接続に転送するタスク
+    forwarder: Option<JoinHandle<()>>,
+    // 接続時の認証情報
+    principal: Principal,
+    // 接続元。メッセージごとの流量制限に使う
+    ip: Option<IpAddr>,
+    // ユーザーごとの接続数に数えている間保持する。`hello` で取得する
+    user_permit: Option<ConnectionPermit>,
+    outbound: mpsc::Sender<Message>,
+    // 番号付きのフレームを送信待ちに入れられなかった
+    overflowed: Arc<AtomicBool>,
+}
+
+impl Connection {
+    fn new(state: AppState, outbound: mpsc::Sender<Message>, principal: Principal, ip: Option<IpAddr>) -> Self {
+        Self {
+            state,
+            session: None,
+            listener: 0,
+            forwarder: None,
+            principal,
+            ip,
+            user_permit: None,
+            outbound,
+            overflowed: Arc::default(),
+        }
+    }
+
+    /// 受信したフレームを処理する。接続を閉じる場合は `false`
+    async fn handle_text(&mut self, text: &str) -> bool {
+        // バージョンが違う場合は、フレームの形式も違う可能性があるため先に確認する
+        let version = serde_json::from_str::<serde_json::Value>(text)
+            .ok()
+            .and_then(|value| value.get("v").and_then(|v| v.as_u64()));
+        if version.is_some_and(|v| v != u64::from(PROTOCOL_VERSION)) {
+            self.send_error(
+                ErrorCode::UnsupportedVersion,
+                format!("プロトコルのバージョン {} のみ対応しています", PROTOCOL_VERSION),
+                None,
+            );
+            self.close(CLOSE_PROTOCOL_ERROR, "unsupported protocol version");
+            return false;
+        }
+        let envelope = match serde_json::from_str::<ClientEnvelope>(text) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                self.send_error(ErrorCode::InvalidMessage, format!("無効なメッセージ形式です: {}", e), None);
+                return true;
+            }
+        };
+
+        match envelope.frame {
+            ClientFrame::Hello { session_id, last_seq } => {
+                if !self.count_user() {
+                    return false;
+                }
+                self.detach();
+                let attached = self.state.sessions().attach_as(Some(&self.principal.user_id), session_id.as_deref(), last_seq);
+                let session_id = attached.session.lock().unwrap().id().to_string();
+                record_conversation(&session_id);
+                // 再送分は送信待ちの上限を超えうるため、溢れたとして閉じずに空くのを待って入れる
+                self.send_waiting(&attached.welcome()).await;
+                for envelope in &attached.replay {
+                    self.send_waiting(envelope).await;
                 }
+                self.forwarder = Some(self.forward(attached.events));
+                self.listener = attached.listener;
+                self.session = Some(attached.session);
+            }
+            ClientFrame::Ack { seq } => {
+                if let Some(session) = &self.session {
+                    session.lock().unwrap().ack(self.listener, seq);
+                }
+            }
+            ClientFrame::Message { id, text, .. } => {
+                return self.handle_message(id, text).await;
+            }
+            // 処理中・処理待ちのものは受信ループで取り消す。ここに届くのは処理済みのメッセージ
+            ClientFrame::Cancel { .. } => {}
+        }
+        true
+    }
+
+    async fn handle_message(&mut self, id: String, text: String) -> bool {
+        let Some(session) = self.session.clone() else {
+            self.send_error(
+                ErrorCode::HelloRequired,
+                "最初に hello を送信してください".to_string(),
+                Some(id),
+            );
+            return true;
+        };
+        // 受け付けなかったメッセージは処理も記録もしないため、この接続にだけ知らせる
+        if let Err(exceeded) = self.state.rate_limits().check(Some(&self.principal), self.ip) {
+            self.send(&ServerEnvelope::transient(ServerFrame::Error {
+                code: ErrorCode::RateLimited,
+                message: "送信が多すぎます。しばらく待ってから再度お試しください".to_string(),
+                reply_to: Some(id.clone()),
+                retry_after_ms: Some(exceeded.retry_after.as_millis() as u64),
+            }));
+            self.send(&ServerEnvelope::transient(ServerFrame::Done { reply_to: id }));
+            return true;
+        }
+        let session_id = session.lock().unwrap().id().to_string();
+        let chatbot = Arc::new(chatbot(&self.state, &self.principal, session_id));
+        let message = IncomingMessage { id, user_id: self.principal.user_id.clone(), text };
+        let history = self.state.history().cloned();
+        let inflight = self.state.shutdown().track();
+        if let Some(reply) = responder::respond(chatbot, history, &session, message, inflight) {
+            // 待つのをやめても（切断・取り消し）生成は続く。取り消しは `cancel` で行う
+            let _ = reply.await;
+        }
+        true
+    }
+
+    // 生成中の応答を取り消す
+    fn cancel(&self, id: &str) {
+        if let Some(session) = &self.session {
+            session.lock().unwrap().cancel(id);
+        }
+    }
+
+    /// ユーザーごとの接続数に数える。上限に達していて接続を閉じる場合は `false`
+    fn count_user(&mut self) -> bool {
+        if self.user_permit.is_some() {
+            return true;
+        }
+        let max = self.state.websocket_config().max_connections_per_user;
+        match self.state.connections().acquire_user(&self.principal.user_id, max) {
+            Some(permit) => {
+                self.user_permit = Some(permit);
+                true
+            }
+            None => {
+                self.send_error(
+                    ErrorCode::TooManyConnections,
+                    "同時に接続できる数の上限に達しました".to_string(),
+                    None,
+                );
+                self.close(CLOSE_POLICY_VIOLATION, "too many connections");
+                false
             }
         }
     }
-} 
\ No newline at end of file
+
+    // セッションのフレームを送信待ちに移す。他の端末の発言や応答もここから届く
+    fn forward(&self, mut events: mpsc::UnboundedReceiver<ServerEnvelope>) -> JoinHandle<()> {
+        let outbound = self.outbound.clone();
+        let overflowed = self.overflowed.clone();
+        tokio::spawn(async move {
+            while let Some(envelope) = events.recv().await {
+                if !send_frame(&outbound, &envelope) && envelope.seq.is_some() {
+                    overflowed.store(true, Ordering::Relaxed);
+                }
+            }
+            // やり取りがなくセッションが破棄された。クライアントは新しいセッションで再接続する
+            let _ = outbound.try_send(Message::Close(Some(CloseFrame {
+                code: CLOSE_NORMAL,
+                reason: "session expired".into(),
+            })));
+        })
+    }
+
+    /// 送信待ちに入れる。キューがいっぱいの場合、番号のないフレーム（途中経過など）は捨て、
+    /// 番号付きのフレームは再接続時の再送に任せて接続を閉じる
+    fn send(&self, envelope: &ServerEnvelope) {
+        if !send_frame(&self.outbound, envelope) && envelope.seq.is_some() {
+            self.overflowed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // 送信待ちが空くまで待って入れる。接続が閉じていれば捨てる
+    async fn send_waiting(&self, envelope: &ServerEnvelope) {
+        if let Ok(text) = serde_json::to_string(envelope) {
+            let _ = self.outbound.send(Message::Text(text)).await;
+        }
+    }
+
+    fn overflowed(&self) -> bool {
+        self.overflowed.load(Ordering::Relaxed)
+    }
+
+    // この接続だけへのエラー。セッションを共有する他の端末には送らず、再送もしない
+    fn send_error(&self, code: ErrorCode, message: String, reply_to: Option<String>) {
+        self.send(&ServerEnvelope::transient(ServerFrame::Error { code, message, reply_to, retry_after_ms: None }));
+    }
+
+    fn close(&self, code: u16, reason: &'static str) {
+        let _ = self.outbound.try_send(Message::Close(Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        })));
+    }
+
+    fn detach(&mut self) {
+        if let Some(forwarder) = self.forwarder.take() {
+            forwarder.abort();
+        }
+        if let Some(session) = self.session.take() {
+            self.state.sessions().detach(&session, self.listener);
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.detach();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::auth::PrincipalKind;
+
+    fn principal(user_id: &str) -> Principal {
+        Principal {
+            user_id: user_id.to_string(),
+            kind: PrincipalKind::Session,
+            admin: false,
+            expires_at: None,
+        }
+    }
+
+    // セッションからの転送はタスクを経由するため、少し待ってから読む
+    async fn received(outbound: &mut mpsc::Receiver<Message>) -> Vec<ServerEnvelope> {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let mut envelopes = Vec::new();
+        while let Ok(message) = outbound.try_recv() {
+            if let Message::Text(text) = message {
+                envelopes.push(serde_json::from_str(&text).unwrap());
+            }
+        }
+        envelopes
+    }
+
+    #[tokio::test]
+    async fn test_resume_replays_missed_responses_and_skips_duplicates() {
+        let state = AppState::new("test_key".to_string());
+        let (outbound, mut rx) = mpsc::channel(64);
+        let mut connection = Connection::new(state.clone(), outbound, principal("u"), None);
+
+        let message = r#"{"v":1,"type":"message","id":"c1","text":"こんにちは"}"#;
+        connection.handle_text(message).await;
+        assert!(matches!(
+            received(&mut rx).await[0].frame,
+            ServerFrame::Error { code: ErrorCode::HelloRequired, .. }
+        ));
+
+        connection.handle_text(r#"{"v":1,"type":"hello"}"#).await;
+        connection.handle_text(message).await;
+        let frames = received(&mut rx).await;
+        let ServerFrame::Welcome { session_id, resumed: false, .. } = &frames[0].frame else {
+            panic!("expected welcome: {:?}", frames);
+        };
+        let session_id = session_id.clone();
+        assert_eq!(frames.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![None, Some(1), Some(2), None, Some(3), None]);
+        connection.detach();
+
+        // 応答を受信する前に切断され、同じメッセージを再送した
+        let (outbound, mut rx) = mpsc::channel(64);
+        let mut connection = Connection::new(state, outbound, principal("u"), None);
+        let hello = format!(r#"{{"v":1,"type":"hello","session_id":"{}","last_seq":2}}"#, session_id);
+        connection.handle_text(&hello).await;
+        connection.handle_text(message).await;
+        let frames = received(&mut rx).await;
+        assert!(matches!(frames[0].frame, ServerFrame::Welcome { resumed: true, replayed: 1, .. }));
+        assert!(matches!(frames[1].frame, ServerFrame::BotResponse { .. }));
+        assert_eq!(frames[2].frame, ServerFrame::Ack { id: "c1".to_string(), duplicate: true });
+        // 重複したメッセージは処理し直さないため、`done` も送らない
+        assert_eq!(frames.len(), 3);
+
+        assert!(!connection.handle_text(r#"{"v":2,"type":"hello"}"#).await);
+    }
+
+    #[tokio::test]
+    async fn test_per_user_limit_and_outbound_overflow() {
+        let config = WebSocketConfig { max_connections_per_user: 1, ..WebSocketConfig::default() };
+        let state = AppState::new("test_key".to_string()).with_websocket_config(config);
+        let message = r#"{"v":1,"type":"message","id":"c1","text":"こんにちは"}"#;
+
+        let (outbound, _rx) = mpsc::channel(64);
+        let mut first = Connection::new(state.clone(), outbound, principal("u"), None);
+        first.handle_text(r#"{"v":1,"type":"hello"}"#).await;
+        assert!(first.handle_text(message).await);
+
+        let (outbound, mut rx) = mpsc::channel(64);
+        let mut second = Connection::new(state.clone(), outbound, principal("u"), None);
+        assert!(!second.handle_text(r#"{"v":1,"type":"hello"}"#).await);
+        assert!(received(&mut rx).await.iter().any(|envelope| matches!(
+            envelope.frame,
+            ServerFrame::Error { code: ErrorCode::TooManyConnections, .. }
+        )));
+
+        // 切断すると同じユーザーで接続できる
+        drop(first);
+        assert_eq!(state.connections().user_connections("u"), 0);
+
+        // 送信待ちが溢れた。`welcome` で埋まり、番号付きの `ack` を入れられない
+        let (outbound, _rx) = mpsc::channel(1);
+        let mut slow = Connection::new(state, outbound, principal("u2"), None);
+        slow.handle_text(r#"{"v":1,"type":"hello"}"#).await;
+        assert!(!slow.overflowed());
+        slow.handle_text(message).await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(slow.overflowed());
+    }
+
+    #[tokio::test]
+    async fn test_replay_larger_than_outbound_queue_does_not_overflow() {
+        let state = AppState::new("test_key".to_string());
+        let attached = state.sessions().attach_as(Some("u"), None, None);
+        for n in 0..10 {
+            attached.session.lock().unwrap().push(ServerFrame::Typing { reply_to: n.to_string() });
+        }
+
+        // 送信待ちの上限（2）より再送分（`welcome` と10件）が多い
+        let (outbound, mut rx) = mpsc::channel(2);
+        let mut connection = Connection::new(state, outbound, principal("u"), None);
+        let reader = tokio::spawn(async move {
+            let mut count = 0;
+            while rx.recv().await.is_some() {
+                count += 1;
+                if count == 11 {
+                    break;
+                }
+            }
+            count
+        });
+        assert!(connection.handle_text(r#"{"v":1,"type":"hello"}"#).await);
+        assert!(!connection.overflowed());
+        assert_eq!(tokio::time::timeout(Duration::from_secs(1), reader).await.unwrap().unwrap(), 11);
+    }
+}
@@ -0,0 +1,265 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::config::config::{ApiKeyConfig, AuthConfig};
+use crate::error::AppError;
+
+// トークンの発行者。他のサービスの同じ鍵で署名されたトークンと区別する
+const ISSUER: &str = "gift-advisor";
+// サーバー間の時刻のずれとして許容する秒数
+const LEEWAY_SECONDS: u64 = 5;
+// 匿名のユーザーIDの接頭辞
+const ANONYMOUS_PREFIX: &str = "anon";
+// パートナーが指定できるユーザーIDの長さの上限
+const MAX_EXTERNAL_USER_ID_LEN: usize = 128;
+
+/// リクエストを送った利用者。ユーザーIDはリクエストの本文ではなく、必ずここから取る
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal {
+    pub user_id: String,
+    pub kind: PrincipalKind,
+    /// 管理用エンドポイントを呼び出せる
+    pub admin: bool,
+    /// 認証情報の有効期限（UNIX時間の秒）。APIキーには期限がない
+    pub expires_at: Option<u64>,
+}
+
+impl Principal {
+    /// 認証情報の期限が切れるまでの時間
+    pub fn remaining(&self) -> Option<Duration> {
+        self.expires_at.map(|expires_at| Duration::from_secs(expires_at.saturating_sub(unix_now())))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PrincipalKind {
+    /// ブラウザなどに発行したセッショントークン
+    Session,
+    /// サーバー間連携のAPIキー。`user_id` はパートナーの名前
+    ApiKey,
+    /// パートナーがAPIキーと一緒に指定したエンドユーザー。`user_id` は `{パートナー名}:{ユーザーID}`
+    PartnerUser { partner: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum AuthError {
+    #[error("認証情報がありません")]
+    Missing,
+    #[error("トークンの有効期限が切れています")]
+    Expired,
+    #[error("トークンが無効です")]
+    InvalidToken,
+    #[error("APIキーが無効です")]
+    InvalidApiKey,
+    #[error("ユーザーIDが不正です")]
+    InvalidUserId,
+    #[error("この操作を行う権限がありません")]
+    Forbidden,
+}
+
+impl From<AuthError> for AppError {
+    fn from(error: AuthError) -> Self {
+        match error {
+            AuthError::Forbidden => AppError::Forbidden(error.to_string()),
+            AuthError::InvalidUserId => AppError::Validation(error.to_string()),
+            _ => AppError::Auth(error.to_string()),
+        }
+    }
+}
+
+/// 発行したセッショントークン
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IssuedToken {
+    pub token: String,
+    pub token_type: String,
+    pub user_id: String,
+    /// 有効期限（UNIX時間の秒）
+    pub expires_at: u64,
+    pub expires_in: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    sub: String,
+    iss: String,
+    iat: u64,
+    exp: u64,
+}
+
+/// セッショントークンの発行・検証とAPIキーの照合
+pub struct Authenticator {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    validation: Validation,
+    ttl: Duration,
+    api_keys: Vec<ApiKeyConfig>,
+}
+
+impl Authenticator {
+    pub fn new(config: &AuthConfig) -> Self {
+        let secret = if config.token_secret.is_empty() {
+            tracing::warn!("auth.token_secret is not set; session tokens will not survive a restart");
+            format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple())
+        } else {
+            config.token_secret.expose().to_string()
+        };
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_issuer(&[ISSUER]);
+        validation.leeway = LEEWAY_SECONDS;
+        Self {
+            encoding: EncodingKey::from_secret(secret.as_bytes()),
+            decoding: DecodingKey::from_secret(secret.as_bytes()),
+            validation,
+            ttl: Duration::from_secs(config.token_ttl_seconds),
+            api_keys: config.api_keys.clone(),
+        }
+    }
+
+    /// ユーザーのセッショントークンを発行する
+    pub fn issue(&self, user_id: &str) -> IssuedToken {
+        let now = unix_now();
+        let claims = Claims {
+            sub: user_id.to_string(),
+            iss: ISSUER.to_string(),
+            iat: now,
+            exp: now + self.ttl.as_secs(),
+        };
+        let token = jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &self.encoding)
+            .expect("HS256 signing does not fail");
+        IssuedToken {
+            token,
+            token_type: "Bearer".to_string(),
+            user_id: claims.sub,
+            expires_at: claims.exp,
+            expires_in: self.ttl.as_secs(),
+        }
+    }
+
+    /// 新しい匿名のユーザーとしてトークンを発行する
+    pub fn issue_anonymous(&self) -> IssuedToken {
+        self.issue(&format!("{}:{}", ANONYMOUS_PREFIX, uuid::Uuid::new_v4()))
+    }
+
+    /// パートナーが認証したユーザーのトークンを発行する。ユーザーIDにはパートナーの名前を付け、他のパートナーと区別する
+    pub fn issue_for_partner(&self, partner: &Principal, user_id: &str) -> Result<IssuedToken, AuthError> {
+        Ok(self.issue(&self.partner_user(partner, user_id)?.user_id))
+    }
+
+    /// パートナーがAPIキーで直接呼び出すときのエンドユーザー。トークンを発行した場合と同じユーザーIDになる
+    pub fn partner_user(&self, partner: &Principal, user_id: &str) -> Result<Principal, AuthError> {
+        if partner.kind != PrincipalKind::ApiKey {
+            return Err(AuthError::Forbidden);
+        }
+        let user_id = user_id.trim();
+        if user_id.is_empty() || user_id.len() > MAX_EXTERNAL_USER_ID_LEN || user_id.chars().any(char::is_control) {
+            return Err(AuthError::InvalidUserId);
+        }
+        Ok(Principal {
+            user_id: format!("{}:{}", partner.user_id, user_id),
+            kind: PrincipalKind::PartnerUser { partner: partner.user_id.clone() },
+            admin: false,
+            expires_at: None,
+        })
+    }
+
+    pub fn verify_token(&self, token: &str) -> Result<Principal, AuthError> {
+        let data = jsonwebtoken::decode::<Claims>(token, &self.decoding, &self.validation).map_err(|e| {
+            match e.kind() {
+                ErrorKind::ExpiredSignature => AuthError::Expired,
+                _ => AuthError::InvalidToken,
+            }
+        })?;
+        Ok(Principal {
+            user_id: data.claims.sub,
+            kind: PrincipalKind::Session,
+            admin: false,
+            expires_at: Some(data.claims.exp),
+        })
+    }
+
+    pub fn verify_api_key(&self, key: &str) -> Result<Principal, AuthError> {
+        // どのキーと一致したかを応答時間から推測されないよう、すべてのキーと比較する
+        let mut matched = None;
+        for api_key in &self.api_keys {
+            if constant_time_eq(api_key.key.expose().as_bytes(), key.as_bytes()) {
+                matched = Some(api_key);
+            }
+        }
+        let api_key = matched.ok_or(AuthError::InvalidApiKey)?;
+        Ok(Principal {
+            user_id: api_key.name.clone(),
+            kind: PrincipalKind::ApiKey,
+            admin: api_key.admin,
+            expires_at: None,
+        })
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::secret::Secret;
+
+    #[test]
+    fn test_tokens_and_api_keys_identify_the_user() {
+        let config = AuthConfig {
+            token_secret: Secret::new("0123456789abcdef0123456789abcdef"),
+            api_keys: vec![ApiKeyConfig {
+                name: "partner".to_string(),
+                key: Secret::new("partner-key-0123456789abcdef"),
+                admin: false,
+            }],
+            ..AuthConfig::default()
+        };
+        let auth = Authenticator::new(&config);
+
+        let issued = auth.issue_anonymous();
+        assert!(issued.user_id.starts_with("anon:"));
+        assert_eq!(auth.verify_token(&issued.token).unwrap().user_id, issued.user_id);
+
+        // 別の鍵で署名されたトークンや改ざんされたトークンは受け付けない
+        let other = Authenticator::new(&AuthConfig::default());
+        assert_eq!(other.verify_token(&issued.token), Err(AuthError::InvalidToken));
+        assert_eq!(auth.verify_token(&format!("{}x", issued.token)), Err(AuthError::InvalidToken));
+
+        let expired = jsonwebtoken::encode(
+            &Header::new(Algorithm::HS256),
+            &Claims { sub: "u".to_string(), iss: ISSUER.to_string(), iat: 0, exp: 1 },
+            &auth.encoding,
+        )
+        .unwrap();
+        assert_eq!(auth.verify_token(&expired), Err(AuthError::Expired));
+
+        let partner = auth.verify_api_key("partner-key-0123456789abcdef").unwrap();
+        assert_eq!((partner.user_id.as_str(), partner.kind.clone()), ("partner", PrincipalKind::ApiKey));
+        assert_eq!(auth.verify_api_key("partner-key"), Err(AuthError::InvalidApiKey));
+        let issued = auth.issue_for_partner(&partner, "u-42").unwrap();
+        assert_eq!(auth.verify_token(&issued.token).unwrap().user_id, "partner:u-42");
+        // APIキーで直接呼び出した場合も、同じエンドユーザーは同じユーザーIDになる
+        let end_user = auth.partner_user(&partner, "u-42").unwrap();
+        assert_eq!(end_user.user_id, "partner:u-42");
+        assert_eq!(end_user.kind, PrincipalKind::PartnerUser { partner: "partner".to_string() });
+        assert_eq!(auth.partner_user(&end_user, "u-43").unwrap_err(), AuthError::Forbidden);
+
+        // セッショントークンの利用者は他のユーザーのトークンを発行できない
+        let user = auth.verify_token(&issued.token).unwrap();
+        assert_eq!(auth.issue_for_partner(&user, "u-43").unwrap_err(), AuthError::Forbidden);
+    }
+}
@@ -0,0 +1,184 @@
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::sync::{mpsc, oneshot};
+
+/// [`BatchWriter`] がまとめて書き込む先
+#[async_trait]
+pub trait BatchStore<T>: Send + Sync {
+    async fn save_batch(&self, items: &[T]) -> Result<()>;
+}
+
+/// 書き込みのまとめ方
+#[derive(Debug, Clone, Copy)]
+pub struct BatchConfig {
+    /// ログに出す記録の種類
+    pub kind: &'static str,
+    /// この件数たまったらすぐに書き込む
+    pub batch_size: usize,
+    /// たまった件数が少なくても、この間隔で書き込む
+    pub flush_interval: Duration,
+    /// 書き込み待ちのキューの上限。超えた分は記録できない
+    pub queue_capacity: usize,
+    /// 書き込みに失敗したときに再試行のため保持する上限
+    pub max_pending: usize,
+}
+
+enum Command<T> {
+    Record(T),
+    Flush(oneshot::Sender<()>),
+}
+
+/// 記録をバックグラウンドでまとめて書き込む
+///
+/// 呼び出し元を保存の完了まで待たせないため、[`BatchWriter::record`] はキューに積むだけにする。
+/// 保存に失敗した分は保持しておき、次の書き込みで再試行する。
+/// 停止時は [`BatchWriter::flush`] で書き込み待ちの記録を保存する。
+pub struct BatchWriter<T> {
+    sender: mpsc::Sender<Command<T>>,
+    kind: &'static str,
+}
+
+impl<T> Clone for BatchWriter<T> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            kind: self.kind,
+        }
+    }
+}
+
+impl<T: Send + 'static> BatchWriter<T> {
+    pub fn spawn<S>(store: Arc<S>, config: BatchConfig) -> Self
+    where
+        S: BatchStore<T> + ?Sized + 'static,
+    {
+        let (sender, receiver) = mpsc::channel(config.queue_capacity);
+        tokio::spawn(run(store, config, receiver));
+        Self {
+            sender,
+            kind: config.kind,
+        }
+    }
+
+    pub fn record(&self, item: T) {
+        if let Err(e) = self.sender.try_send(Command::Record(item)) {
+            tracing::warn!("Dropped {} record: {}", self.kind, e);
+        }
+    }
+
+    /// 書き込み待ちの記録を保存し終えるまで待つ。保存に失敗した分は保持したまま戻る
+    pub async fn flush(&self) {
+        let (ack, done) = oneshot::channel();
+        if self.sender.send(Command::Flush(ack)).await.is_ok() {
+            let _ = done.await;
+        }
+    }
+}
+
+async fn run<T, S>(store: Arc<S>, config: BatchConfig, mut receiver: mpsc::Receiver<Command<T>>)
+where
+    S: BatchStore<T> + ?Sized,
+{
+    let mut pending = Vec::new();
+    let mut ticker = tokio::time::interval(config.flush_interval);
+    loop {
+        tokio::select! {
+            command = receiver.recv() => match command {
+                Some(Command::Record(item)) => {
+                    pending.push(item);
+                    if pending.len() >= config.batch_size {
+                        write(store.as_ref(), &config, &mut pending).await;
+                    }
+                }
+                Some(Command::Flush(ack)) => {
+                    write(store.as_ref(), &config, &mut pending).await;
+                    let _ = ack.send(());
+                }
+                None => {
+                    write(store.as_ref(), &config, &mut pending).await;
+                    break;
+                }
+            },
+            _ = ticker.tick() => write(store.as_ref(), &config, &mut pending).await,
+        }
+    }
+}
+
+async fn write<T, S>(store: &S, config: &BatchConfig, pending: &mut Vec<T>)
+where
+    S: BatchStore<T> + ?Sized,
+{
+    if pending.is_empty() {
+        return;
+    }
+    match store.save_batch(pending).await {
+        Ok(()) => pending.clear(),
+        Err(e) => {
+            tracing::error!("Failed to save {} {} records: {:?}", pending.len(), config.kind, e);
+            // 次回に再試行する。上限を超えた古いものは諦める
+            if pending.len() > config.max_pending {
+                let dropped = pending.len() - config.max_pending;
+                pending.drain(..dropped);
+                tracing::warn!("Dropped {} unsaved {} records", dropped, config.kind);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // 最初の保存だけ失敗し、呼び出しごとの件数を記録する
+    #[derive(Default)]
+    struct FlakyStore {
+        calls: Mutex<Vec<usize>>,
+        saved: Mutex<Vec<u32>>,
+    }
+
+    #[async_trait]
+    impl BatchStore<u32> for FlakyStore {
+        async fn save_batch(&self, items: &[u32]) -> Result<()> {
+            let mut calls = self.calls.lock().unwrap();
+            calls.push(items.len());
+            if calls.len() == 1 {
+                anyhow::bail!("database is down");
+            }
+            self.saved.lock().unwrap().extend_from_slice(items);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_batches_are_retried_and_empty_batches_are_skipped() {
+        let store = Arc::new(FlakyStore::default());
+        let writer = BatchWriter::spawn(
+            store.clone(),
+            BatchConfig {
+                kind: "test",
+                batch_size: 10,
+                flush_interval: Duration::from_secs(60),
+                queue_capacity: 16,
+                max_pending: 100,
+            },
+        );
+
+        // 書き込み待ちがなければ保存しない
+        writer.flush().await;
+        assert!(store.calls.lock().unwrap().is_empty());
+
+        writer.record(1);
+        writer.record(2);
+        writer.flush().await;
+        assert!(store.saved.lock().unwrap().is_empty());
+
+        // 失敗した分は次の書き込みで一緒に保存する
+        writer.record(3);
+        writer.flush().await;
+        assert_eq!(*store.calls.lock().unwrap(), [2, 3]);
+        assert_eq!(*store.saved.lock().unwrap(), [1, 2, 3]);
+    }
+}
@@ -1,16 +1,206 @@
+use std::sync::Arc;
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use super::conversation_handler::{
+    event_label, relationship_label, slot_options, slot_question, ConversationHandler,
+    ConversationState, NextStep, Slot,
+};
+use super::message::{format_yen, BotMessage, PayloadContent, QuickReply, RecommendationCard, SummaryItem};
+use crate::app::gift::recommendation::{
+    EventType, GiftRecommender, GiftRequest, RecommendationEvent, RecommendationSource,
+};
+use crate::app::nlp::intent_classifier::IntentClassifier;
+use crate::app::usage::{UsageContext, UsageFeature};
+use crate::metrics;
 
 pub struct ChatBot {
     // チャットボットの状態を管理するフィールド
+    classifier: IntentClassifier,
+    handler: ConversationHandler,
+    recommender: Option<Arc<GiftRecommender>>,
+    usage: UsageContext,
 }
 
 impl ChatBot {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            classifier: IntentClassifier::new(),
+            handler: ConversationHandler::new(),
+            recommender: None,
+            usage: UsageContext::new(UsageFeature::Chat),
+        }
+    }
+
+    /// 発言の意図を、このルールの分類器で数える
+    pub fn with_classifier(mut self, classifier: IntentClassifier) -> Self {
+        self.classifier = classifier;
+        self
+    }
+
+    /// 聞き取りが済んだら、この推薦エンジンで提案する
+    pub fn with_recommender(mut self, recommender: Arc<GiftRecommender>) -> Self {
+        self.recommender = Some(recommender);
+        self
+    }
+
+    /// 推薦の際に、このユーザーの1日の利用上限を確認する
+    pub fn with_user(mut self, user_id: impl Into<String>) -> Self {
+        self.usage = self.usage.with_user(user_id);
+        self
+    }
+
+    /// 上流の利用記録をこの会話（セッション）に結び付ける
+    pub fn with_conversation(mut self, conversation_id: impl Into<String>) -> Self {
+        self.usage = self.usage.with_conversation(conversation_id);
+        self
+    }
+
+    /// 会話の状態を進めて、表示用の部品を含む応答を返す
+    pub async fn reply(&self, conversation: &mut ConversationState, input: &str) -> Result<BotMessage> {
+        self.reply_with(conversation, input, None).await
+    }
+
+    /// 推薦を生成する場合は、途中経過を `events` に送りながら応答する
+    pub async fn reply_streaming(
+        &self,
+        conversation: &mut ConversationState,
+        input: &str,
+        events: mpsc::UnboundedSender<RecommendationEvent>,
+    ) -> Result<BotMessage> {
+        self.reply_with(conversation, input, Some(events)).await
+    }
+
+    async fn reply_with(
+        &self,
+        conversation: &mut ConversationState,
+        input: &str,
+        events: Option<mpsc::UnboundedSender<RecommendationEvent>>,
+    ) -> Result<BotMessage> {
+        // 発言を意図ごとに数える。応答の内容は会話の状態で決める
+        let intent = self.classifier.classify(input);
+        metrics::global()
+            .chat_messages
+            .with_label_values(&[&format!("{:?}", intent)])
+            .inc();
+
+        let message = match self.handler.process_message(conversation, input) {
+            NextStep::Ask(slot) => BotMessage::new().with(quick_replies(slot)),
+            NextStep::Reply => {
+                let text = self.generate_response(input);
+                let message = BotMessage::new().with(PayloadContent::Text { text });
+                match conversation.next_slot() {
+                    Some(slot) => message.with(quick_replies(slot)),
+                    None => message,
+                }
+            }
+            NextStep::Confirm => BotMessage::new().with(confirmation(conversation)),
+            NextStep::Recommend(request) => self.recommend(request, events).await?,
+        };
+        Ok(message)
     }
 
     pub fn generate_response(&self, input: &str) -> String {
         // TODO: OpenAI APIを使用して応答を生成
         format!("お返しのご相談ありがとうございます。「{}」についてアドバイスさせていただきます。", input)
     }
-} 
\ No newline at end of file
+
+    async fn recommend(
+        &self,
+        request: GiftRequest,
+        events: Option<mpsc::UnboundedSender<RecommendationEvent>>,
+    ) -> Result<BotMessage> {
+        let recommender = self
+            .recommender
+            .as_ref()
+            .ok_or_else(|| anyhow!("No recommender is configured"))?;
+        let event_type = request.event_type().clone();
+        let recommendations = match events {
+            Some(events) => recommender.recommend_streaming(&self.usage, request, events).await?,
+            None => recommender.recommend_for(&self.usage, request).await?,
+        };
+        if recommendations.items.is_empty() {
+            return Ok(BotMessage::new()
+                .with(PayloadContent::Text {
+                    text: "条件に合うギフトが見つかりませんでした。ご予算を変えてお試しください。".to_string(),
+                })
+                .with(quick_replies(Slot::Budget)));
+        }
+
+        let text = match recommendations.source {
+            RecommendationSource::Llm => "おすすめのギフトをご提案します。",
+            RecommendationSource::Catalog => "定番のギフトからご提案します。",
+        };
+        Ok(BotMessage::new()
+            .with(PayloadContent::Text { text: text.to_string() })
+            .with(PayloadContent::RecommendationCards {
+                cards: recommendations.items.iter().map(RecommendationCard::from).collect(),
+                source: recommendations.source,
+            })
+            .with(deadline_reminder(&event_type)))
+    }
+}
+
+fn quick_replies(slot: Slot) -> PayloadContent {
+    PayloadContent::QuickReplies {
+        slot,
+        prompt: slot_question(slot).to_string(),
+        options: slot_options(slot).iter().map(|option| QuickReply::new(*option)).collect(),
+    }
+}
+
+fn confirmation(conversation: &ConversationState) -> PayloadContent {
+    let mut summary = Vec::new();
+    if let Some(event_type) = &conversation.event_type {
+        summary.push(SummaryItem { label: "お祝いの種類".to_string(), value: event_label(event_type).to_string() });
+    }
+    if let Some(relationship) = &conversation.relationship {
+        summary.push(SummaryItem { label: "ご関係".to_string(), value: relationship_label(relationship).to_string() });
+    }
+    if let Some(budget) = &conversation.budget {
+        summary.push(SummaryItem {
+            label: "ご予算".to_string(),
+            value: format!("{}〜{}", format_yen(budget.min()), format_yen(budget.max())),
+        });
+    }
+    PayloadContent::Confirmation {
+        prompt: "この内容でお返しのギフトを探しますか？".to_string(),
+        summary,
+        confirm: QuickReply::new("はい"),
+        cancel: QuickReply { label: "やり直す".to_string(), value: "やり直し".to_string() },
+    }
+}
+
+// 贈る時期の目安。日数は目安の早い側に合わせる
+fn deadline_reminder(event_type: &EventType) -> PayloadContent {
+    let (due_within_days, timing) = match event_type {
+        EventType::Wedding => (30, "挙式後1か月以内"),
+        EventType::Birth => (30, "生後1か月頃（お宮参りの頃）まで"),
+        EventType::Celebration => (30, "いただいてから1か月以内"),
+        EventType::Other => (14, "いただいてから2週間〜1か月以内"),
+    };
+    PayloadContent::DeadlineReminder {
+        due_within_days,
+        message: format!("お返しは{}に贈るのが目安です。", timing),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::nlp::intent_classifier::Intent;
+
+    #[tokio::test]
+    async fn test_messages_are_counted_by_intent() {
+        let counter = metrics::global().chat_messages.with_label_values(&["AskManners"]);
+        let before = counter.get();
+        let chatbot = ChatBot::new().with_classifier(IntentClassifier::from_patterns([(
+            Intent::AskManners,
+            vec!["水引".to_string()],
+        )]));
+
+        chatbot.reply(&mut ConversationState::default(), "水引の色を知りたいです").await.unwrap();
+        assert!(counter.get() > before);
+    }
+}
@@ -1,4 +1,64 @@
+use regex::Regex;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+
+use crate::app::gift::recommendation::{EventType, GiftRequest, PriceRange, Relationship};
+
+/// 推薦に必要な情報の項目。未入力のものを順に質問する
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Slot {
+    EventType,
+    Relationship,
+    Budget,
+}
+
+const SLOT_ORDER: [Slot; 3] = [Slot::EventType, Slot::Relationship, Slot::Budget];
+
+/// 会話の状態（これまでに聞き取った内容）
+#[derive(Debug, Clone, Default)]
+pub struct ConversationState {
+    pub event_type: Option<EventType>,
+    pub relationship: Option<Relationship>,
+    pub budget: Option<PriceRange>,
+    /// 推薦の前に内容の確認を求めている
+    pub confirming: bool,
+}
+
+impl ConversationState {
+    /// まだ聞き取れていない最初の項目
+    pub fn next_slot(&self) -> Option<Slot> {
+        SLOT_ORDER.into_iter().find(|slot| match slot {
+            Slot::EventType => self.event_type.is_none(),
+            Slot::Relationship => self.relationship.is_none(),
+            Slot::Budget => self.budget.is_none(),
+        })
+    }
+
+    fn request(&self) -> Option<GiftRequest> {
+        let event_type = self.event_type.clone()?;
+        Some(GiftRequest::new(
+            event_label(&event_type),
+            self.budget.clone()?,
+            self.relationship.clone()?,
+            event_type,
+            None,
+        ))
+    }
+}
+
+/// 会話の次の一手
+#[derive(Debug, Clone, PartialEq)]
+pub enum NextStep {
+    /// 項目を質問する
+    Ask(Slot),
+    /// 聞き取った内容で推薦してよいか確認する
+    Confirm,
+    Recommend(GiftRequest),
+    /// 項目が埋まらなかったため、発言そのものに応答する
+    Reply,
+}
 
 pub struct ConversationHandler {
     // 会話の状態を管理するフィールド
@@ -9,8 +69,229 @@ impl ConversationHandler {
         Self {}
     }
 
-    pub fn process_message(&self, message: &str) -> String {
-        // TODO: 会話の文脈を理解して適切な応答を生成
-        message.to_string()
+    /// 発言から項目を読み取り、次に何をするかを決める
+    pub fn process_message(&self, state: &mut ConversationState, message: &str) -> NextStep {
+        if state.confirming {
+            if is_affirmative(message) {
+                state.confirming = false;
+                if let Some(request) = state.request() {
+                    return NextStep::Recommend(request);
+                }
+            } else if is_negative(message) {
+                *state = ConversationState::default();
+                return NextStep::Ask(SLOT_ORDER[0]);
+            }
+        }
+
+        let filled = fill_slots(state, message);
+        match state.next_slot() {
+            Some(slot) if filled => NextStep::Ask(slot),
+            // すべて埋まった、または確認中に関係のない発言があった
+            None if filled || state.confirming => {
+                state.confirming = true;
+                NextStep::Confirm
+            }
+            Some(_) | None => NextStep::Reply,
+        }
+    }
+}
+
+/// 選択肢として提示する値と表示名
+pub fn slot_options(slot: Slot) -> &'static [&'static str] {
+    match slot {
+        Slot::EventType => &["結婚祝い", "出産祝い", "その他のお祝い", "その他"],
+        Slot::Relationship => &["上司", "同僚", "友人", "家族・親戚", "その他"],
+        Slot::Budget => &["3,000円〜5,000円", "5,000円〜10,000円", "10,000円〜20,000円"],
+    }
+}
+
+pub fn slot_question(slot: Slot) -> &'static str {
+    match slot {
+        Slot::EventType => "どのようなお祝いへのお返しですか？",
+        Slot::Relationship => "お相手とのご関係を教えてください。",
+        Slot::Budget => "ご予算を教えてください。いただいた金額をお知らせいただければ、半返しの目安で探します。",
+    }
+}
+
+pub fn event_label(event_type: &EventType) -> &'static str {
+    match event_type {
+        EventType::Wedding => "結婚祝い",
+        EventType::Birth => "出産祝い",
+        EventType::Celebration => "お祝い",
+        EventType::Other => "その他",
+    }
+}
+
+pub fn relationship_label(relationship: &Relationship) -> &'static str {
+    match relationship {
+        Relationship::Boss => "上司",
+        Relationship::Colleague => "同僚",
+        Relationship::Friend => "友人",
+        Relationship::Family => "家族・親戚",
+        Relationship::Other => "その他",
+    }
+}
+
+// 読み取れた項目があれば `true`
+fn fill_slots(state: &mut ConversationState, message: &str) -> bool {
+    let mut filled = false;
+    if let Some(event_type) = parse_event_type(message) {
+        state.event_type = Some(event_type);
+        filled = true;
+    }
+    if let Some(relationship) = parse_relationship(message) {
+        state.relationship = Some(relationship);
+        filled = true;
+    }
+    if let Some(budget) = parse_budget(message) {
+        state.budget = Some(budget);
+        filled = true;
+    }
+    filled
+}
+
+fn parse_event_type(message: &str) -> Option<EventType> {
+    if message.contains("結婚") {
+        Some(EventType::Wedding)
+    } else if message.contains("出産") || message.contains("誕生") {
+        Some(EventType::Birth)
+    } else if message.contains("祝") {
+        Some(EventType::Celebration)
+    } else if message.trim() == "その他" {
+        Some(EventType::Other)
+    } else {
+        None
     }
-} 
\ No newline at end of file
+}
+
+fn parse_relationship(message: &str) -> Option<Relationship> {
+    const KEYWORDS: [(&[&str], Relationship); 4] = [
+        (&["上司", "部長", "課長", "社長"], Relationship::Boss),
+        (&["同僚", "先輩", "後輩"], Relationship::Colleague),
+        (&["友人", "友達", "知人"], Relationship::Friend),
+        (&["家族", "親戚", "両親", "兄", "姉", "祖父", "祖母", "叔"], Relationship::Family),
+    ];
+    KEYWORDS
+        .iter()
+        .find(|(keywords, _)| keywords.iter().any(|keyword| message.contains(keyword)))
+        .map(|(_, relationship)| relationship.clone())
+}
+
+// 「5,000円〜10,000円」は価格帯、「1万円いただいた」のような単独の金額はいただいた額として半返し〜3分の1の範囲にする
+//
+// 金額とみなすのは「円」「万円」が付いた数だけ（「2024年」「3人」などを除く）。価格帯の前側は後側の単位を引き継ぐ
+// （「3000〜5000円」「1〜2万円」）。「予算」と書かれている場合は単位のない数も金額とみなし、単独の金額は予算そのものとする。
+fn parse_budget(message: &str) -> Option<PriceRange> {
+    static AMOUNT: OnceLock<Regex> = OnceLock::new();
+    let amount = AMOUNT.get_or_init(|| Regex::new(r"(\d[\d,]*(?:\.\d+)?)\s*(万円|万|円)?").unwrap());
+    let budget_context = message.contains("予算");
+    let tokens: Vec<_> = amount.captures_iter(message).collect();
+    let mut amounts = Vec::new();
+    for (index, captures) in tokens.iter().enumerate() {
+        let whole = captures.get(0).unwrap();
+        // 範囲の記号を挟んで続く金額の単位。前側の単位がない・「円」がない場合に引き継ぐ
+        let next_unit = tokens.get(index + 1).and_then(|next| {
+            let between = message[whole.end()..next.get(0).unwrap().start()].trim();
+            RANGE_SEPARATORS.contains(&between).then(|| next.get(2).map(|unit| unit.as_str()))?
+        });
+        let multiplier = match (captures.get(2).map(|unit| unit.as_str()), next_unit) {
+            (Some("万円"), _) | (Some("万"), Some(_)) | (None, Some("万円" | "万")) => 10_000.0,
+            (Some("円"), _) | (None, Some(_)) => 1.0,
+            // 「1万」「5000」のように円のない数は、予算の文脈でだけ金額とみなす
+            (Some(_), None) if budget_context => 10_000.0,
+            (None, None) if budget_context => 1.0,
+            _ => continue,
+        };
+        let Ok(value) = captures[1].replace(',', "").parse::<f64>() else {
+            continue;
+        };
+        let value = (value * multiplier).round();
+        // 人数や日付などの小さな数は金額とみなさない
+        if (500.0..=f64::from(u32::MAX)).contains(&value) {
+            amounts.push(value as u32);
+        }
+    }
+    match amounts.as_slice() {
+        [] => None,
+        [budget] if budget_context => Some(PriceRange::new(*budget, *budget)),
+        [received] => Some(PriceRange::new(received / 3, received / 2)),
+        [first, second, ..] => Some(PriceRange::new(*first.min(second), *first.max(second))),
+    }
+}
+
+const RANGE_SEPARATORS: &[&str] = &["〜", "～", "~", "-", "－", "から"];
+
+// 確認への答えが「はい」か。「はいらない」「大丈夫じゃない」を含めないよう、答え全体を区切りごとに照合する
+fn is_affirmative(message: &str) -> bool {
+    const ANSWERS: &[&str] = &[
+        "はい", "ええ", "うん", "ok", "okです", "お願い", "お願いします", "お願いいたします", "探して", "探してください",
+        "大丈夫", "大丈夫です", "それで", "それでお願いします", "それでいい", "それでいいです",
+    ];
+    let answer = message.trim().to_lowercase();
+    let answer = answer.trim_end_matches(['。', '！', '!', '.', '〜', 'ー', '♪']);
+    let mut parts = answer.split(['、', ',', '，', ' ', '　']).filter(|part| !part.is_empty()).peekable();
+    parts.peek().is_some() && parts.all(|part| ANSWERS.contains(&part))
+}
+
+fn is_negative(message: &str) -> bool {
+    ["いいえ", "やり直", "変更", "違う"].iter().any(|word| message.contains(word))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots_are_filled_before_confirming() {
+        let handler = ConversationHandler::new();
+        let mut state = ConversationState::default();
+
+        assert_eq!(handler.process_message(&mut state, "こんにちは"), NextStep::Reply);
+        assert_eq!(handler.process_message(&mut state, "結婚祝いのお返しです"), NextStep::Ask(Slot::Relationship));
+        assert_eq!(handler.process_message(&mut state, "上司"), NextStep::Ask(Slot::Budget));
+        assert_eq!(handler.process_message(&mut state, "1万円いただきました"), NextStep::Confirm);
+        assert_eq!(state.budget, Some(PriceRange::new(3333, 5000)));
+
+        // 確認中に項目を言い直した
+        assert_eq!(handler.process_message(&mut state, "5,000円〜10,000円"), NextStep::Confirm);
+        let NextStep::Recommend(request) = handler.process_message(&mut state, "はい") else {
+            panic!("expected a recommendation request");
+        };
+        assert_eq!(request.event_type(), &EventType::Wedding);
+
+        assert_eq!(handler.process_message(&mut state, "いいえ"), NextStep::Reply);
+        state.confirming = true;
+        assert_eq!(handler.process_message(&mut state, "いいえ"), NextStep::Ask(Slot::EventType));
+        assert!(state.relationship.is_none());
+    }
+
+    #[test]
+    fn test_budget_requires_a_yen_amount() {
+        // 年や人数は金額とみなさない
+        assert_eq!(parse_budget("2024年に結婚しました"), None);
+        assert_eq!(parse_budget("職場の30人から"), None);
+        // 小数の万円
+        assert_eq!(parse_budget("1.5万円いただきました"), Some(PriceRange::new(5000, 7500)));
+        assert_eq!(parse_budget("3000〜5000円"), Some(PriceRange::new(3000, 5000)));
+        assert_eq!(parse_budget("1〜2万円くらい"), Some(PriceRange::new(10_000, 20_000)));
+        assert_eq!(parse_budget("1万〜1.5万円"), Some(PriceRange::new(10_000, 15_000)));
+        assert_eq!(parse_budget("1万いただいた"), None);
+        assert_eq!(parse_budget("予算は5000くらい"), Some(PriceRange::new(5000, 5000)));
+
+        let handler = ConversationHandler::new();
+        let mut state = ConversationState::default();
+        assert_eq!(handler.process_message(&mut state, "2024年に結婚しました"), NextStep::Ask(Slot::Relationship));
+        assert_eq!(state.budget, None);
+    }
+
+    #[test]
+    fn test_only_whole_affirmative_answers_confirm() {
+        assert!(is_affirmative("はい"));
+        assert!(is_affirmative("はい、お願いします。"));
+        assert!(is_affirmative("OK!"));
+        assert!(!is_affirmative("大丈夫じゃない"));
+        assert!(!is_affirmative("それはいらない"));
+        assert!(!is_affirmative("はいらない"));
+        assert!(!is_affirmative(""));
+    }
+}
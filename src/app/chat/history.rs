@@ -0,0 +1,183 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::Result;
+use async_trait::async_trait;
+use sqlx::PgPool;
+use time::OffsetDateTime;
+
+use crate::app::batch::{BatchConfig, BatchStore, BatchWriter};
+
+// まとめて書き込む件数と間隔、書き込み待ちと再試行のために保持する上限
+const BATCH: BatchConfig = BatchConfig {
+    kind: "chat history",
+    batch_size: 50,
+    flush_interval: Duration::from_secs(2),
+    queue_capacity: 1024,
+    max_pending: 1000,
+};
+
+/// 会話履歴の1件（ユーザーの発言とボットの応答）
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub user_id: String,
+    pub message: String,
+    pub response: String,
+    pub created_at: OffsetDateTime,
+}
+
+impl HistoryEntry {
+    pub fn new(user_id: impl Into<String>, message: impl Into<String>, response: impl Into<String>) -> Self {
+        Self {
+            user_id: user_id.into(),
+            message: message.into(),
+            response: response.into(),
+            created_at: OffsetDateTime::now_utc(),
+        }
+    }
+}
+
+/// 会話履歴の保存先
+#[async_trait]
+pub trait HistoryStore: Send + Sync {
+    async fn save(&self, entries: &[HistoryEntry]) -> Result<()>;
+
+    /// ユーザーの最近の履歴を `limit` 件まで、古い順に返す
+    async fn recent(&self, user_id: &str, limit: usize) -> Result<Vec<HistoryEntry>>;
+}
+
+#[async_trait]
+impl BatchStore<HistoryEntry> for dyn HistoryStore {
+    async fn save_batch(&self, entries: &[HistoryEntry]) -> Result<()> {
+        self.save(entries).await
+    }
+}
+
+/// `chat_history` テーブルに保存する
+pub struct PgHistoryStore {
+    pool: Arc<PgPool>,
+}
+
+impl PgHistoryStore {
+    pub fn new(pool: Arc<PgPool>) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl HistoryStore for PgHistoryStore {
+    async fn save(&self, entries: &[HistoryEntry]) -> Result<()> {
+        let mut transaction = self.pool.begin().await?;
+        for entry in entries {
+            sqlx::query(
+                "INSERT INTO chat_history (user_id, message, response, created_at) VALUES ($1, $2, $3, $4)",
+            )
+            .bind(&entry.user_id)
+            .bind(&entry.message)
+            .bind(&entry.response)
+            .bind(entry.created_at)
+            .execute(&mut *transaction)
+            .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn recent(&self, user_id: &str, limit: usize) -> Result<Vec<HistoryEntry>> {
+        let rows: Vec<(String, String, String, OffsetDateTime)> = sqlx::query_as(
+            "SELECT user_id, message, response, created_at FROM chat_history \
+             WHERE user_id = $1 ORDER BY created_at DESC, id DESC LIMIT $2",
+        )
+        .bind(user_id)
+        .bind(limit as i64)
+        .fetch_all(&*self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .rev()
+            .map(|(user_id, message, response, created_at)| HistoryEntry { user_id, message, response, created_at })
+            .collect())
+    }
+}
+
+/// データベースを使わない場合の保存先
+#[derive(Default)]
+pub struct MemoryHistoryStore {
+    entries: Mutex<Vec<HistoryEntry>>,
+}
+
+impl MemoryHistoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn entries(&self) -> Vec<HistoryEntry> {
+        self.entries.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl HistoryStore for MemoryHistoryStore {
+    async fn save(&self, entries: &[HistoryEntry]) -> Result<()> {
+        self.entries.lock().unwrap().extend_from_slice(entries);
+        Ok(())
+    }
+
+    async fn recent(&self, user_id: &str, limit: usize) -> Result<Vec<HistoryEntry>> {
+        let entries = self.entries.lock().unwrap();
+        let mut recent: Vec<HistoryEntry> =
+            entries.iter().rev().filter(|entry| entry.user_id == user_id).take(limit).cloned().collect();
+        recent.reverse();
+        Ok(recent)
+    }
+}
+
+/// 会話履歴をバックグラウンドでまとめて書き込む
+///
+/// 応答の送信を保存の完了まで待たせないため、[`HistoryWriter::record`] はキューに積むだけにする。
+/// 停止時は [`HistoryWriter::flush`] で書き込み待ちの履歴を保存する。
+#[derive(Clone)]
+pub struct HistoryWriter {
+    writer: BatchWriter<HistoryEntry>,
+    store: Arc<dyn HistoryStore>,
+}
+
+impl HistoryWriter {
+    pub fn spawn(store: Arc<dyn HistoryStore>) -> Self {
+        Self {
+            writer: BatchWriter::spawn(store.clone(), BATCH),
+            store,
+        }
+    }
+
+    /// 保存済みの最近の履歴。書き込み待ちのものは含まない
+    pub async fn recent(&self, user_id: &str, limit: usize) -> Result<Vec<HistoryEntry>> {
+        self.store.recent(user_id, limit).await
+    }
+
+    pub fn record(&self, entry: HistoryEntry) {
+        self.writer.record(entry);
+    }
+
+    /// 書き込み待ちの履歴をすべて保存し終えるまで待つ
+    pub async fn flush(&self) {
+        self.writer.flush().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_flush_saves_pending_entries() {
+        let store = Arc::new(MemoryHistoryStore::new());
+        let writer = HistoryWriter::spawn(store.clone());
+        writer.record(HistoryEntry::new("user", "上司へのお返し", "ご相談ありがとうございます"));
+        writer.record(HistoryEntry::new("user", "予算は5000円", "承知しました"));
+
+        writer.flush().await;
+        let entries = store.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].message, "予算は5000円");
+    }
+}
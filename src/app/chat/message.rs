@@ -0,0 +1,200 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::conversation_handler::Slot;
+use crate::app::gift::citation::SourceLink;
+use crate::app::gift::recommendation::{GiftRecommendation, RecommendationSource};
+
+/// ボットの応答1件。表示の種類ごとの部品を順に並べる
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct BotMessage {
+    pub payloads: Vec<Payload>,
+}
+
+impl BotMessage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, content: PayloadContent) -> Self {
+        self.payloads.push(Payload::from(content));
+        self
+    }
+
+    /// 部品を表示できないクライアント向けの文章
+    pub fn fallback_text(&self) -> String {
+        self.payloads
+            .iter()
+            .map(|payload| payload.fallback.as_str())
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+}
+
+/// 表示用の部品。`fallback` は対応していないクライアントがそのまま表示できる文章
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Payload {
+    pub fallback: String,
+    #[serde(flatten)]
+    pub content: PayloadContent,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PayloadContent {
+    Text { text: String },
+    /// 質問中の項目の選択肢。選ぶと `value` をメッセージとして送る
+    QuickReplies {
+        slot: Slot,
+        prompt: String,
+        options: Vec<QuickReply>,
+    },
+    RecommendationCards {
+        cards: Vec<RecommendationCard>,
+        source: RecommendationSource,
+    },
+    /// 聞き取った内容で推薦してよいかの確認
+    Confirmation {
+        prompt: String,
+        summary: Vec<SummaryItem>,
+        confirm: QuickReply,
+        cancel: QuickReply,
+    },
+    /// お返しを贈る時期の目安
+    DeadlineReminder {
+        /// いただいてから何日以内に贈るか
+        due_within_days: u32,
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct QuickReply {
+    pub label: String,
+    pub value: String,
+}
+
+impl QuickReply {
+    pub fn new(label: impl Into<String>) -> Self {
+        let label = label.into();
+        Self { value: label.clone(), label }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct SummaryItem {
+    pub label: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct RecommendationCard {
+    pub name: String,
+    pub price: u32,
+    pub store: String,
+    pub reason: String,
+    pub manner_advice: String,
+    /// 商品ページ
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// 購入店舗のページ
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub store_url: Option<String>,
+    /// 商品や価格の根拠にした検索結果
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<SourceLink>,
+}
+
+impl From<&GiftRecommendation> for RecommendationCard {
+    fn from(recommendation: &GiftRecommendation) -> Self {
+        Self {
+            name: recommendation.name().to_string(),
+            price: recommendation.price(),
+            store: recommendation.store().to_string(),
+            reason: recommendation.reason().to_string(),
+            manner_advice: recommendation.manner_advice().to_string(),
+            url: recommendation.url().map(String::from),
+            store_url: recommendation.store_url().map(String::from),
+            sources: recommendation.sources().to_vec(),
+        }
+    }
+}
+
+impl From<PayloadContent> for Payload {
+    fn from(content: PayloadContent) -> Self {
+        Self {
+            fallback: fallback_text(&content),
+            content,
+        }
+    }
+}
+
+/// 金額を「5,000円」の形式にする
+pub fn format_yen(amount: u32) -> String {
+    let digits = amount.to_string();
+    let mut formatted = String::new();
+    for (index, digit) in digits.chars().enumerate() {
+        if index > 0 && (digits.len() - index).is_multiple_of(3) {
+            formatted.push(',');
+        }
+        formatted.push(digit);
+    }
+    formatted + "円"
+}
+
+fn fallback_text(content: &PayloadContent) -> String {
+    match content {
+        PayloadContent::Text { text } => text.clone(),
+        PayloadContent::QuickReplies { prompt, options, .. } => {
+            let labels: Vec<_> = options.iter().map(|option| option.label.as_str()).collect();
+            format!("{}（{}）", prompt, labels.join(" / "))
+        }
+        PayloadContent::RecommendationCards { cards, .. } => cards
+            .iter()
+            .enumerate()
+            .map(|(index, card)| {
+                format!(
+                    "{}. {}（{}・{}）\n   {}\n   マナー: {}",
+                    index + 1,
+                    card.name,
+                    format_yen(card.price),
+                    card.store,
+                    card.reason,
+                    card.manner_advice
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        PayloadContent::Confirmation { prompt, summary, confirm, cancel } => {
+            let lines: Vec<_> = summary
+                .iter()
+                .map(|item| format!("・{}: {}", item.label, item.value))
+                .collect();
+            format!("{}\n{}\n（{} / {}）", prompt, lines.join("\n"), confirm.label, cancel.label)
+        }
+        PayloadContent::DeadlineReminder { message, .. } => message.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_every_payload_has_fallback_text() {
+        let message = BotMessage::new()
+            .with(PayloadContent::Text { text: "かしこまりました。".to_string() })
+            .with(PayloadContent::QuickReplies {
+                slot: Slot::Relationship,
+                prompt: "ご関係は？".to_string(),
+                options: vec![QuickReply::new("上司"), QuickReply::new("友人")],
+            });
+
+        assert_eq!(message.fallback_text(), "かしこまりました。\n\nご関係は？（上司 / 友人）");
+        let json = serde_json::to_value(&message.payloads[1]).unwrap();
+        assert_eq!(json["kind"], "quick_replies");
+        assert_eq!(json["options"][0], serde_json::json!({"label": "上司", "value": "上司"}));
+        assert_eq!(json["fallback"], "ご関係は？（上司 / 友人）");
+        assert_eq!(format_yen(12500), "12,500円");
+    }
+}
@@ -0,0 +1,216 @@
+use schemars::schema::RootSchema;
+use schemars::{schema_for, JsonSchema};
+use serde::{Deserialize, Serialize};
+
+use super::message::{Payload, RecommendationCard};
+
+/// WebSocketプロトコルのバージョン。互換性のない変更をしたときに上げる
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// クライアントから送られるフレーム
+///
+/// 接続直後に `hello` を送る。再接続時は前回の `session_id` と受信済みの最後の `seq` を
+/// 指定すると、その後に送られたサーバーメッセージが再送される。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ClientEnvelope {
+    /// プロトコルのバージョン（[`PROTOCOL_VERSION`]）
+    pub v: u32,
+    #[serde(flatten)]
+    pub frame: ClientFrame,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    /// セッションの開始または再開
+    ///
+    /// 接続時の認証情報のユーザーの他の端末と同じセッション（会話）に接続する。
+    Hello {
+        /// 再開するセッション。省略すると新しいセッションを開始する
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session_id: Option<String>,
+        /// 受信済みの最後のサーバーメッセージの番号
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        last_seq: Option<u64>,
+    },
+    /// ユーザーの発言
+    Message {
+        /// クライアントが付けるID。再送された場合は重複として扱い、処理し直さない
+        id: String,
+        /// 以前のクライアントとの互換性のために受け付けるが使わない。ユーザーは接続時の認証情報から決まる
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user_id: Option<String>,
+        text: String,
+    },
+    /// `seq` までのサーバーメッセージを受信した。再送用に保持している分を破棄できる
+    Ack { seq: u64 },
+    /// 処理中または処理待ちのメッセージを取り消す。生成中の推薦も中断される
+    Cancel { id: String },
+}
+
+/// サーバーから送られるフレーム
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ServerEnvelope {
+    pub v: u32,
+    /// セッション内の通し番号。再送の対象にならないフレームには付かない
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    #[serde(flatten)]
+    pub frame: ServerFrame,
+}
+
+impl ServerEnvelope {
+    /// 番号を付けない（再送しない）フレーム
+    pub fn transient(frame: ServerFrame) -> Self {
+        Self {
+            v: PROTOCOL_VERSION,
+            seq: None,
+            frame,
+        }
+    }
+
+    pub fn sequenced(seq: u64, frame: ServerFrame) -> Self {
+        Self {
+            v: PROTOCOL_VERSION,
+            seq: Some(seq),
+            frame,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    /// `hello` への応答
+    Welcome {
+        session_id: String,
+        /// 既存のセッションを再開できた場合は `true`
+        resumed: bool,
+        /// このあと再送するメッセージの件数
+        replayed: usize,
+        /// 保持期間を過ぎたなどの理由で、再送できないメッセージがあった
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        truncated: bool,
+    },
+    /// クライアントのメッセージを受け付けた
+    Ack {
+        id: String,
+        /// 処理済みのメッセージが再送された
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        duplicate: bool,
+    },
+    /// 受け付けたユーザーの発言。同じセッションのすべての端末に送られ、`seq` の順に処理される
+    UserMessage {
+        id: String,
+        user_id: String,
+        text: String,
+    },
+    /// 応答を生成中
+    Typing { reply_to: String },
+    /// 生成中のテキストの断片。表示の途中経過で、最終的な内容は `bot_response` で送る
+    BotDelta { reply_to: String, text: String },
+    /// 生成中に確定した推薦1件。一覧全体は `bot_response` の `payloads` で送る
+    Recommendation {
+        reply_to: String,
+        /// この応答の中で何件目か（0始まり）
+        index: usize,
+        card: RecommendationCard,
+    },
+    /// ボットの応答。`text` は `payloads` を表示できない場合にそのまま表示する文章
+    BotResponse {
+        reply_to: String,
+        text: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        payloads: Vec<Payload>,
+    },
+    /// `reply_to` のメッセージに対するフレームはこれで終わり
+    Done { reply_to: String },
+    Error {
+        code: ErrorCode,
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_to: Option<String>,
+        /// `rate_limited` の場合に、再送できるようになるまでの時間
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry_after_ms: Option<u64>,
+    },
+    /// サーバーの停止に伴い接続を閉じる。セッションを指定して再接続する
+    ServerRestarting { reconnect_after_ms: u64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// フレームを解釈できない
+    InvalidMessage,
+    /// 対応していないプロトコルのバージョン
+    UnsupportedVersion,
+    /// `hello` より前にメッセージが送られた
+    HelloRequired,
+    /// 応答の生成に失敗した
+    ProcessingFailed,
+    /// 同じユーザーの接続数が上限に達した
+    TooManyConnections,
+    /// 処理待ちのメッセージが多すぎるため、受け付けなかった
+    Busy,
+    /// `cancel` により処理を取り消した
+    Cancelled,
+    /// 送信の頻度か、1日の利用の上限に達した。`retry_after_ms` 後に再送できる
+    RateLimited,
+}
+
+/// クライアント・サーバーそれぞれのフレームのJSON Schema
+#[derive(Debug, Serialize)]
+pub struct ProtocolSchema {
+    pub version: u32,
+    pub client: RootSchema,
+    pub server: RootSchema,
+}
+
+/// Rustの型からプロトコルのスキーマを生成する（`docs/websocket_protocol.schema.json`）
+pub fn protocol_schema() -> ProtocolSchema {
+    ProtocolSchema {
+        version: PROTOCOL_VERSION,
+        client: schema_for!(ClientEnvelope),
+        server: schema_for!(ServerEnvelope),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    const SCHEMA_PATH: &str = "docs/websocket_protocol.schema.json";
+
+    #[test]
+    fn test_envelopes_round_trip_and_schema_is_up_to_date() {
+        let hello: ClientEnvelope =
+            serde_json::from_str(r#"{"v":1,"type":"hello","session_id":"s1","last_seq":4}"#).unwrap();
+        assert_eq!(
+            hello.frame,
+            ClientFrame::Hello { session_id: Some("s1".to_string()), last_seq: Some(4) }
+        );
+        let response = ServerEnvelope::sequenced(
+            5,
+            ServerFrame::BotResponse {
+                reply_to: "c1".to_string(),
+                text: "こんにちは".to_string(),
+                payloads: Vec::new(),
+            },
+        );
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            serde_json::json!({"v": 1, "seq": 5, "type": "bot_response", "reply_to": "c1", "text": "こんにちは"})
+        );
+
+        // 型を変更したら `UPDATE_SCHEMA=1 cargo test` でドキュメントを更新する
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(SCHEMA_PATH);
+        let generated = serde_json::to_string_pretty(&protocol_schema()).unwrap() + "\n";
+        if std::env::var_os("UPDATE_SCHEMA").is_some() {
+            std::fs::write(&path, &generated).unwrap();
+        }
+        let documented = std::fs::read_to_string(&path).unwrap_or_default();
+        assert!(documented == generated, "{} is out of date; run with UPDATE_SCHEMA=1", SCHEMA_PATH);
+    }
+}
@@ -0,0 +1,193 @@
+use std::sync::{Arc, Mutex};
+use anyhow::Result;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use super::chatbot::ChatBot;
+use super::history::{HistoryEntry, HistoryWriter};
+use super::message::{BotMessage, RecommendationCard};
+use super::protocol::{ErrorCode, ServerEnvelope, ServerFrame};
+use super::session::{ChatSession, Turn};
+use crate::app::shutdown::InflightGuard;
+use crate::app::gift::quota::QuotaExceeded;
+use crate::app::gift::recommendation::RecommendationEvent;
+
+/// クライアントのメッセージ1件
+#[derive(Debug, Clone)]
+pub struct IncomingMessage {
+    pub id: String,
+    pub user_id: String,
+    pub text: String,
+}
+
+/// メッセージ1件に応答し、セッションに接続中のすべての端末にフレームを送る
+///
+/// WebSocketとSSE、HTTPの `/chat` で共通の手順。受付（`ack` と `user_message`）、`typing`、生成中の `bot_delta` と `recommendation`、
+/// 応答（`bot_response` または `error`）、最後に `done` の順に送る。
+/// 番号付きのフレームはセッションに記録するため、再接続時に再送される。
+/// 複数の端末から同時に送られた場合は、受け付けた順（`user_message` の番号の順）に1件ずつ処理する。
+///
+/// 応答の生成はセッションが保持するタスクで行い、戻り値で完了を待てる。待つのをやめても生成は続く。
+/// 再接続の前後で同じメッセージが届いた場合は、受付だけ返して `None` を返す。
+pub fn respond(
+    chatbot: Arc<ChatBot>,
+    history: Option<HistoryWriter>,
+    session: &Arc<Mutex<ChatSession>>,
+    message: IncomingMessage,
+    inflight: InflightGuard,
+) -> Option<JoinHandle<Result<BotMessage>>> {
+    let mut guard = session.lock().unwrap();
+    let first_seen = guard.first_seen(&message.id);
+    guard.broadcast(ServerFrame::Ack { id: message.id.clone(), duplicate: !first_seen });
+    if !first_seen {
+        return None;
+    }
+    guard.broadcast(ServerFrame::UserMessage {
+        id: message.id.clone(),
+        user_id: message.user_id.clone(),
+        text: message.text.clone(),
+    });
+    let turn = guard.take_turn();
+    let id = message.id.clone();
+    let reply = generate(chatbot, history, session.clone(), message, turn, inflight);
+    Some(guard.spawn_reply(&id, reply))
+}
+
+async fn generate(
+    chatbot: Arc<ChatBot>,
+    history: Option<HistoryWriter>,
+    session: Arc<Mutex<ChatSession>>,
+    message: IncomingMessage,
+    turn: Turn,
+    _inflight: InflightGuard,
+) -> Result<BotMessage> {
+    let IncomingMessage { id, user_id, text } = message;
+    let emit = |envelope: ServerEnvelope| session.lock().unwrap().publish(&envelope);
+    let sequenced = |frame: ServerFrame| {
+        session.lock().unwrap().broadcast(frame);
+    };
+    turn.wait().await;
+
+    // タイピング状態を送信
+    emit(ServerEnvelope::transient(ServerFrame::Typing { reply_to: id.clone() }));
+
+    // チャットボットで処理（推薦の呼び出し中もセッションをロックしないよう、状態は取り出して戻す）
+    // 生成中のテキストと確定した推薦は、届いた順に番号なしで送る
+    let mut conversation = session.lock().unwrap().conversation.clone();
+    let (events, mut events_rx) = mpsc::unbounded_channel();
+    let mut streamed = 0;
+    let result = {
+        let reply = chatbot.reply_streaming(&mut conversation, &text, events);
+        tokio::pin!(reply);
+        loop {
+            tokio::select! {
+                result = &mut reply => break result,
+                Some(event) = events_rx.recv() => emit(progress(&id, event, &mut streamed)),
+            }
+        }
+    };
+    while let Ok(event) = events_rx.try_recv() {
+        emit(progress(&id, event, &mut streamed));
+    }
+    session.lock().unwrap().conversation = conversation;
+
+    match &result {
+        Ok(bot_message) => {
+            let bot_response = bot_message.fallback_text();
+            if let Some(history) = &history {
+                history.record(HistoryEntry::new(user_id, text, bot_response.clone()));
+            }
+            sequenced(ServerFrame::BotResponse {
+                reply_to: id.clone(),
+                text: bot_response,
+                payloads: bot_message.payloads.clone(),
+            });
+        }
+        Err(e) => {
+            tracing::error!("Failed to process chat message: {:?}", e);
+            sequenced(failure(e, "メッセージの処理中にエラーが発生しました", &id));
+        }
+    }
+    emit(ServerEnvelope::transient(ServerFrame::Done { reply_to: id }));
+    result
+}
+
+/// 推薦の途中経過をフレームにする。`streamed` はこれまでに送った推薦の件数
+pub fn progress(reply_to: &str, event: RecommendationEvent, streamed: &mut usize) -> ServerEnvelope {
+    let frame = match event {
+        RecommendationEvent::Delta(text) => ServerFrame::BotDelta { reply_to: reply_to.to_string(), text },
+        RecommendationEvent::Item(item) => {
+            *streamed += 1;
+            ServerFrame::Recommendation {
+                reply_to: reply_to.to_string(),
+                index: *streamed - 1,
+                card: RecommendationCard::from(&item),
+            }
+        }
+    };
+    ServerEnvelope::transient(frame)
+}
+
+/// 応答の生成に失敗したことを知らせるフレーム。1日の利用上限に達した場合は、再送できるまでの時間を付ける
+pub fn failure(error: &anyhow::Error, message: &str, reply_to: &str) -> ServerFrame {
+    match error.downcast_ref::<QuotaExceeded>() {
+        Some(exceeded) => ServerFrame::Error {
+            code: ErrorCode::RateLimited,
+            message: "本日の利用上限に達しました。明日以降に再度お試しください".to_string(),
+            reply_to: Some(reply_to.to_string()),
+            retry_after_ms: Some(exceeded.retry_after.as_millis() as u64),
+        },
+        None => ServerFrame::Error {
+            code: ErrorCode::ProcessingFailed,
+            message: message.to_string(),
+            reply_to: Some(reply_to.to_string()),
+            retry_after_ms: None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::app::chat::session::SessionRegistry;
+    use crate::app::shutdown::Shutdown;
+
+    fn message(id: &str) -> IncomingMessage {
+        IncomingMessage { id: id.to_string(), user_id: "u".to_string(), text: "こんにちは".to_string() }
+    }
+
+    #[tokio::test]
+    async fn test_reply_reaches_the_connection_that_resumed_during_processing() {
+        let registry = SessionRegistry::new();
+        let shutdown = Shutdown::new();
+        let chatbot = Arc::new(ChatBot::new());
+        let attached = registry.attach_as(Some("u"), None, None);
+        let session_id = attached.session.lock().unwrap().id().to_string();
+
+        // 先に受け付けたメッセージの処理中で、応答の生成が順番待ちになっている
+        let earlier = attached.session.lock().unwrap().take_turn();
+        let reply = respond(chatbot.clone(), None, &attached.session, message("c1"), shutdown.track()).unwrap();
+        registry.detach(&attached.session, attached.listener);
+        drop(attached);
+
+        // 再接続して同じメッセージを再送した。受付だけ返し、処理し直さない
+        let mut resumed = registry.attach_as(Some("u"), Some(&session_id), Some(2));
+        assert!(resumed.resumed);
+        assert!(respond(chatbot, None, &resumed.session, message("c1"), shutdown.track()).is_none());
+
+        // 切断前に始まった生成の応答が、再接続した端末に届く
+        drop(earlier);
+        tokio::time::timeout(Duration::from_secs(1), reply).await.unwrap().unwrap().unwrap();
+        let mut frames = Vec::new();
+        while let Ok(envelope) = resumed.events.try_recv() {
+            frames.push(envelope.frame);
+        }
+        assert_eq!(frames[0], ServerFrame::Ack { id: "c1".to_string(), duplicate: true });
+        assert!(frames.iter().any(|frame| matches!(
+            frame,
+            ServerFrame::BotResponse { reply_to, .. } if reply_to == "c1"
+        )));
+        assert_eq!(frames.last(), Some(&ServerFrame::Done { reply_to: "c1".to_string() }));
+    }
+}
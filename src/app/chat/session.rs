@@ -0,0 +1,653 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::future::Future;
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::{AbortHandle, JoinHandle};
+
+use super::conversation_handler::ConversationState;
+use super::protocol::{ServerEnvelope, ServerFrame};
+
+// 再送用に保持するサーバーメッセージの件数
+const REPLAY_BUFFER_SIZE: usize = 256;
+// 重複を判定するために覚えておくクライアントメッセージIDの件数
+const SEEN_IDS_SIZE: usize = 128;
+// 切断後にセッションを再開できる期間
+const SESSION_TTL: Duration = Duration::from_secs(10 * 60);
+// 接続中でも、この期間やり取りがなければセッションを破棄する
+const IDLE_TTL: Duration = Duration::from_secs(30 * 60);
+// プロセス内に保持するセッションの上限。超えたら最も長くやり取りのないものから破棄する
+const MAX_SESSIONS: usize = 10_000;
+
+/// WebSocketのセッション（再接続をまたいで続く会話）
+///
+/// サーバーメッセージに通し番号を付けて保持し、再接続時に受信できなかった分を再送する。
+/// 同じユーザーの複数の端末は1つのセッションを共有し、フレームはすべての接続に送られる。
+pub struct ChatSession {
+    id: String,
+    next_seq: u64,
+    buffer: VecDeque<ServerEnvelope>,
+    seen_ids: VecDeque<String>,
+    detached_at: Option<Instant>,
+    // 最後にフレームを送ったか、接続・切断した時刻
+    last_active: Instant,
+    // 接続中の端末への送り先
+    listeners: Vec<Listener>,
+    // 切断した端末が受信を確認した番号と切断した時刻。再開できる期間は、その端末が未受信の分を破棄しない
+    departed: Vec<(u64, Instant)>,
+    next_listener: u64,
+    // 共有しているユーザー
+    user_id: Option<String>,
+    // 接続中の端末の数
+    attached: usize,
+    turns: Arc<TurnQueue>,
+    // 生成中の応答（クライアントメッセージIDごと）。取り消しに使う
+    replies: HashMap<String, AbortHandle>,
+    // 他のインスタンスにある同じユーザーの端末への中継先
+    relay: Option<mpsc::UnboundedSender<SessionEvent>>,
+    /// 聞き取り中の内容。接続をまたいで引き継ぐ
+    pub conversation: ConversationState,
+}
+
+impl ChatSession {
+    fn new() -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            next_seq: 1,
+            buffer: VecDeque::new(),
+            seen_ids: VecDeque::new(),
+            detached_at: None,
+            last_active: Instant::now(),
+            listeners: Vec::new(),
+            departed: Vec::new(),
+            next_listener: 1,
+            user_id: None,
+            attached: 1,
+            turns: Arc::default(),
+            replies: HashMap::new(),
+            relay: None,
+            conversation: ConversationState::default(),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// セッションを共有しているユーザー
+    pub fn user_id(&self) -> Option<&str> {
+        self.user_id.as_deref()
+    }
+
+    /// 番号を付けて再送用に保持する
+    pub fn push(&mut self, frame: ServerFrame) -> ServerEnvelope {
+        let envelope = ServerEnvelope::sequenced(self.next_seq, frame);
+        self.next_seq += 1;
+        self.last_active = Instant::now();
+        if self.buffer.len() == REPLAY_BUFFER_SIZE {
+            self.buffer.pop_front();
+        }
+        self.buffer.push_back(envelope.clone());
+        envelope
+    }
+
+    /// 端末（`listener`）が受信を確認した番号を記録し、すべての端末が確認した分を破棄する
+    ///
+    /// 切断した端末も、再開できる期間は確認済みの番号を数える。
+    pub fn ack(&mut self, listener: u64, seq: u64) {
+        let Some(acked) = self.listeners.iter_mut().find(|candidate| candidate.id == listener) else {
+            return;
+        };
+        acked.acked = acked.acked.max(seq);
+        self.departed.retain(|(_, at)| at.elapsed() < SESSION_TTL);
+        let seq = self
+            .listeners
+            .iter()
+            .map(|listener| listener.acked)
+            .chain(self.departed.iter().map(|(acked, _)| *acked))
+            .min()
+            .unwrap_or(0);
+        while self.buffer.front().is_some_and(|envelope| envelope.seq <= Some(seq)) {
+            self.buffer.pop_front();
+        }
+    }
+
+    // 再開した端末の切断時の記録を外す。端末は区別できないため、受信済みの番号を超えない最も進んだものとみなす
+    fn resume_departed(&mut self, last_seq: u64) {
+        let resumed = self
+            .departed
+            .iter()
+            .enumerate()
+            .filter(|(_, (acked, _))| *acked <= last_seq)
+            .max_by_key(|(_, (acked, _))| *acked)
+            .map(|(index, _)| index);
+        if let Some(index) = resumed {
+            self.departed.remove(index);
+        }
+    }
+
+    /// 初めて受け取ったクライアントメッセージIDなら `true`
+    pub fn first_seen(&mut self, id: &str) -> bool {
+        if self.seen_ids.iter().any(|seen| seen == id) {
+            return false;
+        }
+        if self.seen_ids.len() == SEEN_IDS_SIZE {
+            self.seen_ids.pop_front();
+        }
+        self.seen_ids.push_back(id.to_string());
+        true
+    }
+
+    /// このセッションに送られるフレームを受け取る。1つ目の値は `ack` と `unsubscribe` に使う端末の番号
+    pub fn subscribe(&mut self) -> (u64, mpsc::UnboundedReceiver<ServerEnvelope>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let id = self.next_listener;
+        self.next_listener += 1;
+        self.listeners.push(Listener { id, sender, acked: 0 });
+        (id, receiver)
+    }
+
+    /// 端末への送信をやめる。確認済みの番号は、再開できる期間が過ぎるまで残す
+    pub fn unsubscribe(&mut self, listener: u64) {
+        if let Some(index) = self.listeners.iter().position(|candidate| candidate.id == listener) {
+            let departed = self.listeners.remove(index);
+            self.departed.push((departed.acked, Instant::now()));
+        }
+    }
+
+    /// 接続中のすべての端末にフレームを送る。閉じた接続は取り除く
+    ///
+    /// ユーザーのセッションの場合は、他のインスタンスに接続している同じユーザーの端末にも中継する。
+    /// 生成中のテキストの断片（`bot_delta`）は数が多く、最終的な内容は `bot_response` で届くため中継しない。
+    pub fn publish(&mut self, envelope: &ServerEnvelope) {
+        self.deliver(envelope);
+        if matches!(envelope.frame, ServerFrame::BotDelta { .. }) {
+            return;
+        }
+        if let (Some(relay), Some(user_id)) = (&self.relay, &self.user_id) {
+            let _ = relay.send(SessionEvent {
+                user_id: user_id.clone(),
+                session_id: self.id.clone(),
+                envelope: envelope.clone(),
+            });
+        }
+    }
+
+    // このインスタンスに接続中の端末にだけ送る
+    fn deliver(&mut self, envelope: &ServerEnvelope) {
+        let departed = &mut self.departed;
+        self.listeners.retain(|listener| {
+            let open = listener.sender.send(envelope.clone()).is_ok();
+            if !open {
+                departed.push((listener.acked, Instant::now()));
+            }
+            open
+        });
+    }
+
+    /// 番号を付けて記録し、すべての端末に送る。番号の順に届くよう、ロックを保持したまま送る
+    pub fn broadcast(&mut self, frame: ServerFrame) -> ServerEnvelope {
+        let envelope = self.push(frame);
+        self.publish(&envelope);
+        envelope
+    }
+
+    /// メッセージを処理する順番を取る。順番は受け付けた順（`ack` の番号の順）になる
+    pub fn take_turn(&mut self) -> Turn {
+        let number = {
+            let mut state = self.turns.state.lock().unwrap();
+            state.next += 1;
+            state.next - 1
+        };
+        Turn {
+            number,
+            queue: self.turns.clone(),
+        }
+    }
+
+    /// 応答の生成を、送信元の接続から切り離したタスクで行う
+    ///
+    /// 送信元の端末が切断しても生成は続き、応答はセッションに接続中の端末や再接続した端末に届く。
+    /// [`ChatSession::cancel`] で取り消すまで、セッションがタスクを保持する。
+    pub fn spawn_reply<F>(&mut self, id: &str, reply: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.replies.retain(|_, handle| !handle.is_finished());
+        let handle = tokio::spawn(reply);
+        self.replies.insert(id.to_string(), handle.abort_handle());
+        handle
+    }
+
+    /// 生成中の応答を取り消す。見つからない（終わっている）場合は `false`
+    pub fn cancel(&mut self, id: &str) -> bool {
+        match self.replies.remove(id) {
+            Some(handle) if !handle.is_finished() => {
+                handle.abort();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// `last_seq` より後のメッセージ。保持していない分があれば2つ目の値が `true`
+    pub fn replay_after(&self, last_seq: u64) -> (Vec<ServerEnvelope>, bool) {
+        let replay: Vec<_> = self
+            .buffer
+            .iter()
+            .filter(|envelope| envelope.seq > Some(last_seq))
+            .cloned()
+            .collect();
+        let first_expected = last_seq + 1;
+        let truncated = first_expected < self.next_seq
+            && replay.first().and_then(|envelope| envelope.seq) != Some(first_expected);
+        (replay, truncated)
+    }
+}
+
+// 接続中の端末
+struct Listener {
+    id: u64,
+    sender: mpsc::UnboundedSender<ServerEnvelope>,
+    // この端末が受信を確認した番号
+    acked: u64,
+}
+
+// 同じセッションのメッセージを1件ずつ処理するための順番待ち
+#[derive(Default)]
+struct TurnQueue {
+    state: Mutex<TurnState>,
+    notify: Notify,
+}
+
+#[derive(Default)]
+struct TurnState {
+    next: u64,
+    serving: u64,
+    // 順番が来る前に取り消された番号
+    finished: BTreeSet<u64>,
+}
+
+/// メッセージを処理する順番。破棄すると次の番号に進む
+pub struct Turn {
+    number: u64,
+    queue: Arc<TurnQueue>,
+}
+
+impl Turn {
+    /// 前に受け付けたメッセージの処理が終わるまで待つ
+    pub async fn wait(&self) {
+        loop {
+            let notified = self.queue.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if self.queue.state.lock().unwrap().serving == self.number {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        let mut state = self.queue.state.lock().unwrap();
+        state.finished.insert(self.number);
+        loop {
+            let serving = state.serving;
+            if !state.finished.remove(&serving) {
+                break;
+            }
+            state.serving += 1;
+        }
+        drop(state);
+        self.queue.notify.notify_waiters();
+    }
+}
+
+/// 他のインスタンスに中継するユーザーのセッションのフレーム
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionEvent {
+    pub user_id: String,
+    /// 送信元のインスタンスでのセッションID
+    pub session_id: String,
+    pub envelope: ServerEnvelope,
+}
+
+/// `hello` の処理結果
+pub struct Attached {
+    pub session: Arc<Mutex<ChatSession>>,
+    pub resumed: bool,
+    pub replay: Vec<ServerEnvelope>,
+    pub truncated: bool,
+    /// この接続の番号。`ack` と `detach` に使う
+    pub listener: u64,
+    /// このあとセッションに送られるフレーム。再送分と重複も欠落もしない
+    pub events: mpsc::UnboundedReceiver<ServerEnvelope>,
+}
+
+impl Attached {
+    pub fn welcome(&self) -> ServerEnvelope {
+        ServerEnvelope::transient(ServerFrame::Welcome {
+            session_id: self.session.lock().unwrap().id.clone(),
+            resumed: self.resumed,
+            replayed: self.replay.len(),
+            truncated: self.truncated,
+        })
+    }
+}
+
+/// プロセス内のセッションの一覧
+///
+/// 同じユーザーの端末は1つのセッションを共有するため、セッションはユーザーごとに1つまでになる。
+/// 切断したセッションと、やり取りのなくなったセッションは一定期間で破棄する。
+pub struct SessionRegistry {
+    sessions: Mutex<HashMap<String, Arc<Mutex<ChatSession>>>>,
+    relay: Mutex<Option<mpsc::UnboundedSender<SessionEvent>>>,
+    max_sessions: usize,
+    idle_ttl: Duration,
+}
+
+impl Default for SessionRegistry {
+    fn default() -> Self {
+        Self {
+            sessions: Mutex::default(),
+            relay: Mutex::default(),
+            max_sessions: MAX_SESSIONS,
+            idle_ttl: IDLE_TTL,
+        }
+    }
+}
+
+impl SessionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 保持するセッションの上限と、接続中でもやり取りがなければ破棄するまでの期間を変える
+    pub fn with_limits(mut self, max_sessions: usize, idle_ttl: Duration) -> Self {
+        self.max_sessions = max_sessions.max(1);
+        self.idle_ttl = idle_ttl;
+        self
+    }
+
+    /// セッションを再開する。見つからない場合は新しく開始する
+    pub fn attach(&self, session_id: Option<&str>, last_seq: Option<u64>) -> Attached {
+        self.attach_as(None, session_id, last_seq)
+    }
+
+    /// ユーザーのセッションに接続する。同じユーザーの端末はすべて同じセッションを共有する
+    ///
+    /// ユーザーのセッションがなければ `session_id` のセッションを引き継ぎ、それもなければ新しく開始する。
+    pub fn attach_as(&self, user_id: Option<&str>, session_id: Option<&str>, last_seq: Option<u64>) -> Attached {
+        let mut sessions = self.sessions.lock().unwrap();
+        self.expire(&mut sessions);
+
+        let shared = user_id.and_then(|user_id| {
+            sessions
+                .values()
+                .find(|session| session.lock().unwrap().user_id.as_deref() == Some(user_id))
+                .cloned()
+        });
+        let existing = shared.or_else(|| {
+            session_id
+                .and_then(|id| sessions.get(id))
+                // 他のユーザーのセッションは引き継がない
+                .filter(|session| {
+                    let owner = session.lock().unwrap().user_id.clone();
+                    owner.is_none() || owner.as_deref() == user_id
+                })
+                .cloned()
+        });
+
+        if let Some(session) = existing {
+            let mut guard = session.lock().unwrap();
+            guard.detached_at = None;
+            guard.last_active = Instant::now();
+            guard.attached += 1;
+            if guard.user_id.is_none() {
+                guard.user_id = user_id.map(str::to_string);
+            }
+            // 別の端末の場合は、その端末が受信した番号より後を再送する
+            let requested = session_id == Some(guard.id.as_str());
+            if let Some(last_seq) = last_seq.filter(|_| requested) {
+                guard.resume_departed(last_seq);
+            }
+            let (replay, truncated) = guard.replay_after(if requested { last_seq.unwrap_or(0) } else { 0 });
+            let (listener, events) = guard.subscribe();
+            drop(guard);
+            return Attached {
+                session,
+                resumed: true,
+                replay,
+                truncated: requested && truncated,
+                listener,
+                events,
+            };
+        }
+
+        if sessions.len() >= self.max_sessions {
+            Self::evict_least_active(&mut sessions);
+        }
+        let mut session = ChatSession::new();
+        session.user_id = user_id.map(str::to_string);
+        session.relay = self.relay.lock().unwrap().clone();
+        let (listener, events) = session.subscribe();
+        let id = session.id.clone();
+        let session = Arc::new(Mutex::new(session));
+        sessions.insert(id, session.clone());
+        Attached {
+            session,
+            resumed: false,
+            replay: Vec::new(),
+            // 再開を求められたが、セッションが残っていなかった
+            truncated: session_id.is_some(),
+            listener,
+            events,
+        }
+    }
+
+    /// これ以降に開始するユーザーのセッションのフレームを `relay` に送る（[`crate::app::pubsub`] が他のインスタンスに中継する）
+    pub fn relay_to(&self, relay: mpsc::UnboundedSender<SessionEvent>) {
+        *self.relay.lock().unwrap() = Some(relay);
+    }
+
+    /// 他のインスタンスから中継されたフレームを、このインスタンスに接続している同じユーザーの端末に送る
+    ///
+    /// 通し番号は送信元のセッションのものなので外して送る。再接続時の再送の対象にはならない。
+    pub fn deliver_remote(&self, event: &SessionEvent) {
+        let frame = event.envelope.frame.clone();
+        self.notify_user(&event.user_id, frame);
+    }
+
+    /// ユーザーの接続中の端末に、番号を付けずにフレームを送る
+    pub fn notify_user(&self, user_id: &str, frame: ServerFrame) {
+        let envelope = ServerEnvelope::transient(frame);
+        let sessions: Vec<_> = self.sessions.lock().unwrap().values().cloned().collect();
+        for session in sessions {
+            let mut session = session.lock().unwrap();
+            if session.user_id.as_deref() == Some(user_id) {
+                session.deliver(&envelope);
+            }
+        }
+    }
+
+    /// 接続中または再開できるセッション
+    pub fn get(&self, session_id: &str) -> Option<Arc<Mutex<ChatSession>>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        self.expire(&mut sessions);
+        sessions.get(session_id).cloned()
+    }
+
+    /// 接続（`listener`）が切れた。最後の接続が切れてから一定期間は再開できるように残しておく
+    pub fn detach(&self, session: &Arc<Mutex<ChatSession>>, listener: u64) {
+        let mut session = session.lock().unwrap();
+        session.unsubscribe(listener);
+        session.attached = session.attached.saturating_sub(1);
+        session.last_active = Instant::now();
+        if session.attached == 0 {
+            session.detached_at = Some(Instant::now());
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // 接続中の端末への送り先も閉じるため、端末はセッションを指定せずに再接続する
+    fn expire(&self, sessions: &mut HashMap<String, Arc<Mutex<ChatSession>>>) {
+        sessions.retain(|_, session| {
+            let mut session = session.lock().unwrap();
+            let live = match session.detached_at {
+                Some(at) => at.elapsed() < SESSION_TTL,
+                None => session.last_active.elapsed() < self.idle_ttl,
+            };
+            if !live {
+                session.listeners.clear();
+            }
+            live
+        });
+    }
+
+    // 切断したものを優先して、最も長くやり取りのないセッションを破棄する
+    fn evict_least_active(sessions: &mut HashMap<String, Arc<Mutex<ChatSession>>>) {
+        let least_active = sessions
+            .iter()
+            .min_by_key(|(_, session)| {
+                let session = session.lock().unwrap();
+                (session.detached_at.is_none(), session.last_active)
+            })
+            .map(|(id, _)| id.clone());
+        if let Some(session) = least_active.and_then(|id| sessions.remove(&id)) {
+            session.lock().unwrap().listeners.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(text: &str) -> ServerFrame {
+        ServerFrame::BotResponse { reply_to: "c".to_string(), text: text.to_string(), payloads: Vec::new() }
+    }
+
+    #[test]
+    fn test_resume_replays_unacknowledged_messages() {
+        let registry = SessionRegistry::new();
+        let attached = registry.attach(None, None);
+        assert!(!attached.resumed);
+        let session_id = attached.session.lock().unwrap().id().to_string();
+        {
+            let mut session = attached.session.lock().unwrap();
+            for text in ["1", "2", "3"] {
+                session.push(response(text));
+            }
+            session.ack(attached.listener, 1);
+            assert!(session.first_seen("c1"));
+            assert!(!session.first_seen("c1"));
+        }
+        registry.detach(&attached.session, attached.listener);
+
+        let resumed = registry.attach(Some(&session_id), Some(2));
+        assert!(resumed.resumed && !resumed.truncated);
+        assert_eq!(resumed.replay.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![Some(3)]);
+
+        // 確認済みとして破棄した番号から再開しようとした
+        let resumed = registry.attach(Some(&session_id), Some(0));
+        assert!(resumed.truncated);
+        assert_eq!(resumed.replay.len(), 2);
+
+        let unknown = registry.attach(Some("missing"), Some(3));
+        assert!(!unknown.resumed && unknown.truncated);
+        assert_eq!(registry.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_devices_of_one_user_share_a_session_and_take_turns() {
+        let registry = SessionRegistry::new();
+        let mut phone = registry.attach_as(Some("u1"), None, None);
+        let mut laptop = registry.attach_as(Some("u1"), None, None);
+        assert!(laptop.resumed);
+        assert!(Arc::ptr_eq(&phone.session, &laptop.session));
+
+        phone.session.lock().unwrap().broadcast(response("1"));
+        assert_eq!(phone.events.try_recv().unwrap().seq, Some(1));
+        assert_eq!(laptop.events.try_recv().unwrap().seq, Some(1));
+        // 一方の端末が受信を確認しても、もう一方が確認するまでは再送用に残す
+        phone.session.lock().unwrap().ack(phone.listener, 1);
+        assert_eq!(phone.session.lock().unwrap().replay_after(0).0.len(), 1);
+        laptop.session.lock().unwrap().ack(laptop.listener, 1);
+        assert!(phone.session.lock().unwrap().replay_after(0).0.is_empty());
+
+        // 切断した端末が受信していない分は、もう一方が確認しても再開に備えて残す
+        phone.session.lock().unwrap().broadcast(response("2"));
+        registry.detach(&laptop.session, laptop.listener);
+        phone.session.lock().unwrap().ack(phone.listener, 2);
+        assert_eq!(phone.session.lock().unwrap().replay_after(1).0.len(), 1);
+        let session_id = phone.session.lock().unwrap().id().to_string();
+        let laptop = registry.attach_as(Some("u1"), Some(&session_id), Some(1));
+        assert_eq!(laptop.replay.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![Some(2)]);
+        laptop.session.lock().unwrap().ack(laptop.listener, 2);
+        assert!(phone.session.lock().unwrap().replay_after(0).0.is_empty());
+
+        // 他のユーザーはセッションIDを指定しても引き継げない
+        let session_id = phone.session.lock().unwrap().id().to_string();
+        let other = registry.attach_as(Some("u2"), Some(&session_id), None);
+        assert!(!Arc::ptr_eq(&other.session, &phone.session));
+
+        // 受け付けた順に処理する。先の順番が取り消されても後続は進む
+        let (first, second, third) = {
+            let mut session = phone.session.lock().unwrap();
+            (session.take_turn(), session.take_turn(), session.take_turn())
+        };
+        let waiting = tokio::spawn(async move {
+            third.wait().await;
+        });
+        drop(second);
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        first.wait().await;
+        drop(first);
+        tokio::time::timeout(Duration::from_secs(1), waiting).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sessions_are_limited_and_idle_ones_expire() {
+        let registry = SessionRegistry::new().with_limits(2, Duration::from_millis(50));
+
+        // 同じユーザーが何度 `hello` を送っても、セッションは1つ
+        let first = registry.attach_as(Some("u1"), None, None);
+        let first_id = first.session.lock().unwrap().id().to_string();
+        for session_id in [None, Some("missing"), Some(first_id.as_str())] {
+            let again = registry.attach_as(Some("u1"), session_id, None);
+            assert!(Arc::ptr_eq(&again.session, &first.session));
+            registry.detach(&again.session, again.listener);
+        }
+        registry.detach(&first.session, first.listener);
+        assert_eq!(registry.len(), 1);
+
+        // 上限に達したら、切断したセッションから破棄する
+        let mut second = registry.attach_as(Some("u2"), None, None);
+        let third = registry.attach_as(Some("u3"), None, None);
+        assert_eq!(registry.len(), 2);
+        assert!(registry.get(&first_id).is_none());
+
+        // 接続中でも、やり取りがなければ破棄して端末への送り先を閉じる
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let third_id = {
+            let mut session = third.session.lock().unwrap();
+            session.broadcast(response("1"));
+            session.id().to_string()
+        };
+        assert!(registry.get(&third_id).is_some());
+        assert_eq!(registry.len(), 1);
+        assert_eq!(second.events.try_recv(), Err(mpsc::error::TryRecvError::Disconnected));
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::sync::RwLock;
+
+use super::gift_cache::{CacheEntry, CachedGift, EntryExpiry};
+use crate::metrics;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BackendStats {
+    pub size: usize,
+    pub max_size: usize,
+    pub evictions: u64,
+    pub expirations: u64,
+}
+
+/// `GiftCache` の保存先
+///
+/// エントリは `stale_until` を過ぎるまで保持し、それ以降は `get` で返さない。
+/// 鮮度（`fresh_until`）の判定は `GiftCache` 側で行う。
+#[async_trait]
+pub trait CacheBackend: std::fmt::Debug + Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn get(&self, key: &str) -> Result<Option<CacheEntry>>;
+
+    async fn set(&self, key: &str, entry: CacheEntry) -> Result<()>;
+
+    /// 既存エントリへの追加では期限を変更しない。`expiry` は新規作成時のみ使われる
+    async fn add(&self, key: &str, gift: CachedGift, expiry: EntryExpiry) -> Result<()>;
+
+    async fn remove(&self, key: &str) -> Result<()>;
+
+    async fn clear(&self) -> Result<()>;
+
+    /// 期限切れのエントリを削除し、削除した件数を返す。サーバー側でTTLを扱うバックエンドでは何もしない
+    async fn cleanup_expired(&self) -> Result<usize> {
+        Ok(0)
+    }
+
+    async fn stats(&self) -> Result<BackendStats>;
+
+    /// バックエンドに到達できるかを確認する（ヘルスチェック用）
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct MemoryEntry {
+    entry: CacheEntry,
+    last_access: u64,
+}
+
+// エントリ本体と、最終アクセス順に並べたLRUインデックス
+#[derive(Debug, Default)]
+struct MemoryState {
+    entries: HashMap<String, MemoryEntry>,
+    lru: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+impl MemoryState {
+    fn touch(&mut self, key: &str) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.lru.remove(&entry.last_access);
+            entry.last_access = clock;
+            self.lru.insert(clock, key.to_string());
+        }
+    }
+
+    fn insert(&mut self, key: String, entry: CacheEntry) {
+        self.remove(&key);
+        self.clock += 1;
+        self.lru.insert(self.clock, key.clone());
+        self.entries.insert(key, MemoryEntry { entry, last_access: self.clock });
+    }
+
+    fn remove(&mut self, key: &str) -> Option<MemoryEntry> {
+        let entry = self.entries.remove(key)?;
+        self.lru.remove(&entry.last_access);
+        Some(entry)
+    }
+
+    fn pop_lru(&mut self) -> Option<MemoryEntry> {
+        let (_, key) = self.lru.pop_first()?;
+        self.entries.remove(&key)
+    }
+}
+
+/// プロセス内のLRUキャッシュ
+#[derive(Debug)]
+pub struct MemoryCacheBackend {
+    state: RwLock<MemoryState>,
+    max_size: usize,
+    evictions: AtomicU64,
+    expirations: AtomicU64,
+}
+
+impl MemoryCacheBackend {
+    /// `max_size` が0の場合はエントリ数を制限しない
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            state: RwLock::new(MemoryState::default()),
+            max_size,
+            evictions: AtomicU64::new(0),
+            expirations: AtomicU64::new(0),
+        }
+    }
+
+    // 最大エントリ数を超えた分を最も長く参照されていない順に削除する
+    fn evict_over_capacity(&self, state: &mut MemoryState) {
+        if self.max_size == 0 {
+            return;
+        }
+        while state.entries.len() > self.max_size {
+            if state.pop_lru().is_none() {
+                break;
+            }
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+            metrics::global().cache_evictions.with_label_values(&[self.name()]).inc();
+        }
+    }
+}
+
+#[async_trait]
+impl CacheBackend for MemoryCacheBackend {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<CacheEntry>> {
+        let mut state = self.state.write().await;
+        let now = SystemTime::now();
+        let entry = match state.entries.get(key) {
+            Some(stored) if now < stored.entry.expiry.stale_until => stored.entry.clone(),
+            _ => return Ok(None),
+        };
+        state.touch(key);
+        Ok(Some(entry))
+    }
+
+    async fn set(&self, key: &str, entry: CacheEntry) -> Result<()> {
+        let mut state = self.state.write().await;
+        state.insert(key.to_string(), entry);
+        self.evict_over_capacity(&mut state);
+        Ok(())
+    }
+
+    async fn add(&self, key: &str, gift: CachedGift, expiry: EntryExpiry) -> Result<()> {
+        let mut state = self.state.write().await;
+        let now = SystemTime::now();
+        match state.entries.get_mut(key) {
+            Some(stored) if now < stored.entry.expiry.stale_until => {
+                stored.entry.gifts.push(gift);
+                state.touch(key);
+            }
+            _ => {
+                state.insert(key.to_string(), CacheEntry::new(vec![gift], expiry));
+                self.evict_over_capacity(&mut state);
+            }
+        }
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<()> {
+        let mut state = self.state.write().await;
+        state.remove(key);
+        Ok(())
+    }
+
+    async fn clear(&self) -> Result<()> {
+        let mut state = self.state.write().await;
+        *state = MemoryState::default();
+        Ok(())
+    }
+
+    async fn cleanup_expired(&self) -> Result<usize> {
+        let mut state = self.state.write().await;
+        let now = SystemTime::now();
+
+        // 古いデータとしても提供できなくなったエントリを削除
+        let expired_keys: Vec<String> = state
+            .entries
+            .iter()
+            .filter(|(_, stored)| now >= stored.entry.expiry.stale_until)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in &expired_keys {
+            state.remove(key);
+        }
+
+        self.expirations.fetch_add(expired_keys.len() as u64, Ordering::Relaxed);
+        metrics::global()
+            .cache_expirations
+            .with_label_values(&[self.name()])
+            .inc_by(expired_keys.len() as u64);
+        Ok(expired_keys.len())
+    }
+
+    async fn stats(&self) -> Result<BackendStats> {
+        let state = self.state.read().await;
+        Ok(BackendStats {
+            size: state.entries.len(),
+            max_size: self.max_size,
+            evictions: self.evictions.load(Ordering::Relaxed),
+            expirations: self.expirations.load(Ordering::Relaxed),
+        })
+    }
+}
@@ -1,9 +1,21 @@
-use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, SystemTime};
-use anyhow::Result;
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
-use tokio::sync::RwLock;
-use std::sync::Arc;
+use tokio::task::JoinHandle;
+use std::sync::{Arc, Weak};
+
+use super::cache_backend::{BackendStats, CacheBackend, MemoryCacheBackend};
+use super::redis_cache::RedisCacheBackend;
+use crate::app::gift::citation::SourceLink;
+use crate::config::config::{CacheBackendKind, CacheConfig};
+use crate::config::secret::Secret;
+use crate::metrics;
+
+// 最大エントリ数のデフォルト値（CacheConfig の既定値と同じ）
+pub const DEFAULT_MAX_SIZE: usize = 1000;
+// 上流の一時的な失敗を記録しておく期間のデフォルト値（秒）
+pub const DEFAULT_NEGATIVE_TTL_SECONDS: u64 = 10;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CachedGift {
@@ -12,116 +24,355 @@ pub struct CachedGift {
     pub price: i32,
     pub category: String,
     pub url: Option<String>,
+    #[serde(default)]
+    pub store: Option<String>,
+    #[serde(default)]
+    pub manner_advice: Option<String>,
+    #[serde(default)]
+    pub store_url: Option<String>,
+    /// 推薦の根拠にした検索結果
+    #[serde(default)]
+    pub sources: Vec<SourceLink>,
+    /// 生成に使ったプロンプトの版
+    #[serde(default)]
+    pub prompt_version: Option<String>,
     pub cached_at: SystemTime,
 }
 
+/// エントリの有効期限
+///
+/// `fresh_until` までは新鮮なデータとして扱い、`stale_until` までは再取得中の代替として古いデータを返せる。
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct EntryExpiry {
+    pub fresh_until: SystemTime,
+    pub stale_until: SystemTime,
+}
+
+impl EntryExpiry {
+    pub fn new(now: SystemTime, fresh_for: Duration, stale_for: Duration) -> Self {
+        let fresh_until = now + fresh_for;
+        Self {
+            fresh_until,
+            stale_until: fresh_until + stale_for,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub gifts: Vec<CachedGift>,
+    pub expiry: EntryExpiry,
+    /// 上流の呼び出しに失敗したことを示すネガティブキャッシュのエラーメッセージ
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl CacheEntry {
+    pub fn new(gifts: Vec<CachedGift>, expiry: EntryExpiry) -> Self {
+        Self { gifts, expiry, error: None }
+    }
+
+    pub fn negative(message: String, expiry: EntryExpiry) -> Self {
+        Self {
+            gifts: Vec::new(),
+            expiry,
+            error: Some(message),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum CacheLookup {
+    Fresh(Vec<CachedGift>),
+    /// 期限切れだが提供可能なデータ。呼び出し側で再取得を行う
+    Stale(Vec<CachedGift>),
+    /// 直近の上流呼び出しが失敗したことを示す
+    Negative(String),
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct CacheStats {
+    pub backend: &'static str,
+    pub size: usize,
+    pub max_size: usize,
+    pub hits: u64,
+    pub stale_hits: u64,
+    pub negative_hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub expirations: u64,
+}
+
+#[derive(Debug)]
+struct CacheInner {
+    backend: Box<dyn CacheBackend>,
+    hits: AtomicU64,
+    stale_hits: AtomicU64,
+    negative_hits: AtomicU64,
+    misses: AtomicU64,
+    // 設定の再読み込みで変更できるよう、TTLはミリ秒単位で共有する
+    ttl_millis: AtomicU64,
+    stale_ttl_millis: AtomicU64,
+    negative_ttl_millis: AtomicU64,
+}
+
 #[derive(Debug, Clone)]
 pub struct GiftCache {
-    cache: Arc<RwLock<HashMap<String, Vec<CachedGift>>>>,
-    ttl: Duration,
+    inner: Arc<CacheInner>,
 }
 
 impl GiftCache {
     pub fn new(ttl_seconds: u64) -> Self {
+        Self::with_max_size(ttl_seconds, DEFAULT_MAX_SIZE)
+    }
+
+    /// `max_size` が0の場合はエントリ数を制限しない
+    pub fn with_max_size(ttl_seconds: u64, max_size: usize) -> Self {
+        Self::with_backend(ttl_seconds, MemoryCacheBackend::new(max_size))
+    }
+
+    pub fn with_backend(ttl_seconds: u64, backend: impl CacheBackend + 'static) -> Self {
         Self {
-            cache: Arc::new(RwLock::new(HashMap::new())),
-            ttl: Duration::from_secs(ttl_seconds),
+            inner: Arc::new(CacheInner {
+                backend: Box::new(backend),
+                hits: AtomicU64::new(0),
+                stale_hits: AtomicU64::new(0),
+                negative_hits: AtomicU64::new(0),
+                misses: AtomicU64::new(0),
+                ttl_millis: AtomicU64::new(ttl_seconds.saturating_mul(1000)),
+                stale_ttl_millis: AtomicU64::new(0),
+                negative_ttl_millis: AtomicU64::new(DEFAULT_NEGATIVE_TTL_SECONDS * 1000),
+            }),
         }
     }
 
-    pub async fn get(&self, key: &str) -> Option<Vec<CachedGift>> {
-        let cache = self.cache.read().await;
-        if let Some(gifts) = cache.get(key) {
-            // キャッシュの有効期限をチェック
-            let now = SystemTime::now();
-            let valid_gifts: Vec<CachedGift> = gifts
-                .iter()
-                .filter(|gift| {
-                    if let Ok(elapsed) = now.duration_since(gift.cached_at) {
-                        elapsed < self.ttl
-                    } else {
-                        false
-                    }
-                })
-                .cloned()
-                .collect();
+    /// 鮮度切れ後も古いデータを返せる期間を設定する
+    pub fn with_stale_ttl(self, stale_ttl: Duration) -> Self {
+        store_millis(&self.inner.stale_ttl_millis, stale_ttl);
+        self
+    }
 
-            if !valid_gifts.is_empty() {
-                Some(valid_gifts)
-            } else {
-                None
+    /// 上流の失敗を記録しておく期間を設定する
+    pub fn with_negative_ttl(self, negative_ttl: Duration) -> Self {
+        store_millis(&self.inner.negative_ttl_millis, negative_ttl);
+        self
+    }
+
+    /// 設定の再読み込み時にTTLを差し替える。保存済みエントリの期限は変更しない
+    pub fn update_ttls(&self, config: &CacheConfig) {
+        store_millis(&self.inner.ttl_millis, Duration::from_secs(config.ttl_seconds));
+        store_millis(&self.inner.stale_ttl_millis, Duration::from_secs(config.stale_ttl_seconds));
+        store_millis(&self.inner.negative_ttl_millis, Duration::from_secs(config.negative_ttl_seconds));
+    }
+
+    pub fn ttl(&self) -> Duration {
+        load_millis(&self.inner.ttl_millis)
+    }
+
+    fn stale_ttl(&self) -> Duration {
+        load_millis(&self.inner.stale_ttl_millis)
+    }
+
+    fn negative_ttl(&self) -> Duration {
+        load_millis(&self.inner.negative_ttl_millis)
+    }
+
+    /// 設定からキャッシュを作成し、期限切れエントリの定期削除タスクを起動する
+    pub fn from_config(config: &CacheConfig) -> Result<Self> {
+        let cache = match config.backend {
+            CacheBackendKind::Memory => Self::with_max_size(config.ttl_seconds, config.max_size),
+            CacheBackendKind::Redis => {
+                let redis_url = config
+                    .redis_url
+                    .as_ref()
+                    .map(Secret::expose)
+                    .context("CACHE_REDIS_URL must be set for the redis cache backend")?;
+                Self::with_backend(config.ttl_seconds, RedisCacheBackend::new(redis_url)?)
             }
-        } else {
-            None
+        }
+        .with_stale_ttl(Duration::from_secs(config.stale_ttl_seconds))
+        .with_negative_ttl(Duration::from_secs(config.negative_ttl_seconds));
+        cache.spawn_cleanup_task(Duration::from_secs(config.cleanup_interval));
+        Ok(cache)
+    }
+
+    /// 新鮮なエントリのみを返す
+    pub async fn get(&self, key: &str) -> Option<Vec<CachedGift>> {
+        match self.lookup(key).await {
+            Some(CacheLookup::Fresh(gifts)) => Some(gifts),
+            _ => None,
         }
     }
 
+    /// 鮮度に応じてエントリを分類して返す。古いエントリがない場合はネガティブキャッシュを確認する
+    pub async fn lookup(&self, key: &str) -> Option<CacheLookup> {
+        let now = SystemTime::now();
+        let lookup = match self.backend_get(key).await {
+            Some(entry) if !entry.gifts.is_empty() => {
+                if now < entry.expiry.fresh_until {
+                    Some(CacheLookup::Fresh(entry.gifts))
+                } else {
+                    Some(CacheLookup::Stale(entry.gifts))
+                }
+            }
+            _ => self
+                .negative_error(key)
+                .await
+                .map(CacheLookup::Negative),
+        };
+
+        let (counter, result) = match lookup {
+            Some(CacheLookup::Fresh(_)) => (&self.inner.hits, "hit"),
+            Some(CacheLookup::Stale(_)) => (&self.inner.stale_hits, "stale"),
+            Some(CacheLookup::Negative(_)) => (&self.inner.negative_hits, "negative"),
+            None => (&self.inner.misses, "miss"),
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        metrics::global().cache_lookups.with_label_values(&[result]).inc();
+        lookup
+    }
+
     pub async fn set(&self, key: String, gifts: Vec<CachedGift>) -> Result<()> {
-        let mut cache = self.cache.write().await;
-        cache.insert(key, gifts);
-        Ok(())
+        self.set_with_ttl(key, gifts, self.ttl()).await
+    }
+
+    /// エントリ単位でTTLを上書きして保存する
+    pub async fn set_with_ttl(&self, key: String, gifts: Vec<CachedGift>, ttl: Duration) -> Result<()> {
+        let expiry = EntryExpiry::new(SystemTime::now(), ttl, self.stale_ttl());
+        self.inner.backend.set(&key, CacheEntry::new(gifts, expiry)).await?;
+        // 成功した結果で以前の失敗記録を上書きする
+        self.inner.backend.remove(&negative_key(&key)).await
+    }
+
+    /// 上流の失敗を短時間記録し、同じキーでの再呼び出しを抑制する
+    ///
+    /// 既存の（古い）エントリは残したままにする。
+    pub async fn set_negative(&self, key: &str, message: String) -> Result<()> {
+        let expiry = EntryExpiry::new(SystemTime::now(), self.negative_ttl(), Duration::ZERO);
+        self.inner
+            .backend
+            .set(&negative_key(key), CacheEntry::negative(message, expiry))
+            .await
+    }
+
+    /// 直近の上流呼び出しが失敗していれば、そのエラーメッセージを返す
+    pub async fn negative_error(&self, key: &str) -> Option<String> {
+        self.backend_get(&negative_key(key)).await.and_then(|entry| entry.error)
     }
 
     pub async fn add(&self, key: String, gift: CachedGift) -> Result<()> {
-        let mut cache = self.cache.write().await;
-        if let Some(gifts) = cache.get_mut(&key) {
-            gifts.push(gift);
-        } else {
-            cache.insert(key, vec![gift]);
-        }
-        Ok(())
+        let expiry = EntryExpiry::new(SystemTime::now(), self.ttl(), self.stale_ttl());
+        self.inner.backend.add(&key, gift, expiry).await
     }
 
     pub async fn remove(&self, key: &str) -> Result<()> {
-        let mut cache = self.cache.write().await;
-        cache.remove(key);
-        Ok(())
+        self.inner.backend.remove(key).await?;
+        self.inner.backend.remove(&negative_key(key)).await
     }
 
     pub async fn clear(&self) -> Result<()> {
-        let mut cache = self.cache.write().await;
-        cache.clear();
-        Ok(())
+        self.inner.backend.clear().await
     }
 
     pub async fn cleanup_expired(&self) -> Result<usize> {
-        let mut cache = self.cache.write().await;
-        let now = SystemTime::now();
-        let mut removed_count = 0;
-
-        // 期限切れのエントリを削除
-        cache.retain(|_, gifts| {
-            let valid_gifts: Vec<CachedGift> = gifts
-                .iter()
-                .filter(|gift| {
-                    if let Ok(elapsed) = now.duration_since(gift.cached_at) {
-                        if elapsed < self.ttl {
-                            true
-                        } else {
-                            removed_count += 1;
-                            false
-                        }
-                    } else {
-                        removed_count += 1;
-                        false
-                    }
-                })
-                .cloned()
-                .collect();
+        self.inner.backend.cleanup_expired().await
+    }
 
-            *gifts = valid_gifts;
-            !gifts.is_empty()
+    pub async fn stats(&self) -> CacheStats {
+        let backend_stats = self.inner.backend.stats().await.unwrap_or_else(|e| {
+            tracing::warn!("Failed to read gift cache stats: {:?}", e);
+            BackendStats::default()
         });
+        CacheStats {
+            backend: self.inner.backend.name(),
+            size: backend_stats.size,
+            max_size: backend_stats.max_size,
+            hits: self.inner.hits.load(Ordering::Relaxed),
+            stale_hits: self.inner.stale_hits.load(Ordering::Relaxed),
+            negative_hits: self.inner.negative_hits.load(Ordering::Relaxed),
+            misses: self.inner.misses.load(Ordering::Relaxed),
+            evictions: backend_stats.evictions,
+            expirations: backend_stats.expirations,
+        }
+    }
+
+    pub fn backend_name(&self) -> &'static str {
+        self.inner.backend.name()
+    }
+
+    /// バックエンドに到達できるかを確認する
+    pub async fn ping(&self) -> Result<()> {
+        self.inner.backend.ping().await
+    }
+
+    /// 一定間隔で `cleanup_expired` を実行するバックグラウンドタスクを起動する
+    ///
+    /// タスクはキャッシュへの弱参照だけを持ち、すべての `GiftCache` が破棄されると終了する。
+    pub fn spawn_cleanup_task(&self, interval: Duration) -> JoinHandle<()> {
+        let weak: Weak<CacheInner> = Arc::downgrade(&self.inner);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let Some(inner) = weak.upgrade() else { break };
+                match inner.backend.cleanup_expired().await {
+                    Ok(removed) if removed > 0 => {
+                        tracing::debug!("Expired {} cached gifts", removed);
+                    }
+                    Ok(_) => {}
+                    Err(e) => tracing::warn!("Failed to clean up gift cache: {:?}", e),
+                }
+            }
+        })
+    }
 
-        Ok(removed_count)
+    // バックエンドの障害はキャッシュミスとして扱い、リクエスト自体は継続させる
+    async fn backend_get(&self, key: &str) -> Option<CacheEntry> {
+        match self.inner.backend.get(key).await {
+            Ok(entry) => entry,
+            Err(e) => {
+                tracing::warn!("Gift cache lookup failed: {:?}", e);
+                None
+            }
+        }
     }
 }
 
+fn store_millis(target: &AtomicU64, duration: Duration) {
+    let millis = u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+    target.store(millis, Ordering::Relaxed);
+}
+
+fn load_millis(source: &AtomicU64) -> Duration {
+    Duration::from_millis(source.load(Ordering::Relaxed))
+}
+
+fn negative_key(key: &str) -> String {
+    format!("negative:{}", key)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use tokio::time::sleep;
 
+    fn test_gift(name: &str) -> CachedGift {
+        CachedGift {
+            name: name.to_string(),
+            description: "テスト用のギフトです".to_string(),
+            price: 1000,
+            category: "テスト".to_string(),
+            url: None,
+            store: None,
+            manner_advice: None,
+            store_url: None,
+            sources: Vec::new(),
+            prompt_version: None,
+            cached_at: SystemTime::now(),
+        }
+    }
+
     #[tokio::test]
     async fn test_cache_operations() {
         let cache = GiftCache::new(2); // 2秒のTTL
@@ -132,6 +383,11 @@ mod tests {
             price: 1000,
             category: "テスト".to_string(),
             url: None,
+            store: None,
+            manner_advice: None,
+            store_url: None,
+            sources: Vec::new(),
+            prompt_version: None,
             cached_at: SystemTime::now(),
         };
 
@@ -154,4 +410,96 @@ mod tests {
         let removed = cache.cleanup_expired().await.unwrap();
         assert_eq!(removed, 1);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_lru_eviction() {
+        let cache = GiftCache::with_max_size(60, 2);
+
+        cache.set("a".to_string(), vec![test_gift("A")]).await.unwrap();
+        cache.set("b".to_string(), vec![test_gift("B")]).await.unwrap();
+
+        // "a" を参照して "b" を最も古いエントリにする
+        assert!(cache.get("a").await.is_some());
+        cache.set("c".to_string(), vec![test_gift("C")]).await.unwrap();
+
+        assert!(cache.get("a").await.is_some());
+        assert!(cache.get("b").await.is_none());
+        assert!(cache.get("c").await.is_some());
+
+        let stats = cache.stats().await;
+        assert_eq!(stats.size, 2);
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.hits, 3);
+        assert_eq!(stats.misses, 1);
+    }
+
+    #[test]
+    fn test_ttls_are_shared_between_clones() {
+        let cache = GiftCache::new(60);
+        let clone = cache.clone();
+
+        clone.update_ttls(&CacheConfig {
+            ttl_seconds: 120,
+            ..CacheConfig::default()
+        });
+        assert_eq!(cache.ttl(), Duration::from_secs(120));
+    }
+
+    #[tokio::test]
+    async fn test_ttl_override_and_background_cleanup() {
+        let cache = GiftCache::new(60);
+        let handle = cache.spawn_cleanup_task(Duration::from_millis(50));
+
+        cache
+            .set_with_ttl("short".to_string(), vec![test_gift("短期")], Duration::from_millis(100))
+            .await
+            .unwrap();
+        cache.set("long".to_string(), vec![test_gift("長期")]).await.unwrap();
+
+        sleep(Duration::from_millis(300)).await;
+
+        let stats = cache.stats().await;
+        assert_eq!(stats.size, 1);
+        assert_eq!(stats.expirations, 1);
+        assert!(cache.get("long").await.is_some());
+
+        // キャッシュが破棄されるとバックグラウンドタスクも終了する
+        drop(cache);
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stale_and_negative_lookup() {
+        let cache = GiftCache::new(60)
+            .with_stale_ttl(Duration::from_secs(60))
+            .with_negative_ttl(Duration::from_millis(100));
+
+        cache
+            .set_with_ttl("key".to_string(), vec![test_gift("古い")], Duration::ZERO)
+            .await
+            .unwrap();
+
+        // 鮮度切れでも古いデータとして返る
+        assert!(cache.get("key").await.is_none());
+        assert!(matches!(cache.lookup("key").await, Some(CacheLookup::Stale(_))));
+
+        // 失敗の記録は古いデータを消さない
+        cache.set_negative("key", "upstream down".to_string()).await.unwrap();
+        assert!(matches!(cache.lookup("key").await, Some(CacheLookup::Stale(_))));
+        assert_eq!(cache.negative_error("key").await.as_deref(), Some("upstream down"));
+
+        cache.set_negative("missing", "upstream down".to_string()).await.unwrap();
+        assert!(matches!(cache.lookup("missing").await, Some(CacheLookup::Negative(_))));
+
+        sleep(Duration::from_millis(200)).await;
+        assert!(cache.lookup("missing").await.is_none());
+        assert!(cache.negative_error("key").await.is_none());
+
+        let stats = cache.stats().await;
+        assert_eq!(stats.stale_hits, 3);
+        assert_eq!(stats.negative_hits, 1);
+    }
+}
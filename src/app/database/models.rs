@@ -1,7 +1,10 @@
 use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
 use sqlx::FromRow;
 use time::OffsetDateTime;
 
+use crate::app::gift::citation::SourceLink;
+
 #[derive(Debug, Serialize, Deserialize, FromRow)]
 pub struct ChatHistory {
     pub id: i32,
@@ -21,6 +24,14 @@ pub struct GiftRecommendation {
     pub category: String,
     pub rating: f32,
     pub source: String,
+    /// 商品ページ
+    pub url: Option<String>,
+    /// 購入店舗のページ
+    pub store_url: Option<String>,
+    /// 推薦の根拠にした検索結果
+    pub sources: Json<Vec<SourceLink>>,
+    /// 生成に使ったプロンプトの版（`name@version`）
+    pub prompt_version: Option<String>,
     pub created_at: OffsetDateTime,
 }
 
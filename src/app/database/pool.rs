@@ -0,0 +1,98 @@
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::Result;
+use serde::Serialize;
+use sqlx::migrate::Migrator;
+use sqlx::postgres::{PgConnectOptions, PgPool, PgPoolOptions};
+
+use crate::config::config::DatabaseConfig;
+
+// 接続できないときにヘルスチェックやリクエストを長く待たせない
+const ACQUIRE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// ビルド時に埋め込んだ `migrations/` のマイグレーション
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// 適用済みのマイグレーションと、まだ適用されていないもののバージョン
+#[derive(Debug, Clone, Serialize)]
+pub struct MigrationStatus {
+    pub applied: usize,
+    pub pending: Vec<i64>,
+}
+
+impl MigrationStatus {
+    pub fn is_up_to_date(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+pub struct Database {
+    pool: Arc<PgPool>,
+}
+
+impl Database {
+    pub async fn new() -> Result<Self> {
+        let database_url = env::var("DATABASE_URL")?;
+        let pool = PgPoolOptions::new()
+            .max_connections(5)
+            .connect(&database_url)
+            .await?;
+
+        Ok(Self {
+            pool: Arc::new(pool),
+        })
+    }
+
+    /// 設定からプールを作成する。接続は最初に使われたときに確立するため、起動時にデータベースを必要としない
+    pub fn connect_lazy(config: &DatabaseConfig) -> Self {
+        let options = PgConnectOptions::new()
+            .host(&config.host)
+            .port(config.port)
+            .username(&config.username)
+            .password(config.password.expose())
+            .database(&config.database_name);
+        let pool = PgPoolOptions::new()
+            .max_connections(config.max_connections)
+            .acquire_timeout(ACQUIRE_TIMEOUT)
+            .connect_lazy_with(options);
+
+        Self {
+            pool: Arc::new(pool),
+        }
+    }
+
+    pub fn get_pool(&self) -> Arc<PgPool> {
+        self.pool.clone()
+    }
+
+    /// プールから接続を取得して簡単なクエリを実行する
+    pub async fn ping(&self) -> Result<()> {
+        sqlx::query("SELECT 1").execute(&*self.pool).await?;
+        Ok(())
+    }
+
+    /// 埋め込まれたマイグレーションがすべて適用されているかを確認する
+    pub async fn migration_status(&self) -> Result<MigrationStatus> {
+        let applied: Vec<i64> =
+            match sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success")
+                .fetch_all(&*self.pool)
+                .await
+            {
+                Ok(applied) => applied,
+                // 管理テーブルがない場合は一度も適用されていない
+                Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("42P01") => Vec::new(),
+                Err(e) => return Err(e.into()),
+            };
+
+        let pending = MIGRATOR
+            .iter()
+            .map(|migration| migration.version)
+            .filter(|version| !applied.contains(version))
+            .collect();
+        Ok(MigrationStatus {
+            applied: applied.len(),
+            pending,
+        })
+    }
+}
@@ -0,0 +1,307 @@
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use tokio::sync::OnceCell;
+
+use super::cache_backend::{BackendStats, CacheBackend};
+use super::gift_cache::{CacheEntry, CachedGift, EntryExpiry};
+
+pub const DEFAULT_KEY_PREFIX: &str = "gift_cache:";
+const META_SUFFIX: &str = ":meta";
+// エントリの一覧。キャッシュキーを `stale_until` のミリ秒をスコアにして保持する
+const INDEX_KEY: &str = "@index";
+
+/// Redisプロトコルを話すサーバー上のキャッシュ
+///
+/// 各キーはJSONにシリアライズした `CachedGift` のリストとして保存し、`stale_until` をサーバー側の有効期限に設定する。
+/// 書き込みと有効期限の設定は1つのトランザクション（またはスクリプト）で行う。
+/// エントリ数はキー空間を走査せずに数えられるよう、期限をスコアにしたソート済みセットで管理する。
+/// 複数インスタンスで同じサーバーを共有することでヒット率と無効化の一貫性を保つ。
+pub struct RedisCacheBackend {
+    client: redis::Client,
+    connection: OnceCell<ConnectionManager>,
+    prefix: String,
+}
+
+impl std::fmt::Debug for RedisCacheBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisCacheBackend")
+            .field("prefix", &self.prefix)
+            .field("connected", &self.connection.initialized())
+            .finish()
+    }
+}
+
+impl RedisCacheBackend {
+    /// 接続は最初のコマンド実行時に確立する
+    pub fn new(redis_url: &str) -> Result<Self> {
+        Self::with_prefix(redis_url, DEFAULT_KEY_PREFIX)
+    }
+
+    pub fn with_prefix(redis_url: &str, prefix: &str) -> Result<Self> {
+        let client = redis::Client::open(redis_url).context("Invalid Redis URL")?;
+        Ok(Self {
+            client,
+            connection: OnceCell::new(),
+            prefix: prefix.to_string(),
+        })
+    }
+
+    async fn connection(&self) -> Result<ConnectionManager> {
+        let connection = self
+            .connection
+            .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+            .await
+            .context("Failed to connect to Redis")?;
+        Ok(connection.clone())
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    fn meta_key(&self, key: &str) -> String {
+        format!("{}{}{}", self.prefix, key, META_SUFFIX)
+    }
+
+    fn index_key(&self) -> String {
+        format!("{}{}", self.prefix, INDEX_KEY)
+    }
+}
+
+// 新しく作成したリストにだけ期限を設定し、一覧に加える。既存のエントリの期限は延ばさない
+fn add_script() -> &'static redis::Script {
+    static SCRIPT: OnceLock<redis::Script> = OnceLock::new();
+    SCRIPT.get_or_init(|| {
+        redis::Script::new(
+            r#"
+            local length = redis.call('RPUSH', KEYS[1], ARGV[1])
+            if length == 1 then
+                redis.call('SET', KEYS[2], ARGV[2])
+                redis.call('PEXPIREAT', KEYS[2], ARGV[3])
+                redis.call('PEXPIREAT', KEYS[1], ARGV[3])
+                redis.call('ZADD', KEYS[3], ARGV[3], ARGV[4])
+            end
+            return length
+            "#,
+        )
+    })
+}
+
+// エントリの期限とネガティブキャッシュのメッセージ。ギフトのリストとは別のキーに保存する
+#[derive(Debug, Serialize, Deserialize)]
+struct EntryMeta {
+    expiry: EntryExpiry,
+    error: Option<String>,
+}
+
+fn unix_millis(time: SystemTime) -> i64 {
+    let millis = time
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis())
+        .unwrap_or(0);
+    i64::try_from(millis).unwrap_or(i64::MAX)
+}
+
+fn serialize_gift(gift: &CachedGift) -> Result<String> {
+    serde_json::to_string(gift).context("Failed to serialize cached gift")
+}
+
+fn serialize_meta(expiry: EntryExpiry, error: Option<String>) -> Result<String> {
+    serde_json::to_string(&EntryMeta { expiry, error }).context("Failed to serialize cache entry")
+}
+
+#[async_trait]
+impl CacheBackend for RedisCacheBackend {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<CacheEntry>> {
+        let mut connection = self.connection().await?;
+        let (values, meta): (Vec<String>, Option<String>) = redis::pipe()
+            .lrange(self.key(key), 0, -1)
+            .get(self.meta_key(key))
+            .query_async(&mut connection)
+            .await?;
+
+        let Some(meta) = meta else { return Ok(None) };
+        let meta: EntryMeta =
+            serde_json::from_str(&meta).context("Failed to deserialize cache entry")?;
+        if SystemTime::now() >= meta.expiry.stale_until {
+            return Ok(None);
+        }
+
+        let gifts = values
+            .iter()
+            .map(|value| serde_json::from_str(value))
+            .collect::<std::result::Result<Vec<CachedGift>, _>>()
+            .context("Failed to deserialize cached gift")?;
+        Ok(Some(CacheEntry {
+            gifts,
+            expiry: meta.expiry,
+            error: meta.error,
+        }))
+    }
+
+    async fn set(&self, key: &str, entry: CacheEntry) -> Result<()> {
+        let mut connection = self.connection().await?;
+        let (list_key, meta_key) = (self.key(key), self.meta_key(key));
+        let values = entry.gifts.iter().map(serialize_gift).collect::<Result<Vec<_>>>()?;
+        let expire_at = unix_millis(entry.expiry.stale_until);
+
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(&list_key).ignore();
+        if !values.is_empty() {
+            pipe.rpush(&list_key, values).ignore()
+                .cmd("PEXPIREAT").arg(&list_key).arg(expire_at).ignore();
+        }
+        pipe.set(&meta_key, serialize_meta(entry.expiry, entry.error)?).ignore()
+            .cmd("PEXPIREAT").arg(&meta_key).arg(expire_at).ignore()
+            // 期限の過ぎたものは一覧からも外す
+            .zrembyscore(self.index_key(), "-inf", unix_millis(SystemTime::now())).ignore()
+            .zadd(self.index_key(), key, expire_at).ignore();
+        pipe.query_async::<_, ()>(&mut connection).await?;
+        Ok(())
+    }
+
+    async fn add(&self, key: &str, gift: CachedGift, expiry: EntryExpiry) -> Result<()> {
+        let mut connection = self.connection().await?;
+        let (list_key, meta_key) = (self.key(key), self.meta_key(key));
+        add_script()
+            .key(list_key)
+            .key(meta_key)
+            .key(self.index_key())
+            .arg(serialize_gift(&gift)?)
+            .arg(serialize_meta(expiry, None)?)
+            .arg(unix_millis(expiry.stale_until))
+            .arg(key)
+            .invoke_async::<_, usize>(&mut connection)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<()> {
+        let mut connection = self.connection().await?;
+        redis::pipe()
+            .atomic()
+            .del(&[self.key(key), self.meta_key(key)]).ignore()
+            .zrem(self.index_key(), key).ignore()
+            .query_async::<_, ()>(&mut connection)
+            .await?;
+        Ok(())
+    }
+
+    async fn clear(&self) -> Result<()> {
+        let mut connection = self.connection().await?;
+        let keys: Vec<String> = connection.zrange(self.index_key(), 0, -1).await?;
+        for chunk in keys.chunks(250) {
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            for key in chunk {
+                pipe.del(&[self.key(key), self.meta_key(key)]).ignore();
+            }
+            pipe.zrem(self.index_key(), chunk).ignore();
+            pipe.query_async::<_, ()>(&mut connection).await?;
+        }
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<()> {
+        let mut connection = self.connection().await?;
+        redis::cmd("PING").query_async::<_, String>(&mut connection).await?;
+        Ok(())
+    }
+
+    async fn stats(&self) -> Result<BackendStats> {
+        // 期限切れと追い出しはサーバーが管理するためエントリ数のみ返す
+        let mut connection = self.connection().await?;
+        let (size,): (usize,) = redis::pipe()
+            .atomic()
+            .zrembyscore(self.index_key(), "-inf", unix_millis(SystemTime::now())).ignore()
+            .zcard(self.index_key())
+            .query_async(&mut connection)
+            .await?;
+        Ok(BackendStats {
+            size,
+            ..BackendStats::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // ローカルのRedis互換サーバーが必要なため REDIS_TEST_URL が設定されている場合のみ実行する
+    // 例: REDIS_TEST_URL=redis://127.0.0.1:6379/15 cargo test redis_cache
+    fn test_backend() -> Option<RedisCacheBackend> {
+        let url = std::env::var("REDIS_TEST_URL").ok()?;
+        let prefix = format!("gift_cache_test:{}:", std::process::id());
+        Some(RedisCacheBackend::with_prefix(&url, &prefix).unwrap())
+    }
+
+    fn expiry(millis: u64) -> EntryExpiry {
+        EntryExpiry::new(SystemTime::now(), Duration::from_millis(millis), Duration::ZERO)
+    }
+
+    fn test_gift(name: &str) -> CachedGift {
+        CachedGift {
+            name: name.to_string(),
+            description: "テスト用のギフトです".to_string(),
+            price: 3000,
+            category: "テスト".to_string(),
+            url: None,
+            store: Some("高島屋".to_string()),
+            manner_advice: None,
+            store_url: None,
+            sources: Vec::new(),
+            prompt_version: None,
+            cached_at: SystemTime::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_redis_backend_operations() {
+        let Some(backend) = test_backend() else { return };
+
+        backend.set("key", CacheEntry::new(vec![test_gift("A")], expiry(60_000))).await.unwrap();
+        backend.add("key", test_gift("B"), expiry(60_000)).await.unwrap();
+
+        let gifts = backend.get("key").await.unwrap().unwrap().gifts;
+        assert_eq!(gifts.len(), 2);
+        assert_eq!(gifts[1].name, "B");
+        assert_eq!(gifts[0].store.as_deref(), Some("高島屋"));
+        assert_eq!(backend.stats().await.unwrap().size, 1);
+
+        backend.remove("key").await.unwrap();
+        assert!(backend.get("key").await.unwrap().is_none());
+
+        backend.add("a", test_gift("A"), expiry(60_000)).await.unwrap();
+        backend
+            .set("b", CacheEntry::negative("upstream down".to_string(), expiry(60_000)))
+            .await
+            .unwrap();
+        assert_eq!(backend.get("b").await.unwrap().unwrap().error.as_deref(), Some("upstream down"));
+        backend.clear().await.unwrap();
+        assert_eq!(backend.stats().await.unwrap().size, 0);
+    }
+
+    #[tokio::test]
+    async fn test_redis_backend_server_side_ttl() {
+        let Some(backend) = test_backend() else { return };
+
+        backend.set("short", CacheEntry::new(vec![test_gift("A")], expiry(100))).await.unwrap();
+        assert!(backend.get("short").await.unwrap().is_some());
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(backend.get("short").await.unwrap().is_none());
+        // 期限の過ぎたエントリは数えない
+        assert_eq!(backend.stats().await.unwrap().size, 0);
+    }
+}
@@ -1,9 +1,11 @@
 use anyhow::Result;
+use sqlx::types::Json;
 use sqlx::PgPool;
 use std::sync::Arc;
 use time::OffsetDateTime;
 
 use super::models::{ChatHistory, GiftRecommendation, UserPreference};
+use crate::app::gift::citation::SourceLink;
 
 pub struct ChatHistoryRepository {
     pool: Arc<PgPool>,
@@ -67,10 +69,12 @@ impl GiftRecommendationRepository {
             GiftRecommendation,
             r#"
             INSERT INTO gift_recommendations (
-                name, price, description, image_url, category, rating, source, created_at
+                name, price, description, image_url, category, rating, source, url, store_url, sources,
+                prompt_version, created_at
             )
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
-            RETURNING id, name, price, description, image_url, category, rating, source, created_at
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+            RETURNING id, name, price, description, image_url, category, rating, source, url, store_url,
+                sources AS "sources: Json<Vec<SourceLink>>", prompt_version, created_at
             "#,
             recommendation.name,
             recommendation.price,
@@ -79,6 +83,10 @@ impl GiftRecommendationRepository {
             recommendation.category,
             recommendation.rating,
             recommendation.source,
+            recommendation.url,
+            recommendation.store_url,
+            &recommendation.sources as _,
+            recommendation.prompt_version,
             OffsetDateTime::now_utc()
         )
         .fetch_one(&*self.pool)
@@ -96,7 +104,8 @@ impl GiftRecommendationRepository {
         let records = sqlx::query_as!(
             GiftRecommendation,
             r#"
-            SELECT id, name, price, description, image_url, category, rating, source, created_at
+            SELECT id, name, price, description, image_url, category, rating, source, url, store_url,
+                sources AS "sources: Json<Vec<SourceLink>>", prompt_version, created_at
             FROM gift_recommendations
             WHERE ($1::int IS NULL OR price >= $1)
             AND ($2::int IS NULL OR price <= $2)
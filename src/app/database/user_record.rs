@@ -148,6 +148,20 @@ impl UserDatabase {
     }
 }
 
+impl UserRecord {
+    /// これまでの贈り先の氏名（重複なし）。推薦の前に自由入力の欄から伏せるために使う
+    pub fn recipient_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for history in &self.gift_history {
+            let name = history.recipient.trim();
+            if !name.is_empty() && !names.iter().any(|existing| existing == name) {
+                names.push(name.to_string());
+            }
+        }
+        names
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
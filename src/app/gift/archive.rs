@@ -0,0 +1,136 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::Result;
+use async_trait::async_trait;
+use sqlx::PgPool;
+use time::OffsetDateTime;
+
+use super::citation::SourceLink;
+use crate::app::batch::{BatchConfig, BatchStore, BatchWriter};
+
+// まとめて書き込む件数と間隔、書き込み待ちと再試行のために保持する上限
+const BATCH: BatchConfig = BatchConfig {
+    kind: "gift recommendation",
+    batch_size: 50,
+    flush_interval: Duration::from_secs(2),
+    queue_capacity: 1024,
+    max_pending: 1000,
+};
+
+/// 上流が返した推薦1件の記録。プロンプトの版ごとに推薦の質を比べられるよう、根拠のリンクと版も残す
+#[derive(Debug, Clone)]
+pub struct SavedRecommendation {
+    pub name: String,
+    pub price: i32,
+    pub description: String,
+    /// 依頼の行事の種類
+    pub category: String,
+    pub source: String,
+    pub url: Option<String>,
+    pub store_url: Option<String>,
+    pub sources: Vec<SourceLink>,
+    pub prompt_version: Option<String>,
+    pub created_at: OffsetDateTime,
+}
+
+/// 推薦の保存先
+#[async_trait]
+pub trait RecommendationStore: Send + Sync {
+    async fn save(&self, recommendations: &[SavedRecommendation]) -> Result<()>;
+}
+
+#[async_trait]
+impl BatchStore<SavedRecommendation> for dyn RecommendationStore {
+    async fn save_batch(&self, recommendations: &[SavedRecommendation]) -> Result<()> {
+        self.save(recommendations).await
+    }
+}
+
+/// `gift_recommendations` テーブルに保存する
+pub struct PgRecommendationStore {
+    pool: Arc<PgPool>,
+}
+
+impl PgRecommendationStore {
+    pub fn new(pool: Arc<PgPool>) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl RecommendationStore for PgRecommendationStore {
+    async fn save(&self, recommendations: &[SavedRecommendation]) -> Result<()> {
+        let mut transaction = self.pool.begin().await?;
+        for recommendation in recommendations {
+            sqlx::query(
+                "INSERT INTO gift_recommendations (name, price, description, category, rating, source, url, store_url, \
+                 sources, prompt_version, created_at) \
+                 VALUES ($1, $2, $3, $4, 0, $5, $6, $7, $8::jsonb, $9, $10)",
+            )
+            .bind(&recommendation.name)
+            .bind(recommendation.price)
+            .bind(&recommendation.description)
+            .bind(&recommendation.category)
+            .bind(&recommendation.source)
+            .bind(&recommendation.url)
+            .bind(&recommendation.store_url)
+            .bind(serde_json::to_string(&recommendation.sources)?)
+            .bind(&recommendation.prompt_version)
+            .bind(recommendation.created_at)
+            .execute(&mut *transaction)
+            .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+}
+
+/// データベースを使わない場合の保存先
+#[derive(Default)]
+pub struct MemoryRecommendationStore {
+    recommendations: Mutex<Vec<SavedRecommendation>>,
+}
+
+impl MemoryRecommendationStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn recommendations(&self) -> Vec<SavedRecommendation> {
+        self.recommendations.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl RecommendationStore for MemoryRecommendationStore {
+    async fn save(&self, recommendations: &[SavedRecommendation]) -> Result<()> {
+        self.recommendations.lock().unwrap().extend_from_slice(recommendations);
+        Ok(())
+    }
+}
+
+/// 上流が返した推薦をバックグラウンドでまとめて保存する
+///
+/// 応答を保存の完了まで待たせないため、[`RecommendationArchive::record`] はキューに積むだけにする。
+/// 停止時は [`RecommendationArchive::flush`] で書き込み待ちの推薦を保存する。
+#[derive(Clone)]
+pub struct RecommendationArchive {
+    writer: BatchWriter<SavedRecommendation>,
+}
+
+impl RecommendationArchive {
+    pub fn spawn(store: Arc<dyn RecommendationStore>) -> Self {
+        Self {
+            writer: BatchWriter::spawn(store, BATCH),
+        }
+    }
+
+    pub fn record(&self, recommendation: SavedRecommendation) {
+        self.writer.record(recommendation);
+    }
+
+    /// 書き込み待ちの推薦をすべて保存し終えるまで待つ
+    pub async fn flush(&self) {
+        self.writer.flush().await;
+    }
+}
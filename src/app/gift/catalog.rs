@@ -0,0 +1,86 @@
+use super::recommendation::EventType;
+use super::rules::GiftRules;
+
+/// 上流のLLMを使えないときに提案する定番のお返し
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CatalogItem {
+    pub name: &'static str,
+    pub price: u32,
+    pub store: &'static str,
+    pub reason: &'static str,
+    /// 向いているイベント。空の場合はすべてのイベントに向く
+    pub events: &'static [EventType],
+}
+
+const fn item(
+    name: &'static str,
+    price: u32,
+    store: &'static str,
+    reason: &'static str,
+    events: &'static [EventType],
+) -> CatalogItem {
+    CatalogItem { name, price, store, reason, events }
+}
+
+pub const CATALOG: &[CatalogItem] = &[
+    item("洋菓子の詰め合わせ", 2000, "ヨックモック", "日持ちがして家族で分けやすく、相手に気を遣わせません", &[]),
+    item("今治タオル ギフトセット", 3000, "今治タオル本店", "消耗品で好みが分かれにくく、品質の良さが伝わります", &[]),
+    item("高級洗剤ギフト", 3000, "百貨店ギフトサロン", "「幸せが泡のように広がる」とされ、実用的で喜ばれます", &[]),
+    item("名入れバームクーヘン", 4000, "ねんりん家", "年輪を重ねる縁起物で、赤ちゃんの名前を入れられます", &[EventType::Birth]),
+    item("高級タオルセット", 5000, "高島屋", "実用的で上質な贈り物として適切です", &[]),
+    item("カタログギフト", 5000, "リンベル", "相手が好きなものを選べるため、好みが分からない場合に安心です", &[]),
+    item("紅白ワインセット", 8000, "エノテカ", "紅白でお祝いの気持ちを表せ、お酒を好む方に喜ばれます", &[EventType::Wedding, EventType::Celebration]),
+    item("カタログギフト（上位コース）", 10000, "リンベル", "高額のお祝いへのお返しでも品位を保てます", &[]),
+    item("黒毛和牛ギフト券", 15000, "大丸松坂屋", "目上の方にも贈りやすく、受け取る側が時期を選べます", &[]),
+];
+
+/// イベントに応じたのしと贈る時期の目安
+pub fn manner_advice(event_type: &EventType) -> &'static str {
+    match event_type {
+        EventType::Wedding => "のしは紅白10本の結び切りで表書きは「内祝」。挙式後1か月以内に贈ります",
+        EventType::Birth => "のしは紅白の蝶結びで表書きは「内祝」、名入れは赤ちゃんの名前。生後1か月頃までに贈ります",
+        EventType::Celebration => "のしは紅白の蝶結びで表書きは「内祝」。いただいてから1か月以内に贈ります",
+        EventType::Other => "表書きは「御礼」。いただいてから2週間〜1か月以内を目安に贈ります",
+    }
+}
+
+/// 予算とタブーのルールに合う品物を、価格帯の中央に近い順に最大 `limit` 件返す
+pub fn suggest(
+    event_type: &EventType,
+    min: u32,
+    max: u32,
+    rules: &GiftRules,
+    limit: usize,
+) -> Vec<&'static CatalogItem> {
+    let middle = (u64::from(min) + u64::from(max)) / 2;
+    let mut items: Vec<&'static CatalogItem> = CATALOG
+        .iter()
+        .filter(|item| item.events.is_empty() || item.events.contains(event_type))
+        .filter(|item| rules.within_budget(item.price, min, max))
+        .filter(|item| rules.taboo_reason(item.name, event_type).is_none())
+        .collect();
+    items.sort_by_key(|item| u64::from(item.price).abs_diff(middle));
+    items.truncate(limit);
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suggest_within_budget() {
+        let rules = GiftRules::default();
+
+        let items = suggest(&EventType::Birth, 3000, 5000, &rules, 3);
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].name, "名入れバームクーヘン");
+        assert!(items.iter().all(|item| (2700..=5500).contains(&item.price)));
+
+        // イベントに合わない品物は候補にしない
+        assert!(suggest(&EventType::Wedding, 3000, 5000, &rules, 10)
+            .iter()
+            .all(|item| item.name != "名入れバームクーヘン"));
+        assert!(suggest(&EventType::Other, 100, 500, &rules, 3).is_empty());
+    }
+}
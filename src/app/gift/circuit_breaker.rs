@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde::Serialize;
+
+// 連続失敗でサーキットを開く回数と、開いたままにする時間のデフォルト値
+pub const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+pub const DEFAULT_OPEN_SECONDS: u64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// 通常どおり呼び出す
+    Closed,
+    /// 呼び出さずに失敗させる
+    Open,
+    /// 試しに1件だけ呼び出し、結果で閉じるか開き直すかを決める
+    HalfOpen,
+}
+
+impl CircuitState {
+    /// メトリクス用の数値表現
+    pub fn as_gauge(self) -> i64 {
+        match self {
+            Self::Closed => 0,
+            Self::HalfOpen => 1,
+            Self::Open => 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BreakerState {
+    state: CircuitState,
+    consecutive_failures: u32,
+    // 開いた時刻、または半開状態で試行を始めた時刻
+    changed_at: Instant,
+}
+
+/// 上流の連続した失敗を検知し、一定時間呼び出しを止めるサーキットブレーカー
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    state: Mutex<BreakerState>,
+    failure_threshold: u32,
+    open_duration: Duration,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new(DEFAULT_FAILURE_THRESHOLD, Duration::from_secs(DEFAULT_OPEN_SECONDS))
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, open_duration: Duration) -> Self {
+        Self {
+            state: Mutex::new(BreakerState {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                changed_at: Instant::now(),
+            }),
+            failure_threshold: failure_threshold.max(1),
+            open_duration,
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.lock().state
+    }
+
+    /// 呼び出してよいかを判定する
+    ///
+    /// 開いてから `open_duration` が経過していれば半開状態にして1件だけ通す。
+    /// 試行の結果が記録されないまま同じ時間が経った場合も、次の試行を通す。
+    pub fn try_acquire(&self) -> bool {
+        let mut breaker = self.lock();
+        match breaker.state {
+            CircuitState::Closed => true,
+            CircuitState::Open | CircuitState::HalfOpen => {
+                if breaker.changed_at.elapsed() < self.open_duration {
+                    return false;
+                }
+                breaker.state = CircuitState::HalfOpen;
+                breaker.changed_at = Instant::now();
+                true
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut breaker = self.lock();
+        breaker.state = CircuitState::Closed;
+        breaker.consecutive_failures = 0;
+    }
+
+    pub fn record_failure(&self) {
+        let mut breaker = self.lock();
+        breaker.consecutive_failures += 1;
+        let trip = breaker.state == CircuitState::HalfOpen
+            || breaker.consecutive_failures >= self.failure_threshold;
+        if trip && breaker.state != CircuitState::Open {
+            tracing::warn!(
+                "Circuit opened after {} consecutive failures",
+                breaker.consecutive_failures
+            );
+            breaker.state = CircuitState::Open;
+            breaker.changed_at = Instant::now();
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BreakerState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// 上流のプロバイダーごとのサーキットブレーカー。設定は共通で、初めて呼び出すプロバイダーの分は閉じた状態で作る
+///
+/// 1つの上流の障害で、切り替え先の別の上流まで止めないよう分けておく。
+#[derive(Debug)]
+pub struct ProviderCircuits {
+    failure_threshold: u32,
+    open_duration: Duration,
+    breakers: Mutex<HashMap<String, Arc<CircuitBreaker>>>,
+}
+
+impl Default for ProviderCircuits {
+    fn default() -> Self {
+        Self::new(DEFAULT_FAILURE_THRESHOLD, Duration::from_secs(DEFAULT_OPEN_SECONDS))
+    }
+}
+
+impl ProviderCircuits {
+    pub fn new(failure_threshold: u32, open_duration: Duration) -> Self {
+        Self {
+            failure_threshold,
+            open_duration,
+            breakers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, provider: &str) -> Arc<CircuitBreaker> {
+        let mut breakers = self.breakers.lock().unwrap_or_else(|e| e.into_inner());
+        breakers
+            .entry(provider.to_string())
+            .or_insert_with(|| Arc::new(CircuitBreaker::new(self.failure_threshold, self.open_duration)))
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_transitions() {
+        let breaker = CircuitBreaker::new(2, Duration::from_millis(50));
+
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.try_acquire());
+
+        // 待機後は1件だけ試行を通し、失敗すれば開き直す
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.try_acquire());
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(!breaker.try_acquire());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.try_acquire());
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.try_acquire());
+
+        // プロバイダーごとに別のサーキットになる
+        let circuits = ProviderCircuits::new(1, Duration::from_secs(60));
+        circuits.get("primary").record_failure();
+        assert_eq!(circuits.get("primary").state(), CircuitState::Open);
+        assert_eq!(circuits.get("backup").state(), CircuitState::Closed);
+    }
+}
@@ -0,0 +1,149 @@
+use reqwest::Url;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+// 同じページでも付いていると別のURLになる、広告・計測用のクエリ
+const TRACKING_PARAMS: &[&str] = &["gclid", "fbclid", "yclid", "msclkid", "_ga", "ref", "spm"];
+const TRACKING_PREFIXES: &[&str] = &["utm_", "mc_"];
+
+/// 推薦の根拠にした検索結果のページ
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct SourceLink {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+}
+
+/// プロバイダーが応答に付ける検索結果。`citations` と同じ順で、タイトルも含む
+#[derive(Debug, Clone, Deserialize)]
+pub struct SearchResult {
+    pub url: String,
+    #[serde(default)]
+    pub title: Option<String>,
+}
+
+/// 応答の `citations`（URLの配列）と `search_results` から、本文の `[1]` などが指すリンクの一覧を作る
+///
+/// 番号の対応を崩さないよう、正規化できないURLも順番を保ったまま `None` として残す。
+pub fn links(citations: &[String], search_results: &[SearchResult]) -> Vec<Option<SourceLink>> {
+    if !search_results.is_empty() {
+        return search_results
+            .iter()
+            .map(|result| {
+                normalize_url(&result.url).map(|url| SourceLink {
+                    url,
+                    title: result.title.as_deref().map(str::trim).filter(|title| !title.is_empty()).map(String::from),
+                })
+            })
+            .collect();
+    }
+    citations
+        .iter()
+        .map(|citation| normalize_url(citation).map(|url| SourceLink { url, title: None }))
+        .collect()
+}
+
+/// 表示・保存・重複の判定に使う形にURLを揃える
+///
+/// http(s) 以外のスキームは捨てる。フラグメントと計測用のクエリを除き、ホスト名は小文字にする。
+pub fn normalize_url(raw: &str) -> Option<String> {
+    let raw = raw.trim();
+    if raw.is_empty() {
+        return None;
+    }
+    // 「www.example.jp/item」のようにスキームを省略して返すことがある
+    let mut url = Url::parse(raw)
+        .or_else(|_| Url::parse(&format!("https://{}", raw)))
+        .ok()?;
+    if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+        return None;
+    }
+    url.set_fragment(None);
+    let kept: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(key, _)| !is_tracking_param(key))
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    if kept.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(kept);
+    }
+    Some(url.to_string())
+}
+
+fn is_tracking_param(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    TRACKING_PARAMS.contains(&key.as_str()) || TRACKING_PREFIXES.iter().any(|prefix| key.starts_with(prefix))
+}
+
+/// 本文から `[1]` や `[2][3]` の引用番号を取り除き、番号（1始まり）を返す
+pub fn strip_markers(text: &str) -> (String, Vec<usize>) {
+    let mut stripped = String::with_capacity(text.len());
+    let mut refs = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('[') {
+        stripped.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let number = after
+            .find(']')
+            .map(|end| &after[..end])
+            .filter(|inner| !inner.is_empty() && inner.chars().all(|c| c.is_ascii_digit()))
+            .and_then(|inner| inner.parse::<usize>().ok().map(|number| (number, inner.len())));
+        match number {
+            Some((number, len)) => {
+                if number > 0 && !refs.contains(&number) {
+                    refs.push(number);
+                }
+                rest = &after[len + 1..];
+            }
+            None => {
+                stripped.push('[');
+                rest = after;
+            }
+        }
+    }
+    stripped.push_str(rest);
+    (stripped.trim_end().to_string(), refs)
+}
+
+/// 引用番号が指すリンク。範囲外の番号と同じURLの重複は除く
+pub fn resolve(refs: &[usize], links: &[Option<SourceLink>]) -> Vec<SourceLink> {
+    let mut resolved: Vec<SourceLink> = Vec::new();
+    for link in refs.iter().filter_map(|number| links.get(number.checked_sub(1)?)?.as_ref()) {
+        if !resolved.iter().any(|existing| existing.url == link.url) {
+            resolved.push(link.clone());
+        }
+    }
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_urls_are_normalized_and_markers_resolved() {
+        assert_eq!(
+            normalize_url(" https://Shop.Example.JP/item/1?utm_source=x&color=red&gclid=y#reviews ").as_deref(),
+            Some("https://shop.example.jp/item/1?color=red")
+        );
+        assert_eq!(normalize_url("www.example.jp/towel").as_deref(), Some("https://www.example.jp/towel"));
+        assert_eq!(normalize_url("javascript:alert(1)"), None);
+        assert_eq!(normalize_url(""), None);
+
+        let (text, refs) = strip_markers("人気の定番です[2][1]。価格は[税込]表示[9]");
+        assert_eq!(text, "人気の定番です。価格は[税込]表示");
+        assert_eq!(refs, vec![2, 1, 9]);
+
+        let links = links(
+            &[],
+            &[
+                SearchResult { url: "https://a.example.jp/?utm_medium=x".to_string(), title: Some(" A ".to_string()) },
+                SearchResult { url: "ftp://b.example.jp".to_string(), title: None },
+            ],
+        );
+        let resolved = resolve(&[2, 1, 1, 9, 0], &links);
+        assert_eq!(resolved, vec![SourceLink { url: "https://a.example.jp/".to_string(), title: Some("A".to_string()) }]);
+    }
+}
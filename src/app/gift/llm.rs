@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use crate::app::usage::UsageFeature;
+use crate::config::config::{Config, FeatureModelConfig, DEFAULT_LLM_PROVIDER};
+
+/// Chat Completions API 互換の上流1つ
+#[derive(Debug, Clone)]
+pub struct LlmProvider {
+    pub name: String,
+    pub url: String,
+    pub api_key: String,
+    /// `search_domain_filter` などの検索の絞り込みを送る
+    pub supports_search: bool,
+    /// 応答を待つ上限。応答が届き始めた後は、次のデータが届くまでの間隔に使う
+    pub timeout: Duration,
+}
+
+impl LlmProvider {
+    /// `api` の設定のPerplexity
+    pub fn perplexity(base_url: &str, api_key: String, timeout: Duration) -> Self {
+        Self {
+            name: DEFAULT_LLM_PROVIDER.to_string(),
+            url: format!("{}/chat/completions", base_url.trim_end_matches('/')),
+            api_key,
+            supports_search: true,
+            timeout,
+        }
+    }
+}
+
+/// 機能ごとに、どのプロバイダーのどのモデルをどの順に試すか
+#[derive(Debug, Clone)]
+pub struct LlmRouting {
+    providers: Vec<LlmProvider>,
+    chat: FeatureModelConfig,
+    recommendation: FeatureModelConfig,
+}
+
+impl LlmRouting {
+    /// Perplexityの既定のモデルだけを使う
+    pub fn perplexity(api_key: String) -> Self {
+        Self {
+            providers: vec![LlmProvider::perplexity("https://api.perplexity.ai", api_key, Duration::from_secs(30))],
+            chat: FeatureModelConfig::default(),
+            recommendation: FeatureModelConfig::default(),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        let api = &config.api;
+        let timeout = Duration::from_secs(api.timeout_seconds);
+        let mut providers = vec![LlmProvider::perplexity(
+            &api.perplexity_api_url,
+            api.perplexity_api_key.expose().to_string(),
+            timeout,
+        )];
+        providers.extend(config.llm.providers.iter().map(|provider| LlmProvider {
+            name: provider.name.clone(),
+            url: provider.api_url.clone(),
+            api_key: provider.api_key.expose().to_string(),
+            supports_search: provider.supports_search,
+            timeout: provider.timeout_seconds.map(Duration::from_secs).unwrap_or(timeout),
+        }));
+        Self {
+            providers,
+            chat: config.llm.chat.clone(),
+            recommendation: config.llm.recommendation.clone(),
+        }
+    }
+
+    /// 追加のプロバイダーを登録する。同じ名前のものは置き換える
+    pub fn with_provider(mut self, provider: LlmProvider) -> Self {
+        self.providers.retain(|existing| existing.name != provider.name);
+        self.providers.push(provider);
+        self
+    }
+
+    pub fn with_feature(mut self, feature: UsageFeature, settings: FeatureModelConfig) -> Self {
+        match feature {
+            UsageFeature::Chat => self.chat = settings,
+            UsageFeature::Recommendation => self.recommendation = settings,
+        }
+        self
+    }
+
+    pub fn settings(&self, feature: UsageFeature) -> &FeatureModelConfig {
+        match feature {
+            UsageFeature::Chat => &self.chat,
+            UsageFeature::Recommendation => &self.recommendation,
+        }
+    }
+
+    /// 試す順のプロバイダーとモデル。登録されていないプロバイダーは飛ばす
+    pub fn chain(&self, feature: UsageFeature) -> Vec<(&LlmProvider, &str)> {
+        self.settings(feature)
+            .models
+            .iter()
+            .filter_map(|target| {
+                let provider = self.providers.iter().find(|provider| provider.name == target.provider);
+                if provider.is_none() {
+                    tracing::warn!("Skipped model {}: provider {} is not configured", target.model, target.provider);
+                }
+                provider.map(|provider| (provider, target.model.as_str()))
+            })
+            .collect()
+    }
+}
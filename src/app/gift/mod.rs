@@ -1,3 +1,6 @@
 pub mod recommendation;
+pub mod rules;
+pub mod catalog;
+pub mod circuit_breaker;
 
 pub use recommendation::{GiftRecommender, GiftRequest, GiftRecommendation}; 
\ No newline at end of file
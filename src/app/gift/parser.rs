@@ -0,0 +1,221 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::citation;
+use super::recommendation::GiftRecommendation;
+
+/// LLMに求める出力形式。1行に1件のJSONにすることで、生成途中でも1件ずつ取り出せる
+pub const RESPONSE_FORMAT_INSTRUCTION: &str = "各提案は1行に1件のJSONオブジェクトで出力してください。\
+キーは name（商品名）, price（税込の円、数値）, store（購入店舗）, reason（選定理由）, manner_advice（マナーアドバイス）です。\
+分かる場合は url（商品ページのURL）, store_url（購入店舗のURL）, sources（根拠にした検索結果の番号の配列）も含めてください。\
+JSON以外の文章やコードブロックは出力しないでください。";
+const RESPONSE_FORMAT_INSTRUCTION_EN: &str = "Output each suggestion as one JSON object per line. \
+Use the keys name (product name), price (yen including tax, as a number), store (where to buy), reason (why it fits) and manner_advice (etiquette advice). \
+When known, also include url (product page URL), store_url (store URL) and sources (an array of the search result numbers you relied on). \
+Do not output any text or code blocks other than the JSON lines.";
+
+/// プロンプトの言語に合わせた出力形式の指示。キーはどの言語でも同じ
+pub fn response_format_instruction(language: &str) -> &'static str {
+    match language {
+        "en" => RESPONSE_FORMAT_INSTRUCTION_EN,
+        _ => RESPONSE_FORMAT_INSTRUCTION,
+    }
+}
+
+// 価格が「5,000円」のような文字列で返ることがあるため、緩く受け取る
+#[derive(Debug, Deserialize)]
+struct RawRecommendation {
+    name: String,
+    price: Value,
+    #[serde(default)]
+    store: String,
+    #[serde(default)]
+    reason: String,
+    #[serde(default)]
+    manner_advice: String,
+    #[serde(default)]
+    url: Option<String>,
+    #[serde(default)]
+    store_url: Option<String>,
+    // 検索結果の番号（1始まり）。`[1]` のような文字列で返ることもある
+    #[serde(default)]
+    sources: Vec<Value>,
+}
+
+/// 生成途中のテキストを受け取り、1件分が揃うたびに推薦を取り出す
+///
+/// 推薦のJSONを除いた文章は [`RecommendationParser::take_text`] で生成途中の表示に使える。
+#[derive(Debug, Default)]
+pub struct RecommendationParser {
+    buffer: String,
+    // 推薦のJSON以外の、まだ取り出していない文章
+    text: String,
+    // 表示するか決まっていない行頭の箇条書きの記号など
+    held: String,
+    // JSONの中にいる場合の括弧の深さ。文字列中の括弧は数えない
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    // 現在の行に推薦のJSONが始まった
+    line_has_object: bool,
+}
+
+impl RecommendationParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 追加されたテキストを取り込み、行が完成した分の推薦を返す
+    pub fn push(&mut self, delta: &str) -> Vec<GiftRecommendation> {
+        for c in delta.chars() {
+            self.strip(c);
+        }
+        self.buffer.push_str(delta);
+        let Some(end) = self.buffer.rfind('\n') else {
+            return Vec::new();
+        };
+        let complete: String = self.buffer.drain(..=end).collect();
+        complete.lines().filter_map(parse_line).collect()
+    }
+
+    /// これまでに届いた文章のうち、推薦のJSONを除いて表示できる部分を取り出す
+    pub fn take_text(&mut self) -> String {
+        std::mem::take(&mut self.text)
+    }
+
+    // 1文字ずつ、推薦のJSONの中か外かを追う。JSONのある行では、その前後の記号なども表示しない
+    fn strip(&mut self, c: char) {
+        if self.depth > 0 {
+            match c {
+                _ if self.escaped => self.escaped = false,
+                '\\' if self.in_string => self.escaped = true,
+                '"' => self.in_string = !self.in_string,
+                '{' if !self.in_string => self.depth += 1,
+                '}' if !self.in_string => self.depth -= 1,
+                _ => {}
+            }
+            return;
+        }
+        match c {
+            '\n' => {
+                if !self.line_has_object {
+                    self.text.push_str(&self.held);
+                    self.text.push('\n');
+                }
+                self.held.clear();
+                self.line_has_object = false;
+            }
+            '{' => {
+                self.depth = 1;
+                self.line_has_object = true;
+                self.held.clear();
+            }
+            _ if self.line_has_object => {}
+            _ if self.held.chars().all(is_list_marker) && is_list_marker(c) => self.held.push(c),
+            _ => {
+                self.text.push_str(&self.held);
+                self.held.clear();
+                self.text.push(c);
+            }
+        }
+    }
+
+    /// 最後の改行のない行を含めて残りを解析する
+    pub fn finish(mut self) -> Vec<GiftRecommendation> {
+        let rest = std::mem::take(&mut self.buffer);
+        rest.lines().filter_map(parse_line).collect()
+    }
+}
+
+// 行頭の空白や箇条書きの記号。JSONの前に付くことがあるため、文章が続くまで表示を保留する
+fn is_list_marker(c: char) -> bool {
+    c.is_whitespace() || c.is_ascii_digit() || matches!(c, '-' | '*' | '・' | '•' | '.' | ')')
+}
+
+/// 応答全体から推薦を取り出す
+pub fn parse_all(text: &str) -> Vec<GiftRecommendation> {
+    let mut parser = RecommendationParser::new();
+    let mut recommendations = parser.push(text);
+    recommendations.extend(parser.finish());
+    recommendations
+}
+
+// 箇条書きの記号や前後の文章が付いていても、行内の `{...}` を解析する
+fn parse_line(line: &str) -> Option<GiftRecommendation> {
+    let start = line.find('{')?;
+    let end = line.rfind('}')?;
+    if end <= start {
+        return None;
+    }
+    let raw: RawRecommendation = match serde_json::from_str(&line[start..=end]) {
+        Ok(raw) => raw,
+        Err(e) => {
+            tracing::debug!("Skipped unparsable recommendation line: {}", e);
+            return None;
+        }
+    };
+    let price = match &raw.price {
+        Value::Number(number) => number.as_f64().map(|price| price.round() as u32),
+        Value::String(text) => {
+            let digits: String = text.chars().filter(char::is_ascii_digit).collect();
+            digits.parse().ok()
+        }
+        _ => None,
+    }?;
+    let name = raw.name.trim();
+    if name.is_empty() || price == 0 {
+        return None;
+    }
+    // 本文中の `[1]` も引用として扱い、表示用の文章からは取り除く
+    let (reason, mut refs) = citation::strip_markers(raw.reason.trim());
+    let (manner_advice, manner_refs) = citation::strip_markers(raw.manner_advice.trim());
+    let listed = raw.sources.iter().filter_map(|source| match source {
+        Value::Number(number) => number.as_u64().and_then(|number| usize::try_from(number).ok()),
+        Value::String(text) => text.trim_matches(|c: char| !c.is_ascii_digit()).parse().ok(),
+        _ => None,
+    });
+    for number in listed.chain(manner_refs) {
+        if !refs.contains(&number) {
+            refs.push(number);
+        }
+    }
+    Some(
+        GiftRecommendation::new(name, price, raw.store.trim(), reason, manner_advice)
+            .with_urls(raw.url.as_deref(), raw.store_url.as_deref())
+            .with_citation_refs(refs),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recommendations_are_parsed_as_lines_complete() {
+        let mut parser = RecommendationParser::new();
+        assert!(parser.push(r#"{"name":"今治タオル","price":50"#).is_empty());
+        let parsed = parser.push("00,\"store\":\"本店\"}\n- {\"name\":\"カタログ\",");
+        assert_eq!(parsed.len(), 1);
+        assert_eq!((parsed[0].name(), parsed[0].price()), ("今治タオル", 5000));
+
+        assert!(parser.push(r#""price":"10,000円"}"#).is_empty());
+        let rest = parser.finish();
+        assert_eq!((rest[0].name(), rest[0].price()), ("カタログ", 10000));
+
+        assert_eq!(parse_all("おすすめです\n{\"name\":\"\",\"price\":1}\n{broken}").len(), 0);
+    }
+
+    #[test]
+    fn test_text_outside_recommendations_is_streamed() {
+        let mut parser = RecommendationParser::new();
+        parser.push("ご予算に合わせて");
+        assert_eq!(parser.take_text(), "ご予算に合わせて");
+        parser.push("選びました。\n1. {\"name\":\"タオル {特選}\",");
+        assert_eq!(parser.take_text(), "選びました。\n");
+        parser.push("\"reason\":\"\\\"}\\\"\",\"price\":3000}\n");
+        parser.push("- ");
+        assert_eq!(parser.take_text(), "");
+        assert_eq!(parser.push("{\"name\":\"箸\",\"price\":2000}\n以上です").len(), 1);
+        assert_eq!(parser.take_text(), "以上です");
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use thiserror::Error;
+
+// リポジトリに含まれるテンプレート。ファイルを指定しない場合（テストなど）に使う
+const BUILTIN_TEMPLATE: &str = include_str!("../../../config/prompts/gift_recommendation.toml");
+const DEFAULT_LANGUAGE: &str = "ja";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PromptFile {
+    name: String,
+    version: u32,
+    /// テンプレートで使える変数。呼び出し側はすべてを渡す
+    variables: Vec<String>,
+    locales: BTreeMap<String, LocalePrompt>,
+}
+
+/// 1つの言語のシステムメッセージとユーザーメッセージ
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LocalePrompt {
+    pub system: String,
+    pub user: String,
+}
+
+/// 変数を埋め込んだプロンプト
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenderedPrompt {
+    pub system: String,
+    pub user: String,
+    /// 使ったテンプレートの版（`name@version`）
+    pub version: String,
+    pub language: String,
+}
+
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+pub enum PromptError {
+    #[error("Prompt template {template} has no {language:?} locale")]
+    UnknownLanguage { template: String, language: String },
+    #[error("Prompt template {template} is missing variables: {}", names.join(", "))]
+    MissingVariables { template: String, names: Vec<String> },
+}
+
+/// 言語ごとの文面を持つ、版付きのプロンプトのテンプレート
+///
+/// 読み込み時に、文面中の `{name}` がすべて `variables` で宣言されているかを確認する。
+/// 描画時には、宣言された変数がすべて渡されているかを確認する。
+#[derive(Debug, Clone)]
+pub struct PromptTemplate {
+    name: String,
+    version: u32,
+    variables: BTreeSet<String>,
+    locales: BTreeMap<String, LocalePrompt>,
+    fallback_language: String,
+}
+
+impl PromptTemplate {
+    /// リポジトリの `config/prompts/gift_recommendation.toml`
+    pub fn builtin() -> Self {
+        Self::parse(BUILTIN_TEMPLATE).expect("built-in prompt template is valid")
+    }
+
+    /// ファイルを読み込む。ルールファイルと同じく、ファイルがなければ組み込みのテンプレートを使う
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.is_file() {
+            tracing::info!("Prompt template {} not found; using the built-in template", path.display());
+            return Ok(Self::builtin());
+        }
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read prompt template {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid prompt template {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let file: PromptFile = toml::from_str(text)?;
+        if file.name.trim().is_empty() {
+            return Err(anyhow!("name must not be empty"));
+        }
+        if file.locales.is_empty() {
+            return Err(anyhow!("at least one locale is required"));
+        }
+        let variables: BTreeSet<String> = file.variables.into_iter().collect();
+        for (language, prompt) in &file.locales {
+            for (part, text) in [("system", &prompt.system), ("user", &prompt.user)] {
+                if text.trim().is_empty() {
+                    return Err(anyhow!("locales.{}.{} must not be empty", language, part));
+                }
+                let names = placeholders(text).map_err(|e| anyhow!("locales.{}.{}: {}", language, part, e))?;
+                if let Some(unknown) = names.iter().find(|name| !variables.contains(**name)) {
+                    return Err(anyhow!("locales.{}.{} uses undeclared variable {{{}}}", language, part, unknown));
+                }
+            }
+        }
+        let fallback_language = if file.locales.contains_key(DEFAULT_LANGUAGE) {
+            DEFAULT_LANGUAGE.to_string()
+        } else {
+            file.locales.keys().next().cloned().unwrap_or_default()
+        };
+        Ok(Self {
+            name: file.name,
+            version: file.version,
+            variables,
+            locales: file.locales,
+            fallback_language,
+        })
+    }
+
+    /// 要求された言語の文面がない場合に使う言語。テンプレートにない言語は無視する
+    pub fn with_fallback_language(mut self, language: &str) -> Self {
+        if self.locales.contains_key(language) {
+            self.fallback_language = language.to_string();
+        } else {
+            tracing::warn!("Prompt template {} has no {:?} locale; keeping {:?}", self.name, language, self.fallback_language);
+        }
+        self
+    }
+
+    /// 推薦に記録する版（`name@version`）
+    pub fn version(&self) -> String {
+        format!("{}@{}", self.name, self.version)
+    }
+
+    pub fn variables(&self) -> impl Iterator<Item = &str> {
+        self.variables.iter().map(String::as_str)
+    }
+
+    /// 要求された言語（`en-US` などを含む）に対して使う言語。なければフォールバックの言語
+    pub fn resolve_language(&self, requested: Option<&str>) -> &str {
+        let requested = requested.map(|tag| tag.trim().to_ascii_lowercase()).unwrap_or_default();
+        let primary = requested.split(['-', '_']).next().unwrap_or_default();
+        let language = [requested.as_str(), primary]
+            .into_iter()
+            .find_map(|candidate| self.locales.get_key_value(candidate).map(|(language, _)| language.as_str()));
+        language.unwrap_or(&self.fallback_language)
+    }
+
+    /// 変数を埋め込む。宣言された変数が1つでも渡されていなければエラーにする
+    pub fn render(&self, language: &str, values: &BTreeMap<&str, String>) -> Result<RenderedPrompt, PromptError> {
+        let prompt = self.locales.get(language).ok_or_else(|| PromptError::UnknownLanguage {
+            template: self.version(),
+            language: language.to_string(),
+        })?;
+        let missing: Vec<String> = self
+            .variables
+            .iter()
+            .filter(|name| !values.contains_key(name.as_str()))
+            .cloned()
+            .collect();
+        if !missing.is_empty() {
+            return Err(PromptError::MissingVariables {
+                template: self.version(),
+                names: missing,
+            });
+        }
+        Ok(RenderedPrompt {
+            system: substitute(&prompt.system, values),
+            user: substitute(&prompt.user, values),
+            version: self.version(),
+            language: language.to_string(),
+        })
+    }
+}
+
+// 文面中の `{name}` の一覧。`{{` と `}}` は波かっこそのものとして扱う
+fn placeholders(text: &str) -> Result<Vec<&str>, String> {
+    let mut names = Vec::new();
+    let mut rest = text;
+    while let Some(index) = rest.find(['{', '}']) {
+        let after = &rest[index + 1..];
+        if rest[index..].starts_with("{{") || rest[index..].starts_with("}}") {
+            rest = &after[1..];
+            continue;
+        }
+        if rest[index..].starts_with('}') {
+            return Err("unmatched '}' (write '}}' for a literal brace)".to_string());
+        }
+        let end = after.find('}').ok_or("unclosed '{' (write '{{' for a literal brace)")?;
+        let name = &after[..end];
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("invalid variable name {{{}}}", name));
+        }
+        names.push(name);
+        rest = &after[end + 1..];
+    }
+    Ok(names)
+}
+
+// 読み込み時に構文を確認済みの文面に値を埋め込む。値の中の波かっこはそのまま残す
+fn substitute(text: &str, values: &BTreeMap<&str, String>) -> String {
+    let mut rendered = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(index) = rest.find(['{', '}']) {
+        rendered.push_str(&rest[..index]);
+        let after = &rest[index + 1..];
+        if rest[index..].starts_with("{{") || rest[index..].starts_with("}}") {
+            rendered.push_str(&rest[index..=index]);
+            rest = &after[1..];
+            continue;
+        }
+        let end = after.find('}').unwrap_or(after.len());
+        if let Some(value) = values.get(&after[..end]) {
+            rendered.push_str(value);
+        }
+        rest = after.get(end + 1..).unwrap_or_default();
+    }
+    rendered.push_str(rest);
+    rendered.trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_templates_are_validated_and_rendered_per_language() {
+        let template = PromptTemplate::builtin();
+        assert_eq!(template.version(), "gift_recommendation@2");
+        assert_eq!(template.resolve_language(Some("en-US")), "en");
+        assert_eq!(template.resolve_language(Some("fr")), "ja");
+        assert_eq!(template.with_fallback_language("en").resolve_language(None), "en");
+
+        let template = PromptTemplate::parse(
+            r#"
+            name = "t"
+            version = 2
+            variables = ["gift"]
+            [locales.ja]
+            system = "{{固定}}"
+            user = "お返し: {gift}"
+            "#,
+        )
+        .unwrap();
+        let mut values = BTreeMap::new();
+        assert_eq!(
+            template.render("ja", &values),
+            Err(PromptError::MissingVariables { template: "t@2".to_string(), names: vec!["gift".to_string()] })
+        );
+        values.insert("gift", "{タオル}".to_string());
+        let rendered = template.render("ja", &values).unwrap();
+        assert_eq!((rendered.system.as_str(), rendered.user.as_str()), ("{固定}", "お返し: {タオル}"));
+        assert_eq!(rendered.version, "t@2");
+
+        let undeclared = "name = \"t\"\nversion = 1\nvariables = []\n[locales.ja]\nsystem = \"s\"\nuser = \"{gift}\"";
+        assert!(PromptTemplate::parse(undeclared).is_err());
+        let unclosed = "name = \"t\"\nversion = 1\nvariables = [\"gift\"]\n[locales.ja]\nsystem = \"s\"\nuser = \"{gift\"";
+        assert!(PromptTemplate::parse(unclosed).is_err());
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::config::config::RateLimitConfig;
+use crate::error::AppError;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// プロバイダーが応答の `usage` で返すトークン数
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LlmUsage {
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+    #[serde(default)]
+    pub total_tokens: u64,
+}
+
+impl LlmUsage {
+    /// `total_tokens` を返さないプロバイダーもあるため、その場合は内訳から求める
+    pub fn total(&self) -> u64 {
+        self.total_tokens.max(self.prompt_tokens + self.completion_tokens)
+    }
+}
+
+/// ユーザーの1日あたりのLLMの利用上限に達した
+#[derive(Debug, Clone, Error)]
+#[error("Daily LLM quota exceeded; resets in {}s", retry_after.as_secs())]
+pub struct QuotaExceeded {
+    /// 上限がリセットされる（UTCの次の日になる）までの時間
+    pub retry_after: Duration,
+}
+
+impl From<QuotaExceeded> for AppError {
+    fn from(exceeded: QuotaExceeded) -> Self {
+        AppError::RateLimited {
+            message: "本日の利用上限に達しました。明日以降に再度お試しください".to_string(),
+            retry_after: exceeded.retry_after,
+        }
+    }
+}
+
+/// ユーザーごとの1日（UTC）のLLMの呼び出し回数とトークン数の上限
+///
+/// 数はインスタンスごとに持つ。複数のインスタンスでは、ユーザーが接続したインスタンスごとに数える。
+#[derive(Debug)]
+pub struct LlmQuota {
+    max_calls: u32,
+    max_tokens: u64,
+    usage: Mutex<HashMap<String, DailyUsage>>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct DailyUsage {
+    day: u64,
+    calls: u32,
+    tokens: u64,
+}
+
+impl LlmQuota {
+    /// 上限が0の項目は制限しない
+    pub fn new(max_calls: u32, max_tokens: u64) -> Self {
+        Self {
+            max_calls,
+            max_tokens,
+            usage: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_config(config: &RateLimitConfig) -> Self {
+        Self::new(config.daily_llm_calls_per_user, config.daily_llm_tokens_per_user)
+    }
+
+    /// 上流を呼び出せるかを確認し、呼び出し1回分を予約する
+    ///
+    /// 同時に届いたリクエストが揃って上限を超えないよう、確認と予約は同じロックの中で行う。
+    /// 予約は [`QuotaReservation::commit`] しないまま破棄すると取り消される。
+    pub fn check(self: &Arc<Self>, user_id: &str) -> Result<QuotaReservation, QuotaExceeded> {
+        let retry_after = today().1;
+        self.update(user_id, |usage| {
+            let calls_exceeded = self.max_calls > 0 && usage.calls >= self.max_calls;
+            let tokens_exceeded = self.max_tokens > 0 && usage.tokens >= self.max_tokens;
+            if calls_exceeded || tokens_exceeded {
+                return Err(QuotaExceeded { retry_after });
+            }
+            usage.calls += 1;
+            Ok(())
+        })?;
+        Ok(QuotaReservation {
+            quota: Arc::clone(self),
+            user_id: user_id.to_string(),
+            committed: false,
+        })
+    }
+
+    /// プロバイダーが返したトークン数を加算する
+    pub fn record_usage(&self, user_id: &str, usage: &LlmUsage) {
+        let tokens = usage.total();
+        self.update(user_id, |daily| daily.tokens += tokens);
+    }
+
+    /// 今日の呼び出し回数とトークン数
+    pub fn usage(&self, user_id: &str) -> (u32, u64) {
+        let usage = self.today(user_id, today().0);
+        (usage.calls, usage.tokens)
+    }
+
+    fn today(&self, user_id: &str, day: u64) -> DailyUsage {
+        self.usage
+            .lock()
+            .unwrap()
+            .get(user_id)
+            .filter(|usage| usage.day == day)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    // 予約した呼び出しを取り消す
+    fn refund(&self, user_id: &str) {
+        self.update(user_id, |usage| usage.calls = usage.calls.saturating_sub(1));
+    }
+
+    fn update<T>(&self, user_id: &str, apply: impl FnOnce(&mut DailyUsage) -> T) -> T {
+        let day = today().0;
+        let mut usage = self.usage.lock().unwrap();
+        // 日付が変わったら前日までの分は捨てる
+        if usage.values().any(|daily| daily.day != day) {
+            usage.retain(|_, daily| daily.day == day);
+        }
+        let daily = usage.entry(user_id.to_string()).or_insert(DailyUsage { day, ..DailyUsage::default() });
+        apply(daily)
+    }
+}
+
+/// [`LlmQuota::check`] で予約した呼び出し1回分
+///
+/// 上流を呼び出さなかった場合（キャッシュにあった、同じ依頼の取得に相乗りした）や、呼び出しが失敗した場合は
+/// `commit` せずに破棄し、呼び出し回数から取り消す。
+#[derive(Debug)]
+pub struct QuotaReservation {
+    quota: Arc<LlmQuota>,
+    user_id: String,
+    committed: bool,
+}
+
+impl QuotaReservation {
+    /// 上流の呼び出しが推薦を返したので、予約した回数を確定する
+    pub fn commit(mut self) {
+        self.committed = true;
+    }
+}
+
+impl Drop for QuotaReservation {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.quota.refund(&self.user_id);
+        }
+    }
+}
+
+// 今日の番号（UNIX時間の日数）と、次の日になるまでの時間
+fn today() -> (u64, Duration) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let day = now / SECONDS_PER_DAY;
+    (day, Duration::from_secs((day + 1) * SECONDS_PER_DAY - now))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calls_and_tokens_are_limited_per_user() {
+        let quota = Arc::new(LlmQuota::new(2, 1000));
+        quota.check("u1").unwrap().commit();
+        quota.record_usage("u1", &LlmUsage { prompt_tokens: 300, completion_tokens: 200, total_tokens: 0 });
+        assert_eq!(quota.usage("u1"), (1, 500));
+
+        quota.check("u1").unwrap().commit();
+        let exceeded = quota.check("u1").unwrap_err();
+        assert!(exceeded.retry_after <= Duration::from_secs(SECONDS_PER_DAY));
+        assert_eq!(quota.usage("u1"), (2, 500));
+        assert!(quota.check("u2").is_ok());
+
+        // トークン数の上限だけに達した
+        quota.record_usage("u2", &LlmUsage { total_tokens: 1000, ..LlmUsage::default() });
+        assert!(quota.check("u2").is_err());
+        assert!(Arc::new(LlmQuota::new(0, 0)).check("u2").is_ok());
+    }
+
+    #[test]
+    fn test_reservations_are_counted_until_refunded() {
+        let quota = Arc::new(LlmQuota::new(2, 0));
+        // 同時に届いた依頼は、予約した分だけで上限に達する
+        let first = quota.check("u1").unwrap();
+        let second = quota.check("u1").unwrap();
+        assert!(quota.check("u1").is_err());
+        assert_eq!(quota.usage("u1"), (2, 0));
+
+        // 確定しなかった予約は取り消される
+        drop(first);
+        assert_eq!(quota.usage("u1"), (1, 0));
+        second.commit();
+        assert_eq!(quota.usage("u1"), (1, 0));
+        assert!(quota.check("u1").is_ok());
+        assert_eq!(quota.usage("u1"), (1, 0));
+    }
+}
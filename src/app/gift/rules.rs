@@ -0,0 +1,170 @@
+use serde::{Deserialize, Serialize};
+
+use super::recommendation::EventType;
+
+// 予算から外れた推薦を許容する幅のデフォルト値（％）
+pub const DEFAULT_BUDGET_TOLERANCE_PERCENT: u32 = 10;
+
+/// 贈り物として避けるべき品物
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TabooRule {
+    /// 商品名に含まれていれば該当とみなすキーワード
+    ///
+    /// 漢字だけのキーワードは、前後に漢字が続く場合（別の語の一部）は該当とみなさない。
+    pub keywords: Vec<String>,
+    /// 対象のイベント。空の場合はすべてのイベントに適用する
+    #[serde(default)]
+    pub events: Vec<EventType>,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BudgetRules {
+    /// 指定された価格帯の上下にどこまでのはみ出しを許すか（％）
+    #[serde(default = "default_tolerance_percent")]
+    pub tolerance_percent: u32,
+}
+
+fn default_tolerance_percent() -> u32 {
+    DEFAULT_BUDGET_TOLERANCE_PERCENT
+}
+
+impl Default for BudgetRules {
+    fn default() -> Self {
+        Self {
+            tolerance_percent: default_tolerance_percent(),
+        }
+    }
+}
+
+/// 推薦結果に適用するタブーと予算のルール
+///
+/// ルールファイル（`rules.gift_rules_path`）から読み込み、再起動せずに差し替えられる。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GiftRules {
+    pub taboo: Vec<TabooRule>,
+    pub budget: BudgetRules,
+}
+
+impl Default for GiftRules {
+    fn default() -> Self {
+        let rule = |keywords: &[&str], events: Vec<EventType>, reason: &str| TabooRule {
+            keywords: keywords.iter().map(|keyword| keyword.to_string()).collect(),
+            events,
+            reason: reason.to_string(),
+        };
+
+        Self {
+            taboo: vec![
+                rule(
+                    &["刃物", "包丁", "ナイフ", "ハサミ"],
+                    vec![EventType::Wedding],
+                    "「縁を切る」を連想させるため、結婚祝いのお返しには避けます",
+                ),
+                rule(
+                    &["ハンカチ"],
+                    vec![EventType::Wedding],
+                    "「手巾（てぎれ）」が別れを連想させるため、結婚祝いのお返しには避けます",
+                ),
+                rule(
+                    &["日本茶", "緑茶"],
+                    vec![EventType::Wedding, EventType::Birth],
+                    "弔事の返礼品を連想させるため、慶事のお返しには避けます",
+                ),
+                rule(&["櫛"], vec![], "「苦」「死」を連想させるため避けます"),
+            ],
+            budget: BudgetRules::default(),
+        }
+    }
+}
+
+impl GiftRules {
+    /// 商品名が該当するタブーがあればその理由を返す
+    pub fn taboo_reason(&self, name: &str, event_type: &EventType) -> Option<&str> {
+        let name = name.to_lowercase();
+        self.taboo
+            .iter()
+            .filter(|rule| rule.events.is_empty() || rule.events.contains(event_type))
+            .find(|rule| {
+                rule.keywords
+                    .iter()
+                    .any(|keyword| contains_keyword(&name, &keyword.to_lowercase()))
+            })
+            .map(|rule| rule.reason.as_str())
+    }
+
+    /// 価格が許容幅を含めた価格帯に収まっているか
+    pub fn within_budget(&self, price: u32, min: u32, max: u32) -> bool {
+        let tolerance = u64::from(self.budget.tolerance_percent.min(100));
+        let lower = u64::from(min) * (100 - tolerance) / 100;
+        let upper = u64::from(max) * (100 + tolerance) / 100;
+        (lower..=upper).contains(&u64::from(price))
+    }
+
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        for (index, rule) in self.taboo.iter().enumerate() {
+            if rule.keywords.is_empty() || rule.keywords.iter().any(|k| k.trim().is_empty()) {
+                errors.push(format!("taboo[{}]: keywords must be non-empty strings", index));
+            }
+            if rule.reason.trim().is_empty() {
+                errors.push(format!("taboo[{}]: reason must not be empty", index));
+            }
+        }
+        if self.budget.tolerance_percent > 100 {
+            errors.push("budget.tolerance_percent must be between 0 and 100".to_string());
+        }
+        errors
+    }
+}
+
+// 漢字だけのキーワードは、前後が漢字でない位置に現れた場合だけ該当とする（「茶碗」の「茶」などを除く）
+fn contains_keyword(text: &str, keyword: &str) -> bool {
+    if !keyword.chars().all(is_kanji) {
+        return text.contains(keyword);
+    }
+    text.match_indices(keyword).any(|(start, matched)| {
+        let before = text[..start].chars().next_back();
+        let after = text[start + matched.len()..].chars().next();
+        !before.is_some_and(is_kanji) && !after.is_some_and(is_kanji)
+    })
+}
+
+fn is_kanji(c: char) -> bool {
+    matches!(c, '\u{3400}'..='\u{4DBF}' | '\u{4E00}'..='\u{9FFF}' | '\u{F900}'..='\u{FAFF}' | '々')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_taboo_and_budget_rules() {
+        let rules = GiftRules::default();
+
+        assert!(rules.taboo_reason("有名ブランドの包丁セット", &EventType::Wedding).is_some());
+        assert!(rules.taboo_reason("有名ブランドの包丁セット", &EventType::Birth).is_none());
+        assert!(rules.taboo_reason("つげの櫛", &EventType::Other).is_some());
+        assert!(rules.taboo_reason("高級タオルセット", &EventType::Wedding).is_none());
+        // ひらがなの「くし」は他の語の一部になりやすいため、キーワードにしない
+        assert!(rules.taboo_reason("肌にやさしくしっかりしたタオル", &EventType::Other).is_none());
+        // 漢字のキーワードは別の熟語の一部なら該当しない
+        let tea = GiftRules {
+            taboo: vec![TabooRule { keywords: vec!["茶".to_string()], events: vec![], reason: "茶".to_string() }],
+            ..GiftRules::default()
+        };
+        assert!(tea.taboo_reason("有田焼の夫婦茶碗", &EventType::Wedding).is_none());
+        assert!(tea.taboo_reason("静岡の茶・ほうじ茶セット", &EventType::Wedding).is_some());
+
+        assert!(rules.within_budget(5400, 3000, 5000));
+        assert!(!rules.within_budget(5600, 3000, 5000));
+        assert!(!rules.within_budget(2600, 3000, 5000));
+
+        let invalid = GiftRules {
+            taboo: vec![TabooRule { keywords: vec![" ".to_string()], events: vec![], reason: String::new() }],
+            budget: BudgetRules { tolerance_percent: 150 },
+        };
+        assert_eq!(invalid.validate().len(), 3);
+    }
+}
@@ -1,6 +1,7 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum Intent {
     Greeting,
     AskRelationship,
@@ -83,6 +84,35 @@ impl IntentClassifier {
         
         Self { patterns }
     }
+
+    /// 意図ごとのキーワードを指定して作成する（ルールファイルからの読み込み用）
+    pub fn from_patterns(patterns: impl IntoIterator<Item = (Intent, Vec<String>)>) -> Self {
+        Self {
+            patterns: patterns.into_iter().collect(),
+        }
+    }
+
+    /// 意図ごとのキーワードを意図の順に並べて返す
+    pub fn patterns(&self) -> BTreeMap<Intent, Vec<String>> {
+        self.patterns
+            .iter()
+            .map(|(intent, patterns)| (intent.clone(), patterns.clone()))
+            .collect()
+    }
+
+    /// 空のキーワードなど、分類に使えないパターンを検出する
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        for (intent, patterns) in self.patterns() {
+            if intent == Intent::Unknown {
+                errors.push("Unknown cannot have patterns".to_string());
+            }
+            if patterns.iter().any(|pattern| pattern.trim().is_empty()) {
+                errors.push(format!("{:?}: patterns must not be empty strings", intent));
+            }
+        }
+        errors
+    }
     
     pub fn classify(&self, text: &str) -> Intent {
         let text = text.to_lowercase();
@@ -0,0 +1,189 @@
+use std::sync::Arc;
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+
+use crate::app::chat::session::{SessionEvent, SessionRegistry};
+
+// 受信側の処理が遅れた場合に保持しておくイベントの数
+const BUS_CAPACITY: usize = 1024;
+
+/// インスタンス間で配るイベント
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum BusEvent {
+    /// ユーザーのセッションに送られたフレーム
+    Session(Box<SessionEvent>),
+}
+
+/// 送信元のインスタンスを付けたイベント。自身が送ったイベントを受け取った場合の判別に使う
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BusMessage {
+    pub origin: String,
+    #[serde(flatten)]
+    pub event: BusEvent,
+}
+
+/// [`EventBridge`] がイベントを配る経路
+///
+/// 送ったイベントは、送信元を含むすべての購読者に届く。
+/// 購読していなかった間や再接続中に送られたイベントは届かないため、取りこぼしてよい通知だけを流す。
+#[async_trait]
+pub trait EventBus: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn publish(&self, message: &BusMessage) -> Result<()>;
+
+    /// これ以降に送られるイベントを受け取る
+    fn subscribe(&self) -> broadcast::Receiver<BusMessage>;
+}
+
+/// 1台構成向けのプロセス内のバス
+pub struct InProcessBus {
+    sender: broadcast::Sender<BusMessage>,
+}
+
+impl InProcessBus {
+    pub fn new() -> Self {
+        Self {
+            sender: broadcast::channel(BUS_CAPACITY).0,
+        }
+    }
+}
+
+impl Default for InProcessBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl EventBus for InProcessBus {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn publish(&self, message: &BusMessage) -> Result<()> {
+        // 購読者がいない場合は捨てる
+        let _ = self.sender.send(message.clone());
+        Ok(())
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<BusMessage> {
+        self.sender.subscribe()
+    }
+}
+
+/// セッションのフレームを、バスを通じてすべてのインスタンスの接続に届ける
+///
+/// ユーザーのセッションに送られたフレームは他のインスタンスに中継され、そこに接続している
+/// 同じユーザーの端末に番号なしで送られる。通し番号と再送はセッションを持つインスタンスの中だけで扱う。
+pub struct EventBridge {
+    bus: Arc<dyn EventBus>,
+    sessions: Arc<SessionRegistry>,
+    origin: String,
+}
+
+impl EventBridge {
+    pub fn new(bus: Arc<dyn EventBus>, sessions: Arc<SessionRegistry>) -> Self {
+        Self {
+            bus,
+            sessions,
+            origin: uuid::Uuid::new_v4().to_string(),
+        }
+    }
+
+    pub fn bus_name(&self) -> &'static str {
+        self.bus.name()
+    }
+
+    /// セッションのフレームの中継と、他のインスタンスからのイベントの受信を始める
+    pub fn start(self: &Arc<Self>) -> JoinHandle<()> {
+        let (relay, mut relayed) = mpsc::unbounded_channel();
+        self.sessions.relay_to(relay);
+        let mut incoming = self.bus.subscribe();
+        let bridge = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    Some(event) = relayed.recv() => {
+                        if let Err(e) = bridge.publish(BusEvent::Session(Box::new(event))).await {
+                            tracing::warn!("Failed to relay session event: {:?}", e);
+                        }
+                    }
+                    received = incoming.recv() => match received {
+                        Ok(message) => bridge.dispatch(message),
+                        Err(RecvError::Lagged(skipped)) => {
+                            tracing::warn!("Event bus receiver lagged; {} event(s) dropped", skipped);
+                        }
+                        Err(RecvError::Closed) => break,
+                    },
+                }
+            }
+        })
+    }
+
+    async fn publish(&self, event: BusEvent) -> Result<()> {
+        let message = BusMessage {
+            origin: self.origin.clone(),
+            event,
+        };
+        self.bus.publish(&message).await
+    }
+
+    fn dispatch(&self, message: BusMessage) {
+        // 自身が送ったイベントは送信時に届けている
+        if message.origin != self.origin {
+            self.deliver(&message.event);
+        }
+    }
+
+    fn deliver(&self, event: &BusEvent) {
+        match event {
+            BusEvent::Session(event) => self.sessions.deliver_remote(event),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    use crate::app::chat::protocol::{ServerEnvelope, ServerFrame};
+
+    async fn next(events: &mut mpsc::UnboundedReceiver<ServerEnvelope>) -> ServerEnvelope {
+        timeout(Duration::from_secs(1), events.recv()).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_frames_reach_the_same_user_on_other_instances() {
+        // 2台のインスタンスが同じバスを共有している
+        let bus: Arc<dyn EventBus> = Arc::new(InProcessBus::new());
+        let (registry_a, registry_b) = (Arc::new(SessionRegistry::new()), Arc::new(SessionRegistry::new()));
+        let bridge_a = Arc::new(EventBridge::new(bus.clone(), registry_a.clone()));
+        let bridge_b = Arc::new(EventBridge::new(bus.clone(), registry_b.clone()));
+        bridge_a.start();
+        bridge_b.start();
+
+        let mut phone = registry_a.attach_as(Some("u1"), None, None);
+        let mut laptop = registry_b.attach_as(Some("u1"), None, None);
+        let mut stranger = registry_b.attach_as(Some("u2"), None, None);
+
+        let frame = ServerFrame::Typing { reply_to: "c1".to_string() };
+        phone.session.lock().unwrap().broadcast(frame.clone());
+        assert_eq!(next(&mut phone.events).await.seq, Some(1));
+        // 他のインスタンスでは番号を外して届く
+        assert_eq!(next(&mut laptop.events).await, ServerEnvelope::transient(frame));
+
+        // 送信元にも他のユーザーにも重複して届かない
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(phone.events.try_recv().is_err());
+        assert!(laptop.events.try_recv().is_err());
+        assert!(stranger.events.try_recv().is_err());
+    }
+}
@@ -0,0 +1,183 @@
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::{PgListener, PgPool};
+use tokio::sync::broadcast;
+
+use super::event_bus::{BusMessage, EventBus};
+
+// 受信側の処理が遅れた場合に保持しておくイベントの数
+const BUS_CAPACITY: usize = 1024;
+// NOTIFYのペイロードの上限（PostgreSQLの既定の設定では8000バイト未満）
+const MAX_PAYLOAD_BYTES: usize = 7999;
+// 上限を超えるイベントの本文を置くテーブル。通知には行のIDだけを載せる
+const PAYLOAD_TABLE: &str = "event_bus_payloads";
+// 保存した本文を残す期間。受信側は通知を受けてすぐに読み出す
+const STORED_PAYLOAD_TTL: &str = "5 minutes";
+// 受信用の接続が切れた場合の再接続の間隔。失敗が続くと倍にしていく
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// PostgreSQLのLISTEN/NOTIFYで、同じデータベースを使うすべてのインスタンスにイベントを配る
+///
+/// 送信は接続プールから `pg_notify` を呼び、受信はプールとは別の専用の接続で `LISTEN` する。
+/// 受信用の接続が切れた場合は再接続して `LISTEN` し直す。再接続中に送られたイベントは届かない。
+/// NOTIFYの上限（8000バイト）を超えるイベントは本文をテーブルに保存し、通知にはそのIDだけを載せる。
+pub struct PgEventBus {
+    pool: Arc<PgPool>,
+    channel: String,
+    sender: broadcast::Sender<BusMessage>,
+}
+
+impl PgEventBus {
+    /// 受信を開始する。データベースに接続できるまでは再接続を繰り返す
+    pub fn spawn(pool: Arc<PgPool>, channel: impl Into<String>) -> Arc<Self> {
+        let bus = Arc::new(Self {
+            pool,
+            channel: channel.into(),
+            sender: broadcast::channel(BUS_CAPACITY).0,
+        });
+        tokio::spawn(listen(bus.pool.clone(), bus.channel.clone(), bus.sender.clone()));
+        bus
+    }
+}
+
+#[async_trait]
+impl EventBus for PgEventBus {
+    fn name(&self) -> &'static str {
+        "postgres"
+    }
+
+    async fn publish(&self, message: &BusMessage) -> Result<()> {
+        let mut payload = serde_json::to_string(message).context("Failed to serialize event")?;
+        if payload.len() > MAX_PAYLOAD_BYTES {
+            let payload_id = store_payload(&self.pool, &payload).await?;
+            payload = serde_json::to_string(&Notification::Stored { payload_id })?;
+        }
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(&self.channel)
+            .bind(payload)
+            .execute(&*self.pool)
+            .await
+            .context("Failed to publish event")?;
+        Ok(())
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<BusMessage> {
+        self.sender.subscribe()
+    }
+}
+
+/// NOTIFYのペイロード。イベントそのものか、テーブルに保存したイベントのID
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Notification {
+    Stored { payload_id: i64 },
+    Inline(BusMessage),
+}
+
+// 本文を保存してIDを返す。期限を過ぎた本文はここでまとめて消す
+async fn store_payload(pool: &PgPool, payload: &str) -> Result<i64> {
+    let payload_id = sqlx::query_scalar(&format!("INSERT INTO {} (payload) VALUES ($1) RETURNING id", PAYLOAD_TABLE))
+        .bind(payload)
+        .fetch_one(pool)
+        .await
+        .context("Failed to store event payload")?;
+    sqlx::query(&format!(
+        "DELETE FROM {} WHERE created_at < NOW() - INTERVAL '{}'",
+        PAYLOAD_TABLE, STORED_PAYLOAD_TTL
+    ))
+    .execute(pool)
+    .await
+    .context("Failed to delete expired event payloads")?;
+    Ok(payload_id)
+}
+
+// 通知からイベントを取り出す。保存された本文はテーブルから読み出す
+async fn read_notification(pool: &PgPool, payload: &str) -> Result<BusMessage> {
+    match serde_json::from_str::<Notification>(payload).context("Malformed event")? {
+        Notification::Inline(message) => Ok(message),
+        Notification::Stored { payload_id } => {
+            let payload: Option<String> =
+                sqlx::query_scalar(&format!("SELECT payload FROM {} WHERE id = $1", PAYLOAD_TABLE))
+                    .bind(payload_id)
+                    .fetch_optional(pool)
+                    .await
+                    .context("Failed to read stored event payload")?;
+            let payload = payload.with_context(|| format!("Stored event payload {} has expired", payload_id))?;
+            serde_json::from_str(&payload).context("Malformed stored event")
+        }
+    }
+}
+
+async fn listen(pool: Arc<PgPool>, channel: String, sender: broadcast::Sender<BusMessage>) {
+    let mut delay = RECONNECT_BASE_DELAY;
+    loop {
+        let Err(e) = listen_once(&pool, &channel, &sender, &mut delay).await;
+        tracing::warn!("Event bus listener failed; reconnecting in {:?}: {:?}", delay, e);
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+    }
+}
+
+// 接続して受信を続ける。再接続できないエラーになった場合だけ戻る
+async fn listen_once(
+    pool: &PgPool,
+    channel: &str,
+    sender: &broadcast::Sender<BusMessage>,
+    delay: &mut Duration,
+) -> Result<std::convert::Infallible> {
+    let mut listener = PgListener::connect_with(pool)
+        .await
+        .context("Failed to connect event bus listener")?;
+    listener
+        .listen(channel)
+        .await
+        .with_context(|| format!("Failed to LISTEN on {}", channel))?;
+    *delay = RECONNECT_BASE_DELAY;
+    tracing::info!("Listening for events on channel {}", channel);
+
+    loop {
+        // 接続が切れると `None` が返り、次の呼び出しで再接続して `LISTEN` し直す
+        match listener.try_recv().await.context("Event bus listener disconnected")? {
+            Some(notification) => match read_notification(pool, notification.payload()).await {
+                Ok(message) => {
+                    // 購読者がいない場合は捨てる
+                    let _ = sender.send(message);
+                }
+                Err(e) => tracing::warn!("Ignored event on {}: {:?}", channel, e),
+            },
+            None => tracing::warn!(
+                "Event bus connection lost; events published while reconnecting are not received"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::pubsub::event_bus::BusEvent;
+    use crate::app::chat::protocol::{ServerEnvelope, ServerFrame};
+    use crate::app::chat::session::SessionEvent;
+
+    #[test]
+    fn test_notifications_carry_the_event_or_a_stored_payload_id() {
+        let message = BusMessage {
+            origin: "a".to_string(),
+            event: BusEvent::Session(Box::new(SessionEvent {
+                user_id: "u1".to_string(),
+                session_id: "s1".to_string(),
+                envelope: ServerEnvelope::transient(ServerFrame::Typing { reply_to: "c1".to_string() }),
+            })),
+        };
+        let inline = serde_json::to_string(&message).unwrap();
+        assert_eq!(serde_json::from_str::<Notification>(&inline).unwrap(), Notification::Inline(message));
+
+        let stored = serde_json::to_string(&Notification::Stored { payload_id: 42 }).unwrap();
+        assert_eq!(stored, r#"{"payload_id":42}"#);
+        assert_eq!(serde_json::from_str::<Notification>(&stored).unwrap(), Notification::Stored { payload_id: 42 });
+    }
+}
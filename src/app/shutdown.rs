@@ -0,0 +1,151 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{watch, Notify};
+use tokio::time::Instant;
+
+/// 停止要求と処理中の作業を管理する
+///
+/// 停止要求（SIGTERM / Ctrl-C）を受けると [`Shutdown::triggered`] が完了する。
+/// WebSocketセッションや推薦APIの呼び出しは [`Shutdown::track`] のガードを保持し、
+/// 停止時は [`Shutdown::drain`] ですべてのガードが破棄されるまで待つ。
+#[derive(Clone)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    triggered: watch::Sender<bool>,
+    inflight: AtomicUsize,
+    idle: Notify,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (triggered, _) = watch::channel(false);
+        Self {
+            inner: Arc::new(Inner {
+                triggered,
+                inflight: AtomicUsize::new(0),
+                idle: Notify::new(),
+            }),
+        }
+    }
+
+    /// 停止を開始する。最初の呼び出しの場合のみ `true` を返す
+    pub fn trigger(&self) -> bool {
+        !self.inner.triggered.send_replace(true)
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.inner.triggered.borrow()
+    }
+
+    /// 停止が要求されるまで待つ
+    pub async fn triggered(&self) {
+        let mut receiver = self.inner.triggered.subscribe();
+        // 送信側は `self` が保持しているため、エラーにはならない
+        let _ = receiver.wait_for(|triggered| *triggered).await;
+    }
+
+    /// 処理中の作業として登録する。ガードを破棄すると完了扱いになる
+    pub fn track(&self) -> InflightGuard {
+        self.inner.inflight.fetch_add(1, Ordering::SeqCst);
+        InflightGuard {
+            inner: self.inner.clone(),
+        }
+    }
+
+    pub fn inflight(&self) -> usize {
+        self.inner.inflight.load(Ordering::SeqCst)
+    }
+
+    /// 処理中の作業がなくなるまで、`deadline` を上限に待つ。すべて完了した場合は `true`
+    pub async fn drain(&self, deadline: Instant) -> bool {
+        loop {
+            // 件数を確認する前に通知の待ち受けを作り、取りこぼさないようにする
+            let idle = self.inner.idle.notified();
+            if self.inflight() == 0 {
+                return true;
+            }
+            if tokio::time::timeout_at(deadline, idle).await.is_err() {
+                return self.inflight() == 0;
+            }
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct InflightGuard {
+    inner: Arc<Inner>,
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        if self.inner.inflight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.inner.idle.notify_waiters();
+        }
+    }
+}
+
+/// SIGTERM または Ctrl-C を受け取るまで待つ
+pub async fn signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for Ctrl-C: {:?}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut stream) => {
+                stream.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {:?}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => tracing::info!("Received Ctrl-C"),
+        _ = terminate => tracing::info!("Received SIGTERM"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_drain_waits_for_inflight_work() {
+        let shutdown = Shutdown::new();
+        let guard = shutdown.track();
+        let stuck = shutdown.track();
+        assert!(shutdown.trigger());
+        assert!(!shutdown.trigger());
+        shutdown.triggered().await;
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(guard);
+        });
+        // 期限までに終わらない作業が残っている
+        assert!(!shutdown.drain(Instant::now() + Duration::from_millis(100)).await);
+        assert_eq!(shutdown.inflight(), 1);
+
+        drop(stuck);
+        assert!(shutdown.drain(Instant::now() + Duration::from_millis(100)).await);
+    }
+}
@@ -0,0 +1,392 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Serialize;
+use sqlx::PgPool;
+use time::{Date, OffsetDateTime};
+
+use crate::app::batch::{BatchConfig, BatchStore, BatchWriter};
+use crate::app::gift::quota::LlmUsage;
+use crate::config::config::{ModelPriceConfig, PricingConfig};
+
+// まとめて書き込む件数と間隔、書き込み待ちと再試行のために保持する上限
+const BATCH: BatchConfig = BatchConfig {
+    kind: "LLM usage",
+    batch_size: 100,
+    flush_interval: Duration::from_secs(2),
+    queue_capacity: 1024,
+    max_pending: 1000,
+};
+
+/// 上流を呼び出した機能
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageFeature {
+    /// チャットの会話から推薦した
+    Chat,
+    /// 推薦のAPI（REST・SSE）から直接呼び出した
+    #[default]
+    Recommendation,
+}
+
+impl UsageFeature {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UsageFeature::Chat => "chat",
+            UsageFeature::Recommendation => "recommendation",
+        }
+    }
+}
+
+/// 上流の呼び出しを誰が、どの会話・機能から行ったか
+#[derive(Debug, Clone, Default)]
+pub struct UsageContext {
+    pub user_id: Option<String>,
+    /// チャットのセッションID
+    pub conversation_id: Option<String>,
+    pub feature: UsageFeature,
+}
+
+impl UsageContext {
+    pub fn new(feature: UsageFeature) -> Self {
+        Self {
+            feature,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_user(mut self, user_id: impl Into<String>) -> Self {
+        self.user_id = Some(user_id.into());
+        self
+    }
+
+    pub fn with_conversation(mut self, conversation_id: impl Into<String>) -> Self {
+        self.conversation_id = Some(conversation_id.into());
+        self
+    }
+}
+
+/// 上流の呼び出し1回分の記録。失敗して次のモデルに切り替えた呼び出しも記録する
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageRecord {
+    pub user_id: Option<String>,
+    pub conversation_id: Option<String>,
+    pub feature: UsageFeature,
+    pub provider: String,
+    pub model: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub latency_ms: u64,
+    /// 料金表から求めた推定費用（米ドル）
+    pub cost_usd: f64,
+    /// 推薦を返した。失敗した呼び出しのトークン数は0
+    pub succeeded: bool,
+    pub created_at: OffsetDateTime,
+}
+
+/// モデルごとの料金表
+#[derive(Debug, Clone, Default)]
+pub struct PriceTable {
+    models: Vec<ModelPriceConfig>,
+}
+
+impl PriceTable {
+    pub fn from_config(config: &PricingConfig) -> Self {
+        Self {
+            models: config.models.clone(),
+        }
+    }
+
+    /// 呼び出し1回の推定費用（米ドル）。料金表にないモデルは0
+    pub fn estimate(&self, model: &str, usage: &LlmUsage) -> f64 {
+        let Some(price) = self.models.iter().find(|price| price.model == model) else {
+            tracing::debug!("No price is configured for model {}", model);
+            return 0.0;
+        };
+        price.per_request
+            + usage.prompt_tokens as f64 * price.prompt_per_million / 1_000_000.0
+            + usage.completion_tokens as f64 * price.completion_per_million / 1_000_000.0
+    }
+}
+
+/// 1日・1つのキー（モデルまたはユーザー）の合計
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DailyUsageTotal {
+    /// UTCの日付（YYYY-MM-DD）
+    pub day: String,
+    /// モデル名またはユーザーID。ユーザーが分からない呼び出しは `null`
+    pub key: Option<String>,
+    pub calls: u64,
+    /// `calls` のうち失敗した呼び出し
+    pub failed_calls: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost_usd: f64,
+}
+
+/// 期間内の日ごとの合計
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UsageReport {
+    /// 集計の初日（UTC、YYYY-MM-DD）。この日から今日までを含む
+    pub since: String,
+    pub by_model: Vec<DailyUsageTotal>,
+    pub by_user: Vec<DailyUsageTotal>,
+}
+
+/// 利用記録の保存先
+#[async_trait]
+pub trait UsageStore: Send + Sync {
+    async fn save(&self, records: &[UsageRecord]) -> Result<()>;
+    /// `since` の日（UTC）から今日までの日ごとの合計
+    async fn report(&self, since: Date) -> Result<UsageReport>;
+}
+
+// 日・キー・呼び出し数・失敗数・入力トークン数・出力トークン数・費用
+type TotalRow = (String, Option<String>, i64, i64, i64, i64, f64);
+
+/// `llm_usage` テーブルに保存する
+pub struct PgUsageStore {
+    pool: Arc<PgPool>,
+}
+
+impl PgUsageStore {
+    pub fn new(pool: Arc<PgPool>) -> Self {
+        Self { pool }
+    }
+
+    async fn totals(&self, key: &str, since: Date) -> Result<Vec<DailyUsageTotal>> {
+        // `key` は呼び出し元で決めた列名だけを渡す
+        let query = format!(
+            "SELECT to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD') AS day, {key} AS key, \
+             COUNT(*) AS calls, COUNT(*) FILTER (WHERE NOT succeeded) AS failed_calls, \
+             SUM(prompt_tokens)::BIGINT AS prompt_tokens, \
+             SUM(completion_tokens)::BIGINT AS completion_tokens, SUM(cost_usd) AS cost_usd \
+             FROM llm_usage WHERE created_at >= $1 GROUP BY 1, 2 ORDER BY 1, 2",
+        );
+        let rows: Vec<TotalRow> = sqlx::query_as(&query)
+            .bind(since.midnight().assume_utc())
+            .fetch_all(&*self.pool)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(day, key, calls, failed_calls, prompt_tokens, completion_tokens, cost_usd)| DailyUsageTotal {
+                day,
+                key,
+                calls: calls as u64,
+                failed_calls: failed_calls as u64,
+                prompt_tokens: prompt_tokens as u64,
+                completion_tokens: completion_tokens as u64,
+                cost_usd,
+            })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl UsageStore for PgUsageStore {
+    async fn save(&self, records: &[UsageRecord]) -> Result<()> {
+        let mut transaction = self.pool.begin().await?;
+        for record in records {
+            sqlx::query(
+                "INSERT INTO llm_usage (user_id, conversation_id, feature, provider, model, prompt_tokens, \
+                 completion_tokens, latency_ms, cost_usd, succeeded, created_at) \
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+            )
+            .bind(&record.user_id)
+            .bind(&record.conversation_id)
+            .bind(record.feature.as_str())
+            .bind(&record.provider)
+            .bind(&record.model)
+            .bind(record.prompt_tokens as i64)
+            .bind(record.completion_tokens as i64)
+            .bind(record.latency_ms as i64)
+            .bind(record.cost_usd)
+            .bind(record.succeeded)
+            .bind(record.created_at)
+            .execute(&mut *transaction)
+            .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn report(&self, since: Date) -> Result<UsageReport> {
+        Ok(UsageReport {
+            since: since.to_string(),
+            by_model: self.totals("model", since).await?,
+            by_user: self.totals("user_id", since).await?,
+        })
+    }
+}
+
+/// データベースを使わない場合の保存先
+#[derive(Default)]
+pub struct MemoryUsageStore {
+    records: Mutex<Vec<UsageRecord>>,
+}
+
+impl MemoryUsageStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn records(&self) -> Vec<UsageRecord> {
+        self.records.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl UsageStore for MemoryUsageStore {
+    async fn save(&self, records: &[UsageRecord]) -> Result<()> {
+        self.records.lock().unwrap().extend_from_slice(records);
+        Ok(())
+    }
+
+    async fn report(&self, since: Date) -> Result<UsageReport> {
+        let records = self.records.lock().unwrap();
+        let records: Vec<&UsageRecord> = records.iter().filter(|record| record.created_at.date() >= since).collect();
+        Ok(UsageReport {
+            since: since.to_string(),
+            by_model: totals(&records, |record| Some(record.model.clone())),
+            by_user: totals(&records, |record| record.user_id.clone()),
+        })
+    }
+}
+
+fn totals(records: &[&UsageRecord], key: impl Fn(&UsageRecord) -> Option<String>) -> Vec<DailyUsageTotal> {
+    let mut totals: BTreeMap<(String, Option<String>), DailyUsageTotal> = BTreeMap::new();
+    for record in records {
+        let day = record.created_at.date().to_string();
+        let key = key(record);
+        let total = totals.entry((day.clone(), key.clone())).or_insert(DailyUsageTotal {
+            day,
+            key,
+            calls: 0,
+            failed_calls: 0,
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            cost_usd: 0.0,
+        });
+        total.calls += 1;
+        total.failed_calls += u64::from(!record.succeeded);
+        total.prompt_tokens += record.prompt_tokens;
+        total.completion_tokens += record.completion_tokens;
+        total.cost_usd += record.cost_usd;
+    }
+    totals.into_values().collect()
+}
+
+#[async_trait]
+impl BatchStore<UsageRecord> for dyn UsageStore {
+    async fn save_batch(&self, records: &[UsageRecord]) -> Result<()> {
+        self.save(records).await
+    }
+}
+
+/// 上流の呼び出しを記録する。費用は料金表から求め、保存はバックグラウンドでまとめて行う
+#[derive(Clone)]
+pub struct UsageRecorder {
+    writer: BatchWriter<UsageRecord>,
+    store: Arc<dyn UsageStore>,
+    prices: Arc<PriceTable>,
+}
+
+impl UsageRecorder {
+    pub fn spawn(store: Arc<dyn UsageStore>, prices: PriceTable) -> Self {
+        Self {
+            writer: BatchWriter::spawn(store.clone(), BATCH),
+            store,
+            prices: Arc::new(prices),
+        }
+    }
+
+    /// 推薦を返した呼び出し1回分を記録する。プロバイダーが `usage` を返さなかった場合はトークン数を0とする
+    pub fn record(
+        &self,
+        context: &UsageContext,
+        provider: &str,
+        model: &str,
+        usage: Option<&LlmUsage>,
+        latency: Duration,
+    ) {
+        let usage = usage.copied().unwrap_or_default();
+        let cost_usd = self.prices.estimate(model, &usage);
+        self.writer.record(Self::new_record(context, provider, model, usage, latency, cost_usd, true));
+    }
+
+    /// 失敗した呼び出し1回分を記録する。トークン数と費用は0とする
+    pub fn record_failure(&self, context: &UsageContext, provider: &str, model: &str, latency: Duration) {
+        self.writer.record(Self::new_record(context, provider, model, LlmUsage::default(), latency, 0.0, false));
+    }
+
+    fn new_record(
+        context: &UsageContext,
+        provider: &str,
+        model: &str,
+        usage: LlmUsage,
+        latency: Duration,
+        cost_usd: f64,
+        succeeded: bool,
+    ) -> UsageRecord {
+        UsageRecord {
+            user_id: context.user_id.clone(),
+            conversation_id: context.conversation_id.clone(),
+            feature: context.feature,
+            provider: provider.to_string(),
+            model: model.to_string(),
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            latency_ms: latency.as_millis() as u64,
+            cost_usd,
+            succeeded,
+            created_at: OffsetDateTime::now_utc(),
+        }
+    }
+
+    /// 書き込み待ちの記録をすべて保存し終えるまで待つ
+    pub async fn flush(&self) {
+        self.writer.flush().await;
+    }
+
+    /// 直近 `days` 日（今日を含む）の日ごとの合計。書き込み待ちの記録は含まない
+    pub async fn report(&self, days: u32) -> Result<UsageReport> {
+        let today = OffsetDateTime::now_utc().date();
+        let since = today - time::Duration::days(i64::from(days.max(1)) - 1);
+        self.store.report(since).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_usage_is_priced_and_totalled_per_day() {
+        let store = Arc::new(MemoryUsageStore::new());
+        let recorder = UsageRecorder::spawn(store.clone(), PriceTable::from_config(&PricingConfig::default()));
+        let usage = LlmUsage { prompt_tokens: 1000, completion_tokens: 500, total_tokens: 1500 };
+        let chat = UsageContext::new(UsageFeature::Chat).with_user("u1").with_conversation("s1");
+
+        recorder.record(&chat, "perplexity", "sonar", Some(&usage), Duration::from_millis(800));
+        recorder.record(&chat, "perplexity", "sonar", Some(&usage), Duration::from_millis(600));
+        recorder.record(&UsageContext::default(), "perplexity", "unknown-model", None, Duration::ZERO);
+        recorder.record_failure(&chat, "perplexity", "sonar", Duration::from_millis(300));
+        recorder.flush().await;
+
+        let records = store.records();
+        assert_eq!(records.len(), 4);
+        assert!(!records[3].succeeded && records[3].cost_usd == 0.0);
+        assert_eq!(records[0].conversation_id.as_deref(), Some("s1"));
+        // 1回 0.005 + 入力 1000 * 1.0 / 100万 + 出力 500 * 1.0 / 100万
+        assert!((records[0].cost_usd - 0.0065).abs() < 1e-9);
+        assert_eq!(records[2].cost_usd, 0.0);
+
+        let report = recorder.report(7).await.unwrap();
+        let sonar = report.by_model.iter().find(|total| total.key.as_deref() == Some("sonar")).unwrap();
+        assert_eq!((sonar.calls, sonar.failed_calls, sonar.prompt_tokens, sonar.completion_tokens), (3, 1, 2000, 1000));
+        assert_eq!(report.by_user.len(), 2);
+        assert!(report.by_user.iter().any(|total| total.key.is_none() && total.calls == 1));
+    }
+}
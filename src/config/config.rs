@@ -5,29 +5,105 @@ use anyhow::{Result, Context};
 use serde::{Deserialize, Serialize};
 use dotenv::dotenv;
 
+use super::loader::{ConfigErrors, ConfigLoader};
+use super::secret::Secret;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DatabaseConfig {
     pub host: String,
     pub port: u16,
     pub username: String,
-    pub password: String,
+    pub password: Secret,
     pub database_name: String,
     pub max_connections: u32,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheBackendKind {
+    #[default]
+    Memory,
+    Redis,
+}
+
+impl std::str::FromStr for CacheBackendKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "memory" => Ok(Self::Memory),
+            "redis" => Ok(Self::Redis),
+            other => Err(anyhow::anyhow!("Unknown cache backend: {}", other)),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CacheConfig {
     pub ttl_seconds: u64,
     pub max_size: usize,
     pub cleanup_interval: u64,
+    /// 鮮度切れ後も、再取得中の代替として古い推薦を返せる期間
+    #[serde(default = "default_stale_ttl_seconds")]
+    pub stale_ttl_seconds: u64,
+    /// 上流の一時的な失敗を記録し、同じリクエストでの再呼び出しを抑制する期間（60秒まで）
+    #[serde(default = "default_negative_ttl_seconds")]
+    pub negative_ttl_seconds: u64,
+    #[serde(default)]
+    pub backend: CacheBackendKind,
+    #[serde(default)]
+    pub redis_url: Option<Secret>,
+}
+
+fn default_stale_ttl_seconds() -> u64 {
+    3600
+}
+
+// 失敗を長く覚えていると、上流が回復しても推薦を返せないため短く抑える
+const MAX_NEGATIVE_TTL_SECONDS: u64 = 60;
+
+fn default_negative_ttl_seconds() -> u64 {
+    10
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl_seconds: 3600,
+            max_size: 1000,
+            cleanup_interval: 300,
+            stale_ttl_seconds: default_stale_ttl_seconds(),
+            negative_ttl_seconds: default_negative_ttl_seconds(),
+            backend: CacheBackendKind::Memory,
+            redis_url: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ApiConfig {
-    pub perplexity_api_key: String,
+    pub perplexity_api_key: Secret,
     pub perplexity_api_url: String,
     pub timeout_seconds: u64,
+    /// 上流の一時的な失敗（接続エラー・タイムアウト・429・5xx）を同じモデルで再試行する回数。既定は 0（再試行しない）
+    ///
+    /// 待ち時間は 200ms から1回ごとに倍にする。再試行しても失敗した場合は、次のモデルに切り替える。
+    /// 再試行の回数は `upstream_retries_total` に記録する。
     pub max_retries: u32,
+    /// この回数続けて失敗するとサーキットを開き、カタログのみの推薦に切り替える
+    #[serde(default = "default_circuit_failure_threshold")]
+    pub circuit_failure_threshold: u32,
+    /// サーキットを開いてから再び試行するまでの時間
+    #[serde(default = "default_circuit_open_seconds")]
+    pub circuit_open_seconds: u64,
+}
+
+fn default_circuit_failure_threshold() -> u32 {
+    5
+}
+
+fn default_circuit_open_seconds() -> u64 {
+    30
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -37,121 +113,741 @@ pub struct LocalizationConfig {
     pub fallback_language: String,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// 1行1オブジェクトのJSON。リクエストID・ユーザーID・会話IDをフィールドとして含む
+    Json,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LoggingConfig {
     pub level: String,
+    /// ログファイルのパス。空の場合はファイルに出力しない
     pub file_path: PathBuf,
+    /// このサイズ（バイト）を超えるとファイルをローテーションする
     pub rotation_size: u64,
+    /// ローテーション済みのファイルを残す数
     pub max_files: u32,
+    #[serde(default)]
+    pub format: LogFormat,
+    /// メッセージ本文と個人情報（メールアドレス・電話番号・郵便番号）をマスクする
+    #[serde(default = "default_redact")]
+    pub redact: bool,
+}
+
+fn default_redact() -> bool {
+    true
+}
+
+/// 実行中に差し替えられるルール・パターンファイルと、その監視間隔
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RulesConfig {
+    pub intent_patterns_path: PathBuf,
+    pub gift_rules_path: PathBuf,
+    /// 設定・ルールファイルの変更を確認する間隔。0の場合は監視しない
+    pub reload_interval_seconds: u64,
+}
+
+/// WebSocket接続の死活監視・タイムアウト・上限
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebSocketConfig {
+    /// サーバーからpingを送る間隔
+    pub ping_interval_seconds: u64,
+    /// pingのあと、この時間内に何も受信しなければ切断されたとみなす
+    pub pong_timeout_seconds: u64,
+    /// クライアントからメッセージが届かない状態がこの時間続くと閉じる
+    pub idle_timeout_seconds: u64,
+    /// 1回の接続を続けられる上限。クライアントはセッションを指定して再接続する
+    pub max_session_seconds: u64,
+    pub max_connections_per_ip: usize,
+    pub max_connections_per_user: usize,
+    /// 受信するメッセージの最大サイズ（バイト）
+    pub max_message_bytes: usize,
+    /// 送信待ちにできるフレームの数。超えた場合は途中経過を間引き、それでも溢れると接続を閉じる
+    pub outbound_queue_size: usize,
+    /// 応答の生成中に受け付けておけるメッセージの数
+    pub max_pending_messages: usize,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval_seconds: 20,
+            pong_timeout_seconds: 10,
+            idle_timeout_seconds: 600,
+            max_session_seconds: 7200,
+            max_connections_per_ip: 20,
+            max_connections_per_user: 5,
+            max_message_bytes: 16 * 1024,
+            outbound_queue_size: 256,
+            max_pending_messages: 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PubSubBackendKind {
+    /// 1台構成向け。イベントはプロセス内にだけ配られる
+    #[default]
+    Memory,
+    /// PostgreSQLのLISTEN/NOTIFYで、すべてのインスタンスに配る
+    Postgres,
+}
+
+impl std::str::FromStr for PubSubBackendKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "memory" => Ok(Self::Memory),
+            "postgres" => Ok(Self::Postgres),
+            other => Err(anyhow::anyhow!("Unknown pubsub backend: {}", other)),
+        }
+    }
+}
+
+/// インスタンス間でセッションのイベントを配る仕組み
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PubSubConfig {
+    pub backend: PubSubBackendKind,
+    /// LISTEN/NOTIFYのチャンネル名。同じデータベースを使う環境ごとに分ける
+    pub channel: String,
+}
+
+impl Default for PubSubConfig {
+    fn default() -> Self {
+        Self {
+            backend: PubSubBackendKind::Memory,
+            channel: "gift_advisor_events".to_string(),
+        }
+    }
+}
+
+/// 認証の設定
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// セッショントークンの署名鍵（32バイト以上）。複数のインスタンスでは同じ値にする。
+    /// 空の場合は起動ごとに生成するため、再起動すると発行済みのトークンは使えなくなる（開発用）
+    pub token_secret: Secret,
+    /// セッショントークンの有効期間。期限が切れる前に `/auth/refresh` で更新する
+    pub token_ttl_seconds: u64,
+    /// サーバー間連携のパートナーに発行したAPIキー
+    pub api_keys: Vec<ApiKeyConfig>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            token_secret: Secret::default(),
+            token_ttl_seconds: 900,
+            api_keys: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKeyConfig {
+    /// パートナーの名前。このキーで発行したトークンのユーザーIDの接頭辞になる
+    pub name: String,
+    /// 環境変数 `AUTH_API_KEYS_<NAME>_KEY`（`_FILE`）でも指定できる
+    #[serde(default)]
+    pub key: Secret,
+    /// 管理用エンドポイントを呼び出せる
+    #[serde(default)]
+    pub admin: bool,
+}
+
+/// トークンバケットの容量と補充の速さ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BucketConfig {
+    /// 続けて受け付けられる数
+    pub burst: u32,
+    /// 1分あたりに補充される数
+    pub per_minute: u32,
+}
+
+/// リクエスト・WebSocketのメッセージの流量制限と、LLMの1日あたりの利用上限
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// セッショントークンのユーザーごと
+    pub per_user: BucketConfig,
+    /// サーバー間連携のAPIキーごと
+    pub per_api_key: BucketConfig,
+    /// 接続元のIPアドレスごと。認証済みのリクエストにも適用する
+    pub per_ip: BucketConfig,
+    /// ユーザーごとの1日（UTC）のLLMの呼び出し回数。0の場合は制限しない
+    pub daily_llm_calls_per_user: u32,
+    /// ユーザーごとの1日（UTC）のLLMのトークン数。プロバイダーが返す `usage` で数える。0の場合は制限しない
+    pub daily_llm_tokens_per_user: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            per_user: BucketConfig { burst: 20, per_minute: 30 },
+            per_api_key: BucketConfig { burst: 100, per_minute: 600 },
+            per_ip: BucketConfig { burst: 60, per_minute: 120 },
+            daily_llm_calls_per_user: 100,
+            daily_llm_tokens_per_user: 200_000,
+        }
+    }
+}
+
+/// モデルの料金（米ドル）。上流の呼び出しごとの推定費用の計算に使う
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelPriceConfig {
+    pub model: String,
+    /// 入力100万トークンあたり
+    pub prompt_per_million: f64,
+    /// 出力100万トークンあたり
+    pub completion_per_million: f64,
+    /// 1回の呼び出しごとの料金
+    #[serde(default)]
+    pub per_request: f64,
+}
+
+/// 上流の料金表。載っていないモデルの費用は0として記録する
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PricingConfig {
+    pub models: Vec<ModelPriceConfig>,
+}
+
+impl Default for PricingConfig {
+    fn default() -> Self {
+        Self {
+            models: vec![
+                ModelPriceConfig {
+                    model: "sonar".to_string(),
+                    prompt_per_million: 1.0,
+                    completion_per_million: 1.0,
+                    per_request: 0.005,
+                },
+                ModelPriceConfig {
+                    model: "sonar-pro".to_string(),
+                    prompt_per_million: 3.0,
+                    completion_per_million: 15.0,
+                    per_request: 0.006,
+                },
+            ],
+        }
+    }
+}
+
+/// `api` の設定から作る組み込みのプロバイダーの名前
+pub const DEFAULT_LLM_PROVIDER: &str = "perplexity";
+// Perplexityの `search_domain_filter` に指定できるドメインの数
+const MAX_SEARCH_DOMAINS: usize = 10;
+
+/// 検索結果の新しさの絞り込み（Perplexityの `search_recency_filter`）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchRecency {
+    Hour,
+    Day,
+    Week,
+    Month,
+}
+
+/// フォールバックの順序の1件
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModelTargetConfig {
+    /// `llm.providers` の名前。省略した場合は組み込みの `perplexity`
+    #[serde(default = "default_llm_provider")]
+    pub provider: String,
+    pub model: String,
+}
+
+fn default_llm_provider() -> String {
+    DEFAULT_LLM_PROVIDER.to_string()
+}
+
+/// 機能ごとのモデルと生成の設定
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FeatureModelConfig {
+    /// 先頭から順に試す。失敗・タイムアウトした場合は次のモデルに切り替える
+    pub models: Vec<ModelTargetConfig>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+    /// 検索するドメイン（`-` で始めると除外）。検索に対応したプロバイダーにだけ送る
+    pub search_domain_filter: Vec<String>,
+    pub search_recency_filter: Option<SearchRecency>,
+}
+
+impl Default for FeatureModelConfig {
+    fn default() -> Self {
+        Self {
+            models: vec![ModelTargetConfig {
+                provider: default_llm_provider(),
+                model: "sonar".to_string(),
+            }],
+            temperature: Some(0.2),
+            max_tokens: Some(1024),
+            search_domain_filter: Vec::new(),
+            search_recency_filter: None,
+        }
+    }
+}
+
+/// Chat Completions API 互換の追加のプロバイダー
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LlmProviderConfig {
+    pub name: String,
+    /// Chat Completions のエンドポイントのURL
+    pub api_url: String,
+    /// 環境変数 `LLM_PROVIDERS_<NAME>_API_KEY`（`_FILE`）でも指定できる
+    #[serde(default)]
+    pub api_key: Secret,
+    /// Perplexityの検索の絞り込み（`search_domain_filter` など）を受け付ける
+    #[serde(default)]
+    pub supports_search: bool,
+    /// 省略した場合は `api.timeout_seconds`
+    #[serde(default)]
+    pub timeout_seconds: Option<u64>,
+}
+
+/// 上流のLLMの選択。組み込みの `perplexity` は `api` の設定を使う
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LlmConfig {
+    pub providers: Vec<LlmProviderConfig>,
+    /// チャットの会話からの推薦
+    pub chat: FeatureModelConfig,
+    /// 推薦のAPI（REST・SSE）
+    pub recommendation: FeatureModelConfig,
+    /// 推薦のプロンプトのテンプレート（言語ごとの文面と版を持つTOML）
+    pub prompt_template_path: PathBuf,
+}
+
+impl Default for LlmConfig {
+    fn default() -> Self {
+        Self {
+            providers: Vec::new(),
+            chat: FeatureModelConfig::default(),
+            recommendation: FeatureModelConfig::default(),
+            prompt_template_path: PathBuf::from("config/prompts/gift_recommendation.toml"),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub environment: String,
+    #[serde(default = "default_server_host")]
+    pub server_host: String,
     pub server_port: u16,
+    /// 停止時に処理中のリクエストやWebSocketセッションの終了を待つ上限
+    #[serde(default = "default_shutdown_timeout_seconds")]
+    pub shutdown_timeout_seconds: u64,
     pub database: DatabaseConfig,
     pub cache: CacheConfig,
     pub api: ApiConfig,
     pub localization: LocalizationConfig,
     pub logging: LoggingConfig,
+    #[serde(default)]
+    pub rules: RulesConfig,
+    #[serde(default)]
+    pub websocket: WebSocketConfig,
+    #[serde(default)]
+    pub pubsub: PubSubConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub pricing: PricingConfig,
+    #[serde(default)]
+    pub llm: LlmConfig,
+}
+
+fn default_server_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_shutdown_timeout_seconds() -> u64 {
+    30
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: 5432,
+            username: String::new(),
+            password: Secret::default(),
+            database_name: String::new(),
+            max_connections: 10,
+        }
+    }
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            perplexity_api_key: Secret::default(),
+            perplexity_api_url: "https://api.perplexity.ai".to_string(),
+            timeout_seconds: 30,
+            max_retries: 0,
+            circuit_failure_threshold: default_circuit_failure_threshold(),
+            circuit_open_seconds: default_circuit_open_seconds(),
+        }
+    }
+}
+
+impl Default for LocalizationConfig {
+    fn default() -> Self {
+        Self {
+            default_language: "ja".to_string(),
+            available_languages: vec!["ja".to_string(), "en".to_string()],
+            fallback_language: "en".to_string(),
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            file_path: PathBuf::from("logs/app.log"),
+            rotation_size: 10485760, // 10MB
+            max_files: 5,
+            format: LogFormat::Text,
+            redact: default_redact(),
+        }
+    }
+}
+
+impl Default for RulesConfig {
+    fn default() -> Self {
+        Self {
+            intent_patterns_path: PathBuf::from("config/rules/intent_patterns.toml"),
+            gift_rules_path: PathBuf::from("config/rules/gift_rules.toml"),
+            reload_interval_seconds: 5,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            environment: "development".to_string(),
+            server_host: default_server_host(),
+            server_port: 8080,
+            shutdown_timeout_seconds: default_shutdown_timeout_seconds(),
+            database: DatabaseConfig::default(),
+            cache: CacheConfig::default(),
+            api: ApiConfig::default(),
+            localization: LocalizationConfig::default(),
+            logging: LoggingConfig::default(),
+            rules: RulesConfig::default(),
+            websocket: WebSocketConfig::default(),
+            pubsub: PubSubConfig::default(),
+            auth: AuthConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            pricing: PricingConfig::default(),
+            llm: LlmConfig::default(),
+        }
+    }
 }
 
+const MIN_TOKEN_SECRET_BYTES: usize = 32;
+const MIN_API_KEY_BYTES: usize = 24;
+
+const LOG_LEVELS: [&str; 5] = ["trace", "debug", "info", "warn", "error"];
+
 impl Config {
+    /// デフォルト値・設定ファイル・環境変数・コマンドライン引数をマージして読み込む
+    ///
+    /// 優先順位は [`ConfigLoader`] を参照。
+    pub fn load() -> std::result::Result<Self, ConfigErrors> {
+        ConfigLoader::from_process().load()
+    }
+
+    /// デフォルト値と環境変数のみから読み込む
     pub fn new() -> Result<Self> {
         dotenv().ok();
+        Ok(ConfigLoader::new().with_env(env::vars()).load()?)
+    }
 
-        let config = Config {
-            environment: env::var("ENVIRONMENT").unwrap_or_else(|_| "development".to_string()),
-            server_port: env::var("SERVER_PORT")
-                .unwrap_or_else(|_| "8080".to_string())
-                .parse()
-                .context("Failed to parse SERVER_PORT")?,
-            
-            database: DatabaseConfig {
-                host: env::var("DB_HOST").unwrap_or_else(|_| "localhost".to_string()),
-                port: env::var("DB_PORT")
-                    .unwrap_or_else(|_| "5432".to_string())
-                    .parse()
-                    .context("Failed to parse DB_PORT")?,
-                username: env::var("DB_USERNAME").context("DB_USERNAME not set")?,
-                password: env::var("DB_PASSWORD").context("DB_PASSWORD not set")?,
-                database_name: env::var("DB_NAME").context("DB_NAME not set")?,
-                max_connections: env::var("DB_MAX_CONNECTIONS")
-                    .unwrap_or_else(|_| "10".to_string())
-                    .parse()
-                    .context("Failed to parse DB_MAX_CONNECTIONS")?,
-            },
-            
-            cache: CacheConfig {
-                ttl_seconds: env::var("CACHE_TTL_SECONDS")
-                    .unwrap_or_else(|_| "3600".to_string())
-                    .parse()
-                    .context("Failed to parse CACHE_TTL_SECONDS")?,
-                max_size: env::var("CACHE_MAX_SIZE")
-                    .unwrap_or_else(|_| "1000".to_string())
-                    .parse()
-                    .context("Failed to parse CACHE_MAX_SIZE")?,
-                cleanup_interval: env::var("CACHE_CLEANUP_INTERVAL")
-                    .unwrap_or_else(|_| "300".to_string())
-                    .parse()
-                    .context("Failed to parse CACHE_CLEANUP_INTERVAL")?,
-            },
-            
-            api: ApiConfig {
-                perplexity_api_key: env::var("PERPLEXITY_API_KEY").context("PERPLEXITY_API_KEY not set")?,
-                perplexity_api_url: env::var("PERPLEXITY_API_URL")
-                    .unwrap_or_else(|_| "https://api.perplexity.ai".to_string()),
-                timeout_seconds: env::var("API_TIMEOUT_SECONDS")
-                    .unwrap_or_else(|_| "30".to_string())
-                    .parse()
-                    .context("Failed to parse API_TIMEOUT_SECONDS")?,
-                max_retries: env::var("API_MAX_RETRIES")
-                    .unwrap_or_else(|_| "3".to_string())
-                    .parse()
-                    .context("Failed to parse API_MAX_RETRIES")?,
-            },
-            
-            localization: LocalizationConfig {
-                default_language: env::var("DEFAULT_LANGUAGE")
-                    .unwrap_or_else(|_| "ja".to_string()),
-                available_languages: env::var("AVAILABLE_LANGUAGES")
-                    .unwrap_or_else(|_| "ja,en".to_string())
-                    .split(',')
-                    .map(String::from)
-                    .collect(),
-                fallback_language: env::var("FALLBACK_LANGUAGE")
-                    .unwrap_or_else(|_| "en".to_string()),
-            },
-            
-            logging: LoggingConfig {
-                level: env::var("LOG_LEVEL")
-                    .unwrap_or_else(|_| "info".to_string()),
-                file_path: PathBuf::from(
-                    env::var("LOG_FILE_PATH")
-                        .unwrap_or_else(|_| "logs/app.log".to_string())
-                ),
-                rotation_size: env::var("LOG_ROTATION_SIZE")
-                    .unwrap_or_else(|_| "10485760".to_string()) // 10MB
-                    .parse()
-                    .context("Failed to parse LOG_ROTATION_SIZE")?,
-                max_files: env::var("LOG_MAX_FILES")
-                    .unwrap_or_else(|_| "5".to_string())
-                    .parse()
-                    .context("Failed to parse LOG_MAX_FILES")?,
-            },
-        };
+    /// 単一の設定ファイル（TOML / YAML / JSON）から読み込む。記載のない項目はデフォルト値になる
+    pub fn from_file(path: &str) -> Result<Self> {
+        Ok(ConfigLoader::new()
+            .without_profiles()
+            .with_config_file(path)
+            .load()?)
+    }
 
-        Ok(config)
+    /// データベースなしでは運用できない。本番環境と、インスタンス間の配信にデータベースを使う場合
+    pub fn database_required(&self) -> bool {
+        self.environment == "production" || self.pubsub.backend == PubSubBackendKind::Postgres
     }
 
-    pub fn from_file(path: &str) -> Result<Self> {
-        let config_str = fs::read_to_string(path)
-            .context("Failed to read config file")?;
-        let config: Config = serde_json::from_str(&config_str)
-            .context("Failed to parse config file")?;
-        Ok(config)
+    /// 機密値とその設定項目のパス。シリアライズではマスクされるため、変更の検知に使う
+    pub fn secrets(&self) -> Vec<(String, &Secret)> {
+        let mut secrets = vec![
+            ("database.password".to_string(), &self.database.password),
+            ("api.perplexity_api_key".to_string(), &self.api.perplexity_api_key),
+            ("auth.token_secret".to_string(), &self.auth.token_secret),
+        ];
+        if let Some(redis_url) = &self.cache.redis_url {
+            secrets.push(("cache.redis_url".to_string(), redis_url));
+        }
+        for api_key in &self.auth.api_keys {
+            secrets.push((format!("auth.api_keys.{}.key", api_key.name), &api_key.key));
+        }
+        for provider in &self.llm.providers {
+            secrets.push((format!("llm.providers.{}.api_key", provider.name), &provider.api_key));
+        }
+        secrets
+    }
+
+    /// すべての設定値を検証し、見つかった問題をまとめて返す
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if self.environment.trim().is_empty() {
+            errors.push("environment must not be empty".to_string());
+        }
+        if self.server_host.trim().is_empty() {
+            errors.push("server_host must not be empty".to_string());
+        }
+        if self.server_port == 0 {
+            errors.push("server_port must be between 1 and 65535".to_string());
+        }
+
+        if self.database.host.trim().is_empty() {
+            errors.push("database.host must not be empty".to_string());
+        }
+        if self.database.port == 0 {
+            errors.push("database.port must be between 1 and 65535".to_string());
+        }
+        if self.database.max_connections == 0 {
+            errors.push("database.max_connections must be at least 1".to_string());
+        }
+
+        if self.cache.ttl_seconds == 0 {
+            errors.push("cache.ttl_seconds must be at least 1".to_string());
+        }
+        if self.cache.cleanup_interval == 0 {
+            errors.push("cache.cleanup_interval must be at least 1".to_string());
+        }
+        if self.cache.negative_ttl_seconds > MAX_NEGATIVE_TTL_SECONDS {
+            errors.push(format!("cache.negative_ttl_seconds must be at most {}", MAX_NEGATIVE_TTL_SECONDS));
+        }
+        if self.cache.backend == CacheBackendKind::Redis {
+            match &self.cache.redis_url {
+                // URLに認証情報が含まれることがあるため、エラーメッセージには値を出さない
+                Some(url) => {
+                    if validate_url(url.expose(), &["redis", "rediss"]).is_err() {
+                        errors.push("cache.redis_url must be a redis:// or rediss:// URL".to_string());
+                    }
+                }
+                None => errors.push("cache.redis_url is required for the redis backend".to_string()),
+            }
+        }
+
+        if self.api.perplexity_api_key.is_empty() {
+            errors.push(
+                "api.perplexity_api_key is required (PERPLEXITY_API_KEY or PERPLEXITY_API_KEY_FILE)"
+                    .to_string(),
+            );
+        }
+        if let Err(e) = validate_url(&self.api.perplexity_api_url, &["http", "https"]) {
+            errors.push(format!("api.perplexity_api_url {}", e));
+        }
+        if self.api.timeout_seconds == 0 {
+            errors.push("api.timeout_seconds must be at least 1".to_string());
+        }
+        if self.api.circuit_failure_threshold == 0 {
+            errors.push("api.circuit_failure_threshold must be at least 1".to_string());
+        }
+
+        let localization = &self.localization;
+        if localization.available_languages.is_empty() {
+            errors.push("localization.available_languages must not be empty".to_string());
+        }
+        for language in &localization.available_languages {
+            if !is_language_code(language) {
+                errors.push(format!(
+                    "localization.available_languages: {:?} is not a valid language code",
+                    language
+                ));
+            }
+        }
+        for (name, language) in [
+            ("default_language", &localization.default_language),
+            ("fallback_language", &localization.fallback_language),
+        ] {
+            if !localization.available_languages.contains(language) {
+                errors.push(format!(
+                    "localization.{} {:?} is not in available_languages",
+                    name, language
+                ));
+            }
+        }
+
+        if !LOG_LEVELS.contains(&self.logging.level.to_lowercase().as_str()) {
+            errors.push(format!(
+                "logging.level {:?} must be one of {}",
+                self.logging.level,
+                LOG_LEVELS.join(", ")
+            ));
+        }
+        if self.logging.rotation_size == 0 {
+            errors.push("logging.rotation_size must be at least 1".to_string());
+        }
+
+        let websocket = &self.websocket;
+        for (name, value) in [
+            ("ping_interval_seconds", websocket.ping_interval_seconds as usize),
+            ("pong_timeout_seconds", websocket.pong_timeout_seconds as usize),
+            ("idle_timeout_seconds", websocket.idle_timeout_seconds as usize),
+            ("max_session_seconds", websocket.max_session_seconds as usize),
+            ("max_connections_per_ip", websocket.max_connections_per_ip),
+            ("max_connections_per_user", websocket.max_connections_per_user),
+            ("max_message_bytes", websocket.max_message_bytes),
+            ("outbound_queue_size", websocket.outbound_queue_size),
+            ("max_pending_messages", websocket.max_pending_messages),
+        ] {
+            if value == 0 {
+                errors.push(format!("websocket.{} must be at least 1", name));
+            }
+        }
+
+        let channel = &self.pubsub.channel;
+        if channel.is_empty()
+            || channel.len() > 63
+            || !channel.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            errors.push(format!(
+                "pubsub.channel {:?} must be 1-63 ASCII letters, digits or underscores",
+                channel
+            ));
+        }
+        if self.environment == "production" && self.database.database_name.is_empty() {
+            errors.push("database.database_name is required in production".to_string());
+        } else if self.pubsub.backend == PubSubBackendKind::Postgres && self.database.database_name.is_empty() {
+            errors.push("pubsub.backend postgres requires database.database_name".to_string());
+        }
+
+        let auth = &self.auth;
+        if auth.token_secret.is_empty() {
+            if self.environment == "production" {
+                errors.push("auth.token_secret is required in production (AUTH_TOKEN_SECRET or AUTH_TOKEN_SECRET_FILE)".to_string());
+            }
+        } else if auth.token_secret.expose().len() < MIN_TOKEN_SECRET_BYTES {
+            errors.push(format!("auth.token_secret must be at least {} bytes", MIN_TOKEN_SECRET_BYTES));
+        }
+        if auth.token_ttl_seconds < 60 {
+            errors.push("auth.token_ttl_seconds must be at least 60".to_string());
+        }
+        for (index, api_key) in auth.api_keys.iter().enumerate() {
+            if api_key.name.trim().is_empty() || api_key.name.contains(':') {
+                errors.push(format!("auth.api_keys[{}].name must be non-empty and must not contain ':'", index));
+            }
+            if auth.api_keys[..index].iter().any(|other| other.name == api_key.name) {
+                errors.push(format!("auth.api_keys[{}].name {:?} is duplicated", index, api_key.name));
+            }
+            // キーの値はエラーメッセージに出さない
+            if api_key.key.expose().len() < MIN_API_KEY_BYTES {
+                errors.push(format!("auth.api_keys[{}].key must be at least {} bytes", index, MIN_API_KEY_BYTES));
+            }
+        }
+
+        let rate_limit = &self.rate_limit;
+        for (name, bucket) in [
+            ("per_user", rate_limit.per_user),
+            ("per_api_key", rate_limit.per_api_key),
+            ("per_ip", rate_limit.per_ip),
+        ] {
+            if bucket.burst == 0 || bucket.per_minute == 0 {
+                errors.push(format!("rate_limit.{}.burst and per_minute must be at least 1", name));
+            }
+        }
+
+        let models = &self.pricing.models;
+        for (index, price) in models.iter().enumerate() {
+            if price.model.trim().is_empty() {
+                errors.push(format!("pricing.models[{}].model must not be empty", index));
+            }
+            if models[..index].iter().any(|other| other.model == price.model) {
+                errors.push(format!("pricing.models[{}].model {:?} is duplicated", index, price.model));
+            }
+            let prices = [price.prompt_per_million, price.completion_per_million, price.per_request];
+            if prices.iter().any(|value| !value.is_finite() || *value < 0.0) {
+                errors.push(format!("pricing.models[{}] prices must be non-negative numbers", index));
+            }
+        }
+
+        let providers = &self.llm.providers;
+        for (index, provider) in providers.iter().enumerate() {
+            if provider.name.trim().is_empty() || provider.name == DEFAULT_LLM_PROVIDER {
+                errors.push(format!(
+                    "llm.providers[{}].name must be non-empty and must not be {:?}",
+                    index, DEFAULT_LLM_PROVIDER
+                ));
+            }
+            if providers[..index].iter().any(|other| other.name == provider.name) {
+                errors.push(format!("llm.providers[{}].name {:?} is duplicated", index, provider.name));
+            }
+            if let Err(e) = validate_url(&provider.api_url, &["http", "https"]) {
+                errors.push(format!("llm.providers[{}].api_url {}", index, e));
+            }
+            if provider.timeout_seconds == Some(0) {
+                errors.push(format!("llm.providers[{}].timeout_seconds must be at least 1", index));
+            }
+        }
+        for (name, feature) in [("chat", &self.llm.chat), ("recommendation", &self.llm.recommendation)] {
+            if feature.models.is_empty() {
+                errors.push(format!("llm.{}.models must not be empty", name));
+            }
+            for (index, target) in feature.models.iter().enumerate() {
+                let known = target.provider == DEFAULT_LLM_PROVIDER
+                    || providers.iter().any(|provider| provider.name == target.provider);
+                if !known {
+                    errors.push(format!("llm.{}.models[{}].provider {:?} is not defined", name, index, target.provider));
+                }
+                if target.model.trim().is_empty() {
+                    errors.push(format!("llm.{}.models[{}].model must not be empty", name, index));
+                }
+            }
+            if feature.temperature.is_some_and(|temperature| !(0.0..=2.0).contains(&temperature)) {
+                errors.push(format!("llm.{}.temperature must be between 0 and 2", name));
+            }
+            if feature.max_tokens == Some(0) {
+                errors.push(format!("llm.{}.max_tokens must be at least 1", name));
+            }
+            if feature.search_domain_filter.len() > MAX_SEARCH_DOMAINS
+                || feature.search_domain_filter.iter().any(|domain| domain.trim_start_matches('-').trim().is_empty())
+            {
+                errors.push(format!(
+                    "llm.{}.search_domain_filter must have at most {} non-empty domains",
+                    name, MAX_SEARCH_DOMAINS
+                ));
+            }
+        }
+        if self.llm.prompt_template_path.as_os_str().is_empty() {
+            errors.push("llm.prompt_template_path must not be empty".to_string());
+        }
+
+        errors
     }
 
+    /// サーバーの待ち受けアドレス
+    pub fn server_addr(&self) -> String {
+        format!("{}:{}", self.server_host, self.server_port)
+    }
+
+    /// 設定をJSONで書き出す。機密値はマスクされるため、そのまま共有してよい
     pub fn to_file(&self, path: &str) -> Result<()> {
         let config_str = serde_json::to_string_pretty(self)
             .context("Failed to serialize config")?;
@@ -161,6 +857,32 @@ impl Config {
     }
 }
 
+// "ja"、"en"、"en-US"、"zh-Hant" のような言語コードかどうか
+fn is_language_code(code: &str) -> bool {
+    let mut parts = code.split('-');
+    let language_ok = parts
+        .next()
+        .map(|language| {
+            (2..=3).contains(&language.len()) && language.chars().all(|c| c.is_ascii_lowercase())
+        })
+        .unwrap_or(false);
+    language_ok
+        && parts.all(|subtag| {
+            (2..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+}
+
+fn validate_url(url: &str, schemes: &[&str]) -> std::result::Result<(), String> {
+    let parsed = reqwest::Url::parse(url).map_err(|e| format!("{:?} is not a valid URL: {}", url, e))?;
+    if !schemes.contains(&parsed.scheme()) {
+        return Err(format!("{:?} must use one of: {}", url, schemes.join(", ")));
+    }
+    if parsed.host_str().is_none() {
+        return Err(format!("{:?} has no host", url));
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -170,12 +892,14 @@ mod tests {
     fn test_config_serialization() {
         let config = Config {
             environment: "test".to_string(),
+            server_host: "127.0.0.1".to_string(),
             server_port: 8080,
+            shutdown_timeout_seconds: 30,
             database: DatabaseConfig {
                 host: "localhost".to_string(),
                 port: 5432,
                 username: "test_user".to_string(),
-                password: "test_pass".to_string(),
+                password: Secret::new("test_pass"),
                 database_name: "test_db".to_string(),
                 max_connections: 10,
             },
@@ -183,12 +907,18 @@ mod tests {
                 ttl_seconds: 3600,
                 max_size: 1000,
                 cleanup_interval: 300,
+                stale_ttl_seconds: 3600,
+                negative_ttl_seconds: 30,
+                backend: CacheBackendKind::Memory,
+                redis_url: None,
             },
             api: ApiConfig {
-                perplexity_api_key: "test_key".to_string(),
+                perplexity_api_key: Secret::new("test_key"),
                 perplexity_api_url: "https://api.test.com".to_string(),
                 timeout_seconds: 30,
                 max_retries: 3,
+                circuit_failure_threshold: 5,
+                circuit_open_seconds: 30,
             },
             localization: LocalizationConfig {
                 default_language: "ja".to_string(),
@@ -200,7 +930,16 @@ mod tests {
                 file_path: PathBuf::from("test.log"),
                 rotation_size: 10485760,
                 max_files: 5,
+                format: LogFormat::Json,
+                redact: true,
             },
+            rules: RulesConfig::default(),
+            websocket: WebSocketConfig::default(),
+            pubsub: PubSubConfig::default(),
+            auth: AuthConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            pricing: PricingConfig::default(),
+            llm: LlmConfig::default(),
         };
 
         let temp_file = NamedTempFile::new().unwrap();
@@ -209,8 +948,18 @@ mod tests {
         // 設定をファイルに保存
         config.to_file(path).unwrap();
 
-        // 設定をファイルから読み込み
-        let loaded_config = Config::from_file(path).unwrap();
+        // 機密値はファイルに書き出されない
+        let written = std::fs::read_to_string(path).unwrap();
+        assert!(!written.contains("test_pass"));
+        assert!(!written.contains("test_key"));
+
+        // 設定をファイルから読み込み（機密値は環境変数から補う）
+        let loaded_config = ConfigLoader::new()
+            .without_profiles()
+            .with_config_file(path)
+            .with_env([("PERPLEXITY_API_KEY".to_string(), "test_key".to_string())])
+            .load()
+            .unwrap();
 
         // 設定が正しく保存・読み込みされたことを確認
         assert_eq!(config.environment, loaded_config.environment);
@@ -220,5 +969,19 @@ mod tests {
         assert_eq!(config.api.perplexity_api_key, loaded_config.api.perplexity_api_key);
         assert_eq!(config.localization.default_language, loaded_config.localization.default_language);
         assert_eq!(config.logging.level, loaded_config.logging.level);
+        assert!(loaded_config.database.password.is_empty());
+    }
+
+    #[test]
+    fn test_debug_output_redacts_secrets() {
+        let mut config = Config::default();
+        config.database.password = Secret::new("db-secret");
+        config.api.perplexity_api_key = Secret::new("pplx-secret");
+        config.cache.redis_url = Some(Secret::new("redis://:redis-secret@localhost"));
+
+        let debug = format!("{:?}", config);
+        assert!(!debug.contains("db-secret"));
+        assert!(!debug.contains("pplx-secret"));
+        assert!(!debug.contains("redis-secret"));
     }
 } 
\ No newline at end of file
@@ -0,0 +1,616 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde_json::{Map, Value};
+
+use super::config::Config;
+
+// 設定ファイルを探すディレクトリのデフォルト値
+pub const DEFAULT_CONFIG_DIR: &str = "config";
+const FILE_EXTENSIONS: [&str; 4] = ["toml", "yaml", "yml", "json"];
+
+/// 環境変数名と設定項目のパスの対応表
+pub const ENV_MAPPINGS: &[(&str, &str)] = &[
+    ("ENVIRONMENT", "environment"),
+    ("SERVER_HOST", "server_host"),
+    ("SERVER_PORT", "server_port"),
+    ("SHUTDOWN_TIMEOUT_SECONDS", "shutdown_timeout_seconds"),
+    ("DB_HOST", "database.host"),
+    ("DB_PORT", "database.port"),
+    ("DB_USERNAME", "database.username"),
+    ("DB_PASSWORD", "database.password"),
+    ("DB_NAME", "database.database_name"),
+    ("DB_MAX_CONNECTIONS", "database.max_connections"),
+    ("CACHE_TTL_SECONDS", "cache.ttl_seconds"),
+    ("CACHE_MAX_SIZE", "cache.max_size"),
+    ("CACHE_CLEANUP_INTERVAL", "cache.cleanup_interval"),
+    ("CACHE_STALE_TTL_SECONDS", "cache.stale_ttl_seconds"),
+    ("CACHE_NEGATIVE_TTL_SECONDS", "cache.negative_ttl_seconds"),
+    ("CACHE_BACKEND", "cache.backend"),
+    ("CACHE_REDIS_URL", "cache.redis_url"),
+    ("PERPLEXITY_API_KEY", "api.perplexity_api_key"),
+    ("PERPLEXITY_API_URL", "api.perplexity_api_url"),
+    ("API_TIMEOUT_SECONDS", "api.timeout_seconds"),
+    ("API_MAX_RETRIES", "api.max_retries"),
+    ("API_CIRCUIT_FAILURE_THRESHOLD", "api.circuit_failure_threshold"),
+    ("API_CIRCUIT_OPEN_SECONDS", "api.circuit_open_seconds"),
+    ("DEFAULT_LANGUAGE", "localization.default_language"),
+    ("AVAILABLE_LANGUAGES", "localization.available_languages"),
+    ("FALLBACK_LANGUAGE", "localization.fallback_language"),
+    ("LOG_LEVEL", "logging.level"),
+    ("LOG_FILE_PATH", "logging.file_path"),
+    ("LOG_ROTATION_SIZE", "logging.rotation_size"),
+    ("LOG_MAX_FILES", "logging.max_files"),
+    ("LOG_FORMAT", "logging.format"),
+    ("LOG_REDACT", "logging.redact"),
+    ("RULES_INTENT_PATTERNS_PATH", "rules.intent_patterns_path"),
+    ("RULES_GIFT_RULES_PATH", "rules.gift_rules_path"),
+    ("CONFIG_RELOAD_INTERVAL", "rules.reload_interval_seconds"),
+    ("WS_PING_INTERVAL_SECONDS", "websocket.ping_interval_seconds"),
+    ("WS_PONG_TIMEOUT_SECONDS", "websocket.pong_timeout_seconds"),
+    ("WS_IDLE_TIMEOUT_SECONDS", "websocket.idle_timeout_seconds"),
+    ("WS_MAX_SESSION_SECONDS", "websocket.max_session_seconds"),
+    ("WS_MAX_CONNECTIONS_PER_IP", "websocket.max_connections_per_ip"),
+    ("WS_MAX_CONNECTIONS_PER_USER", "websocket.max_connections_per_user"),
+    ("WS_MAX_MESSAGE_BYTES", "websocket.max_message_bytes"),
+    ("WS_OUTBOUND_QUEUE_SIZE", "websocket.outbound_queue_size"),
+    ("WS_MAX_PENDING_MESSAGES", "websocket.max_pending_messages"),
+    ("PUBSUB_BACKEND", "pubsub.backend"),
+    ("PUBSUB_CHANNEL", "pubsub.channel"),
+    ("AUTH_TOKEN_SECRET", "auth.token_secret"),
+    ("AUTH_TOKEN_TTL_SECONDS", "auth.token_ttl_seconds"),
+    ("RATE_LIMIT_USER_BURST", "rate_limit.per_user.burst"),
+    ("RATE_LIMIT_USER_PER_MINUTE", "rate_limit.per_user.per_minute"),
+    ("RATE_LIMIT_API_KEY_BURST", "rate_limit.per_api_key.burst"),
+    ("RATE_LIMIT_API_KEY_PER_MINUTE", "rate_limit.per_api_key.per_minute"),
+    ("RATE_LIMIT_IP_BURST", "rate_limit.per_ip.burst"),
+    ("RATE_LIMIT_IP_PER_MINUTE", "rate_limit.per_ip.per_minute"),
+    ("LLM_DAILY_CALLS_PER_USER", "rate_limit.daily_llm_calls_per_user"),
+    ("LLM_DAILY_TOKENS_PER_USER", "rate_limit.daily_llm_tokens_per_user"),
+    ("LLM_PROMPT_TEMPLATE_PATH", "llm.prompt_template_path"),
+];
+
+/// 名前付きの一覧の項目に対応する環境変数（接頭辞、一覧のパス、項目、接尾辞）
+///
+/// `LLM_PROVIDERS_<NAME>_API_KEY` のように、`<NAME>` には項目の `name` を大文字にし、英数字以外を `_` にしたものを入れる。
+/// 設定ファイルにある項目の値だけを上書きする。
+pub const NAMED_ENV_MAPPINGS: &[(&str, &str, &str, &str)] = &[
+    ("AUTH_API_KEYS_", "auth.api_keys", "key", "_KEY"),
+    ("LLM_PROVIDERS_", "llm.providers", "api_key", "_API_KEY"),
+];
+
+/// 設定の読み込み・検証で見つかった問題の一覧
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigErrors(pub Vec<String>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Invalid configuration ({} problem(s)):", self.0.len())?;
+        for error in &self.0 {
+            writeln!(f, "  - {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+/// 複数の設定ソースを優先順位に従ってマージする
+///
+/// 優先順位（後のものが優先）:
+/// 1. 組み込みのデフォルト値
+/// 2. 基本設定ファイル（`--config` / `CONFIG_FILE`、なければ `config/default.*`）
+/// 3. 環境別プロファイル（`config/{environment}.*`）
+/// 4. 環境変数
+/// 5. コマンドライン引数（`--port`、`--host`、`--set key.path=value`）
+///
+/// ファイルは拡張子に応じて TOML / YAML / JSON として読み込む。
+/// 環境変数は `PERPLEXITY_API_KEY_FILE` のように `_FILE` を付けると、そのファイルの内容を値として使う。
+/// 一覧の項目の機密値は [`NAMED_ENV_MAPPINGS`] の環境変数で指定する。
+#[derive(Debug, Clone, Default)]
+pub struct ConfigLoader {
+    config_dir: Option<PathBuf>,
+    config_file: Option<PathBuf>,
+    use_profiles: bool,
+    env: HashMap<String, String>,
+    args: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct CliOverrides {
+    config_file: Option<PathBuf>,
+    environment: Option<String>,
+    values: Vec<(String, String)>,
+}
+
+impl ConfigLoader {
+    pub fn new() -> Self {
+        Self {
+            use_profiles: true,
+            ..Self::default()
+        }
+    }
+
+    /// `.env`、プロセスの環境変数、コマンドライン引数を読み込み対象にする
+    pub fn from_process() -> Self {
+        dotenv::dotenv().ok();
+        Self::new()
+            .with_env(env::vars())
+            .with_args(env::args().skip(1))
+    }
+
+    pub fn with_env(mut self, vars: impl IntoIterator<Item = (String, String)>) -> Self {
+        self.env.extend(vars);
+        self
+    }
+
+    pub fn with_args(mut self, args: impl IntoIterator<Item = String>) -> Self {
+        self.args.extend(args);
+        self
+    }
+
+    pub fn with_config_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config_dir = Some(dir.into());
+        self
+    }
+
+    pub fn with_config_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config_file = Some(path.into());
+        self
+    }
+
+    /// 環境別プロファイルの読み込みを無効にする
+    pub fn without_profiles(mut self) -> Self {
+        self.use_profiles = false;
+        self
+    }
+
+    /// すべてのソースをマージして検証する。問題はまとめて返す
+    pub fn load(&self) -> Result<Config, ConfigErrors> {
+        let mut errors = Vec::new();
+        let defaults = serde_json::to_value(Config::default())
+            .expect("default config must serialize");
+        let mut merged = defaults.clone();
+
+        let cli = self.parse_args(&mut errors);
+
+        // 基本設定ファイル
+        let config_dir = self.config_dir();
+        match self.base_file(&cli) {
+            Some(path) => {
+                if let Some(value) = read_file(&path, &mut errors) {
+                    merge(&mut merged, value);
+                }
+            }
+            None => {
+                if let Some(path) = find_file(&config_dir, "default") {
+                    if let Some(value) = read_file(&path, &mut errors) {
+                        merge(&mut merged, value);
+                    }
+                }
+            }
+        }
+
+        // 環境別プロファイル
+        let environment = cli
+            .environment
+            .clone()
+            .or_else(|| self.env.get("ENVIRONMENT").cloned())
+            .or_else(|| merged.get("environment").and_then(Value::as_str).map(String::from))
+            .unwrap_or_else(|| "development".to_string());
+        if self.use_profiles {
+            if let Some(path) = find_file(&config_dir, &environment) {
+                if let Some(value) = read_file(&path, &mut errors) {
+                    merge(&mut merged, value);
+                }
+            }
+        }
+
+        // 環境変数（`NAME_FILE` があればファイルの内容を値とする）
+        for (name, path) in ENV_MAPPINGS {
+            let Some(raw) = self.env_value(name, &mut errors) else { continue };
+            match coerce(&raw, lookup(&defaults, path)) {
+                Ok(value) => set_path(&mut merged, path, value),
+                Err(e) => errors.push(format!("{}: {}", name, e)),
+            }
+        }
+        self.apply_named_env(&mut merged, &mut errors);
+
+        // コマンドライン引数
+        if let Some(environment) = cli.environment {
+            set_path(&mut merged, "environment", Value::String(environment));
+        }
+        for (path, raw) in cli.values {
+            if lookup(&defaults, &path).is_none() {
+                errors.push(format!("--set {}: unknown configuration key", path));
+                continue;
+            }
+            match coerce(&raw, lookup(&defaults, &path)) {
+                Ok(value) => set_path(&mut merged, &path, value),
+                Err(e) => errors.push(format!("--set {}: {}", path, e)),
+            }
+        }
+
+        let config = match serde_json::from_value::<Config>(merged) {
+            Ok(config) => Some(config),
+            Err(e) => {
+                errors.push(format!("Failed to build configuration: {}", e));
+                None
+            }
+        };
+
+        if let Some(config) = &config {
+            errors.extend(config.validate());
+        }
+
+        match config {
+            Some(config) if errors.is_empty() => Ok(config),
+            _ => Err(ConfigErrors(errors)),
+        }
+    }
+
+    /// 読み込み対象になりうる設定ファイルの一覧（変更の監視用）
+    ///
+    /// まだ存在しないファイルも含め、作成されたときに検知できるようにする。
+    pub fn watched_files(&self, environment: &str) -> Vec<PathBuf> {
+        let cli = self.parse_args(&mut Vec::new());
+        let config_dir = self.config_dir();
+        let mut files = match self.base_file(&cli) {
+            Some(path) => vec![path],
+            None => candidates(&config_dir, "default").collect(),
+        };
+        if self.use_profiles {
+            files.extend(candidates(&config_dir, environment));
+        }
+        files
+    }
+
+    fn config_dir(&self) -> PathBuf {
+        self.config_dir
+            .clone()
+            .or_else(|| self.env.get("CONFIG_DIR").map(PathBuf::from))
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_DIR))
+    }
+
+    fn base_file(&self, cli: &CliOverrides) -> Option<PathBuf> {
+        cli.config_file
+            .clone()
+            .or_else(|| self.config_file.clone())
+            .or_else(|| self.env.get("CONFIG_FILE").map(PathBuf::from))
+    }
+
+    // Docker / Kubernetes のシークレットのマウントに合わせ、`NAME_FILE` が指すファイルからも値を読む
+    fn env_value(&self, name: &str, errors: &mut Vec<String>) -> Option<String> {
+        let file_var = format!("{}_FILE", name);
+        match (self.env.get(name), self.env.get(&file_var)) {
+            (Some(_), Some(_)) => {
+                errors.push(format!("{} and {} must not both be set", name, file_var));
+                None
+            }
+            (Some(raw), None) => Some(raw.clone()),
+            (None, Some(file)) => match fs::read_to_string(file) {
+                Ok(content) => Some(content.trim_end_matches(['\r', '\n']).to_string()),
+                Err(e) => {
+                    errors.push(format!("{}: failed to read {}: {}", file_var, file, e));
+                    None
+                }
+            },
+            (None, None) => None,
+        }
+    }
+
+    // 一覧の項目の値を、項目の名前を含む環境変数で上書きする。どの項目にも当たらない変数は誤りとする
+    fn apply_named_env(&self, merged: &mut Value, errors: &mut Vec<String>) {
+        for (prefix, list, field, suffix) in NAMED_ENV_MAPPINGS {
+            let mut matched = Vec::new();
+            if let Some(Value::Array(items)) = lookup_mut(merged, list) {
+                for item in items.iter_mut() {
+                    let Some(name) = item.get("name").and_then(Value::as_str) else { continue };
+                    let var = format!("{}{}{}", prefix, env_name(name), suffix);
+                    if let Some(value) = self.env_value(&var, errors) {
+                        item[*field] = Value::String(value);
+                    }
+                    matched.push(var);
+                }
+            }
+            let mut unknown: Vec<&String> = self
+                .env
+                .keys()
+                .filter(|var| {
+                    let var = var.strip_suffix("_FILE").unwrap_or(var);
+                    var.starts_with(prefix) && var.ends_with(suffix) && !matched.iter().any(|known| known == var)
+                })
+                .collect();
+            unknown.sort();
+            for var in unknown {
+                errors.push(format!("{}: no {} entry has a matching name", var, list));
+            }
+        }
+    }
+
+    fn parse_args(&self, errors: &mut Vec<String>) -> CliOverrides {
+        let mut cli = CliOverrides::default();
+        let mut args = self.args.iter();
+
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag, Some(value.to_string())),
+                _ => (arg.as_str(), None),
+            };
+            let mut value = || inline.clone().or_else(|| args.next().cloned());
+
+            match flag {
+                "--config" | "-c" => match value() {
+                    Some(path) => cli.config_file = Some(PathBuf::from(path)),
+                    None => errors.push("--config requires a path".to_string()),
+                },
+                "--env" | "--profile" => match value() {
+                    Some(environment) => cli.environment = Some(environment),
+                    None => errors.push(format!("{} requires a name", flag)),
+                },
+                "--host" => match value() {
+                    Some(host) => cli.values.push(("server_host".to_string(), host)),
+                    None => errors.push("--host requires a value".to_string()),
+                },
+                "--port" | "-p" => match value() {
+                    Some(port) => cli.values.push(("server_port".to_string(), port)),
+                    None => errors.push("--port requires a value".to_string()),
+                },
+                "--set" => match value().as_deref().and_then(|kv| kv.split_once('=')) {
+                    Some((path, raw)) => cli.values.push((path.trim().to_string(), raw.to_string())),
+                    None => errors.push("--set expects key.path=value".to_string()),
+                },
+                other => errors.push(format!("Unknown command-line argument: {}", other)),
+            }
+        }
+
+        cli
+    }
+}
+
+fn candidates<'a>(dir: &'a Path, stem: &'a str) -> impl Iterator<Item = PathBuf> + 'a {
+    FILE_EXTENSIONS
+        .iter()
+        .map(move |ext| dir.join(format!("{}.{}", stem, ext)))
+}
+
+fn find_file(dir: &Path, stem: &str) -> Option<PathBuf> {
+    candidates(dir, stem).find(|path| path.is_file())
+}
+
+/// TOML / YAML / JSON のファイルを読み込む。ルールファイルの読み込みにも使う
+pub(crate) fn read_file(path: &Path, errors: &mut Vec<String>) -> Option<Value> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            errors.push(format!("{}: failed to read config file: {}", path.display(), e));
+            return None;
+        }
+    };
+
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("json");
+    let parsed = match extension {
+        "toml" => toml::from_str::<Value>(&content).map_err(|e| e.to_string()),
+        "yaml" | "yml" => serde_yaml::from_str::<Value>(&content).map_err(|e| e.to_string()),
+        _ => serde_json::from_str::<Value>(&content).map_err(|e| e.to_string()),
+    };
+
+    match parsed {
+        Ok(value) if value.is_object() => Some(value),
+        Ok(_) => {
+            errors.push(format!("{}: config file must contain a table at the top level", path.display()));
+            None
+        }
+        Err(e) => {
+            errors.push(format!("{}: failed to parse config file: {}", path.display(), e));
+            None
+        }
+    }
+}
+
+// オブジェクトは再帰的にマージし、それ以外の値は上書きする
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+fn lookup<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(value, |current, key| current.get(key))
+}
+
+fn lookup_mut<'a>(value: &'a mut Value, path: &str) -> Option<&'a mut Value> {
+    path.split('.').try_fold(value, |current, key| current.get_mut(key))
+}
+
+// 一覧の項目の名前を環境変数名に使える形にする（`openrouter-eu` なら `OPENROUTER_EU`）
+fn env_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect()
+}
+
+fn set_path(value: &mut Value, path: &str, new_value: Value) {
+    let mut current = value;
+    let mut keys = path.split('.').peekable();
+    while let Some(key) = keys.next() {
+        if !current.is_object() {
+            *current = Value::Object(Map::new());
+        }
+        let map = current.as_object_mut().expect("checked above");
+        if keys.peek().is_none() {
+            map.insert(key.to_string(), new_value);
+            return;
+        }
+        current = map.entry(key.to_string()).or_insert_with(|| Value::Object(Map::new()));
+    }
+}
+
+// 文字列の値をデフォルト値と同じ型に変換する
+fn coerce(raw: &str, template: Option<&Value>) -> Result<Value, String> {
+    match template {
+        Some(Value::Number(_)) => {
+            let raw = raw.trim();
+            raw.parse::<u64>()
+                .map(Value::from)
+                .or_else(|_| raw.parse::<i64>().map(Value::from))
+                .or_else(|_| raw.parse::<f64>().map(Value::from))
+                .map_err(|_| format!("expected a number, got {:?}", raw))
+        }
+        Some(Value::Bool(_)) => raw
+            .trim()
+            .parse::<bool>()
+            .map(Value::Bool)
+            .map_err(|_| format!("expected true or false, got {:?}", raw)),
+        Some(Value::Array(_)) => Ok(Value::Array(
+            raw.split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| Value::String(item.to_string()))
+                .collect(),
+        )),
+        _ => Ok(Value::String(raw.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_layer_precedence() {
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join("default.toml"),
+            "server_port = 9000\n[cache]\nttl_seconds = 120\nmax_size = 10\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("staging.yaml"),
+            "cache:\n  max_size: 20\nlogging:\n  level: debug\n",
+        )
+        .unwrap();
+
+        let config = ConfigLoader::new()
+            .with_config_dir(dir.path())
+            .with_env(env(&[
+                ("ENVIRONMENT", "staging"),
+                ("PERPLEXITY_API_KEY", "key"),
+                ("CACHE_TTL_SECONDS", "300"),
+                ("AVAILABLE_LANGUAGES", "ja, en"),
+            ]))
+            .with_args(args(&["--port", "9100", "--set", "logging.level=warn"]))
+            .load()
+            .unwrap();
+
+        assert_eq!(config.environment, "staging");
+        assert_eq!(config.server_port, 9100);
+        assert_eq!(config.cache.ttl_seconds, 300);
+        assert_eq!(config.cache.max_size, 20);
+        assert_eq!(config.logging.level, "warn");
+        assert_eq!(config.localization.available_languages, vec!["ja", "en"]);
+        // ファイルで指定されていない項目はデフォルト値のまま
+        assert_eq!(config.cache.cleanup_interval, 300);
+    }
+
+    #[test]
+    fn test_all_problems_are_reported_together() {
+        let dir = tempdir().unwrap();
+        let errors = ConfigLoader::new()
+            .with_config_dir(dir.path())
+            .with_env(env(&[
+                ("SERVER_PORT", "0"),
+                ("DB_PORT", "not-a-port"),
+                ("AVAILABLE_LANGUAGES", "ja,english"),
+                ("PERPLEXITY_API_URL", "ftp://example.com"),
+            ]))
+            .with_args(args(&["--unknown"]))
+            .load()
+            .unwrap_err();
+
+        let joined = errors.0.join("\n");
+        assert!(joined.contains("--unknown"), "{}", joined);
+        assert!(joined.contains("DB_PORT"), "{}", joined);
+        assert!(joined.contains("server_port"), "{}", joined);
+        assert!(joined.contains("english"), "{}", joined);
+        assert!(joined.contains("perplexity_api_url"), "{}", joined);
+        assert!(joined.contains("perplexity_api_key"), "{}", joined);
+    }
+
+    #[test]
+    fn test_secrets_from_file_variables() {
+        let dir = tempdir().unwrap();
+        let key_file = dir.path().join("perplexity_api_key");
+        fs::write(&key_file, "pplx-from-file\n").unwrap();
+
+        let config = ConfigLoader::new()
+            .with_config_dir(dir.path())
+            .with_env(env(&[("PERPLEXITY_API_KEY_FILE", key_file.to_str().unwrap())]))
+            .load()
+            .unwrap();
+        assert_eq!(config.api.perplexity_api_key.expose(), "pplx-from-file");
+
+        let errors = ConfigLoader::new()
+            .with_config_dir(dir.path())
+            .with_env(env(&[
+                ("PERPLEXITY_API_KEY", "pplx-direct"),
+                ("PERPLEXITY_API_KEY_FILE", key_file.to_str().unwrap()),
+                ("DB_PASSWORD_FILE", "/nonexistent/db_password"),
+            ]))
+            .load()
+            .unwrap_err();
+        let joined = errors.0.join("\n");
+        assert!(joined.contains("must not both be set"), "{}", joined);
+        assert!(joined.contains("DB_PASSWORD_FILE"), "{}", joined);
+        assert!(!joined.contains("pplx-"), "{}", joined);
+
+        // 一覧の項目の機密値は、項目の名前を含む環境変数で指定する
+        let provider_key = dir.path().join("openrouter_api_key");
+        fs::write(&provider_key, "sk-from-file\n").unwrap();
+        fs::write(
+            dir.path().join("default.toml"),
+            "[[auth.api_keys]]\nname = \"partner-a\"\n\n[[llm.providers]]\nname = \"openrouter\"\napi_url = \"https://openrouter.ai/api/v1/chat/completions\"\n",
+        )
+        .unwrap();
+        let config = ConfigLoader::new()
+            .with_config_dir(dir.path())
+            .with_env(env(&[
+                ("PERPLEXITY_API_KEY", "pplx-direct"),
+                ("AUTH_API_KEYS_PARTNER_A_KEY", "partner-a-key-0123456789abcdef"),
+                ("LLM_PROVIDERS_OPENROUTER_API_KEY_FILE", provider_key.to_str().unwrap()),
+            ]))
+            .load()
+            .unwrap();
+        assert_eq!(config.auth.api_keys[0].key.expose(), "partner-a-key-0123456789abcdef");
+        assert_eq!(config.llm.providers[0].api_key.expose(), "sk-from-file");
+
+        let errors = ConfigLoader::new()
+            .with_config_dir(dir.path())
+            .with_env(env(&[("PERPLEXITY_API_KEY", "pplx-direct"), ("LLM_PROVIDERS_MISSING_API_KEY", "sk-x")]))
+            .load()
+            .unwrap_err();
+        let joined = errors.0.join("\n");
+        assert!(joined.contains("LLM_PROVIDERS_MISSING_API_KEY: no llm.providers entry"), "{}", joined);
+    }
+}
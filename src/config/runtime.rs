@@ -0,0 +1,410 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use super::config::Config;
+use super::loader::{read_file, ConfigErrors, ConfigLoader};
+use crate::app::gift::rules::GiftRules;
+use crate::app::nlp::intent_classifier::{Intent, IntentClassifier};
+
+// 再起動せずに反映できる設定項目。これ以外の変更は再起動まで反映されない
+const RELOADABLE_PATHS: &[&str] = &[
+    "logging.level",
+    "cache.ttl_seconds",
+    "cache.stale_ttl_seconds",
+    "cache.negative_ttl_seconds",
+    "rules.intent_patterns_path",
+    "rules.gift_rules_path",
+];
+
+/// ある時点で有効な設定とルールの組
+#[derive(Debug, Clone)]
+pub struct RuntimeSnapshot {
+    /// 読み込みのたびに1ずつ増える。内容が変わらない再読み込みでは増えない
+    pub version: u64,
+    /// 設定とルールの内容から計算した値。機密値は含まない
+    pub fingerprint: String,
+    pub loaded_at: SystemTime,
+    pub config: Config,
+    pub intent_classifier: IntentClassifier,
+    pub gift_rules: GiftRules,
+}
+
+/// 現在有効な設定の概要（管理用エンドポイントの応答）
+#[derive(Debug, Clone, Serialize)]
+pub struct RuntimeStatus {
+    pub version: u64,
+    pub fingerprint: String,
+    /// 読み込み時刻（UNIX時間の秒）
+    pub loaded_at: u64,
+    pub environment: String,
+    pub watched_files: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReloadOutcome {
+    pub version: u64,
+    pub fingerprint: String,
+    pub changed: bool,
+    /// 変更されたが、反映に再起動が必要な設定項目
+    pub restart_required: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IntentPatternsFile {
+    patterns: BTreeMap<Intent, Vec<String>>,
+}
+
+/// 実行中に再読み込みできる設定
+///
+/// 設定ファイルとルール・パターンファイルをまとめて読み込み、検証に通った場合だけ
+/// スナップショットを一度に差し替える。不正なファイルは拒否し、それまでの設定を使い続ける。
+/// 各コンポーネントは [`RuntimeConfig::on_reload`] で変更を受け取る。
+pub struct RuntimeConfig {
+    loader: ConfigLoader,
+    sender: watch::Sender<Arc<RuntimeSnapshot>>,
+    // 再読み込みが同時に走らないようにする
+    reload_lock: Mutex<()>,
+}
+
+impl std::fmt::Debug for RuntimeConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RuntimeConfig")
+            .field("version", &self.current().version)
+            .finish()
+    }
+}
+
+impl RuntimeConfig {
+    pub fn new(loader: ConfigLoader) -> Result<Self, ConfigErrors> {
+        let snapshot = load_snapshot(&loader, 1)?;
+        let (sender, _) = watch::channel(Arc::new(snapshot));
+        Ok(Self {
+            loader,
+            sender,
+            reload_lock: Mutex::new(()),
+        })
+    }
+
+    pub fn current(&self) -> Arc<RuntimeSnapshot> {
+        self.sender.borrow().clone()
+    }
+
+    pub fn status(&self) -> RuntimeStatus {
+        let current = self.current();
+        RuntimeStatus {
+            version: current.version,
+            fingerprint: current.fingerprint.clone(),
+            loaded_at: current
+                .loaded_at
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or(0),
+            environment: current.config.environment.clone(),
+            watched_files: self.watched_files(&current),
+        }
+    }
+
+    /// すべてのソースを読み直し、問題がなければ差し替える
+    pub fn reload(&self) -> Result<ReloadOutcome, ConfigErrors> {
+        let _guard = self.reload_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let current = self.current();
+
+        let next = match load_snapshot(&self.loader, current.version + 1) {
+            Ok(next) => next,
+            Err(errors) => {
+                tracing::warn!(
+                    "Rejected configuration reload, keeping version {}: {}",
+                    current.version,
+                    errors
+                );
+                return Err(errors);
+            }
+        };
+
+        if next.fingerprint == current.fingerprint {
+            return Ok(ReloadOutcome {
+                version: current.version,
+                fingerprint: current.fingerprint.clone(),
+                changed: false,
+                restart_required: Vec::new(),
+            });
+        }
+
+        let restart_required = restart_required(&current.config, &next.config);
+        if !restart_required.is_empty() {
+            tracing::warn!(
+                "Configuration changes require a restart to take effect: {}",
+                restart_required.join(", ")
+            );
+        }
+        let outcome = ReloadOutcome {
+            version: next.version,
+            fingerprint: next.fingerprint.clone(),
+            changed: true,
+            restart_required,
+        };
+        tracing::info!("Configuration reloaded (version {})", next.version);
+        self.sender.send_replace(Arc::new(next));
+        Ok(outcome)
+    }
+
+    /// 設定が差し替えられるたびに `apply` を呼び出すタスクを起動する
+    pub fn on_reload<F>(&self, apply: F) -> JoinHandle<()>
+    where
+        F: Fn(&RuntimeSnapshot) + Send + 'static,
+    {
+        let mut receiver = self.sender.subscribe();
+        tokio::spawn(async move {
+            while receiver.changed().await.is_ok() {
+                let snapshot = receiver.borrow_and_update().clone();
+                apply(&snapshot);
+            }
+        })
+    }
+
+    /// 設定・ルールファイルの更新を一定間隔で確認し、変更があれば再読み込みする
+    ///
+    /// タスクは弱参照だけを持ち、`RuntimeConfig` が破棄されると終了する。
+    pub fn spawn_watcher(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let weak: Weak<Self> = Arc::downgrade(self);
+        let mut last_seen = file_states(&self.watched_files(&self.current()));
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let Some(runtime) = weak.upgrade() else { break };
+                let states = file_states(&runtime.watched_files(&runtime.current()));
+                if states == last_seen {
+                    continue;
+                }
+                last_seen = states;
+                tracing::info!("Configuration files changed on disk, reloading");
+                // 失敗時は reload 内でログを出し、現在の設定を使い続ける
+                let _ = tokio::task::spawn_blocking(move || runtime.reload()).await;
+            }
+        })
+    }
+
+    fn watched_files(&self, snapshot: &RuntimeSnapshot) -> Vec<PathBuf> {
+        let mut files = self.loader.watched_files(&snapshot.config.environment);
+        files.push(snapshot.config.rules.intent_patterns_path.clone());
+        files.push(snapshot.config.rules.gift_rules_path.clone());
+        files
+    }
+}
+
+fn load_snapshot(loader: &ConfigLoader, version: u64) -> Result<RuntimeSnapshot, ConfigErrors> {
+    let config = loader.load()?;
+    let mut errors = Vec::new();
+
+    let intent_classifier = load_rule_file::<IntentPatternsFile>(&config.rules.intent_patterns_path, &mut errors)
+        .map(|file| IntentClassifier::from_patterns(file.patterns))
+        .unwrap_or_else(IntentClassifier::new);
+    errors.extend(
+        intent_classifier
+            .validate()
+            .into_iter()
+            .map(|e| format!("{}: {}", config.rules.intent_patterns_path.display(), e)),
+    );
+
+    let gift_rules = load_rule_file::<GiftRules>(&config.rules.gift_rules_path, &mut errors)
+        .unwrap_or_default();
+    errors.extend(
+        gift_rules
+            .validate()
+            .into_iter()
+            .map(|e| format!("{}: {}", config.rules.gift_rules_path.display(), e)),
+    );
+
+    if !errors.is_empty() {
+        return Err(ConfigErrors(errors));
+    }
+
+    let fingerprint = fingerprint(&config, &intent_classifier, &gift_rules);
+    Ok(RuntimeSnapshot {
+        version,
+        fingerprint,
+        loaded_at: SystemTime::now(),
+        config,
+        intent_classifier,
+        gift_rules,
+    })
+}
+
+// ファイルがなければ組み込みのデフォルトを使う
+fn load_rule_file<T: serde::de::DeserializeOwned>(path: &Path, errors: &mut Vec<String>) -> Option<T> {
+    if !path.is_file() {
+        return None;
+    }
+    let value = read_file(path, errors)?;
+    match serde_json::from_value(value) {
+        Ok(parsed) => Some(parsed),
+        Err(e) => {
+            errors.push(format!("{}: {}", path.display(), e));
+            None
+        }
+    }
+}
+
+fn fingerprint(config: &Config, intent_classifier: &IntentClassifier, gift_rules: &GiftRules) -> String {
+    let mut hasher = DefaultHasher::new();
+    // 機密値はシリアライズ時にマスクされるため、値そのものを別にハッシュへ含める
+    serde_json::to_string(config).unwrap_or_default().hash(&mut hasher);
+    for (path, secret) in config.secrets() {
+        (path, secret.expose()).hash(&mut hasher);
+    }
+    serde_json::to_string(&intent_classifier.patterns()).unwrap_or_default().hash(&mut hasher);
+    serde_json::to_string(gift_rules).unwrap_or_default().hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+fn restart_required(current_config: &Config, next_config: &Config) -> Vec<String> {
+    let (Ok(current), Ok(next)) = (serde_json::to_value(current_config), serde_json::to_value(next_config)) else {
+        return Vec::new();
+    };
+    let mut changed = Vec::new();
+    diff_paths("", &current, &next, &mut changed);
+    // 機密値は変更された項目のパスだけを報告し、値は出さない
+    let current_secrets: BTreeMap<String, &str> =
+        current_config.secrets().into_iter().map(|(path, secret)| (path, secret.expose())).collect();
+    for (path, secret) in next_config.secrets() {
+        if current_secrets.get(&path) != Some(&secret.expose()) && !changed.contains(&path) {
+            changed.push(path);
+        }
+    }
+    changed.retain(|path| !RELOADABLE_PATHS.contains(&path.as_str()));
+    changed
+}
+
+// 値が異なる末端の項目のパスを集める
+fn diff_paths(prefix: &str, current: &Value, next: &Value, changed: &mut Vec<String>) {
+    match (current, next) {
+        (Value::Object(current), Value::Object(next)) => {
+            let keys: std::collections::BTreeSet<&String> = current.keys().chain(next.keys()).collect();
+            for key in keys {
+                let path = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                diff_paths(
+                    &path,
+                    current.get(key).unwrap_or(&Value::Null),
+                    next.get(key).unwrap_or(&Value::Null),
+                    changed,
+                );
+            }
+        }
+        (current, next) if current != next => changed.push(prefix.to_string()),
+        _ => {}
+    }
+}
+
+// 更新の検知に使う、各ファイルの更新時刻とサイズ（存在しない場合は None）
+fn file_states(paths: &[PathBuf]) -> Vec<Option<(SystemTime, u64)>> {
+    paths
+        .iter()
+        .map(|path| {
+            let metadata = fs::metadata(path).ok()?;
+            Some((metadata.modified().ok()?, metadata.len()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn loader(dir: &Path) -> ConfigLoader {
+        let env = [
+            ("PERPLEXITY_API_KEY", "key".to_string()),
+            ("RULES_INTENT_PATTERNS_PATH", dir.join("intent_patterns.toml").display().to_string()),
+            ("RULES_GIFT_RULES_PATH", dir.join("gift_rules.toml").display().to_string()),
+        ];
+        ConfigLoader::new()
+            .with_config_dir(dir)
+            .with_env(env.into_iter().map(|(k, v)| (k.to_string(), v)))
+    }
+
+    #[tokio::test]
+    async fn test_reload_swaps_valid_config_and_keeps_it_on_error() {
+        let dir = tempdir().unwrap();
+        let runtime = RuntimeConfig::new(loader(dir.path())).unwrap();
+        let receiver = runtime.sender.subscribe();
+        assert_eq!(runtime.current().version, 1);
+
+        // 変更がなければバージョンは変わらない
+        assert!(!runtime.reload().unwrap().changed);
+
+        fs::write(
+            dir.path().join("default.toml"),
+            "server_port = 9000\n[logging]\nlevel = \"debug\"\n[cache]\nttl_seconds = 60\nmax_size = 1000\ncleanup_interval = 300\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("intent_patterns.toml"),
+            "[patterns]\nGreeting = [\"やあ\"]\n",
+        )
+        .unwrap();
+        let outcome = runtime.reload().unwrap();
+        assert_eq!((outcome.version, outcome.changed), (2, true));
+        assert_eq!(outcome.restart_required, ["server_port"]);
+        assert!(receiver.has_changed().unwrap());
+
+        let current = runtime.current();
+        assert_eq!(current.config.logging.level, "debug");
+        assert_eq!(current.config.cache.ttl_seconds, 60);
+        assert_eq!(current.intent_classifier.classify("やあ"), Intent::Greeting);
+
+        // 不正なルールは拒否され、それまでの設定が残る
+        fs::write(dir.path().join("gift_rules.toml"), "[budget]\ntolerance_percent = 500\n").unwrap();
+        let errors = runtime.reload().unwrap_err();
+        assert!(errors.0[0].contains("tolerance_percent"), "{}", errors);
+        fs::write(dir.path().join("intent_patterns.toml"), "[patterns]\nGreeting = \"やあ\"\n").unwrap();
+        assert!(runtime.reload().is_err());
+        assert_eq!(runtime.current().version, 2);
+        assert_eq!(runtime.current().gift_rules, GiftRules::default());
+    }
+
+    #[test]
+    fn test_rotated_secret_is_detected_without_exposing_it() {
+        let dir = tempdir().unwrap();
+        let runtime = RuntimeConfig::new(loader(dir.path())).unwrap();
+        let rotated = loader(dir.path())
+            .with_env([("PERPLEXITY_API_KEY".to_string(), "rotated".to_string())])
+            .load()
+            .unwrap();
+
+        let current = runtime.current();
+        assert_ne!(
+            fingerprint(&current.config, &current.intent_classifier, &current.gift_rules),
+            fingerprint(&rotated, &current.intent_classifier, &current.gift_rules)
+        );
+        let changed = restart_required(&current.config, &rotated);
+        assert_eq!(changed, ["api.perplexity_api_key"]);
+    }
+
+    #[test]
+    fn test_bundled_rule_files_match_defaults() {
+        let mut errors = Vec::new();
+        let rules: GiftRules = load_rule_file(Path::new("config/rules/gift_rules.toml"), &mut errors).unwrap();
+        let patterns: IntentPatternsFile =
+            load_rule_file(Path::new("config/rules/intent_patterns.toml"), &mut errors).unwrap();
+
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(rules, GiftRules::default());
+        assert_eq!(patterns.patterns, IntentClassifier::new().patterns());
+    }
+}
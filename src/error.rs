@@ -1,9 +1,10 @@
 use axum::{
-    http::StatusCode,
+    http::{header, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
 use serde_json::json;
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -23,6 +24,12 @@ pub enum AppError {
     #[error("認証エラー: {0}")]
     Auth(String),
 
+    #[error("権限エラー: {0}")]
+    Forbidden(String),
+
+    #[error("利用の上限に達しました: {message}")]
+    RateLimited { message: String, retry_after: Duration },
+
     #[error("内部エラー: {0}")]
     Internal(String),
 }
@@ -44,12 +51,28 @@ impl IntoResponse for AppError {
             ),
             AppError::Validation(ref msg) => (StatusCode::BAD_REQUEST, msg.clone()),
             AppError::Auth(ref msg) => (StatusCode::UNAUTHORIZED, msg.clone()),
+            AppError::Forbidden(ref msg) => (StatusCode::FORBIDDEN, msg.clone()),
+            AppError::RateLimited { ref message, .. } => (StatusCode::TOO_MANY_REQUESTS, message.clone()),
             AppError::Internal(ref msg) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 format!("内部エラーが発生しました: {}", msg),
             ),
         };
 
+        // 上限に達した場合は、再試行できるまでの秒数を `Retry-After` と本文で返す
+        if let AppError::RateLimited { retry_after, .. } = self {
+            let seconds = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+            let body = Json(json!({
+                "error": {
+                    "message": error_message,
+                    "code": status.as_u16(),
+                    "type": "RATE_LIMITED",
+                    "retry_after_seconds": seconds
+                }
+            }));
+            return (status, [(header::RETRY_AFTER, seconds.to_string())], body).into_response();
+        }
+
         let body = Json(json!({
             "error": {
                 "message": error_message,
@@ -57,6 +80,10 @@ impl IntoResponse for AppError {
             }
         }));
 
+        // 認証が必要なことと、その方式をクライアントに伝える
+        if status == StatusCode::UNAUTHORIZED {
+            return (status, [(header::WWW_AUTHENTICATE, "Bearer")], body).into_response();
+        }
         (status, body).into_response()
     }
 }
@@ -2,19 +2,73 @@ pub mod app {
     pub mod chat {
         pub mod chatbot;
         pub mod conversation_handler;
+        pub mod history;
+        pub mod message;
+        pub mod protocol;
+        pub mod responder;
+        pub mod session;
     }
     pub mod nlp {
         pub mod intent_classifier;
     }
     pub mod gift {
         pub mod recommendation;
+        pub mod rules;
+        pub mod catalog;
+        pub mod circuit_breaker;
+        pub mod parser;
+        pub mod quota;
+        pub mod llm;
+        pub mod citation;
+        pub mod prompt;
+        pub mod sanitize;
+        pub mod archive;
     }
     pub mod database {
         pub mod user_record;
         pub mod gift_cache;
+        pub mod cache_backend;
+        pub mod redis_cache;
+        pub mod pool;
     }
+    pub mod auth;
+    pub mod batch;
+    pub mod usage;
+    pub mod pubsub {
+        pub mod event_bus;
+        pub mod pg_event_bus;
+    }
+    pub mod shutdown;
+}
+
+pub mod api {
+    pub mod gift;
+    pub mod chat;
+    pub mod history;
+    pub mod admin;
+    pub mod metrics;
+    pub mod health;
+    pub mod websocket;
+    pub mod sse;
+    pub mod connection_limits;
+    pub mod rate_limit;
+    pub mod auth;
 }
 
 pub mod config {
     pub mod config;
-} 
\ No newline at end of file
+    pub mod loader;
+    pub mod secret;
+    pub mod runtime;
+}
+
+pub mod error;
+
+pub mod metrics;
+
+pub mod logging {
+    pub mod redact;
+    pub mod request;
+    pub mod rolling_file;
+    pub mod subscriber;
+}
@@ -0,0 +1,99 @@
+use axum::http::Request;
+use tracing::field::{self, Empty};
+use tracing::Span;
+
+/// リクエストIDを受け渡しするヘッダー。クライアントが指定しなければサーバーで採番する
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// HTTPリクエストごとのスパン
+///
+/// `user_id` は認証した時点で、`conversation_id` はセッションが決まった時点で
+/// [`record_user`] と [`record_conversation`] で記録する。
+pub fn request_span<B>(request: &Request<B>) -> Span {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+
+    tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        method = %request.method(),
+        path = %request.uri().path(),
+        user_id = Empty,
+        conversation_id = Empty,
+    )
+}
+
+/// 認証した利用者を現在のリクエストのスパンに記録する
+pub fn record_user(user_id: &str) {
+    Span::current().record("user_id", field::display(user_id));
+}
+
+/// 会話（チャットのセッション）を現在のリクエストのスパンに記録する
+pub fn record_conversation(conversation_id: &str) {
+    Span::current().record("conversation_id", field::display(conversation_id));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+    use axum::{routing::get, Router};
+    use tower_http::trace::TraceLayer;
+    use crate::api::gift::AppState;
+    use crate::app::auth::Principal;
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_user_and_conversation_are_recorded_on_the_request_span() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt().with_ansi(false).with_writer(move || writer.clone()).finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let state = AppState::new("test_key".to_string());
+        let token = state.auth().issue("u1").token;
+        let app = Router::new()
+            .route(
+                "/chat",
+                get(|_principal: Principal| async {
+                    record_conversation("c1");
+                    tracing::info!("handled");
+                }),
+            )
+            .layer(TraceLayer::new_for_http().make_span_with(request_span))
+            .with_state(state);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/chat", addr))
+            .bearer_auth(token)
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let line = output.lines().find(|line| line.contains("handled")).unwrap();
+        assert!(line.contains("user_id=u1"), "{}", line);
+        assert!(line.contains("conversation_id=c1"), "{}", line);
+    }
+}
@@ -0,0 +1,138 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// サイズでローテーションするログファイル
+///
+/// 書き込みで `max_size` を超える場合、`app.log` を `app.log.1` に、`app.log.1` を `app.log.2` に…と
+/// 名前を変えてから新しいファイルに書き込む。`max_files` より古いファイルは削除する。
+#[derive(Debug, Clone)]
+pub struct RollingFileWriter {
+    state: Arc<Mutex<RollingState>>,
+}
+
+#[derive(Debug)]
+struct RollingState {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_size: u64,
+    max_files: u32,
+}
+
+impl RollingFileWriter {
+    /// 親ディレクトリがなければ作成し、既存のファイルには追記する
+    pub fn new(path: impl Into<PathBuf>, max_size: u64, max_files: u32) -> io::Result<Self> {
+        let path = path.into();
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let file = open_append(&path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            state: Arc::new(Mutex::new(RollingState {
+                path,
+                file,
+                written,
+                max_size,
+                max_files,
+            })),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, RollingState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl RollingState {
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let oldest = rotated_path(&self.path, self.max_files);
+            if oldest.exists() {
+                fs::remove_file(&oldest)?;
+            }
+            for index in (1..self.max_files).rev() {
+                let from = rotated_path(&self.path, index);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(&self.path, index + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+        self.file = open_append(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated_path(path: &Path, index: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+impl Write for RollingFileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.lock();
+        // 1行が上限より大きい場合でも、空のファイルには書き込む
+        if state.written > 0 && state.written + buf.len() as u64 > state.max_size {
+            if let Err(e) = state.rotate() {
+                eprintln!("Failed to rotate log file {}: {}", state.path.display(), e);
+            }
+        }
+        state.file.write_all(buf)?;
+        state.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock().file.flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for RollingFileWriter {
+    type Writer = RollingFileWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_rotation_and_retention() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("logs").join("app.log");
+        let mut writer = RollingFileWriter::new(&path, 10, 2).unwrap();
+
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            writer.write_all(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(rotated_path(&path, 1)).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(rotated_path(&path, 2)).unwrap(), "second\n");
+        // 保持数を超えた最も古いファイルは削除される
+        assert!(!rotated_path(&path, 3).exists());
+
+        // 再起動後は既存のファイルに追記し、サイズも引き継ぐ
+        let mut reopened = RollingFileWriter::new(&path, 10, 2).unwrap();
+        reopened.write_all(b"fifth\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fifth\n");
+        assert_eq!(fs::read_to_string(rotated_path(&path, 1)).unwrap(), "fourth\n");
+    }
+}
@@ -0,0 +1,84 @@
+use anyhow::{Context, Result};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::{self, MakeWriter};
+use tracing_subscriber::layer::Layered;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{reload, Layer, Registry};
+
+use super::redact::RedactingMakeWriter;
+use super::rolling_file::RollingFileWriter;
+use crate::config::config::{LogFormat, LoggingConfig};
+
+type Subscriber = Layered<reload::Layer<LevelFilter, Registry>, Registry>;
+type BoxedLayer = Box<dyn Layer<Subscriber> + Send + Sync>;
+
+/// 実行中にログレベルを変更するためのハンドル
+#[derive(Debug, Clone)]
+pub struct LogLevelHandle {
+    handle: reload::Handle<LevelFilter, Registry>,
+}
+
+impl LogLevelHandle {
+    pub fn set_level(&self, level: &str) -> Result<()> {
+        let level: LevelFilter = level
+            .parse()
+            .with_context(|| format!("Invalid log level: {}", level))?;
+        self.handle
+            .modify(|filter| *filter = level)
+            .context("Failed to update log level")
+    }
+}
+
+/// `LoggingConfig` に従ってグローバルなサブスクライバーを初期化する
+///
+/// 標準出力に加え、`file_path` が空でなければサイズでローテーションするファイルにも出力する。
+/// `redact` が有効な場合は、どちらの出力でも本文と個人情報をマスクする。
+pub fn init(config: &LoggingConfig) -> Result<LogLevelHandle> {
+    let level: LevelFilter = config
+        .level
+        .parse()
+        .with_context(|| format!("Invalid log level: {}", config.level))?;
+    let (filter, handle) = reload::Layer::new(level);
+
+    let mut layers: Vec<BoxedLayer> = vec![output_layer(config, std::io::stdout, true)];
+    if !config.file_path.as_os_str().is_empty() {
+        let file = RollingFileWriter::new(&config.file_path, config.rotation_size, config.max_files)
+            .with_context(|| format!("Failed to open log file {}", config.file_path.display()))?;
+        layers.push(output_layer(config, file, false));
+    }
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(layers)
+        .try_init()
+        .context("Failed to install the tracing subscriber")?;
+    Ok(LogLevelHandle { handle })
+}
+
+fn output_layer<W>(config: &LoggingConfig, writer: W, ansi: bool) -> BoxedLayer
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    if config.redact {
+        format_layer(config.format, RedactingMakeWriter::new(writer), ansi)
+    } else {
+        format_layer(config.format, writer, ansi)
+    }
+}
+
+fn format_layer<W>(format: LogFormat, writer: W, ansi: bool) -> BoxedLayer
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let layer = fmt::layer().with_writer(writer);
+    match format {
+        LogFormat::Text => layer.with_ansi(ansi).boxed(),
+        // 現在のスパン（リクエストID・ユーザーID・会話ID）をイベントと同じ行に含める
+        LogFormat::Json => layer
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    }
+}
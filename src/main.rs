@@ -1,24 +1,52 @@
-use axum::Router;
-use dotenv::dotenv;
-use std::env;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::future::IntoFuture;
+use std::time::Duration;
+use axum::{middleware, Router};
 use tower_http::cors::{CorsLayer, Any};
-use tracing_subscriber;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+use tower_http::trace::TraceLayer;
 
-mod app;
-mod api;
+use my_project::api;
+use my_project::app::auth::Authenticator;
+use my_project::app::chat::history::{HistoryStore, HistoryWriter, MemoryHistoryStore, PgHistoryStore};
+use my_project::app::database::gift_cache::GiftCache;
+use my_project::app::gift::archive::{PgRecommendationStore, RecommendationArchive};
+use my_project::app::database::pool::Database;
+use my_project::app::gift::circuit_breaker::ProviderCircuits;
+use my_project::app::gift::llm::LlmRouting;
+use my_project::app::gift::prompt::PromptTemplate;
+use my_project::app::gift::quota::LlmQuota;
+use my_project::app::gift::recommendation::GiftRecommender;
+use my_project::app::pubsub::pg_event_bus::PgEventBus;
+use my_project::app::shutdown::{self, Shutdown};
+use my_project::app::usage::{MemoryUsageStore, PgUsageStore, PriceTable, UsageRecorder, UsageStore};
+use my_project::config::config::PubSubBackendKind;
+use my_project::config::loader::ConfigLoader;
+use my_project::config::runtime::RuntimeConfig;
+use my_project::logging::{request, subscriber};
+use my_project::metrics;
 
 #[tokio::main]
 async fn main() {
-    // 環境変数の読み込み
-    dotenv().ok();
+    // 設定の読み込み（ファイル・環境変数・コマンドライン引数・ルールファイル）
+    let runtime = match RuntimeConfig::new(ConfigLoader::from_process()) {
+        Ok(runtime) => Arc::new(runtime),
+        Err(errors) => {
+            eprintln!("{}", errors);
+            std::process::exit(2);
+        }
+    };
+    let config = runtime.current().config.clone();
     
-    // ロギングの初期化
-    tracing_subscriber::fmt::init();
-
-    // Perplexity API キーの取得
-    let perplexity_api_key = env::var("PERPLEXITY_API_KEY")
-        .expect("PERPLEXITY_API_KEY must be set");
+    // ロギングの初期化（ログレベルは再読み込みで変更できる）
+    let log_level = match subscriber::init(&config.logging) {
+        Ok(handle) => handle,
+        Err(e) => {
+            eprintln!("{:?}", e);
+            std::process::exit(2);
+        }
+    };
 
     // CORSの設定
     let cors = CorsLayer::new()
@@ -26,25 +54,195 @@ async fn main() {
         .allow_methods(Any)
         .allow_headers(Any);
 
+    // ギフトキャッシュの初期化（期限切れエントリの定期削除を含む）
+    let gift_cache = GiftCache::from_config(&config.cache)
+        .expect("Failed to initialize gift cache");
+
+    // データベース（接続は最初の利用時に確立し、状態は /readyz で確認する）
+    let database = if config.database.database_name.is_empty() {
+        None
+    } else {
+        Some(Arc::new(Database::connect_lazy(&config.database)))
+    };
+    let (history_store, usage_store): (Arc<dyn HistoryStore>, Arc<dyn UsageStore>) = match &database {
+        Some(database) => (
+            Arc::new(PgHistoryStore::new(database.get_pool())),
+            Arc::new(PgUsageStore::new(database.get_pool())),
+        ),
+        None => (Arc::new(MemoryHistoryStore::new()), Arc::new(MemoryUsageStore::new())),
+    };
+    // 上流の呼び出しごとのトークン数と推定費用はバックグラウンドでまとめて保存する
+    let usage = UsageRecorder::spawn(usage_store, PriceTable::from_config(&config.pricing));
+    // 上流が返した推薦は、プロンプトの版を比べられるようデータベースに残す
+    let archive = database
+        .as_ref()
+        .map(|database| RecommendationArchive::spawn(Arc::new(PgRecommendationStore::new(database.get_pool()))));
+
+    // 推薦のプロンプト（言語ごとの文面と版）
+    let prompt = match PromptTemplate::load(&config.llm.prompt_template_path) {
+        Ok(prompt) => prompt.with_fallback_language(&config.localization.fallback_language),
+        Err(e) => {
+            eprintln!("{:?}", e);
+            std::process::exit(2);
+        }
+    };
+    tracing::info!("Prompt template: {}", prompt.version());
+
     // アプリケーション状態の初期化
-    let app_state = api::gift::AppState::new(perplexity_api_key);
+    let mut recommender = GiftRecommender::with_cache(
+        config.api.perplexity_api_key.expose().to_string(),
+        gift_cache.clone(),
+    )
+    .with_llm(LlmRouting::from_config(&config))
+    .with_prompt_template(prompt)
+    .with_max_retries(config.api.max_retries)
+    .with_circuit_breakers(ProviderCircuits::new(
+        config.api.circuit_failure_threshold,
+        Duration::from_secs(config.api.circuit_open_seconds),
+    ))
+    .with_quota(LlmQuota::from_config(&config.rate_limit))
+    .with_usage_recorder(usage.clone());
+    if let Some(archive) = &archive {
+        recommender = recommender.with_archive(archive.clone());
+    }
+    let shutdown = Shutdown::new();
+    let mut app_state = api::gift::AppState::with_recommender(recommender)
+        .with_runtime_config(runtime.clone())
+        .with_shutdown(shutdown.clone())
+        .with_websocket_config(config.websocket.clone())
+        .with_authenticator(Authenticator::new(&config.auth))
+        .with_rate_limits(config.rate_limit.clone())
+        .with_usage_recorder(usage.clone())
+        .with_database_required(config.database_required());
+    if let Some(database) = database {
+        app_state = app_state.with_database(database);
+    }
+    // インスタンス間でセッションのイベントを配る（1台構成ではプロセス内だけで配る）
+    if config.pubsub.backend == PubSubBackendKind::Postgres {
+        // 他のインスタンスに届かないまま動き続けないよう、プロセス内のバスに切り替えずに停止する
+        let Some(database) = app_state.database() else {
+            tracing::error!("pubsub.backend postgres requires a database; refusing to start");
+            std::process::exit(2);
+        };
+        let bus = PgEventBus::spawn(database.get_pool(), config.pubsub.channel.clone());
+        app_state = app_state.with_event_bus(bus);
+    }
+    app_state.events().start();
+    tracing::info!("Event bus: {}", app_state.events().bus_name());
+    // 会話履歴はバックグラウンドでまとめて保存する
+    let history = HistoryWriter::spawn(history_store);
+    app_state = app_state.with_history(history.clone());
+    app_state.recommender().set_rules(runtime.current().gift_rules.clone());
+
+    // 設定の再読み込み時に、再起動せずに反映できる項目を差し替える
+    let recommender = app_state.recommender().clone();
+    runtime.on_reload(move |snapshot| {
+        if let Err(e) = log_level.set_level(&snapshot.config.logging.level) {
+            tracing::warn!("Failed to update log level: {:?}", e);
+        }
+        gift_cache.update_ttls(&snapshot.config.cache);
+        recommender.set_rules(snapshot.gift_rules.clone());
+    });
+    if config.rules.reload_interval_seconds > 0 {
+        runtime.spawn_watcher(Duration::from_secs(config.rules.reload_interval_seconds));
+    }
 
     // ルーターの設定
     let app = Router::new()
+        .merge(api::auth::auth_routes())
         .merge(api::gift::gift_routes())
+        .merge(api::chat::chat_routes())
+        .merge(api::history::history_routes())
+        .merge(api::admin::admin_routes())
+        .merge(api::metrics::metrics_routes())
+        .merge(api::health::health_routes())
+        .merge(api::websocket::websocket_routes())
+        .merge(api::sse::sse_routes())
+        .layer(middleware::from_fn_with_state(app_state.clone(), api::rate_limit::limit_requests))
+        .layer(middleware::from_fn(metrics::track_http))
+        .layer(TraceLayer::new_for_http().make_span_with(request::request_span))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
         .layer(cors)
         .with_state(app_state);
 
     // サーバーの起動
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
-    tracing::info!("Server listening on {}", addr);
-    
-    axum::serve(
-        tokio::net::TcpListener::bind(&addr)
-            .await
-            .unwrap(),
-        app
-    )
-    .await
-    .unwrap();
-} 
\ No newline at end of file
+    let addr = config.server_addr();
+    let listener = match tokio::net::TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            tracing::error!("Failed to bind {}: {:?}", addr, e);
+            std::process::exit(1);
+        }
+    };
+    tracing::info!("Server listening on {} ({})", addr, config.environment);
+
+    // SIGTERM / Ctrl-C を受けたら停止を開始する
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown::signal().await;
+            shutdown.trigger();
+        }
+    });
+
+    // 停止が始まると新しい接続の受け付けをやめ、処理中のHTTPリクエストの完了を待つ
+    let mut server = tokio::spawn(
+        // 接続元のアドレスはWebSocketの接続数の制限に使う
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown({
+                let shutdown = shutdown.clone();
+                async move { shutdown.triggered().await }
+            })
+            .into_future(),
+    );
+
+    let timeout = Duration::from_secs(config.shutdown_timeout_seconds);
+    let deadline = tokio::select! {
+        result = &mut server => {
+            // 停止要求より前にサーバーが終了した
+            match result {
+                Ok(Err(e)) => tracing::error!("Server error: {:?}", e),
+                Err(e) => tracing::error!("Server task failed: {:?}", e),
+                Ok(Ok(())) => {}
+            }
+            tokio::time::Instant::now() + timeout
+        }
+        _ = shutdown.triggered() => {
+            tracing::info!(
+                "Shutting down: waiting up to {:?} for {} in-flight session(s) and request(s)",
+                timeout,
+                shutdown.inflight(),
+            );
+            let deadline = tokio::time::Instant::now() + timeout;
+
+            // WebSocketセッションには再接続を促すメッセージを送ってから閉じる
+            if !shutdown.drain(deadline).await {
+                tracing::warn!(
+                    "Shutdown deadline reached with {} in-flight session(s) and request(s)",
+                    shutdown.inflight(),
+                );
+            }
+            match tokio::time::timeout_at(deadline, &mut server).await {
+                Ok(Ok(Err(e))) => tracing::error!("Server error: {:?}", e),
+                Ok(Err(e)) => tracing::error!("Server task failed: {:?}", e),
+                Err(_) => tracing::warn!("Shutdown deadline reached with open connections"),
+                Ok(Ok(Ok(()))) => {}
+            }
+            deadline
+        }
+    };
+
+    // 書き込み待ちの会話履歴と利用記録、推薦を、残りの猶予の範囲で保存してから終了する
+    let flush = async {
+        history.flush().await;
+        usage.flush().await;
+        if let Some(archive) = &archive {
+            archive.flush().await;
+        }
+    };
+    if tokio::time::timeout_at(deadline, flush).await.is_err() {
+        tracing::warn!("Shutdown deadline reached before pending history, usage and recommendation records were saved");
+    }
+    tracing::info!("Shutdown complete");
+}
@@ -0,0 +1,204 @@
+use std::sync::OnceLock;
+use std::time::Instant;
+use axum::extract::{MatchedPath, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+
+// 推薦は上流のLLM検索を含むため、HTTPより長めのバケットを使う
+const RECOMMENDATION_BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// プロセス全体で共有するPrometheusのメトリクス
+///
+/// 各コンポーネントは [`global`] から直接記録し、`/metrics` でまとめて公開する。
+pub struct Metrics {
+    registry: Registry,
+    pub http_requests: IntCounterVec,
+    pub http_request_duration: HistogramVec,
+    pub websocket_sessions: IntGauge,
+    pub chat_messages: IntCounterVec,
+    pub recommendation_duration: HistogramVec,
+    pub upstream_requests: IntCounterVec,
+    pub upstream_errors: IntCounterVec,
+    pub upstream_retries: IntCounterVec,
+    pub upstream_fallbacks: IntCounterVec,
+    pub upstream_duration: HistogramVec,
+    pub upstream_circuit_state: IntGaugeVec,
+    pub cache_lookups: IntCounterVec,
+    pub cache_evictions: IntCounterVec,
+    pub cache_expirations: IntCounterVec,
+    pub rate_limited: IntCounterVec,
+    pub prompt_injection_flags: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("gift_advisor".to_string()), None)?;
+
+        let metrics = Self {
+            http_requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests by route, method and status"),
+                &["route", "method", "status"],
+            )?,
+            http_request_duration: HistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "HTTP request latency"),
+                &["route", "method"],
+            )?,
+            websocket_sessions: IntGauge::new("websocket_sessions_active", "Open WebSocket chat sessions")?,
+            chat_messages: IntCounterVec::new(
+                Opts::new("chat_messages_total", "Classified chat messages by intent"),
+                &["intent"],
+            )?,
+            recommendation_duration: HistogramVec::new(
+                HistogramOpts::new("recommendation_duration_seconds", "Time to produce gift recommendations")
+                    .buckets(RECOMMENDATION_BUCKETS.to_vec()),
+                &["outcome"],
+            )?,
+            upstream_requests: IntCounterVec::new(
+                Opts::new("upstream_requests_total", "Calls to upstream providers"),
+                &["provider"],
+            )?,
+            upstream_errors: IntCounterVec::new(
+                Opts::new("upstream_errors_total", "Failed calls to upstream providers"),
+                &["provider", "kind"],
+            )?,
+            upstream_retries: IntCounterVec::new(
+                Opts::new("upstream_retries_total", "Retried calls to upstream providers"),
+                &["provider"],
+            )?,
+            upstream_fallbacks: IntCounterVec::new(
+                Opts::new("upstream_fallbacks_total", "Models that failed and fell back to the next in the chain"),
+                &["provider", "model"],
+            )?,
+            upstream_duration: HistogramVec::new(
+                HistogramOpts::new("upstream_request_duration_seconds", "Upstream call latency")
+                    .buckets(RECOMMENDATION_BUCKETS.to_vec()),
+                &["provider"],
+            )?,
+            upstream_circuit_state: IntGaugeVec::new(
+                Opts::new(
+                    "upstream_circuit_state",
+                    "Circuit breaker state by provider (0 = closed, 1 = half-open, 2 = open)",
+                ),
+                &["provider"],
+            )?,
+            cache_lookups: IntCounterVec::new(
+                Opts::new("gift_cache_lookups_total", "GiftCache lookups by result"),
+                &["result"],
+            )?,
+            cache_evictions: IntCounterVec::new(
+                Opts::new("gift_cache_evictions_total", "Entries evicted to stay within max_size"),
+                &["backend"],
+            )?,
+            cache_expirations: IntCounterVec::new(
+                Opts::new("gift_cache_expirations_total", "Expired entries removed by cleanup"),
+                &["backend"],
+            )?,
+            rate_limited: IntCounterVec::new(
+                Opts::new("rate_limited_total", "Requests and messages rejected by rate limits and quotas"),
+                &["scope"],
+            )?,
+            prompt_injection_flags: IntCounterVec::new(
+                Opts::new("prompt_injection_flags_total", "User inputs flagged as possible prompt injection by kind"),
+                &["kind"],
+            )?,
+            registry,
+        };
+
+        metrics.registry.register(Box::new(metrics.http_requests.clone()))?;
+        metrics.registry.register(Box::new(metrics.http_request_duration.clone()))?;
+        metrics.registry.register(Box::new(metrics.websocket_sessions.clone()))?;
+        metrics.registry.register(Box::new(metrics.chat_messages.clone()))?;
+        metrics.registry.register(Box::new(metrics.recommendation_duration.clone()))?;
+        metrics.registry.register(Box::new(metrics.upstream_requests.clone()))?;
+        metrics.registry.register(Box::new(metrics.upstream_errors.clone()))?;
+        metrics.registry.register(Box::new(metrics.upstream_retries.clone()))?;
+        metrics.registry.register(Box::new(metrics.upstream_fallbacks.clone()))?;
+        metrics.registry.register(Box::new(metrics.upstream_duration.clone()))?;
+        metrics.registry.register(Box::new(metrics.upstream_circuit_state.clone()))?;
+        metrics.registry.register(Box::new(metrics.cache_lookups.clone()))?;
+        metrics.registry.register(Box::new(metrics.cache_evictions.clone()))?;
+        metrics.registry.register(Box::new(metrics.cache_expirations.clone()))?;
+        metrics.registry.register(Box::new(metrics.rate_limited.clone()))?;
+        metrics.registry.register(Box::new(metrics.prompt_injection_flags.clone()))?;
+        Ok(metrics)
+    }
+
+    /// Prometheusのテキスト形式で出力する
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            tracing::warn!("Failed to encode metrics: {:?}", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+
+    /// WebSocketセッションの開始を記録する。返り値が破棄されると終了として扱う
+    pub fn websocket_session(&self) -> WebSocketSessionGuard {
+        self.websocket_sessions.inc();
+        WebSocketSessionGuard { _private: () }
+    }
+}
+
+pub fn global() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics::new().expect("metric definitions must be valid"))
+}
+
+pub struct WebSocketSessionGuard {
+    _private: (),
+}
+
+impl Drop for WebSocketSessionGuard {
+    fn drop(&mut self) {
+        global().websocket_sessions.dec();
+    }
+}
+
+/// ルーティング後のパステンプレート（`/recommendations` など）ごとにリクエスト数と処理時間を記録する
+///
+/// IDなどを含む実際のパスではなくテンプレートを使うため、ラベルの種類は増えない。
+pub async fn track_http(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let method = request.method().to_string();
+    let started = Instant::now();
+
+    let response = next.run(request).await;
+
+    let metrics = global();
+    metrics
+        .http_requests
+        .with_label_values(&[&route, &method, response.status().as_str()])
+        .inc();
+    metrics
+        .http_request_duration
+        .with_label_values(&[&route, &method])
+        .observe(started.elapsed().as_secs_f64());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_includes_recorded_metrics() {
+        let metrics = global();
+        metrics.upstream_retries.with_label_values(&["test_provider"]).inc();
+        {
+            let _session = metrics.websocket_session();
+            assert!(metrics.websocket_sessions.get() >= 1);
+        }
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("gift_advisor_upstream_retries_total{provider=\"test_provider\"} 1"));
+        assert!(rendered.contains("# TYPE gift_advisor_websocket_sessions_active gauge"));
+    }
+}
@@ -93,6 +93,11 @@ async fn test_cache_functionality() -> Result<()> {
         price: 10000,
         category: "テスト".to_string(),
         url: None,
+        store: None,
+        manner_advice: None,
+        store_url: None,
+        sources: Vec::new(),
+        prompt_version: None,
         cached_at: std::time::SystemTime::now(),
     };
 